@@ -77,6 +77,8 @@ pub struct Commit<B> {
     pub original_oid: Option<OriginalOid<B>>,
     pub author: Option<PersonIdent<B>>,
     pub committer: PersonIdent<B>,
+    /// Signatures from `gpgsig` directives, at most one per hash algorithm.
+    pub signatures: Vec<CommitSignature<B>>,
     pub encoding: Option<Encoding<B>>,
     pub message: B,
     pub from: Option<Commitish<B>>,
@@ -93,7 +95,11 @@ pub struct Tag<B> {
     // TODO: `tagger` is optional in fast-import.c, but required in the
     // fast-import docs.
     pub tagger: Option<PersonIdent<B>>,
+    /// The message, without a trailing signature.
     pub message: B,
+    /// The signature block, which fast-export appends to the message of
+    /// signed tags.
+    pub signature: Option<B>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -249,10 +255,12 @@ impl<B> Branch<B> {
 ///
 /// # Differences from fast-import
 ///
-/// If `:0` is explicitly used in a mark definition, it is rejected as an error.
-/// fast-import allows it and treats it as if no mark was given, even though its
-/// [docs](https://git-scm.com/docs/git-fast-import#_mark) state it is reserved.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// If `:0` is explicitly used in a mark definition, it is rejected as an error
+/// in strict mode. fast-import allows it and treats it as if no mark was given,
+/// even though its [docs](https://git-scm.com/docs/git-fast-import#_mark) state
+/// it is reserved. That behavior is emulated with
+/// [`Compat::FastImport`](crate::parse::Compat::FastImport).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Mark {
     pub mark: NonZeroU64, // uintmax_t in fast-import (at least u64)
@@ -313,7 +321,7 @@ impl Mode {
 // fuzzy.
 // TODO: Parse refs like `git check-ref-format`.
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Objectish<B> {
     Mark(Mark),
     // TODO: Parse branches and oids
@@ -350,6 +358,33 @@ pub struct Encoding<B> {
     pub encoding: B,
 }
 
+/// A commit signature from a `gpgsig` directive.
+///
+/// Only emitted by `git fast-export --signed-commits`, since Git 2.48.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitSignature<B> {
+    /// The hash algorithm of the commit that was signed.
+    pub hash_algo: HashAlgorithm,
+    /// The format of the signature. This is omitted before Git 2.51.
+    pub format: Option<SignatureFormat>,
+    pub signature: B,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+// Corresponds to `git.git/gpg-interface.c:get_signature_format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SignatureFormat {
+    OpenPgp,
+    X509,
+    Ssh,
+    Unknown,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DataHeader<B> {
     Counted {
@@ -394,7 +429,7 @@ impl DataBuf {
                 Err(DelimitedError::DelimContainsNul)
             } else if !self.data.ends_with(b"\n") {
                 Err(DelimitedError::NoFinalLf)
-            } else if self.data.split(|&b| b == b'\n').any(|line| line == &*delim) {
+            } else if self.data.split(|&b| b == b'\n').any(|line| line == delim) {
                 Err(DelimitedError::DataContainsDelim)
             } else {
                 Ok(())
@@ -497,6 +532,7 @@ impl<T, U> MapBytes<T, U> for Commit<T> {
             original_oid: self.original_oid.map_bytes(f),
             author: self.author.map_bytes(f),
             committer: self.committer.map_bytes(f),
+            signatures: self.signatures.map_bytes(f),
             encoding: self.encoding.map_bytes(f),
             message: f(self.message),
             from: self.from.map_bytes(f),
@@ -517,6 +553,7 @@ impl<T, U> MapBytes<T, U> for Tag<T> {
             original_oid: self.original_oid.map_bytes(f),
             tagger: self.tagger.map_bytes(f),
             message: f(self.message),
+            signature: self.signature.map(f),
         }
     }
 }
@@ -775,6 +812,19 @@ impl<T, U> MapBytes<T, U> for Encoding<T> {
     }
 }
 
+impl<T, U> MapBytes<T, U> for CommitSignature<T> {
+    type Output = CommitSignature<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        CommitSignature {
+            hash_algo: self.hash_algo,
+            format: self.format,
+            signature: f(self.signature),
+        }
+    }
+}
+
 impl<T, U> MapBytes<T, U> for DataHeader<T> {
     type Output = DataHeader<U>;
 
@@ -5,7 +5,18 @@
 
 use std::io::{self, Write};
 
-use crate::command::{DataBuf, FileSize, Mark, OptionGit, OptionOther, OriginalOid, UnitFactor};
+use crate::{
+    command::{
+        Alias, Blobish, CatBlob, Commit, CommitSignature, Commitish, DataBuf, DataHeader,
+        DateFormat, Done, Encoding, FastImportPath, Feature, FileSize, GetMark, HashAlgorithm, Ls,
+        Mark, Objectish, OptionCommand, OptionGit, OptionOther, OriginalOid, PersonIdent, Progress,
+        Reset, SignatureFormat, Tag, Treeish, UnitFactor,
+    },
+    parse::{
+        Change, CommitLs, DataRef, FileCopyChange, FileDeleteChange, FileModifyChange,
+        FileRenameChange, NoteModifyChange,
+    },
+};
 
 pub trait Dump {
     fn dump<W: Write>(&self, w: &mut W) -> io::Result<()>;
@@ -26,8 +37,8 @@ impl<B: AsRef<[u8]>> Dump for OptionGit<B> {
                 size.dump(w)?;
                 w.write_all(b"\n")
             }
-            OptionGit::Depth { depth } => write!(w, "depth={depth}\n"),
-            OptionGit::ActiveBranches { count } => write!(w, "active-branches={count}\n"),
+            OptionGit::Depth { depth } => writeln!(w, "depth={depth}"),
+            OptionGit::ActiveBranches { count } => writeln!(w, "active-branches={count}"),
             OptionGit::ExportPackEdges { path } => {
                 write!(w, "export-pack-edges=")?;
                 w.write_all(path.as_ref())?;
@@ -50,7 +61,7 @@ impl<B: AsRef<[u8]>> Dump for OptionOther<B> {
 
 impl Dump for Mark {
     fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        write!(w, "mark :{}\n", self.mark)
+        writeln!(w, "mark :{}", self.mark)
     }
 }
 
@@ -62,6 +73,336 @@ impl<B: AsRef<[u8]>> Dump for OriginalOid<B> {
     }
 }
 
+/// Writes a commit header, up to its `merge` directives. Its file changes
+/// follow and it is terminated by a blank line, which are both written
+/// separately.
+impl<B: AsRef<[u8]>> Dump for Commit<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"commit ")?;
+        w.write_all(self.branch.branch.as_ref())?;
+        w.write_all(b"\n")?;
+        self.mark.dump(w)?;
+        self.original_oid.dump(w)?;
+        if let Some(author) = &self.author {
+            dump_ident(w, b"author ", author)?;
+        }
+        dump_ident(w, b"committer ", &self.committer)?;
+        for signature in &self.signatures {
+            signature.dump(w)?;
+        }
+        self.encoding.dump(w)?;
+        dump_data(w, self.message.as_ref())?;
+        if let Some(from) = &self.from {
+            w.write_all(b"from ")?;
+            dump_commitish(w, from)?;
+            w.write_all(b"\n")?;
+        }
+        for merge in &self.merge {
+            w.write_all(b"merge ")?;
+            dump_commitish(w, merge)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for CommitSignature<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"gpgsig ")?;
+        w.write_all(match self.hash_algo {
+            HashAlgorithm::Sha1 => b"sha1",
+            HashAlgorithm::Sha256 => b"sha256",
+        })?;
+        if let Some(format) = self.format {
+            w.write_all(match format {
+                SignatureFormat::OpenPgp => b" openpgp",
+                SignatureFormat::X509 => b" x509",
+                SignatureFormat::Ssh => b" ssh",
+                SignatureFormat::Unknown => b" unknown",
+            })?;
+        }
+        w.write_all(b"\n")?;
+        dump_data(w, self.signature.as_ref())
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Encoding<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"encoding ")?;
+        w.write_all(self.encoding.as_ref())?;
+        w.write_all(b"\n")
+    }
+}
+
+/// Writes a file change. The data for an inline change is written separately.
+impl<B: AsRef<[u8]>> Dump for Change<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Change::FileModify(change) => change.dump(w),
+            Change::FileDelete(change) => change.dump(w),
+            Change::FileRename(change) => change.dump(w),
+            Change::FileCopy(change) => change.dump(w),
+            Change::FileDeleteAll => w.write_all(b"deleteall\n"),
+            Change::NoteModify(change) => change.dump(w),
+            Change::Ls(ls) => ls.dump(w),
+            Change::CatBlob(cat_blob) => cat_blob.dump(w),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for FileModifyChange<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "M {:06o} ", self.mode as u16)?;
+        dump_data_ref(w, &self.data_ref)?;
+        w.write_all(b" ")?;
+        dump_path(w, self.path.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for FileDeleteChange<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"D ")?;
+        dump_path(w, self.path.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for FileRenameChange<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"R ")?;
+        dump_path(w, self.source.as_ref(), true)?;
+        w.write_all(b" ")?;
+        dump_path(w, self.dest.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for FileCopyChange<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"C ")?;
+        dump_path(w, self.source.as_ref(), true)?;
+        w.write_all(b" ")?;
+        dump_path(w, self.dest.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for NoteModifyChange<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"N ")?;
+        dump_data_ref(w, &self.data_ref)?;
+        w.write_all(b" ")?;
+        dump_commitish(w, &self.commit)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for CommitLs<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"ls ")?;
+        if let Some(root) = &self.root {
+            dump_treeish(w, root)?;
+            w.write_all(b" ")?;
+        }
+        dump_path(w, self.path.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Tag<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"tag ")?;
+        w.write_all(self.name.name.as_ref())?;
+        w.write_all(b"\n")?;
+        self.mark.dump(w)?;
+        w.write_all(b"from ")?;
+        dump_objectish(w, &self.from)?;
+        w.write_all(b"\n")?;
+        self.original_oid.dump(w)?;
+        if let Some(tagger) = &self.tagger {
+            dump_ident(w, b"tagger ", tagger)?;
+        }
+        let message = self.message.as_ref();
+        match &self.signature {
+            Some(signature) => {
+                let signature = signature.as_ref();
+                writeln!(w, "data {}", message.len() + signature.len())?;
+                w.write_all(message)?;
+                w.write_all(signature)?;
+                w.write_all(b"\n")
+            }
+            None => dump_data(w, message),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Reset<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"reset ")?;
+        w.write_all(self.branch.branch.as_ref())?;
+        w.write_all(b"\n")?;
+        if let Some(from) = &self.from {
+            w.write_all(b"from ")?;
+            dump_commitish(w, from)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Ls<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"ls ")?;
+        dump_treeish(w, &self.root)?;
+        w.write_all(b" ")?;
+        dump_path(w, self.path.as_ref(), false)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for CatBlob<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"cat-blob ")?;
+        match &self.blob {
+            Blobish::Mark(mark) => dump_mark_ref(w, *mark)?,
+            Blobish::Oid(oid) => w.write_all(oid.as_ref())?,
+        }
+        w.write_all(b"\n")
+    }
+}
+
+impl Dump for GetMark {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"get-mark ")?;
+        dump_mark_ref(w, self.mark)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl Dump for Done {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Done::Explicit => w.write_all(b"done\n"),
+            Done::Eof => Ok(()),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Alias<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"alias\n")?;
+        self.mark.dump(w)?;
+        w.write_all(b"to ")?;
+        dump_commitish(w, &self.to)?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Progress<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"progress ")?;
+        w.write_all(self.message.as_ref())?;
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for Feature<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(b"feature ")?;
+        match self {
+            Feature::DateFormat { format } => {
+                w.write_all(b"date-format=")?;
+                w.write_all(match format {
+                    DateFormat::Raw => b"raw",
+                    DateFormat::RawPermissive => b"raw-permissive",
+                    DateFormat::Rfc2822 => b"rfc2822",
+                    DateFormat::Now => b"now",
+                })?;
+            }
+            Feature::ImportMarks {
+                path,
+                ignore_missing,
+            } => {
+                w.write_all(if *ignore_missing {
+                    b"import-marks-if-exists="
+                } else {
+                    b"import-marks="
+                })?;
+                path.dump(w)?;
+            }
+            Feature::ExportMarks { path } => {
+                w.write_all(b"export-marks=")?;
+                path.dump(w)?;
+            }
+            Feature::Alias => w.write_all(b"alias")?,
+            Feature::RewriteSubmodulesTo {
+                submodule_name,
+                marks_path,
+            } => {
+                w.write_all(b"rewrite-submodules-to=")?;
+                w.write_all(submodule_name.as_ref())?;
+                w.write_all(b":")?;
+                w.write_all(marks_path.as_ref())?;
+            }
+            Feature::RewriteSubmodulesFrom {
+                submodule_name,
+                marks_path,
+            } => {
+                w.write_all(b"rewrite-submodules-from=")?;
+                w.write_all(submodule_name.as_ref())?;
+                w.write_all(b":")?;
+                w.write_all(marks_path.as_ref())?;
+            }
+            Feature::GetMark => w.write_all(b"get-mark")?,
+            Feature::CatBlob => w.write_all(b"cat-blob")?,
+            Feature::RelativeMarks { relative } => {
+                w.write_all(if *relative {
+                    b"relative-marks"
+                } else {
+                    b"no-relative-marks"
+                })?;
+            }
+            Feature::Done => w.write_all(b"done")?,
+            Feature::Force => w.write_all(b"force")?,
+            Feature::Notes => w.write_all(b"notes")?,
+            Feature::Ls => w.write_all(b"ls")?,
+            Feature::Other { feature } => w.write_all(feature.as_ref())?,
+        }
+        w.write_all(b"\n")
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for FastImportPath<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(self.path.as_ref())
+    }
+}
+
+impl<B: AsRef<[u8]>> Dump for OptionCommand<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            OptionCommand::Git(option) => option.dump(w),
+            OptionCommand::Other(option) => option.dump(w),
+        }
+    }
+}
+
+/// Writes the header of a data stream. Its contents follow, then an optional
+/// LF.
+impl<B: AsRef<[u8]>> Dump for DataHeader<B> {
+    fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            DataHeader::Counted { len } => writeln!(w, "data {len}"),
+            DataHeader::Delimited { delim } => {
+                w.write_all(b"data <<")?;
+                w.write_all(delim.as_ref())?;
+                w.write_all(b"\n")
+            }
+        }
+    }
+}
+
 impl Dump for DataBuf {
     fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
         if let Some(delim) = &self.delim {
@@ -77,9 +418,7 @@ impl Dump for DataBuf {
                 return Ok(());
             }
         }
-        write!(w, "data {}\n", self.data.len())?;
-        w.write_all(&self.data)?;
-        w.write_all(b"\n") // Optional LF
+        dump_data(w, &self.data)
     }
 }
 
@@ -96,6 +435,91 @@ impl Dump for FileSize {
     }
 }
 
+/// Writes counted data with the given contents.
+pub fn dump_data<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    writeln!(w, "data {}", data.len())?;
+    w.write_all(data)?;
+    w.write_all(b"\n") // Optional LF
+}
+
+fn dump_ident<W: Write, B: AsRef<[u8]>>(
+    w: &mut W,
+    directive: &[u8],
+    ident: &PersonIdent<B>,
+) -> io::Result<()> {
+    w.write_all(directive)?;
+    let name = ident.name.as_ref();
+    if !name.is_empty() {
+        w.write_all(name)?;
+        w.write_all(b" ")?;
+    }
+    w.write_all(b"<")?;
+    w.write_all(ident.email.as_ref())?;
+    w.write_all(b"> ")?;
+    w.write_all(ident.date.as_ref())?;
+    w.write_all(b"\n")
+}
+
+#[inline]
+fn dump_mark_ref<W: Write>(w: &mut W, mark: Mark) -> io::Result<()> {
+    write!(w, ":{}", mark.mark)
+}
+
+fn dump_objectish<W: Write, B: AsRef<[u8]>>(w: &mut W, object: &Objectish<B>) -> io::Result<()> {
+    match object {
+        Objectish::Mark(mark) => dump_mark_ref(w, *mark),
+        Objectish::BranchOrOid(object) => w.write_all(object.as_ref()),
+    }
+}
+
+#[inline]
+fn dump_commitish<W: Write, B: AsRef<[u8]>>(w: &mut W, commit: &Commitish<B>) -> io::Result<()> {
+    dump_objectish(w, &commit.commit)
+}
+
+fn dump_treeish<W: Write, B: AsRef<[u8]>>(w: &mut W, tree: &Treeish<B>) -> io::Result<()> {
+    match tree {
+        Treeish::Mark(mark) => dump_mark_ref(w, *mark),
+        Treeish::Oid(oid) => w.write_all(oid.as_ref()),
+    }
+}
+
+fn dump_data_ref<W: Write, B: AsRef<[u8]>>(w: &mut W, data_ref: &DataRef<B>) -> io::Result<()> {
+    match data_ref {
+        DataRef::Mark(mark) => dump_mark_ref(w, *mark),
+        DataRef::Oid(oid) => w.write_all(oid.as_ref()),
+        DataRef::Inline(_) => w.write_all(b"inline"),
+    }
+}
+
+/// Writes a path, quoting it only when fast-import would not parse it
+/// literally. A path that is followed by another argument is also quoted when
+/// it contains a space.
+///
+// Corresponds to `git.git/quote.c:quote_c_style`, but without quoting
+// non-ASCII bytes.
+fn dump_path<W: Write>(w: &mut W, path: &[u8], space_terminated: bool) -> io::Result<()> {
+    let needs_quote = path.starts_with(b"\"")
+        || path
+            .iter()
+            .any(|&b| b == b'\n' || b == b'\0' || (space_terminated && b == b' '));
+    if !needs_quote {
+        return w.write_all(path);
+    }
+    w.write_all(b"\"")?;
+    for &b in path {
+        match b {
+            b'"' => w.write_all(b"\\\"")?,
+            b'\\' => w.write_all(b"\\\\")?,
+            b'\n' => w.write_all(b"\\n")?,
+            b'\t' => w.write_all(b"\\t")?,
+            0..=0x1f | 0x7f => write!(w, "\\{b:03o}")?,
+            _ => w.write_all(&[b])?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
 impl<T: Dump> Dump for Option<T> {
     fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
         if let Some(value) = self {
@@ -177,4 +601,66 @@ mod tests {
             b"option vcs some config\n",
         );
     }
+
+    #[test]
+    fn commit() {
+        let ident = PersonIdent {
+            name: &b"Name"[..],
+            email: b"user@example.com",
+            date: b"1700000000 +0000",
+        };
+        let commit = Commit {
+            branch: crate::command::Branch {
+                branch: &b"refs/heads/main"[..],
+            },
+            mark: Mark::new(2),
+            original_oid: Some(OriginalOid { oid: b"0123abcd" }),
+            author: Some(ident.clone()),
+            committer: ident,
+            signatures: Vec::new(),
+            encoding: None,
+            message: b"Message\n",
+            from: Some(Commitish {
+                commit: Objectish::Mark(Mark::new(1).unwrap()),
+            }),
+            merge: vec![Commitish {
+                commit: Objectish::BranchOrOid(b"refs/heads/topic"),
+            }],
+        };
+        assert_eq!(
+            dump(commit).as_slice(),
+            &b"commit refs/heads/main\n\
+               mark :2\n\
+               original-oid 0123abcd\n\
+               author Name <user@example.com> 1700000000 +0000\n\
+               committer Name <user@example.com> 1700000000 +0000\n\
+               data 8\n\
+               Message\n\n\
+               from :1\n\
+               merge refs/heads/topic\n"[..],
+        );
+    }
+
+    #[test]
+    fn path_quoting() {
+        assert_eq!(
+            dump(Change::FileDelete(FileDeleteChange { path: &b"a b"[..] })),
+            b"D a b\n",
+        );
+        assert_eq!(
+            dump(Change::FileRename(FileRenameChange {
+                source: &b"a b"[..],
+                dest: b"\"c\"\n",
+            })),
+            b"R \"a b\" \"\\\"c\\\"\\n\"\n",
+        );
+        assert_eq!(
+            dump(Change::FileModify(FileModifyChange {
+                mode: crate::command::Mode::Exe,
+                data_ref: DataRef::Inline(DataHeader::Counted { len: 0 }),
+                path: &b"x\ty"[..],
+            })),
+            b"M 100755 inline x\ty\n",
+        );
+    }
 }
@@ -13,5 +13,5 @@ pub mod parse;
 mod refs;
 
 pub use bytes::FromBytes;
-pub use dump::Dump;
+pub use dump::{dump_data, Dump};
 pub use refs::*;
@@ -1,15 +1,35 @@
-use std::{io::BufRead, str};
+use std::{io::BufRead, str, sync::atomic::Ordering};
 
 use memchr::memchr;
+use thiserror::Error;
 
 use crate::{
-    command::{Blobish, CatBlob, Commitish, Mark, Mode, Treeish},
-    parse::{parse_ls, BufInput, DirectiveParser, PResult, ParseError},
+    command::{Blobish, CatBlob, Commitish, DataHeader, MapBytes, Mark, Mode, Treeish},
+    parse::{
+        parse_ls, BufInput, DataReader, DataReaderError, DirectiveParser, PResult, ParseError,
+        Parser, QuirkKind,
+    },
 };
 
+/// A pull parser for the file changes of the current commit, which is opened
+/// with [`Parser::changes`].
+///
+/// The data for `inline` changes is read with [`ChangeIter::open_data`]. If it
+/// is not read, it is skipped by the next call to [`ChangeIter::next`].
 pub struct ChangeIter<'a, R> {
-    /// A borrow of `Parser::input`.
-    input: &'a BufInput<R>,
+    /// A borrow of the parser. Only one `ChangeIter` can be opened per commit,
+    /// which is guarded by `Parser::changes_opened`, so it has exclusive access
+    /// to `Parser::input` and `Parser::data_state`, as long as it has no open
+    /// `DataReader`.
+    parser: &'a Parser<R>,
+}
+
+/// An error from opening a [`ChangeIter`].
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Hash)]
+pub enum ChangeIterError {
+    /// The changes of a commit can only be opened once.
+    #[error("commit changes already opened for reading")]
+    AlreadyOpened,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -26,39 +46,44 @@ pub enum Change<B> {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileModifyChange<B> {
-    data_ref: DataRef<B>,
-    mode: Mode,
-    path: B,
+    pub data_ref: DataRef<B>,
+    pub mode: Mode,
+    pub path: B,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileDeleteChange<B> {
-    path: B,
+    pub path: B,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileRenameChange<B> {
-    source: B,
-    dest: B,
+    pub source: B,
+    pub dest: B,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileCopyChange<B> {
-    source: B,
-    dest: B,
+    pub source: B,
+    pub dest: B,
 }
 
+/// Adds or replaces the note for a commit, in a commit to a notes ref.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct NoteModifyChange<B> {
-    data_ref: DataRef<B>,
-    commit: Commitish<B>,
+    pub data_ref: DataRef<B>,
+    /// The commit annotated by the note.
+    pub commit: Commitish<B>,
 }
 
+/// The blob contents for a file or note change.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DataRef<B> {
     Mark(Mark),
     Oid(B),
-    Inline,
+    /// The contents follow the change in a `data` directive and are read with
+    /// [`ChangeIter::open_data`].
+    Inline(DataHeader<B>),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -68,8 +93,54 @@ pub struct CommitLs<B> {
 }
 
 impl<'a, R: BufRead> ChangeIter<'a, R> {
-    pub fn next(&'a self) -> PResult<Option<Change<&'a [u8]>>> {
-        let Some(line) = self.input.next_directive()? else {
+    #[inline]
+    pub(super) fn new(parser: &'a Parser<R>) -> Self {
+        ChangeIter { parser }
+    }
+
+    /// Parses the next file change in the current commit. Returns `None`, when
+    /// the commit has no more changes.
+    ///
+    /// The returned changes borrow from the parser, so remain valid until the
+    /// next call to [`Parser::next`].
+    ///
+    // Corresponds to the `file_change*` loop in
+    // `git.git/builtin/fast-import.c:parse_new_commit`. It cannot implement
+    // `Iterator`, because the data for an inline change must be read before
+    // the next change.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> PResult<Option<Change<&'a [u8]>>> {
+        if self.parser.changes_finished.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        // Skip the data of the previous inline change, if the user didn't.
+        //
+        // SAFETY: No `DataReader` is alive, because `ChangeIter::open_data`
+        // borrows `self` mutably.
+        let data_state = unsafe { &mut *self.parser.data_state.get() };
+        if !data_state.finished() {
+            if self.parser.data_opened.load(Ordering::Acquire) {
+                return Err(DataReaderError::Unfinished.into());
+            }
+            self.input().skip_data(data_state)?;
+        }
+
+        let change = self.parse_change();
+        if !matches!(change, Ok(Some(_))) {
+            self.parser.changes_finished.store(true, Ordering::Release);
+        }
+        change
+    }
+
+    /// Opens the data of the current inline change for reading. Only one
+    /// instance of [`DataReader`] can exist at a time.
+    #[inline]
+    pub fn open_data(&mut self) -> PResult<DataReader<'_, R>> {
+        DataReader::open(self.parser)
+    }
+
+    fn parse_change(&self) -> PResult<Option<Change<&'a [u8]>>> {
+        let Some(line) = self.input().next_directive()? else {
             return Ok(None);
         };
 
@@ -89,26 +160,35 @@ impl<'a, R: BufRead> ChangeIter<'a, R> {
             self.parse_ls(args)
         } else if let Some(data_ref) = line.strip_prefix(b"cat-blob ") {
             self.parse_cat_blob(data_ref)
+        } else if line.is_empty() {
+            // The blank line ending a commit is consumed.
+            return Ok(None);
         } else {
-            self.input.unread_directive();
+            self.input().unread_directive();
             return Ok(None);
         };
         change.map(Some)
     }
 
+    #[inline(always)]
+    fn input(&self) -> &'a BufInput<R> {
+        &self.parser.input
+    }
+
     // Corresponds to `git.git/builtin/fast-import.c:file_change_m`.
-    fn parse_file_modify(&'a self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+    fn parse_file_modify(&self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
         let (mode, rest) = split_at_space(args).ok_or(ParseError::NoSpaceAfterMode)?;
         let mode = Mode::parse(mode)?;
 
         let (data_ref, path) = split_at_space(rest).ok_or(ParseError::NoSpaceAfterDataRef)?;
-        let data_ref = DataRef::parse(data_ref)?;
         let path = self
-            .unquote_eol(path)
+            .unquote_eol(path)?
             .ok_or(ParseError::JunkAfterFileModifyPath)?;
+        // Like fast-import, read the header of inline data only after the
+        // path has been parsed.
+        let data_ref = self.parse_data_ref(data_ref)?;
 
         // TODO: Emit `cat-blob` commands before the modify.
-        // TODO: Parse data.
 
         Ok(Change::from(FileModifyChange {
             data_ref,
@@ -118,110 +198,136 @@ impl<'a, R: BufRead> ChangeIter<'a, R> {
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:file_change_d`.
-    fn parse_file_delete(&'a self, path: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+    fn parse_file_delete(&self, path: &'a [u8]) -> PResult<Change<&'a [u8]>> {
         let path = self
-            .unquote_eol(path)
+            .unquote_eol(path)?
             .ok_or(ParseError::JunkAfterFileDeletePath)?;
         Ok(Change::from(FileDeleteChange { path }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:file_change_cr(s, b, 1)`.
-    fn parse_file_rename(&'a self, paths: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+    fn parse_file_rename(&self, paths: &'a [u8]) -> PResult<Change<&'a [u8]>> {
         let (source, dest) = self.parse_file_rename_copy(paths)?;
         Ok(Change::from(FileRenameChange { source, dest }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:file_change_cr(s, b, 0)`.
-    fn parse_file_copy(&'a self, paths: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+    fn parse_file_copy(&self, paths: &'a [u8]) -> PResult<Change<&'a [u8]>> {
         let (source, dest) = self.parse_file_rename_copy(paths)?;
         Ok(Change::from(FileCopyChange { source, dest }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:file_change_cr`.
-    fn parse_file_rename_copy(&'a self, paths: &'a [u8]) -> PResult<(&'a [u8], &'a [u8])> {
+    fn parse_file_rename_copy(&self, paths: &'a [u8]) -> PResult<(&'a [u8], &'a [u8])> {
         let (source, dest) = self
-            .unquote_space(paths)
+            .unquote_space(paths)?
             .ok_or(ParseError::NoSpaceAfterSource)?;
         if dest.is_empty() {
             return Err(ParseError::MissingDest.into());
         }
-        let dest = self.unquote_eol(dest).ok_or(ParseError::JunkAfterDest)?;
+        let dest = self.unquote_eol(dest)?.ok_or(ParseError::JunkAfterDest)?;
         Ok((source, dest))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:file_change_deleteall`.
-    fn parse_file_delete_all(&'a self) -> PResult<Change<&'a [u8]>> {
+    fn parse_file_delete_all(&self) -> PResult<Change<&'a [u8]>> {
         Ok(Change::FileDeleteAll)
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:note_change_n`.
-    fn parse_note_modify(&'a self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+    fn parse_note_modify(&self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
         let (data_ref, commit) = split_at_space(args).ok_or(ParseError::NoSpaceAfterDataRef)?;
-        let data_ref = DataRef::parse(data_ref)?;
-        let commit = Commitish::parse(commit)?;
-
-        // TODO: Parse data.
+        let commit = Commitish::parse(commit, self.input())?;
+        let data_ref = self.parse_data_ref(data_ref)?;
 
         Ok(Change::from(NoteModifyChange { data_ref, commit }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_ls(p, b)`.
-    fn parse_ls(&'a self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
-        let (root, path) = parse_ls(self, args, true)?;
+    fn parse_ls(&self, args: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+        let (root, path) = parse_ls(self.parser, args, true)?;
         Ok(Change::from(CommitLs { root, path }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_cat_blob`.
-    fn parse_cat_blob(&'a self, data_ref: &'a [u8]) -> PResult<Change<&'a [u8]>> {
-        let blob = Blobish::parse(data_ref)?;
+    fn parse_cat_blob(&self, data_ref: &'a [u8]) -> PResult<Change<&'a [u8]>> {
+        let blob = Blobish::parse(data_ref, self.input())?;
         Ok(Change::from(CatBlob { blob }))
     }
 
     /// Returns `None` when the string is not followed by a space.
-    fn unquote_space(&'a self, s: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
-        // BUG-COMPAT: fast-import only treats this path as a quoted string when
-        // it parses successfully, in contrast to `ls`.
-        if let Ok((unquoted, rest)) = self.unquote_c_style_string(s) {
-            if !rest.starts_with(b" ") {
-                return None;
+    fn unquote_space(&self, s: &'a [u8]) -> PResult<Option<(&'a [u8], &'a [u8])>> {
+        match self.unquote_path(s)? {
+            Some((unquoted, rest)) => {
+                if !rest.starts_with(b" ") {
+                    return Ok(None);
+                }
+                Ok(Some((unquoted, &rest[1..])))
             }
-            Some((unquoted, &rest[1..]))
-        } else {
-            split_at_space(s)
+            None => Ok(split_at_space(s)),
         }
     }
 
     /// Returns `None` when the string is followed by junk.
-    fn unquote_eol(&'a self, s: &'a [u8]) -> Option<&'a [u8]> {
-        // BUG-COMPAT: fast-import only treats this path as a quoted string when
-        // it parses successfully, in contrast to `ls`.
-        if let Ok((unquoted, rest)) = self.unquote_c_style_string(s) {
-            if !rest.is_empty() {
-                return None;
+    fn unquote_eol(&self, s: &'a [u8]) -> PResult<Option<&'a [u8]>> {
+        match self.unquote_path(s)? {
+            Some((unquoted, rest)) => {
+                if !rest.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(unquoted))
             }
-            Some(unquoted)
-        } else {
             // BUG-COMPAT: Allows spaces when unquoted.
-            Some(s)
+            None => Ok(Some(s)),
         }
     }
-}
 
-impl<R: BufRead> DirectiveParser<R> for ChangeIter<'_, R> {
-    #[inline(always)]
-    fn input(&self) -> &BufInput<R> {
-        &self.input
+    /// Unquotes a path if it starts with `"` and returns it with the rest of
+    /// the line, or returns `None` if it should be used literally.
+    ///
+    /// BUG-COMPAT: Before Git 2.45, fast-import only treated a path as a quoted
+    /// string when it parsed successfully, in contrast to `ls`, and truncated
+    /// it at an escaped NUL. Both are errors in strict mode.
+    fn unquote_path(&self, s: &'a [u8]) -> PResult<Option<(&'a [u8], &'a [u8])>> {
+        if !s.starts_with(b"\"") {
+            return Ok(None);
+        }
+        match self.parser.unquote_c_style_string(s) {
+            Ok((mut unquoted, rest)) => {
+                if let Some(nul) = memchr(b'\0', unquoted) {
+                    self.input()
+                        .accept_quirk(QuirkKind::PathEscapedNul, ParseError::PathContainsNul)?;
+                    unquoted = &unquoted[..nul];
+                }
+                Ok(Some((unquoted, rest)))
+            }
+            Err(err) => {
+                self.input()
+                    .accept_quirk(QuirkKind::PathInvalidQuoting, ParseError::PathString(err))?;
+                Ok(None)
+            }
+        }
     }
 }
 
-impl<'a> DataRef<&'a [u8]> {
+impl<'a, R: BufRead> ChangeIter<'a, R> {
+    /// Parses a data ref and, for `inline`, the header of the data that
+    /// follows it.
+    ///
     // Corresponds to parts of `file_change_m` and `note_change_n` in
     // `git.git/builtin/fast-import.c`.
-    fn parse(data_ref: &'a [u8]) -> PResult<Self> {
+    fn parse_data_ref(&self, data_ref: &'a [u8]) -> PResult<DataRef<&'a [u8]>> {
+        let input = self.input();
         if data_ref == b"inline" {
-            Ok(DataRef::Inline)
+            let header = input
+                .parse_directive(b"data ", |arg| DataHeader::parse(arg, input))?
+                .ok_or(ParseError::ExpectedInlineData)?;
+            // SAFETY: See `ChangeIter::next`.
+            let data_state = unsafe { &mut *self.parser.data_state.get() };
+            data_state.init(&header, &self.parser.data_opened);
+            Ok(DataRef::Inline(header))
         } else if data_ref.starts_with(b":") {
-            Ok(DataRef::Mark(Mark::parse(data_ref)?))
+            Ok(DataRef::Mark(Mark::parse(data_ref, input, false)?))
         } else {
             Ok(DataRef::Oid(data_ref))
         }
@@ -257,6 +363,107 @@ fn split_at_space(b: &[u8]) -> Option<(&[u8], &[u8])> {
     })
 }
 
+impl<T, U> MapBytes<T, U> for Change<T> {
+    type Output = Change<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        match self {
+            Change::FileModify(change) => Change::FileModify(change.map_bytes(f)),
+            Change::FileDelete(change) => Change::FileDelete(change.map_bytes(f)),
+            Change::FileRename(change) => Change::FileRename(change.map_bytes(f)),
+            Change::FileCopy(change) => Change::FileCopy(change.map_bytes(f)),
+            Change::FileDeleteAll => Change::FileDeleteAll,
+            Change::NoteModify(change) => Change::NoteModify(change.map_bytes(f)),
+            Change::Ls(change) => Change::Ls(change.map_bytes(f)),
+            Change::CatBlob(change) => Change::CatBlob(change.map_bytes(f)),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for FileModifyChange<T> {
+    type Output = FileModifyChange<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        FileModifyChange {
+            data_ref: self.data_ref.map_bytes(f),
+            mode: self.mode,
+            path: f(self.path),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for FileDeleteChange<T> {
+    type Output = FileDeleteChange<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        FileDeleteChange { path: f(self.path) }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for FileRenameChange<T> {
+    type Output = FileRenameChange<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        FileRenameChange {
+            source: f(self.source),
+            dest: f(self.dest),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for FileCopyChange<T> {
+    type Output = FileCopyChange<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        FileCopyChange {
+            source: f(self.source),
+            dest: f(self.dest),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for NoteModifyChange<T> {
+    type Output = NoteModifyChange<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        NoteModifyChange {
+            data_ref: self.data_ref.map_bytes(f),
+            commit: self.commit.map_bytes(f),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for DataRef<T> {
+    type Output = DataRef<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        match self {
+            DataRef::Mark(mark) => DataRef::Mark(mark),
+            DataRef::Oid(oid) => DataRef::Oid(f(oid)),
+            DataRef::Inline(header) => DataRef::Inline(header.map_bytes(f)),
+        }
+    }
+}
+
+impl<T, U> MapBytes<T, U> for CommitLs<T> {
+    type Output = CommitLs<U>;
+
+    #[inline(always)]
+    fn map_bytes<F: FnMut(T) -> U>(self, f: &mut F) -> Self::Output {
+        CommitLs {
+            root: self.root.map_bytes(f),
+            path: f(self.path),
+        }
+    }
+}
+
 impl<B> From<FileModifyChange<B>> for Change<B> {
     #[inline(always)]
     fn from(change: FileModifyChange<B>) -> Self {
@@ -305,3 +512,142 @@ impl<B> From<CatBlob<B>> for Change<B> {
         Change::CatBlob(change)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::{
+        command::{Command, Commitish, DataHeader, Done, Mark, Mode, Objectish},
+        parse::{
+            Change, ChangeIterError, DataRef, FileDeleteChange, FileModifyChange, NoteModifyChange,
+            Parser, StreamError,
+        },
+    };
+
+    const COMMITS: &[u8] = b"\
+commit refs/heads/main
+mark :2
+committer C <c@example.com> 1700000000 +0000
+data 4
+msg
+M 100644 :1 a.txt
+M 644 inline \"b c.txt\"
+data 6
+hello
+D old.txt
+
+commit refs/notes/commits
+committer C <c@example.com> 1700000000 +0000
+data 0
+N inline :2
+data <<EOF
+Reviewed-by: R
+EOF
+N 3141592653589793238462643383279502884197 0123456789012345678901234567890123456789
+";
+
+    #[test]
+    fn parse_changes() {
+        let mut input = COMMITS;
+        let mut parser = Parser::new(&mut input);
+
+        let command = parser.next().unwrap();
+        assert!(matches!(command, Command::Commit(_)), "{command:?}");
+        let mut changes = parser.changes().unwrap();
+        assert!(matches!(
+            parser.changes(),
+            Err(StreamError::ChangeIter(ChangeIterError::AlreadyOpened)),
+        ));
+        assert_eq!(
+            changes.next().unwrap(),
+            Some(Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Mark(Mark::new(1).unwrap()),
+                mode: Mode::File,
+                path: &b"a.txt"[..],
+            })),
+        );
+        assert_eq!(
+            changes.next().unwrap(),
+            Some(Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Inline(DataHeader::Counted { len: 6 }),
+                mode: Mode::File,
+                path: &b"b c.txt"[..],
+            })),
+        );
+        let mut data = Vec::new();
+        changes.open_data().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello\n");
+        assert_eq!(
+            changes.next().unwrap(),
+            Some(Change::FileDelete(FileDeleteChange {
+                path: &b"old.txt"[..],
+            })),
+        );
+        assert_eq!(changes.next().unwrap(), None);
+        assert_eq!(changes.next().unwrap(), None);
+
+        let command = parser.next().unwrap();
+        assert!(matches!(command, Command::Commit(_)), "{command:?}");
+        let mut changes = parser.changes().unwrap();
+        assert_eq!(
+            changes.next().unwrap(),
+            Some(Change::NoteModify(NoteModifyChange {
+                data_ref: DataRef::Inline(DataHeader::Delimited { delim: &b"EOF"[..] }),
+                commit: Commitish {
+                    commit: Objectish::Mark(Mark::new(2).unwrap()),
+                },
+            })),
+        );
+        // The inline note data is skipped without opening it.
+        assert_eq!(
+            changes.next().unwrap(),
+            Some(Change::NoteModify(NoteModifyChange {
+                data_ref: DataRef::Oid(&b"3141592653589793238462643383279502884197"[..]),
+                commit: Commitish {
+                    commit: Objectish::BranchOrOid(
+                        &b"0123456789012345678901234567890123456789"[..],
+                    ),
+                },
+            })),
+        );
+        assert_eq!(parser.next().unwrap(), Command::Done(Done::Eof));
+    }
+
+    #[test]
+    fn skip_changes() {
+        let mut input = COMMITS;
+        let mut parser = Parser::new(&mut input);
+        assert!(matches!(parser.next().unwrap(), Command::Commit(_)));
+        assert!(matches!(parser.next().unwrap(), Command::Commit(_)));
+        let mut changes = parser.changes().unwrap();
+        assert!(matches!(
+            changes.next().unwrap(),
+            Some(Change::NoteModify(_))
+        ));
+        assert_eq!(parser.next().unwrap(), Command::Done(Done::Eof));
+        assert_eq!(parser.changes().unwrap().next().unwrap(), None);
+    }
+
+    #[test]
+    fn changes_with_commit() {
+        let mut input = COMMITS;
+        let mut parser = Parser::new(&mut input);
+        let (command, shared) = parser.next_shared().unwrap();
+        let Command::Commit(commit) = command else {
+            panic!("{command:?}");
+        };
+        let mut changes = shared.changes().unwrap();
+        let mut paths = Vec::new();
+        while let Some(change) = changes.next().unwrap() {
+            if let Change::FileModify(FileModifyChange { path, .. })
+            | Change::FileDelete(FileDeleteChange { path }) = change
+            {
+                paths.push(path);
+            }
+        }
+        // The commit is still borrowed after its changes were parsed.
+        assert_eq!(commit.message, b"msg\n");
+        assert_eq!(paths, [&b"a.txt"[..], b"b c.txt", b"old.txt"]);
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of fast-export-rust, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::BufRead,
+    str::FromStr,
+};
+
+use enumflags2::{bitflags, BitFlags};
+use thiserror::Error;
+
+use crate::parse::{BufInput, PResult, ParseError};
+
+/// How permissively a stream is parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Compat {
+    /// Reject inputs that fast-import accepts only because of lax parsing.
+    #[default]
+    Strict,
+    /// Accept exactly what fast-import from the given Git version accepts, and
+    /// record each [`Quirk`] that was relied upon.
+    FastImport(GitVersion),
+}
+
+/// A version of Git, for emulating the fast-import of that release.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GitVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+/// An input which fast-import accepts, that is rejected in strict mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Quirk {
+    pub kind: QuirkKind,
+    /// The line in the stream at which it occurred.
+    pub line: u64,
+}
+
+/// A kind of [`Quirk`].
+#[bitflags]
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Hash)]
+pub enum QuirkKind {
+    /// `strto*` skips leading whitespace.
+    #[error("whitespace before integer")]
+    IntLeadingSpace,
+    /// `strtou*` accepts an explicit `+` sign.
+    #[error("'+' sign before unsigned integer")]
+    IntPlusSign,
+    /// `strtou*` accepts a `-` sign and negates the value as unsigned.
+    #[error("'-' sign before unsigned integer wraps around")]
+    IntMinusSign,
+    /// An integer with no digits is parsed as 0, when the end is unchecked.
+    #[error("integer with no digits parsed as 0")]
+    IntNoDigits,
+    /// An integer which overflows saturates, when `errno` is unchecked.
+    #[error("integer overflow saturated to the maximum")]
+    IntOverflow,
+    /// Junk after an integer is ignored, when the end is unchecked.
+    #[error("junk after integer ignored")]
+    IntJunk,
+    /// Anything after NUL is ignored, because the line is handled as a C
+    /// string.
+    #[error("integer truncated at NUL")]
+    IntNul,
+    /// An integer parsed with base 0 is octal with a leading `0` or hexadecimal
+    /// with a leading `0x`.
+    #[error("integer in octal or hexadecimal")]
+    IntRadix,
+    /// `mark :0` is accepted and treated as if no mark were given.
+    #[error("'mark :0' treated as no mark")]
+    ZeroMark,
+    /// Before Git 2.45, a quoted path which fails to unquote is used
+    /// literally.
+    #[error("path with invalid quoting used literally")]
+    PathInvalidQuoting,
+    /// Before Git 2.45, a quoted path containing an escaped NUL is truncated
+    /// there.
+    #[error("path truncated at escaped NUL")]
+    PathEscapedNul,
+}
+
+/// The rules for an integer, corresponding to how fast-import calls one of the
+/// `strto*` functions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum IntSyntax {
+    /// `strtoumax(s, NULL, 10)`, with no error checking at all.
+    ///
+    // Corresponds to `parse_mark` and `parse_data` in
+    // `git.git/builtin/fast-import.c`.
+    Unchecked,
+    /// `strtoumax(s, &end, 10)`, requiring digits and checking the end, but not
+    /// `errno`. When `eol` is false, the end must be followed by a space, so
+    /// NUL does not terminate it.
+    ///
+    // Corresponds to `parse_mark_ref_eol` and `parse_mark_ref_space` in
+    // `git.git/builtin/fast-import.c`.
+    MarkRef { eol: bool },
+    /// `strtoul(s, &end, 0)`, rejecting `-` anywhere, requiring digits, and
+    /// checking the end, but not `errno`.
+    ///
+    // Corresponds to `git.git/builtin/fast-import.c:ulong_arg`.
+    UlongArg,
+    /// `strtoumax(s, &end, 0)`, rejecting `-` anywhere, requiring digits, and
+    /// checking the end and `errno`. The unit suffix is split off by the
+    /// caller.
+    ///
+    // Corresponds to `git.git/parse.c:git_parse_unsigned`.
+    GitParseUnsigned,
+}
+
+/// The result of `strtoumax` on a C string.
+struct CInt<'a> {
+    value: u64,
+    /// Whether any digits were parsed. When false, `end == nptr`.
+    has_digits: bool,
+    /// The input after the last digit.
+    rest: &'a [u8],
+    quirks: BitFlags<QuirkKind>,
+}
+
+impl GitVersion {
+    /// Git 2.45.0 tightened the parsing of paths in fast-import.
+    pub const V2_45_0: GitVersion = GitVersion::new(2, 45, 0);
+
+    #[inline]
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        GitVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl Compat {
+    /// Returns whether the quirk is accepted in this mode.
+    #[inline]
+    pub fn allows(&self, kind: QuirkKind) -> bool {
+        match *self {
+            Compat::Strict => false,
+            Compat::FastImport(version) => match kind {
+                QuirkKind::PathInvalidQuoting | QuirkKind::PathEscapedNul => {
+                    version < GitVersion::V2_45_0
+                }
+                _ => true,
+            },
+        }
+    }
+}
+
+impl<R: BufRead> BufInput<R> {
+    /// Parses an integer, as fast-import would with the given `strto*` call.
+    /// In strict mode, only plain decimal digits which fit in a `u64` are
+    /// accepted. Otherwise, fast-import is emulated and each quirk it relied
+    /// upon is recorded.
+    pub(super) fn parse_c_int(&self, s: &[u8], syntax: IntSyntax, err: ParseError) -> PResult<u64> {
+        let (int, accepted) = parse_c_int(s, syntax);
+        if !accepted || !int.quirks.iter().all(|kind| self.compat().allows(kind)) {
+            return Err(err.into());
+        }
+        self.record_quirks(int.quirks);
+        Ok(int.value)
+    }
+
+    /// Records the quirk when this mode allows it, or otherwise returns the
+    /// error.
+    pub(super) fn accept_quirk(&self, kind: QuirkKind, err: ParseError) -> PResult<()> {
+        if !self.compat().allows(kind) {
+            return Err(err.into());
+        }
+        self.record_quirks(kind.into());
+        Ok(())
+    }
+
+    /// Records that the quirks were accepted at the current line.
+    pub(super) fn record_quirks(&self, kinds: BitFlags<QuirkKind>) {
+        if kinds.is_empty() {
+            return;
+        }
+        let line = self.line();
+        let quirks = unsafe { &mut *self.quirks.get() };
+        quirks.extend(kinds.iter().map(|kind| Quirk { kind, line }));
+    }
+}
+
+/// Emulates fast-import's parsing of an integer and returns whether it would
+/// be accepted.
+fn parse_c_int(s: &[u8], syntax: IntSyntax) -> (CInt<'_>, bool) {
+    // The line is handled as a C string, so it ends at the first NUL.
+    let (s, after_nul) = match memchr::memchr(b'\0', s) {
+        Some(i) => (&s[..i], true),
+        None => (s, false),
+    };
+    let base0 = matches!(syntax, IntSyntax::UlongArg | IntSyntax::GitParseUnsigned);
+    let mut int = strtoumax(s, base0);
+    if after_nul {
+        int.quirks |= QuirkKind::IntNul;
+    }
+    let accepted = match syntax {
+        IntSyntax::Unchecked => {
+            if !int.has_digits {
+                int.quirks |= QuirkKind::IntNoDigits;
+            } else if !int.rest.is_empty() {
+                int.quirks |= QuirkKind::IntJunk;
+            }
+            true
+        }
+        IntSyntax::MarkRef { eol } => int.has_digits && int.rest.is_empty() && (eol || !after_nul),
+        IntSyntax::UlongArg => int.has_digits && int.rest.is_empty() && !s.contains(&b'-'),
+        IntSyntax::GitParseUnsigned => {
+            int.has_digits
+                && int.rest.is_empty()
+                && !s.contains(&b'-')
+                && !int.quirks.contains(QuirkKind::IntOverflow)
+        }
+    };
+    (int, accepted)
+}
+
+/// Emulates `strtoumax` with base 10 or, when `base0` is set, base 0.
+///
+// Corresponds to `strtoumax` in glibc.
+fn strtoumax(s: &[u8], base0: bool) -> CInt<'_> {
+    let mut quirks = BitFlags::empty();
+    let mut i = 0;
+    while i < s.len() && matches!(s[i], b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r') {
+        i += 1;
+    }
+    if i != 0 {
+        quirks |= QuirkKind::IntLeadingSpace;
+    }
+    let mut negative = false;
+    match s.get(i) {
+        Some(b'+') => {
+            quirks |= QuirkKind::IntPlusSign;
+            i += 1;
+        }
+        Some(b'-') => {
+            quirks |= QuirkKind::IntMinusSign;
+            negative = true;
+            i += 1;
+        }
+        _ => {}
+    }
+    let mut radix = 10;
+    if base0 && s.get(i) == Some(&b'0') {
+        match s.get(i + 1) {
+            Some(b'x' | b'X') if s.get(i + 2).is_some_and(u8::is_ascii_hexdigit) => {
+                quirks |= QuirkKind::IntRadix;
+                radix = 16;
+                i += 2;
+            }
+            Some(b'0'..=b'7') => {
+                quirks |= QuirkKind::IntRadix;
+                radix = 8;
+            }
+            // A lone `0` is the same in any base, but it stops at non-octal
+            // digits.
+            _ => radix = 8,
+        }
+    }
+
+    let start = i;
+    let mut value = 0u64;
+    let mut overflow = false;
+    while let Some(digit) = s.get(i).and_then(|&b| (b as char).to_digit(radix)) {
+        match value
+            .checked_mul(radix as u64)
+            .and_then(|v| v.checked_add(digit as u64))
+        {
+            Some(v) => value = v,
+            None => overflow = true,
+        }
+        i += 1;
+    }
+    if i == start {
+        return CInt {
+            value: 0,
+            has_digits: false,
+            rest: s,
+            quirks: BitFlags::empty(),
+        };
+    }
+    if overflow {
+        quirks |= QuirkKind::IntOverflow;
+        value = u64::MAX;
+    } else if negative {
+        value = value.wrapping_neg();
+    }
+    CInt {
+        value,
+        has_digits: true,
+        rest: &s[i..],
+        quirks,
+    }
+}
+
+impl Display for GitVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// An error from parsing a [`GitVersion`].
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Hash)]
+#[error("invalid Git version (expected MAJOR.MINOR or MAJOR.MINOR.PATCH)")]
+pub struct ParseGitVersionError;
+
+impl FromStr for GitVersion {
+    type Err = ParseGitVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let mut parts = s.split('.').map(|part| {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseGitVersionError);
+            }
+            part.parse().map_err(|_| ParseGitVersionError)
+        });
+        let major = parts.next().ok_or(ParseGitVersionError)??;
+        let minor = parts.next().ok_or(ParseGitVersionError)??;
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return Err(ParseGitVersionError);
+        }
+        Ok(GitVersion::new(major, minor, patch))
+    }
+}
+
+impl Display for Quirk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{Command, Mark},
+        parse::{Parser, StreamError},
+    };
+    use IntSyntax::*;
+    use QuirkKind::*;
+
+    fn parse(s: &[u8], syntax: IntSyntax) -> Option<(u64, BitFlags<QuirkKind>)> {
+        let (int, accepted) = parse_c_int(s, syntax);
+        accepted.then_some((int.value, int.quirks))
+    }
+
+    #[test]
+    fn unchecked() {
+        assert_eq!(parse(b"42", Unchecked), Some((42, BitFlags::empty())));
+        assert_eq!(parse(b"007", Unchecked), Some((7, BitFlags::empty())));
+        assert_eq!(parse(b"+42", Unchecked), Some((42, IntPlusSign.into())));
+        assert_eq!(
+            parse(b"-1", Unchecked),
+            Some((u64::MAX, IntMinusSign.into()))
+        );
+        assert_eq!(
+            parse(b" \t42", Unchecked),
+            Some((42, IntLeadingSpace.into()))
+        );
+        assert_eq!(parse(b"42abc", Unchecked), Some((42, IntJunk.into())));
+        assert_eq!(parse(b"", Unchecked), Some((0, IntNoDigits.into())));
+        assert_eq!(parse(b"+", Unchecked), Some((0, IntNoDigits.into())));
+        assert_eq!(parse(b"42\0junk", Unchecked), Some((42, IntNul.into())));
+        assert_eq!(
+            parse(b"99999999999999999999", Unchecked),
+            Some((u64::MAX, IntOverflow.into())),
+        );
+        assert_eq!(
+            parse(b"-99999999999999999999", Unchecked),
+            Some((u64::MAX, IntMinusSign | IntOverflow)),
+        );
+        // Base 10 never reads hex or octal.
+        assert_eq!(parse(b"0x10", Unchecked), Some((0, IntJunk.into())));
+        assert_eq!(parse(b"010", Unchecked), Some((10, BitFlags::empty())));
+    }
+
+    #[test]
+    fn mark_ref() {
+        let eol = MarkRef { eol: true };
+        let space = MarkRef { eol: false };
+        assert_eq!(parse(b"42", eol), Some((42, BitFlags::empty())));
+        assert_eq!(parse(b"+42", eol), Some((42, IntPlusSign.into())));
+        assert_eq!(parse(b" 42", space), Some((42, IntLeadingSpace.into())));
+        assert_eq!(parse(b"42\0", eol), Some((42, IntNul.into())));
+        assert_eq!(parse(b"42\0", space), None);
+        assert_eq!(parse(b"42abc", eol), None);
+        assert_eq!(parse(b"", eol), None);
+        assert_eq!(parse(b"-", eol), None);
+    }
+
+    #[test]
+    fn ulong_arg() {
+        assert_eq!(parse(b"42", UlongArg), Some((42, BitFlags::empty())));
+        assert_eq!(parse(b"0x2a", UlongArg), Some((42, IntRadix.into())));
+        assert_eq!(parse(b"052", UlongArg), Some((42, IntRadix.into())));
+        assert_eq!(parse(b"0", UlongArg), Some((0, BitFlags::empty())));
+        assert_eq!(parse(b"0x", UlongArg), None);
+        assert_eq!(parse(b"08", UlongArg), None);
+        assert_eq!(parse(b"+42", UlongArg), Some((42, IntPlusSign.into())));
+        assert_eq!(parse(b"-42", UlongArg), None);
+        assert_eq!(parse(b"42-", UlongArg), None);
+        assert_eq!(
+            parse(b"99999999999999999999", UlongArg),
+            Some((u64::MAX, IntOverflow.into())),
+        );
+    }
+
+    #[test]
+    fn git_parse_unsigned() {
+        assert_eq!(
+            parse(b"42", GitParseUnsigned),
+            Some((42, BitFlags::empty()))
+        );
+        assert_eq!(
+            parse(b"0X2A", GitParseUnsigned),
+            Some((42, IntRadix.into()))
+        );
+        assert_eq!(parse(b"99999999999999999999", GitParseUnsigned), None);
+        assert_eq!(parse(b"-0", GitParseUnsigned), None);
+    }
+
+    #[test]
+    fn parse_stream() {
+        let input = b"blob\nmark :0\ndata +3\nabc\nblob\nmark : 7junk\ndata 0\n";
+
+        let mut parser = Parser::new(&input[..]);
+        match parser.next() {
+            Err(StreamError::Parse(ParseError::ZeroMark)) => {}
+            res => panic!("strict: {res:?}"),
+        }
+
+        let compat = Compat::FastImport(GitVersion::new(2, 44, 0));
+        let mut parser = Parser::with_compat(&input[..], compat);
+        let Command::Blob(blob) = parser.next().unwrap() else {
+            panic!("not a blob");
+        };
+        assert_eq!(blob.mark, None);
+        let Command::Blob(blob) = parser.next().unwrap() else {
+            panic!("not a blob");
+        };
+        assert_eq!(blob.mark, Mark::new(7));
+        assert_eq!(
+            parser.take_quirks(),
+            [
+                Quirk {
+                    kind: ZeroMark,
+                    line: 2
+                },
+                Quirk {
+                    kind: IntPlusSign,
+                    line: 3
+                },
+                Quirk {
+                    kind: IntLeadingSpace,
+                    line: 6
+                },
+                Quirk {
+                    kind: IntJunk,
+                    line: 6
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn allows() {
+        let old = Compat::FastImport(GitVersion::new(2, 44, 0));
+        let new = Compat::FastImport(GitVersion::V2_45_0);
+        assert!(!Compat::Strict.allows(IntPlusSign));
+        assert!(old.allows(IntPlusSign) && new.allows(IntPlusSign));
+        assert!(old.allows(PathInvalidQuoting));
+        assert!(!new.allows(PathInvalidQuoting));
+    }
+
+    #[test]
+    fn git_version() {
+        assert_eq!("2.44".parse(), Ok(GitVersion::new(2, 44, 0)));
+        assert_eq!("v2.45.1".parse(), Ok(GitVersion::new(2, 45, 1)));
+        assert_eq!("2".parse::<GitVersion>(), Err(ParseGitVersionError));
+        assert_eq!("2.+4".parse::<GitVersion>(), Err(ParseGitVersionError));
+        assert_eq!("2.44.0.1".parse::<GitVersion>(), Err(ParseGitVersionError));
+    }
+}
@@ -10,7 +10,9 @@ use std::{
 
 use crate::{
     command::DataHeader,
-    parse::{BufPool, DataReaderError, DataState, PResult, ParseError, ParseStringError},
+    parse::{
+        BufPool, Compat, DataReaderError, DataState, PResult, ParseError, ParseStringError, Quirk,
+    },
 };
 
 /// Input for a fast-export stream.
@@ -28,6 +30,10 @@ pub(super) struct BufInput<R> {
     lines: BufPool,
     /// Whether the last command has been consumed.
     unread: UnsafeCell<bool>,
+    /// How permissively to parse.
+    compat: Compat,
+    /// The quirks accepted so far, when not in strict mode.
+    pub(super) quirks: UnsafeCell<Vec<Quirk>>,
 }
 
 pub(super) trait DirectiveParser<R: BufRead> {
@@ -252,6 +258,7 @@ impl<R: BufRead> Input<R> {
         let buf = self.r.fill_buf()?;
         if buf.starts_with(b"\n") {
             self.r.consume(1);
+            self.line += 1;
             Ok(true)
         } else {
             Ok(false)
@@ -270,14 +277,29 @@ impl<R: BufRead> BufInput<R> {
     const CONTEXT_LINES_BEFORE: usize = 20;
 
     #[inline]
-    pub fn new(input: R) -> Self {
+    pub fn new(input: R, compat: Compat) -> Self {
         BufInput {
             input: UnsafeCell::new(Input::new(input)),
             lines: BufPool::new(),
             unread: UnsafeCell::new(false),
+            compat,
+            quirks: UnsafeCell::new(Vec::new()),
         }
     }
 
+    /// Returns how permissively this input is parsed.
+    #[inline(always)]
+    pub fn compat(&self) -> Compat {
+        self.compat
+    }
+
+    /// Returns the current line number.
+    #[inline(always)]
+    pub fn line(&self) -> u64 {
+        let input = unsafe { &*self.input.get() };
+        input.line
+    }
+
     /// Truncates the contextual lines shown in a crash dump to a fixed amount.
     #[inline]
     pub fn truncate_context(&mut self) {
@@ -317,7 +339,8 @@ impl<R: BufRead> BufInput<R> {
         if *unread {
             let back = self.lines.back();
             debug_assert!(back.is_some(), "unread line not in BufPool");
-            Ok(back)
+            // The buffer retains the LF, which `read_line` strips.
+            Ok(back.map(|line| line.strip_suffix(b"\n").unwrap_or(line)))
         } else {
             let line = self.read_directive()?;
             *unread = line.is_some();
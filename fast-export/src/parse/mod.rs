@@ -4,6 +4,7 @@
 // linking exception. For the full terms, see the included COPYING file.
 
 mod commit;
+mod compat;
 mod data;
 mod input;
 mod parser;
@@ -11,10 +12,11 @@ mod pool;
 mod quote;
 
 pub use commit::*;
+pub use compat::*;
 pub use data::*;
-pub(self) use input::*;
+use input::*;
 pub use parser::*;
-pub(self) use pool::*;
-pub(self) use quote::*;
+use pool::*;
+use quote::*;
 
 pub(crate) type PResult<T> = Result<T, StreamError>;
@@ -6,7 +6,6 @@
 use std::{
     cell::UnsafeCell,
     io::{self, BufRead},
-    str::{self, FromStr},
     sync::atomic::{AtomicBool, Ordering},
 };
 
@@ -15,12 +14,15 @@ use thiserror::Error;
 
 use crate::{
     command::{
-        Alias, Blob, Blobish, Branch, CatBlob, Command, Commit, Commitish, DataHeader, DateFormat,
-        Done, Encoding, FastImportPath, Feature, FileSize, GetMark, Ls, Mark, Objectish,
-        OptionCommand, OptionGit, OptionOther, OriginalOid, PersonIdent, Progress, Reset, Tag,
-        TagName, Treeish, UnitFactor,
+        Alias, Blob, Blobish, Branch, CatBlob, Command, Commit, CommitSignature, Commitish,
+        DataHeader, DateFormat, Done, Encoding, FastImportPath, Feature, FileSize, GetMark,
+        HashAlgorithm, Ls, Mark, Objectish, OptionCommand, OptionGit, OptionOther, OriginalOid,
+        PersonIdent, Progress, Reset, SignatureFormat, Tag, TagName, Treeish, UnitFactor,
+    },
+    parse::{
+        BufInput, ChangeIter, ChangeIterError, Compat, DataReaderError, DataState, DirectiveParser,
+        IntSyntax, PResult, ParseStringError, Quirk, QuirkKind,
     },
-    parse::{BufInput, DataReaderError, DataState, DirectiveParser, PResult, ParseStringError},
 };
 
 /// A zero-copy pull parser for fast-export streams.
@@ -34,6 +36,9 @@ use crate::{
 /// Commands are parsed separately from data streams. To read a data stream,
 /// open a [`DataReader`](super::DataReader) from the returned [`Blob`](Blob)
 /// with [`Blob::open`](Blob::open).
+///
+/// By default, inputs that fast-import accepts only because of lax parsing are
+/// rejected. To parse such streams, construct it with [`Parser::with_compat`].
 pub struct Parser<R> {
     /// The input reader being parsed.
     ///
@@ -55,14 +60,23 @@ pub struct Parser<R> {
     pub(super) data_opened: AtomicBool,
     /// The state for reading a data stream.
     ///
-    /// It may only be mutated under `&` within the `DataReader`.
+    /// It may only be mutated under `&` within the `DataReader` or the
+    /// `ChangeIter`.
     pub(super) data_state: UnsafeCell<DataState>,
+
+    /// Whether a `ChangeIter` has been opened for the current command. This
+    /// guards `Parser::changes`, to ensure that only one `ChangeIter` can be
+    /// opened per call to `Parser::next`.
+    pub(super) changes_opened: AtomicBool,
+    /// Whether all file changes of the current commit have been parsed. It is
+    /// always true for other commands.
+    pub(super) changes_finished: AtomicBool,
 }
 
 // SAFETY: All `UnsafeCell` fields are guaranteed only be modified by a single
 // thread. When mutation occurs under an `&`-reference, it is atomically guarded
-// by `Parser::data_opened` to ensure it can only happen by one thread. See the
-// invariants of `Parser::input`.
+// by `Parser::data_opened` or `Parser::changes_opened` to ensure it can only
+// happen by one thread. See the invariants of `Parser::input`.
 unsafe impl<R> Sync for Parser<R> {}
 
 /// An error from parsing a fast-export stream, including IO errors.
@@ -71,6 +85,7 @@ unsafe impl<R> Sync for Parser<R> {}
 pub enum StreamError {
     Parse(#[from] ParseError),
     DataReader(#[from] DataReaderError),
+    ChangeIter(#[from] ChangeIterError),
     Io(#[from] io::Error),
 }
 
@@ -91,6 +106,8 @@ pub enum ParseError {
     ExpectedAliasMark,
     #[error("expected 'to' directive in alias")]
     ExpectedAliasTo,
+    #[error("expected 'data' directive after inline change")]
+    ExpectedInlineData,
 
     // Fields that are silently truncated by fast-import when they contain NUL.
     #[error("branch name contains NUL")]
@@ -128,6 +145,8 @@ pub enum ParseError {
     MissingDest,
     #[error("junk after destination path")]
     JunkAfterDest,
+    #[error("invalid quoted path: {0}")]
+    PathString(#[source] ParseStringError),
 
     #[error("person identifier does not have '<' or '>'")]
     IdentNoLtOrGt,
@@ -144,13 +163,11 @@ pub enum ParseError {
     #[error("mark does not start with ':'")]
     MarkMissingColon,
     /// The mark is not a valid integer. fast-import allows more forms of
-    /// ill-formatted integers than here.
+    /// ill-formatted integers than strict mode (see [`Compat`]).
     #[error("invalid mark integer")]
     InvalidMark,
-    /// fast-import allows `mark :0`, but it 0 is used for when no mark has been
-    /// set.
-    // TODO: Revisit this after parsing fast-export streams from git fast-export
-    // and other tools.
+    /// fast-import allows `mark :0`, but treats it as if no mark were given,
+    /// so it is rejected in strict mode. Referencing `:0` is always an error.
     #[error("cannot use ':0' as a mark")]
     ZeroMark,
 
@@ -177,6 +194,15 @@ pub enum ParseError {
     #[error("junk after path in 'ls'")]
     JunkAfterLsPath,
 
+    #[error("unknown hash algorithm in 'gpgsig'")]
+    UnknownSignatureHashAlgorithm,
+    #[error("invalid signature format in 'gpgsig'")]
+    InvalidSignatureFormat,
+    #[error("multiple signatures with the same hash algorithm in commit")]
+    DuplicateSignature,
+    #[error("expected data after 'gpgsig' directive")]
+    ExpectedSignatureData,
+
     #[error("invalid date format")]
     InvalidDateFormat,
     /// Expected format `name:filename`` for rewrite submodules feature.
@@ -199,23 +225,71 @@ pub enum ParseError {
 }
 
 impl<R: BufRead> Parser<R> {
-    /// Creates a new `Parser` for reading the given input.
+    /// Creates a new `Parser` for reading the given input in strict mode.
     #[inline]
     pub fn new(input: R) -> Self {
+        Parser::with_compat(input, Compat::Strict)
+    }
+
+    /// Creates a new `Parser` for reading the given input, which is parsed as
+    /// permissively as `compat` specifies.
+    #[inline]
+    pub fn with_compat(input: R, compat: Compat) -> Self {
         Parser {
-            input: BufInput::new(input),
+            input: BufInput::new(input, compat),
             data_opened: AtomicBool::new(false),
             data_state: UnsafeCell::new(DataState::new()),
+            changes_opened: AtomicBool::new(false),
+            changes_finished: AtomicBool::new(true),
         }
     }
 
+    /// Returns how permissively this parser parses.
+    #[inline]
+    pub fn compat(&self) -> Compat {
+        self.input.compat()
+    }
+
+    /// Returns the quirks accepted so far, in the order they were encountered.
+    /// It is always empty in strict mode.
+    #[inline]
+    pub fn quirks(&mut self) -> &[Quirk] {
+        self.input.quirks.get_mut()
+    }
+
+    /// Removes and returns the quirks accepted so far.
+    #[inline]
+    pub fn take_quirks(&mut self) -> Vec<Quirk> {
+        std::mem::take(self.input.quirks.get_mut())
+    }
+
     /// Parses the next command in the fast-export stream.
     ///
     /// The parsed commands borrow from the parser's buffer, so need to be
     /// copied before calling `next` again to retain them.
     ///
     // Corresponds to the loop in `git.git/builtin/fast-import.c:cmd_fast_import`.
+    // It cannot implement `Iterator`, because the returned command borrows
+    // from the parser.
+    #[allow(clippy::should_implement_trait)]
+    #[inline]
     pub fn next(&mut self) -> PResult<Command<'_, &[u8], R>> {
+        self.next_shared().map(|(command, _)| command)
+    }
+
+    /// Parses the next command in the fast-export stream, like
+    /// [`Parser::next`], and returns it with a shared reference to the parser.
+    /// The file changes of a commit can then be opened with
+    /// [`Parser::changes`] while the commit is still borrowed, so neither
+    /// needs to be copied.
+    pub fn next_shared(&mut self) -> PResult<(Command<'_, &[u8], R>, &Self)> {
+        // Skip the file changes of the previous commit, if the user didn't.
+        if !*self.changes_finished.get_mut() {
+            let mut changes = ChangeIter::new(self);
+            while changes.next()?.is_some() {}
+        }
+        *self.changes_opened.get_mut() = false;
+
         // Read the previous data stream, if the user didn't. Error if the user
         // only partially read the data stream.
         if !self.data_state.get_mut().finished() {
@@ -226,6 +300,12 @@ impl<R: BufRead> Parser<R> {
         }
 
         self.input.truncate_context();
+        let this = &*self;
+        let command = this.parse_command()?;
+        Ok((command, this))
+    }
+
+    fn parse_command(&self) -> PResult<Command<'_, &[u8], R>> {
         let Some(line) = self.input.next_directive()? else {
             return Ok(Command::from(Done::Eof));
         };
@@ -263,12 +343,23 @@ impl<R: BufRead> Parser<R> {
         }
     }
 
+    /// Opens the file changes of the current commit for parsing. Only one
+    /// [`ChangeIter`] can be opened per command and it yields no changes for
+    /// commands other than commits.
+    #[inline]
+    pub fn changes(&self) -> PResult<ChangeIter<'_, R>> {
+        if self.changes_opened.swap(true, Ordering::Acquire) {
+            return Err(ChangeIterError::AlreadyOpened.into());
+        }
+        Ok(ChangeIter::new(self))
+    }
+
     // Corresponds to `git.git/builtin/fast-import.c:parse_new_blob`.
     fn parse_blob(&self) -> PResult<Command<'_, &[u8], R>> {
-        let mark = self.parse_directive(b"mark ", Mark::parse)?;
+        let mark = self.parse_mark()?;
         let original_oid = self.parse_directive(b"original-oid ", OriginalOid::parse)?;
         let data_header = self
-            .parse_directive(b"data ", DataHeader::parse)?
+            .parse_directive(b"data ", |arg| DataHeader::parse(arg, &self.input))?
             .ok_or(ParseError::ExpectedBlobData)?;
 
         let data_state = unsafe { &mut *self.data_state.get() };
@@ -285,18 +376,21 @@ impl<R: BufRead> Parser<R> {
     // Corresponds to `git.git/builtin/fast-import.c:parse_new_commit`.
     fn parse_commit<'a>(&'a self, branch: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
         let branch = Branch::parse(branch)?;
-        let mark = self.parse_directive(b"mark ", Mark::parse)?;
+        let mark = self.parse_mark()?;
         let original_oid = self.parse_directive(b"original-oid ", OriginalOid::parse)?;
         let author = self.parse_directive(b"author ", PersonIdent::parse)?;
         let committer = self
             .parse_directive(b"committer ", PersonIdent::parse)?
             .ok_or(ParseError::ExpectedCommitCommitter)?;
+        let signatures = self.parse_commit_signatures()?;
         let encoding = self.parse_directive(b"encoding ", Encoding::parse)?;
         let message = self
             .parse_data_small()?
             .ok_or(ParseError::ExpectedCommitMessage)?;
-        let from = self.parse_directive(b"from ", Commitish::parse)?;
-        let merge = self.parse_directive_many(b"merge ", Commitish::parse)?;
+        let from = self.parse_directive(b"from ", |from| Commitish::parse(from, &self.input))?;
+        let merge =
+            self.parse_directive_many(b"merge ", |merge| Commitish::parse(merge, &self.input))?;
+        self.changes_finished.store(false, Ordering::Release);
 
         Ok(Command::from(Commit {
             branch,
@@ -304,6 +398,7 @@ impl<R: BufRead> Parser<R> {
             original_oid,
             author,
             committer,
+            signatures,
             encoding,
             message,
             from,
@@ -315,9 +410,9 @@ impl<R: BufRead> Parser<R> {
     // Corresponds to `git.git/builtin/fast-import.c:parse_new_tag`.
     fn parse_tag<'a>(&'a self, name: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
         let name = TagName::parse(name)?;
-        let mark = self.parse_directive(b"mark ", Mark::parse)?;
+        let mark = self.parse_mark()?;
         let from = self
-            .parse_directive(b"from ", Objectish::parse)?
+            .parse_directive(b"from ", |from| Objectish::parse(from, &self.input))?
             .ok_or(ParseError::ExpectedTagFrom)?;
         let original_oid = self.parse_directive(b"original-oid ", OriginalOid::parse)?;
         // TODO: `tagger` is optional in fast-import.c, but required in the
@@ -326,6 +421,7 @@ impl<R: BufRead> Parser<R> {
         let message = self
             .parse_data_small()?
             .ok_or(ParseError::ExpectedTagMessage)?;
+        let (message, signature) = split_tag_signature(message);
 
         Ok(Command::from(Tag {
             name,
@@ -334,6 +430,7 @@ impl<R: BufRead> Parser<R> {
             original_oid,
             tagger,
             message,
+            signature,
         }))
     }
 
@@ -341,9 +438,12 @@ impl<R: BufRead> Parser<R> {
     fn parse_reset<'a>(&'a self, branch: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
         // TODO: Handle deletions and ref namespaces.
         let branch = Branch::parse(branch)?;
-        let from = self.parse_directive(b"from ", Commitish::parse)?;
-        // TODO: fast-import docs include an optional LF, but fast-import.c
-        // doesn't seem to.
+        let from = self.parse_directive(b"from ", |from| Commitish::parse(from, &self.input))?;
+        // fast-import consumes a blank line after the command, since it only
+        // unreads a non-empty line. git fast-export emits it.
+        if self.input.peek_directive()? == Some(b"") {
+            self.input.bump_directive();
+        }
 
         Ok(Command::from(Reset { branch, from }))
     }
@@ -359,20 +459,20 @@ impl<R: BufRead> Parser<R> {
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_cat_blob`.
     fn parse_cat_blob<'a>(&'a self, data_ref: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
-        let blob = Blobish::parse(data_ref)?;
+        let blob = Blobish::parse(data_ref, &self.input)?;
         Ok(Command::from(CatBlob { blob }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_get_mark`.
     fn parse_get_mark<'a>(&'a self, mark: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
-        // TODO: :0 is not forbidden by fast-import. How would it behave?
-        let mark = Mark::parse(mark)?;
+        // fast-import rejects `:0`, because it has never been declared.
+        let mark = Mark::parse(mark, &self.input, true)?;
         Ok(Command::from(GetMark { mark }))
     }
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_checkpoint`.
     fn parse_checkpoint(&self) -> PResult<Command<'_, &[u8], R>> {
-        self.input.skip_optional_lf()?;
+        self.skip_optional_lf()?;
         Ok(Command::Checkpoint)
     }
 
@@ -380,12 +480,10 @@ impl<R: BufRead> Parser<R> {
     fn parse_alias(&self) -> PResult<Command<'_, &[u8], R>> {
         // TODO: This optional LF is at the start of the command in
         // fast-import.c, but at the end in the fast-import docs.
-        self.input.skip_optional_lf()?;
-        let mark = self
-            .parse_directive(b"mark ", Mark::parse)?
-            .ok_or(ParseError::ExpectedAliasMark)?;
+        self.skip_optional_lf()?;
+        let mark = self.parse_mark()?.ok_or(ParseError::ExpectedAliasMark)?;
         let to = self
-            .parse_directive(b"to ", Commitish::parse)?
+            .parse_directive(b"to ", |to| Commitish::parse(to, &self.input))?
             .ok_or(ParseError::ExpectedAliasTo)?;
 
         Ok(Command::from(Alias { mark, to }))
@@ -393,7 +491,7 @@ impl<R: BufRead> Parser<R> {
 
     // Corresponds to `git.git/builtin/fast-import.c:parse_progress`.
     fn parse_progress<'a>(&'a self, message: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
-        self.input.skip_optional_lf()?;
+        self.skip_optional_lf()?;
         Ok(Command::from(Progress { message }))
     }
 
@@ -405,7 +503,7 @@ impl<R: BufRead> Parser<R> {
     // Corresponds to `git.git/builtin/fast-import.c:parse_option`.
     fn parse_option<'a>(&'a self, option: &'a [u8]) -> PResult<Command<'a, &'a [u8], R>> {
         let option = if let Some(option) = option.strip_prefix(b"git ") {
-            OptionCommand::Git(OptionGit::parse(option)?)
+            OptionCommand::Git(OptionGit::parse(option, &self.input)?)
         } else {
             OptionCommand::Other(OptionOther { option })
         };
@@ -418,13 +516,49 @@ impl<R: BufRead> Parser<R> {
     ///
     // Corresponds to `git.git/builtin/fast-import.c:parse_data`.
     fn parse_data_small(&self) -> PResult<Option<&[u8]>> {
-        let Some(header) = self.parse_directive(b"data ", DataHeader::parse)? else {
+        let Some(header) =
+            self.parse_directive(b"data ", |arg| DataHeader::parse(arg, &self.input))?
+        else {
             return Ok(None);
         };
         let message_buf = self.new_aux_buffer();
         self.input.read_data_to_end(header, message_buf)?;
         Ok(Some(message_buf))
     }
+
+    /// Parses `gpgsig` directives with their data, allowing at most one
+    /// signature per hash algorithm.
+    ///
+    // Corresponds to the `gpgsig` loop in
+    // `git.git/builtin/fast-import.c:parse_new_commit`.
+    fn parse_commit_signatures(&self) -> PResult<Vec<CommitSignature<&[u8]>>> {
+        let mut signatures: Vec<CommitSignature<&[u8]>> = Vec::new();
+        while let Some((hash_algo, format)) =
+            self.parse_directive(b"gpgsig ", CommitSignature::parse_header)?
+        {
+            if signatures.iter().any(|sig| sig.hash_algo == hash_algo) {
+                return Err(ParseError::DuplicateSignature.into());
+            }
+            let signature = self
+                .parse_data_small()?
+                .ok_or(ParseError::ExpectedSignatureData)?;
+            signatures.push(CommitSignature {
+                hash_algo,
+                format,
+                signature,
+            });
+        }
+        Ok(signatures)
+    }
+
+    /// Parses an optional `mark` directive, which defines a mark for the
+    /// current object.
+    ///
+    // Corresponds to `git.git/builtin/fast-import.c:parse_mark`.
+    fn parse_mark(&self) -> PResult<Option<Mark>> {
+        self.parse_directive(b"mark ", |mark| Mark::parse_def(mark, &self.input))
+            .map(Option::flatten)
+    }
 }
 
 impl<R: BufRead> DirectiveParser<R> for Parser<R> {
@@ -446,7 +580,8 @@ impl From<StreamError> for io::Error {
     fn from(err: StreamError) -> Self {
         match err {
             StreamError::Parse(err) => io::Error::new(io::ErrorKind::InvalidData, err),
-            StreamError::DataReader(err) => io::Error::new(io::ErrorKind::Other, err),
+            StreamError::DataReader(err) => io::Error::other(err),
+            StreamError::ChangeIter(err) => io::Error::other(err),
             StreamError::Io(err) => err,
         }
     }
@@ -476,21 +611,45 @@ impl<'a> TagName<&'a [u8]> {
 }
 
 impl Mark {
+    /// Parses the argument of a `mark` directive, which defines a mark.
+    ///
     /// # Differences from fast-import
     ///
-    /// `mark :0` is rejected here, but not by fast-import.
+    /// In strict mode, `mark :0` is rejected here. fast-import accepts it and
+    /// treats it as if no mark were given, which is returned as `None`.
     ///
-    /// filter-repo does not check any errors for this integer. It allows `+`
-    /// sign, parse errors, empty digits, and junk after the integer.
+    /// fast-import does not check any errors for this integer. It allows signs,
+    /// leading whitespace, overflow, empty digits, and junk after the integer.
     ///
-    // Corresponds to `parse_mark` and `parse_mark_ref` in
+    // Corresponds to `git.git/builtin/fast-import.c:parse_mark`.
+    pub(super) fn parse_def<R: BufRead>(mark: &[u8], input: &BufInput<R>) -> PResult<Option<Self>> {
+        let [b':', mark @ ..] = mark else {
+            return Err(ParseError::MarkMissingColon.into());
+        };
+        let mark = input.parse_c_int(mark, IntSyntax::Unchecked, ParseError::InvalidMark)?;
+        match Mark::new(mark) {
+            Some(mark) => Ok(Some(mark)),
+            None => {
+                input.accept_quirk(QuirkKind::ZeroMark, ParseError::ZeroMark)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Parses a reference to a mark. When `eol` is set, it is the last field
+    /// on the line; otherwise, it was followed by a space.
+    ///
+    /// fast-import checks that this integer has digits and no junk after it,
+    /// but allows signs, leading whitespace, and overflow.
+    ///
+    // Corresponds to `parse_mark_ref_eol` and `parse_mark_ref_space` in
     // `git.git/builtin/fast-import.c`.
     #[inline]
-    pub(super) fn parse(mark: &[u8]) -> PResult<Self> {
+    pub(super) fn parse<R: BufRead>(mark: &[u8], input: &BufInput<R>, eol: bool) -> PResult<Self> {
         let [b':', mark @ ..] = mark else {
             return Err(ParseError::MarkMissingColon.into());
         };
-        let mark = parse_int(mark).ok_or(ParseError::InvalidMark)?;
+        let mark = input.parse_c_int(mark, IntSyntax::MarkRef { eol }, ParseError::InvalidMark)?;
         let mark = Mark::new(mark).ok_or(ParseError::ZeroMark)?;
         Ok(mark)
     }
@@ -506,10 +665,10 @@ impl<'a> OriginalOid<&'a [u8]> {
 
 impl<'a> Objectish<&'a [u8]> {
     // Corresponds to `from` in `git.git/builtin/fast-import.c:parse_new_tag`.
-    fn parse(objectish: &'a [u8]) -> PResult<Self> {
+    fn parse<R: BufRead>(objectish: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         // Non-commits are allowed.
         if objectish.starts_with(b":") {
-            Mark::parse(objectish).map(Objectish::Mark)
+            Mark::parse(objectish, input, true).map(Objectish::Mark)
         } else {
             Ok(Objectish::BranchOrOid(objectish))
         }
@@ -519,20 +678,20 @@ impl<'a> Objectish<&'a [u8]> {
 impl<'a> Commitish<&'a [u8]> {
     // Corresponds to `parse_objectish` and `parse_merge` in
     // `git.git/builtin/fast-import.c`.
-    pub(super) fn parse(commitish: &'a [u8]) -> PResult<Self> {
+    pub(super) fn parse<R: BufRead>(commitish: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         // TODO: How much of `parse_objectish` should be here or in the
         // front-end?
         // Only commits are allowed.
-        Objectish::parse(commitish).map(|objectish| Commitish { commit: objectish })
+        Objectish::parse(commitish, input).map(|objectish| Commitish { commit: objectish })
     }
 }
 
 impl<'a> Blobish<&'a [u8]> {
     // Corresponds to part of `git.git/builtin/fast-import:parse_cat_blob`.
-    pub(super) fn parse(blobish: &'a [u8]) -> PResult<Self> {
+    pub(super) fn parse<R: BufRead>(blobish: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         // TODO: Parse oids.
         if blobish.starts_with(b":") {
-            Mark::parse(blobish).map(Blobish::Mark)
+            Mark::parse(blobish, input, true).map(Blobish::Mark)
         } else {
             Ok(Blobish::Oid(blobish))
         }
@@ -541,10 +700,10 @@ impl<'a> Blobish<&'a [u8]> {
 
 impl<'a> Treeish<&'a [u8]> {
     // Corresponds to `git.git/builtin/fast-import.c:parse_treeish_dataref`.
-    fn parse(treeish: &'a [u8]) -> PResult<Self> {
+    fn parse<R: BufRead>(treeish: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         // TODO: Parse oids.
         if treeish.starts_with(b":") {
-            Mark::parse(treeish).map(Treeish::Mark)
+            Mark::parse(treeish, input, false).map(Treeish::Mark)
         } else {
             Ok(Treeish::Oid(treeish))
         }
@@ -590,8 +749,8 @@ impl<'a> PersonIdent<&'a [u8]> {
         // TODO: Parse dates
 
         Ok(PersonIdent {
-            name: &ident[..lt.saturating_sub(2)],
-            email: &ident[lt + 1..gt - 1],
+            name: &ident[..lt.saturating_sub(1)],
+            email: &ident[lt + 1..gt],
             date: &ident[(gt + 2).min(ident.len())..],
         })
     }
@@ -608,13 +767,73 @@ impl<'a> Encoding<&'a [u8]> {
     }
 }
 
+impl CommitSignature<&[u8]> {
+    /// Parses the arguments of a `gpgsig` directive. Git 2.51 added the
+    /// signature format and requires it, but it is optional here, so that
+    /// streams from Git 2.48 through 2.50 are accepted.
+    ///
+    // Corresponds to `git.git/builtin/fast-import.c:parse_one_signature`.
+    fn parse_header(args: &[u8]) -> PResult<(HashAlgorithm, Option<SignatureFormat>)> {
+        let (hash_algo, format) = match args.iter().position(|&b| b == b' ') {
+            Some(i) => (&args[..i], Some(&args[i + 1..])),
+            None => (args, None),
+        };
+        let hash_algo = match hash_algo {
+            b"sha1" => HashAlgorithm::Sha1,
+            b"sha256" => HashAlgorithm::Sha256,
+            _ => return Err(ParseError::UnknownSignatureHashAlgorithm.into()),
+        };
+        let format = match format {
+            None => None,
+            Some(b"openpgp") => Some(SignatureFormat::OpenPgp),
+            Some(b"x509") => Some(SignatureFormat::X509),
+            Some(b"ssh") => Some(SignatureFormat::Ssh),
+            Some(b"unknown") => Some(SignatureFormat::Unknown),
+            Some(_) => return Err(ParseError::InvalidSignatureFormat.into()),
+        };
+        Ok((hash_algo, format))
+    }
+}
+
+/// Splits the signature block from the end of a tag message. The signature
+/// starts at the last line that begins a signature of a known format.
+///
+// Corresponds to `git.git/gpg-interface.c:parse_signed_buffer`.
+fn split_tag_signature(message: &[u8]) -> (&[u8], Option<&[u8]>) {
+    const SIGNATURE_STARTS: [&[u8]; 4] = [
+        b"-----BEGIN PGP SIGNATURE-----",
+        b"-----BEGIN PGP MESSAGE-----",
+        b"-----BEGIN SIGNED MESSAGE-----",
+        b"-----BEGIN SSH SIGNATURE-----",
+    ];
+    let mut start = None;
+    let mut line_start = 0;
+    while line_start < message.len() {
+        let line = &message[line_start..];
+        if SIGNATURE_STARTS.iter().any(|sig| line.starts_with(sig)) {
+            start = Some(line_start);
+        }
+        line_start += match memchr(b'\n', line) {
+            Some(i) => i + 1,
+            None => line.len(),
+        };
+    }
+    match start {
+        Some(start) => (&message[..start], Some(&message[start..])),
+        None => (message, None),
+    }
+}
+
 impl<'a> DataHeader<&'a [u8]> {
     /// Parses a `data` directive, but does not read its contents. git
     /// fast-import reads blobs into memory or switches to streaming when they
     /// exceed `--big-file-threshold` (default 512MiB).
     ///
+    /// fast-import does not check any errors for the length, like for
+    /// [`Mark::parse_def`].
+    ///
     // Corresponds to `git.git/builtin/fast-import.c:parse_and_store_blob`.
-    fn parse(arg: &'a [u8]) -> PResult<Self> {
+    pub(super) fn parse<R: BufRead>(arg: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         if let Some(delim) = arg.strip_prefix(b"<<") {
             if delim == b"" {
                 return Err(ParseError::EmptyDelim.into());
@@ -623,7 +842,8 @@ impl<'a> DataHeader<&'a [u8]> {
             }
             Ok(DataHeader::Delimited { delim })
         } else {
-            let len = parse_int(arg).ok_or(ParseError::InvalidDataLength)?;
+            let len =
+                input.parse_c_int(arg, IntSyntax::Unchecked, ParseError::InvalidDataLength)?;
             Ok(DataHeader::Counted { len })
         }
     }
@@ -710,12 +930,15 @@ impl<'a> FastImportPath<&'a [u8]> {
     }
 }
 
+/// The optional root and the path of an `ls` command.
+type LsArgs<'a> = (Option<Treeish<&'a [u8]>>, &'a [u8]);
+
 // Corresponds to `git.git/builtin/fast-import.c:parse_ls`.
 pub(super) fn parse_ls<'a, P: DirectiveParser<R>, R: BufRead + 'a>(
     parser: &'a P,
     args: &'a [u8],
     in_commit: bool,
-) -> PResult<(Option<Treeish<&'a [u8]>>, &'a [u8])> {
+) -> PResult<LsArgs<'a>> {
     if args.is_empty() {
         return Err(ParseError::MissingLsPath.into());
     }
@@ -727,7 +950,7 @@ pub(super) fn parse_ls<'a, P: DirectiveParser<R>, R: BufRead + 'a>(
     } else {
         let i = memchr(b' ', args).ok_or(ParseError::MissingLsPath)?;
         let (root, path) = args.split_at(i);
-        (Some(Treeish::parse(root)?), path)
+        (Some(Treeish::parse(root, parser.input())?), path)
     };
     if path.is_empty() {
         return Err(ParseError::MissingLsPath.into());
@@ -759,22 +982,22 @@ fn parse_rewrite_submodules(args: &[u8]) -> PResult<(&[u8], &[u8])> {
 
 impl<'a> OptionGit<&'a [u8]> {
     // Corresponds to `git.git/builtin/fast-import.c:parse_one_option`.
-    fn parse(option: &'a [u8]) -> PResult<Self> {
+    fn parse<R: BufRead>(option: &'a [u8], input: &BufInput<R>) -> PResult<Self> {
         if let Some(size) = option.strip_prefix(b"max-pack-size=") {
             Ok(OptionGit::MaxPackSize {
-                size: FileSize::parse(size)?,
+                size: FileSize::parse(size, input)?,
             })
         } else if let Some(size) = option.strip_prefix(b"big-file-threshold=") {
             Ok(OptionGit::BigFileThreshold {
-                size: FileSize::parse(size)?,
+                size: FileSize::parse(size, input)?,
             })
         } else if let Some(depth) = option.strip_prefix(b"depth=") {
             Ok(OptionGit::Depth {
-                depth: parse_int(depth).ok_or(ParseError::InvalidOptionInt)?,
+                depth: parse_ulong_arg(depth, input)?,
             })
         } else if let Some(count) = option.strip_prefix(b"active-branches=") {
             Ok(OptionGit::ActiveBranches {
-                count: parse_int(count).ok_or(ParseError::InvalidOptionInt)?,
+                count: parse_ulong_arg(count, input)?,
             })
         } else if let Some(path) = option.strip_prefix(b"export-pack-edges=") {
             Ok(OptionGit::ExportPackEdges { path })
@@ -791,29 +1014,127 @@ impl<'a> OptionGit<&'a [u8]> {
 }
 
 impl FileSize {
-    fn parse(size: &[u8]) -> PResult<Self> {
+    // Corresponds to `git.git/parse.c:git_parse_ulong`.
+    fn parse<R: BufRead>(size: &[u8], input: &BufInput<R>) -> PResult<Self> {
+        let size = match memchr(b'\0', size) {
+            Some(nul) => {
+                // The unit is read from the C string, so is before NUL.
+                let (size, _) = size.split_at(nul);
+                input.accept_quirk(QuirkKind::IntNul, ParseError::InvalidOptionFileSize)?;
+                size
+            }
+            None => size,
+        };
         let (value, unit) = match size {
             [value @ .., b'k' | b'K'] => (value, UnitFactor::K),
             [value @ .., b'm' | b'M'] => (value, UnitFactor::M),
             [value @ .., b'g' | b'G'] => (value, UnitFactor::G),
             _ => (size, UnitFactor::B),
         };
+        let value = input.parse_c_int(
+            value,
+            IntSyntax::GitParseUnsigned,
+            ParseError::InvalidOptionFileSize,
+        )?;
         Ok(FileSize {
-            value: parse_int(value).ok_or(ParseError::InvalidOptionFileSize)?,
+            value: value
+                .try_into()
+                .map_err(|_| ParseError::InvalidOptionFileSize)?,
             unit,
         })
     }
 }
 
-// TODO: Return `PResult<T>`.
-#[inline]
-fn parse_int<T: FromStr>(b: &[u8]) -> Option<T> {
-    // TODO: Make an integer parsing routine, to precisely control the grammar
-    // and protect from upstream changes.
-    if b.starts_with(b"+") {
-        return None;
-    }
-    // SAFETY: `from_str` for integer types operates on bytes and accepts only
-    // ASCII.
-    T::from_str(unsafe { str::from_utf8_unchecked(b) }).ok()
+// Corresponds to `git.git/builtin/fast-import.c:ulong_arg`.
+fn parse_ulong_arg<T: TryFrom<u64>, R: BufRead>(arg: &[u8], input: &BufInput<R>) -> PResult<T> {
+    let value = input.parse_c_int(arg, IntSyntax::UlongArg, ParseError::InvalidOptionInt)?;
+    Ok(value.try_into().map_err(|_| ParseError::InvalidOptionInt)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        command::{Command, CommitSignature, HashAlgorithm, SignatureFormat},
+        parse::{ParseError, Parser, StreamError},
+    };
+
+    const SIG: &[u8] = b"-----BEGIN PGP SIGNATURE-----\n\nabc=\n-----END PGP SIGNATURE-----\n";
+
+    #[test]
+    fn parse_commit_signatures() {
+        let mut input = &b"\
+commit refs/heads/main
+committer C <c@example.com> 1700000000 +0000
+gpgsig sha1 openpgp
+data 64
+-----BEGIN PGP SIGNATURE-----
+
+abc=
+-----END PGP SIGNATURE-----
+gpgsig sha256
+data 4
+sig
+data 4
+msg
+"[..];
+        let mut parser = Parser::new(&mut input);
+        let Command::Commit(commit) = parser.next().unwrap() else {
+            panic!("not a commit");
+        };
+        assert_eq!(
+            commit.signatures,
+            [
+                CommitSignature {
+                    hash_algo: HashAlgorithm::Sha1,
+                    format: Some(SignatureFormat::OpenPgp),
+                    signature: SIG,
+                },
+                CommitSignature {
+                    hash_algo: HashAlgorithm::Sha256,
+                    format: None,
+                    signature: &b"sig\n"[..],
+                },
+            ],
+        );
+        assert_eq!(commit.message, b"msg\n");
+    }
+
+    #[test]
+    fn parse_commit_duplicate_signature() {
+        let mut input = &b"\
+commit refs/heads/main
+committer C <c@example.com> 1700000000 +0000
+gpgsig sha1 ssh
+data 0
+gpgsig sha1 ssh
+data 0
+data 0
+"[..];
+        let mut parser = Parser::new(&mut input);
+        assert!(matches!(
+            parser.next(),
+            Err(StreamError::Parse(ParseError::DuplicateSignature)),
+        ));
+    }
+
+    #[test]
+    fn parse_signed_tag() {
+        let mut input = &b"\
+tag v1.0
+from :1
+tagger T <t@example.com> 1700000000 +0000
+data 72
+Release
+-----BEGIN PGP SIGNATURE-----
+
+abc=
+-----END PGP SIGNATURE-----
+"[..];
+        let mut parser = Parser::new(&mut input);
+        let Command::Tag(tag) = parser.next().unwrap() else {
+            panic!("not a tag");
+        };
+        assert_eq!(tag.message, b"Release\n");
+        assert_eq!(tag.signature, Some(SIG));
+    }
 }
@@ -71,6 +71,7 @@ impl BufPool {
     /// returned `Vec` is performed by the caller and a slice of it is stable
     /// until the next call to [`BufPool::truncate_back`].
     #[inline]
+    #[allow(clippy::mut_from_ref)]
     pub fn push_back(&self) -> &mut Vec<u8> {
         let pool = unsafe { &mut *self.inner.get() };
         let mut buf = pool.free.pop().unwrap_or_default();
@@ -89,6 +90,7 @@ impl BufPool {
     /// Gets an empty auxiliary buffer. Initialization of the returned `Vec` is
     /// performed by the caller and a slice of it is stable until the next call
     /// to [`BufPool::truncate_back`].
+    #[allow(clippy::mut_from_ref)]
     pub fn new_aux_buffer(&self) -> &mut Vec<u8> {
         let pool = unsafe { &mut *self.inner.get() };
         let mut buf = pool.free.pop().unwrap_or_default();
@@ -27,7 +27,7 @@ impl BufPool {
         type Error = ParseStringError;
         assert!(s[0] == b'"', "not a string");
         let mut i = 1;
-        let mut j = memchr2(b'"', b'\\', &s[i..]).ok_or(Error::Unterminated)?;
+        let mut j = i + memchr2(b'"', b'\\', &s[i..]).ok_or(Error::Unterminated)?;
         if s[j] == b'"' {
             // Avoid allocating when we have no escapes.
             return Ok((&s[i..j], &s[j + 1..]));
@@ -77,7 +77,36 @@ impl BufPool {
                 _ => unreachable!(),
             }
             i = j + 1;
-            j = memchr2(b'"', b'\\', &s[i..]).ok_or(Error::Unterminated)?;
+            j = i + memchr2(b'"', b'\\', &s[i..]).ok_or(Error::Unterminated)?;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bstr::ByteSlice;
+
+    use crate::parse::{BufPool, ParseStringError};
+
+    fn unquote(s: &[u8]) -> Result<(String, String), ParseStringError> {
+        let pool = BufPool::new();
+        pool.unquote_c_style_string(s).map(|(unquoted, rest)| {
+            (
+                unquoted.to_str_lossy().into_owned(),
+                rest.to_str_lossy().into_owned(),
+            )
+        })
+    }
+
+    #[test]
+    fn unquote_c_style_string() {
+        assert_eq!(unquote(br#""a b" c"#), Ok(("a b".into(), " c".into())));
+        assert_eq!(
+            unquote(br#""x\ty\\z\"\303\251" rest"#),
+            Ok(("x\ty\\z\"é".into(), " rest".into())),
+        );
+        assert_eq!(unquote(br#""abc"#), Err(ParseStringError::Unterminated));
+        assert_eq!(unquote(br#""a\q""#), Err(ParseStringError::InvalidEscape));
+        assert_eq!(unquote(br#""\400""#), Err(ParseStringError::OctalOverflow));
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! The ancestry of commits, for detecting redundant parents.
+
+use std::collections::HashMap;
+
+use fast_export::command::{Mark, Objectish};
+
+use crate::parser::Bytes;
+
+/// The ancestry of the commits seen so far in a stream. Commits are identified
+/// by their mark or, for commits outside of the stream, by their ID, so the
+/// same graph type records both the original and the rewritten history.
+///
+/// Each commit records its depth and parents. The depth of a commit is one
+/// more than the max depth of any of its parents, so a commit can only be an
+/// ancestor of commits with a greater depth.
+///
+// Corresponds to `git-filter-repo:AncestryGraph`.
+#[derive(Clone, Debug, Default)]
+pub struct AncestryGraph {
+    /// Commits in the stream mapped to their index in `graph`, by mark.
+    marks: HashMap<Mark, usize>,
+    /// Commits outside of the stream mapped to their index in `graph`, by ID.
+    oids: HashMap<Vec<u8>, usize>,
+    /// The depth and parents of each commit, by index.
+    graph: Vec<(u32, Vec<usize>)>,
+    /// Memoized results of `is_ancestor`, by pairs of indices.
+    cached_is_ancestor: HashMap<(usize, usize), bool>,
+    /// The generation of the `is_ancestor` query, which last visited each
+    /// commit, by index. It is reused, so that queries do not allocate.
+    visited: Vec<u32>,
+    /// The generation of the current `is_ancestor` query.
+    generation: u32,
+    /// The commits which remain to be visited by `is_ancestor`.
+    to_visit: Vec<usize>,
+}
+
+impl AncestryGraph {
+    #[inline]
+    pub fn new() -> Self {
+        AncestryGraph::default()
+    }
+
+    /// Records commits which are not in the stream, such as parents excluded
+    /// by a revision range. They are treated as root commits.
+    ///
+    // Corresponds to `git-filter-repo:AncestryGraph.record_external_commits`.
+    pub fn record_external_commits<'a, 'b: 'a, I>(&mut self, external_commits: I)
+    where
+        I: IntoIterator<Item = &'a Objectish<Bytes<'b>>>,
+    {
+        for commit in external_commits {
+            if self.index(commit).is_none() {
+                self.insert(commit, (1, Vec::new()));
+            }
+        }
+    }
+
+    /// Records a commit with its parents. Parents which have not been recorded
+    /// are recorded as external commits.
+    ///
+    // Corresponds to `git-filter-repo:AncestryGraph.add_commit_and_parents`.
+    pub fn add_commit_and_parents<'a, 'b: 'a, I>(
+        &mut self,
+        commit: &Objectish<Bytes<'_>>,
+        parents: I,
+    ) where
+        I: IntoIterator<Item = &'a Objectish<Bytes<'b>>>,
+    {
+        let parents = parents
+            .into_iter()
+            .map(|parent| {
+                self.record_external_commits([parent]);
+                self.index(parent).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let depth = parents
+            .iter()
+            .map(|&parent| self.graph[parent].0 + 1)
+            .max()
+            .unwrap_or(1);
+        self.insert(commit, (depth, parents));
+    }
+
+    /// Returns whether `possible_ancestor` is an ancestor of `check`. A commit
+    /// is considered to be its own ancestor. Commits which have not been
+    /// recorded are not ancestors of anything.
+    ///
+    // Corresponds to `git-filter-repo:AncestryGraph.is_ancestor`.
+    pub fn is_ancestor(
+        &mut self,
+        possible_ancestor: &Objectish<Bytes<'_>>,
+        check: &Objectish<Bytes<'_>>,
+    ) -> bool {
+        let (Some(a1), Some(a2)) = (self.index(possible_ancestor), self.index(check)) else {
+            return false;
+        };
+        if let Some(&cached) = self.cached_is_ancestor.get(&(a1, a2)) {
+            return cached;
+        }
+
+        // A commit has been visited in this query, when its generation is
+        // the current one, so the buffer does not need to be cleared.
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.visited.fill(0);
+            self.generation = 1;
+        }
+        self.visited.resize(self.graph.len(), 0);
+
+        let a1_depth = self.graph[a1].0;
+        self.to_visit.clear();
+        self.to_visit.push(a2);
+        let mut found = false;
+        while let Some(cur) = self.to_visit.pop() {
+            if cur == a1 {
+                found = true;
+                break;
+            }
+            if self.visited[cur] == self.generation {
+                continue;
+            }
+            self.visited[cur] = self.generation;
+            let (depth, parents) = &self.graph[cur];
+            // Commits no deeper than the possible ancestor cannot descend
+            // from it.
+            if *depth > a1_depth {
+                self.to_visit.extend(parents);
+            }
+        }
+        self.cached_is_ancestor.insert((a1, a2), found);
+        found
+    }
+
+    /// Returns the index in `graph` of a recorded commit.
+    fn index(&self, commit: &Objectish<Bytes<'_>>) -> Option<usize> {
+        match commit {
+            Objectish::Mark(mark) => self.marks.get(mark),
+            Objectish::BranchOrOid(oid) => self.oids.get(&**oid),
+        }
+        .copied()
+    }
+
+    /// Records a commit at the end of `graph`, copying its ID only when it is
+    /// outside of the stream.
+    fn insert(&mut self, commit: &Objectish<Bytes<'_>>, node: (u32, Vec<usize>)) {
+        let index = self.graph.len();
+        match commit {
+            Objectish::Mark(mark) => self.marks.insert(*mark, index),
+            Objectish::BranchOrOid(oid) => self.oids.insert(oid.to_vec(), index),
+        };
+        self.graph.push(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, num::NonZeroU64};
+
+    use super::*;
+
+    fn mark(mark: u64) -> Objectish<Bytes<'static>> {
+        Objectish::Mark(Mark {
+            mark: NonZeroU64::new(mark).unwrap(),
+        })
+    }
+
+    #[test]
+    fn ancestry() {
+        let external = Objectish::BranchOrOid(Cow::Borrowed(&b"0123456789abcdef"[..]));
+        let mut graph = AncestryGraph::new();
+        graph.record_external_commits([&external]);
+        graph.add_commit_and_parents(&mark(1), [&external]);
+        graph.add_commit_and_parents(&mark(2), [&mark(1)]);
+        graph.add_commit_and_parents(&mark(3), [&mark(1)]);
+        graph.add_commit_and_parents(&mark(4), [&mark(2), &mark(3)]);
+        graph.add_commit_and_parents(&mark(5), []);
+
+        assert!(graph.is_ancestor(&external, &mark(4)));
+        assert!(graph.is_ancestor(&mark(3), &mark(4)));
+        assert!(graph.is_ancestor(&mark(4), &mark(4)));
+        assert!(!graph.is_ancestor(&mark(2), &mark(3)));
+        assert!(!graph.is_ancestor(&mark(4), &mark(1)));
+        assert!(!graph.is_ancestor(&mark(5), &mark(4)));
+        assert!(!graph.is_ancestor(&mark(6), &mark(4)));
+        // Memoized
+        assert!(graph.is_ancestor(&mark(3), &mark(4)));
+
+        // Visits from earlier queries are not seen after the generation wraps.
+        graph.generation = u32::MAX;
+        assert!(graph.is_ancestor(&mark(1), &mark(4)));
+        assert_eq!(graph.generation, 1);
+        assert!(graph.is_ancestor(&mark(2), &mark(4)));
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! The contents of blobs, which callbacks can read on demand.
+//!
+//! In filter-repo, commit callbacks cannot see the contents of files, so
+//! scripts like lint-history run their own `git cat-file --batch`. Here, the
+//! contents of any file modification can be read from the store.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    rc::Rc,
+};
+
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+use fast_export::{
+    command::{Mark, Mode},
+    parse::{Change, DataRef, FileModifyChange},
+};
+
+use crate::parser::{BlobObject, Bytes, FileChange, NewMarks};
+
+/// The current contents of blobs. Blobs in the stream are read as they were
+/// written, after filtering, and blobs outside it are read from the source
+/// repo. Blobs in the stream, which filtering did not change, are also read
+/// from the source repo, so only changed blobs are saved. Clones share the same
+/// store.
+#[derive(Clone)]
+pub struct BlobStore(Rc<RefCell<Inner>>);
+
+struct Inner {
+    /// The repo, from which blobs outside the stream are read.
+    repo: Option<PathBuf>,
+    /// Whether blobs in the stream are saved.
+    save: bool,
+    /// A temporary file, to which blobs in the stream are appended, which is
+    /// created on the first save.
+    saved: Option<BufWriter<File>>,
+    /// The offset of the end of `saved`.
+    saved_len: u64,
+    /// The offset and length of each saved blob, by mark.
+    offsets: HashMap<Mark, (u64, usize)>,
+    /// The original ID of each unchanged blob in the stream, by mark, which is
+    /// read from `repo` instead of being saved.
+    oids: HashMap<Mark, Vec<u8>>,
+    /// Blobs with contents given by a commit callback, which are written
+    /// before the commit.
+    added: Vec<BlobObject<'static>>,
+    /// The counter of marks for added blobs, which is shared with the filter.
+    new_marks: NewMarks,
+    /// A `git cat-file --batch` process in `repo`, which is spawned on the
+    /// first read from it.
+    cat_file: Option<CatFile>,
+    /// The number of reads, so that callers can tell whether a callback
+    /// depended on the contents of a file.
+    reads: u64,
+}
+
+struct CatFile {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// The contents of the file, whose change a callback is processing, which are
+/// only read when requested.
+#[derive(Clone, Copy, Debug)]
+pub struct FileContents<'a> {
+    pub(crate) store: &'a BlobStore,
+    pub(crate) change: Option<&'a FileChange<'a>>,
+}
+
+impl BlobStore {
+    /// Creates a store, which reads blobs outside the stream from `repo`. When
+    /// `save`, blobs in the stream are saved as they are written, so that they
+    /// can be read back. Added blobs are given marks from `new_marks`.
+    pub(crate) fn new(repo: Option<PathBuf>, save: bool, new_marks: NewMarks) -> Self {
+        BlobStore(Rc::new(RefCell::new(Inner {
+            repo,
+            save,
+            saved: None,
+            saved_len: 0,
+            offsets: HashMap::new(),
+            oids: HashMap::new(),
+            added: Vec::new(),
+            new_marks,
+            cat_file: None,
+            reads: 0,
+        })))
+    }
+
+    /// Saves the contents of a blob in the stream, if saving is enabled. When
+    /// its original ID is given, because filtering did not change it, and
+    /// there is a source repo, only the ID is kept and the contents are read
+    /// from the repo on demand. Otherwise, the contents are appended to a
+    /// temporary file.
+    pub(crate) fn save(&self, mark: Mark, original_oid: Option<&[u8]>, data: &[u8]) -> Result<()> {
+        let mut inner = self.0.borrow_mut();
+        if !inner.save {
+            return Ok(());
+        }
+        match original_oid {
+            Some(oid) if inner.repo.is_some() => {
+                inner.oids.insert(mark, oid.to_vec());
+            }
+            _ => inner.append(mark, data)?,
+        }
+        Ok(())
+    }
+
+    /// Replaces the contents of a file modification with a new blob, which is
+    /// written before the commit. Other references to the old contents keep
+    /// sharing the old blob.
+    pub fn set_contents(&self, change: &mut FileChange<'_>, data: Vec<u8>) -> Result<()> {
+        let Change::FileModify(FileModifyChange { data_ref, mode, .. }) = &mut change.change else {
+            bail!("only the contents of file modifications can be replaced");
+        };
+        if *mode == Mode::GitLink {
+            bail!("the contents of a submodule cannot be replaced");
+        }
+        let mut inner = self.0.borrow_mut();
+        let mark = inner.new_marks.next();
+        inner.append(mark, &data)?;
+        inner.added.push(BlobObject {
+            mark: Some(mark),
+            original_oid: None,
+            data,
+        });
+        *data_ref = DataRef::Mark(mark);
+        change.inline_data = None;
+        Ok(())
+    }
+
+    /// Takes the blobs, which were added by `set_contents`, to be written.
+    pub(crate) fn take_added(&self) -> Vec<BlobObject<'static>> {
+        mem::take(&mut self.0.borrow_mut().added)
+    }
+
+    /// Reads the contents of the blob, which a file modification refers to.
+    /// Inline data is not in the store, so is an error.
+    pub fn read(&self, data_ref: &DataRef<Bytes<'_>>) -> Result<Vec<u8>> {
+        let mut inner = self.0.borrow_mut();
+        inner.reads += 1;
+        match data_ref {
+            DataRef::Mark(mark) => inner.read_saved(*mark),
+            DataRef::Oid(oid) => inner.read_repo(oid),
+            DataRef::Inline(_) => bail!("inline data is not in the blob store"),
+        }
+    }
+
+    /// Reads the contents of a file modification. Other changes and
+    /// submodules have no contents.
+    pub fn read_change(&self, change: &FileChange<'_>) -> Result<Option<Vec<u8>>> {
+        match &change.change {
+            Change::FileModify(FileModifyChange { mode, .. }) if *mode == Mode::GitLink => Ok(None),
+            Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Inline(_),
+                ..
+            }) => {
+                self.0.borrow_mut().reads += 1;
+                Ok(change.inline_data.clone())
+            }
+            Change::FileModify(FileModifyChange { data_ref, .. }) => self.read(data_ref).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the number of reads so far.
+    pub(crate) fn reads(&self) -> u64 {
+        self.0.borrow().reads
+    }
+}
+
+impl Inner {
+    fn append(&mut self, mark: Mark, data: &[u8]) -> Result<()> {
+        let offset = self.saved_len;
+        let saved = match &mut self.saved {
+            Some(saved) => saved,
+            None => {
+                let file = tempfile::tempfile().context("could not create a file to save blobs")?;
+                self.saved.insert(BufWriter::new(file))
+            }
+        };
+        saved.write_all(data)?;
+        self.saved_len += data.len() as u64;
+        self.offsets.insert(mark, (offset, data.len()));
+        Ok(())
+    }
+
+    fn read_saved(&mut self, mark: Mark) -> Result<Vec<u8>> {
+        if let Some(oid) = self.oids.get(&mark) {
+            return self.read_repo(&oid.clone());
+        }
+        let (Some(saved), Some(&(offset, len))) = (&mut self.saved, self.offsets.get(&mark)) else {
+            if !self.save {
+                bail!("blobs are only saved when there is a commit or filename callback");
+            }
+            bail!("blob :{} is not in the stream", mark.mark);
+        };
+        saved.flush()?;
+        let file = saved.get_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(data)
+    }
+
+    fn read_repo(&mut self, oid: &[u8]) -> Result<Vec<u8>> {
+        let Some(repo) = &self.repo else {
+            bail!("Cannot read blob {} without a source repo", oid.as_bstr());
+        };
+        let cat_file = match &mut self.cat_file {
+            Some(cat_file) => cat_file,
+            None => {
+                let mut child = Command::new("git")
+                    .arg("-C")
+                    .arg(repo)
+                    .args(["cat-file", "--batch"])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("could not run git cat-file")?;
+                let stdin = child.stdin.take();
+                let stdout = BufReader::new(child.stdout.take().unwrap());
+                self.cat_file.insert(CatFile {
+                    child,
+                    stdin,
+                    stdout,
+                })
+            }
+        };
+        let stdin = cat_file.stdin.as_mut().unwrap();
+        stdin.write_all(&[oid, b"\n"].concat())?;
+        stdin.flush()?;
+        let mut header = Vec::new();
+        cat_file.stdout.read_until(b'\n', &mut header)?;
+        // The header is `<oid> blob <size>` or `<oid> missing`.
+        let len = match header.trim_end().split_str(" ").collect::<Vec<_>>()[..] {
+            [_, b"blob", len] => len.to_str()?.parse::<usize>()?,
+            _ => bail!("{} is not a blob", oid.as_bstr()),
+        };
+        let mut data = vec![0; len + 1];
+        cat_file.stdout.read_exact(&mut data)?;
+        data.pop();
+        Ok(data)
+    }
+}
+
+impl Drop for CatFile {
+    fn drop(&mut self) {
+        // Closing stdin ends the batch.
+        self.stdin = None;
+        let _ = self.child.wait();
+    }
+}
+
+impl Debug for BlobStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let inner = self.0.borrow();
+        f.debug_struct("BlobStore")
+            .field("repo", &inner.repo)
+            .field("save", &inner.save)
+            .field("saved", &inner.offsets.len())
+            .field("unchanged", &inner.oids.len())
+            .finish()
+    }
+}
+
+impl FileContents<'_> {
+    /// Reads the contents of the file. Deletions, renames, and submodules
+    /// have no contents.
+    pub fn read(&self) -> Result<Option<Vec<u8>>> {
+        match self.change {
+            Some(change) => self.store.read_change(change),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, num::NonZeroU64};
+
+    use fast_export::command::DataHeader;
+
+    use super::*;
+    use crate::test_util::{git, init_repo};
+
+    #[test]
+    fn saved_and_inline() {
+        let mark = |mark| Mark {
+            mark: NonZeroU64::new(mark).unwrap(),
+        };
+        let store = BlobStore::new(None, true, NewMarks::new());
+        store.save(mark(1), None, b"one").unwrap();
+        store.save(mark(2), None, b"two").unwrap();
+        assert_eq!(store.read(&DataRef::Mark(mark(2))).unwrap(), b"two");
+        // Without a repo, blobs with an original ID are saved too.
+        store.save(mark(3), Some(b"0123"), b"three").unwrap();
+        assert_eq!(store.read(&DataRef::Mark(mark(1))).unwrap(), b"one");
+        assert_eq!(store.read(&DataRef::Mark(mark(3))).unwrap(), b"three");
+        assert!(store.read(&DataRef::Mark(mark(4))).is_err());
+
+        let inline = FileChange {
+            change: Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Inline(DataHeader::Counted { len: 2 }),
+                mode: Mode::File,
+                path: Cow::Borrowed(b"a"),
+            }),
+            inline_data: Some(b"hi".to_vec()),
+        };
+        let contents = FileContents {
+            store: &store,
+            change: Some(&inline),
+        };
+        assert_eq!(contents.read().unwrap().as_deref(), Some(&b"hi"[..]));
+        assert_eq!(store.reads(), 5);
+    }
+
+    #[test]
+    fn set_contents_marks() {
+        let modify = || FileChange {
+            change: Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Oid(Cow::Borrowed(b"0123")),
+                mode: Mode::File,
+                path: Cow::Borrowed(b"a"),
+            }),
+            inline_data: None,
+        };
+        let data_ref = |change: &FileChange<'static>| match &change.change {
+            Change::FileModify(FileModifyChange { data_ref, .. }) => data_ref.clone(),
+            _ => unreachable!(),
+        };
+        // Stores with separate counters do not affect each other, and a
+        // shared counter is advanced by both.
+        let marks = NewMarks::new();
+        let first = marks.next();
+        let (a, b) = (
+            BlobStore::new(None, true, marks.clone()),
+            BlobStore::new(None, true, NewMarks::new()),
+        );
+        let (mut change_a, mut change_b) = (modify(), modify());
+        a.set_contents(&mut change_a, b"a".to_vec()).unwrap();
+        b.set_contents(&mut change_b, b"b".to_vec()).unwrap();
+        let mark_a = match data_ref(&change_a) {
+            DataRef::Mark(mark) => mark,
+            _ => unreachable!(),
+        };
+        assert_eq!(mark_a.mark.get(), first.mark.get() + 1);
+        assert_eq!(data_ref(&change_b), DataRef::Mark(first));
+        assert_eq!(marks.next().mark.get(), first.mark.get() + 2);
+        assert_eq!(a.read(&DataRef::Mark(mark_a)).unwrap(), b"a");
+        assert_eq!(a.take_added().len(), 1);
+    }
+
+    #[test]
+    fn unchanged_from_repo() {
+        let repo = init_repo();
+        std::fs::write(repo.path().join("a"), "original\n").unwrap();
+        let oid = git(repo.path(), &["hash-object", "-w", "a"]);
+        let oid = oid.trim_end().as_bytes();
+
+        let mark = Mark {
+            mark: NonZeroU64::new(1).unwrap(),
+        };
+        let store = BlobStore::new(Some(repo.path().to_owned()), true, NewMarks::new());
+        store.save(mark, Some(oid), b"original\n").unwrap();
+        assert_eq!(store.read(&DataRef::Mark(mark)).unwrap(), b"original\n");
+        // Only the ID was kept.
+        assert!(store.0.borrow().saved.is_none());
+    }
+}
@@ -3,116 +3,380 @@
 // This file is part of git-transform-repo, distributed under the GPL 2.0 with a
 // linking exception. For the full terms, see the included COPYING file.
 
+#[cfg(any(feature = "python", feature = "lua"))]
+use std::io::BufRead;
+#[cfg(feature = "lua")]
+use std::rc::Rc;
 use std::{
-    fmt::{self, Debug, Formatter},
-    fs::File,
-    io::{BufRead, BufReader},
-    path::Path,
+    fmt::{self, Debug, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
 };
+#[cfg(feature = "python")]
+use std::{fs::File, io::BufReader};
 
 use anyhow::{bail, Result};
+use fast_export::{
+    command::{Commit, Reset, Tag},
+    parse::Compat,
+};
+#[cfg(feature = "lua")]
+use mlua::Lua;
 use paste::paste;
+#[cfg(feature = "python")]
 use pyo3::{
-    types::{PyDict, PyFunction, PyList},
-    PyResult, Python,
+    types::{PyDict, PyFunction, PyList, PyModule},
+    wrap_pyfunction, Py, PyAny, PyResult, Python,
 };
 
-use crate::filter::{RepoFilter, TODO};
+#[cfg(feature = "lua")]
+use crate::lua_api::{self, LuaCallback};
+#[cfg(feature = "python")]
+use crate::py_api;
+use crate::{
+    blob_store::FileContents,
+    callback::{
+        BlobCallback, BytesCallback, Callback, CommitCallback, CommitMetadata, DoneCallback,
+        FilenameCallback, ResetCallback, TagCallback,
+    },
+    filter::RepoFilter,
+    options::FilteringOptions,
+    parser::{BlobObject, Bytes, FileChange, NewMarks},
+    progress::ProgressEvent,
+    reencode::{Charset, Reencode},
+    signature::SignaturePolicy,
+    stream::{Input, Output},
+};
 
 /// A builder for constructing a [`RepoFilter`].
-pub struct Builder<'py> {
-    py: Python<'py>,
-    pub(crate) args: TODO,
-    pub(crate) filename_callback: Option<&'py PyFunction>,
-    pub(crate) message_callback: Option<&'py PyFunction>,
-    pub(crate) name_callback: Option<&'py PyFunction>,
-    pub(crate) email_callback: Option<&'py PyFunction>,
-    pub(crate) refname_callback: Option<&'py PyFunction>,
-    pub(crate) blob_callback: Option<&'py PyFunction>,
-    pub(crate) commit_callback: Option<&'py PyFunction>,
-    pub(crate) tag_callback: Option<&'py PyFunction>,
-    pub(crate) reset_callback: Option<&'py PyFunction>,
-    pub(crate) done_callback: Option<&'py PyFunction>,
+pub struct Builder<'a> {
+    pub(crate) args: FilteringOptions,
+    pub(crate) filename_callback: FilenameCallback<'a>,
+    pub(crate) message_callback: BytesCallback<'a>,
+    pub(crate) name_callback: BytesCallback<'a>,
+    pub(crate) email_callback: BytesCallback<'a>,
+    pub(crate) refname_callback: BytesCallback<'a>,
+    pub(crate) blob_callback: BlobCallback<'a>,
+    pub(crate) commit_callback: CommitCallback<'a>,
+    pub(crate) tag_callback: TagCallback<'a>,
+    pub(crate) reset_callback: ResetCallback<'a>,
+    pub(crate) done_callback: DoneCallback<'a>,
+    pub(crate) input: Input,
+    pub(crate) output: Output,
+    pub(crate) progress_events: Option<Sender<ProgressEvent>>,
+    pub(crate) new_marks: NewMarks,
+    #[cfg(feature = "python")]
     code_buf: String,
+    /// The Lua state, which is shared by Lua callbacks, once one is set.
+    #[cfg(feature = "lua")]
+    lua: Option<Rc<Lua>>,
 }
 
-macro_rules! callback(($name:ident, $doc_name:literal) => {
+macro_rules! callback(($name:ident, $doc_name:literal, $(for<$lt:lifetime>)? ($($arg:ty),*) -> $ret:ty) => {
     paste! {
         /// Sets the Python callback for processing
         #[doc = concat!($doc_name, ".")]
         /// It may be a function body from a `&str`, `&Path`, or `&mut BufRead`,
-        /// or an already parsed `&'py PyFunction`.
+        /// or an already parsed `&PyFunction`.
+        #[cfg(feature = "python")]
         #[inline]
-        pub fn [<$name _callback>]<T: ToCallback<'py>>(&mut self, callback: T) -> Result<&mut Self> {
+        pub fn [<$name _callback>]<T: ToCallback>(&mut self, callback: T) -> Result<&mut Self> {
             if self.[<$name _callback>].is_some() {
                 bail!("{} callback redefined", stringify!($name));
             }
             self.code_buf.clear();
-            let callback = callback.to_callback(self.py, stringify!($name), &mut self.code_buf)?;
-            self.[<$name _callback>] = Some(callback);
+            let callback = callback.to_callback(stringify!($name), &mut self.code_buf)?;
+            self.[<$name _callback>] = Callback::Python(callback);
+            Ok(self)
+        }
+
+        /// Sets the Lua callback for processing
+        #[doc = concat!($doc_name, ".")]
+        /// It may be a chunk body from a `&str`, `&Path`, or `&mut BufRead`,
+        /// which receives the same parameters as the Python callback.
+        #[cfg(feature = "lua")]
+        #[inline]
+        pub fn [<$name _callback_lua>]<T: ToLuaCallback>(
+            &mut self,
+            callback: T,
+        ) -> Result<&mut Self> {
+            if self.[<$name _callback>].is_some() {
+                bail!("{} callback redefined", stringify!($name));
+            }
+            let callback = callback.to_lua_callback(stringify!($name), &self.lua())?;
+            self.[<$name _callback>] = Callback::Lua(callback);
+            Ok(self)
+        }
+
+        /// Sets a Rust callback for processing
+        #[doc = concat!($doc_name, ".")]
+        /// It receives the parsed values directly, without converting them.
+        #[inline]
+        pub fn [<$name _callback_fn>](
+            &mut self,
+            callback: impl $(for<$lt>)? FnMut($($arg),*) -> Result<$ret> + 'a,
+        ) -> Result<&mut Self> {
+            if self.[<$name _callback>].is_some() {
+                bail!("{} callback redefined", stringify!($name));
+            }
+            self.[<$name _callback>] = Callback::Rust(Box::new(callback));
             Ok(self)
         }
     }
 });
 
-impl<'py> Builder<'py> {
-    /// Creates a new `RepoFilter` builder with no callbacks.
+macro_rules! hook(($name:ident, $doc_name:literal, ($($arg:ident),*)) => {
+    paste! {
+        /// Sets a Python hook for processing
+        #[doc = concat!($doc_name, ",")]
+        /// which reads and writes fields with getter and setter functions,
+        /// like `message()` and `message(set=...)`, instead of object
+        /// properties. Fields it never reads are not copied to Python. It
+        /// occupies the same slot as the callback. It may be a function body
+        /// from a `&str`, `&Path`, or `&mut BufRead`.
+        #[cfg(feature = "python")]
+        #[inline]
+        pub fn [<$name _hook>]<T: ToHook>(&mut self, hook: T) -> Result<&mut Self> {
+            self.code_buf.clear();
+            let hook = hook.to_hook(stringify!($name), &mut self.code_buf)?;
+            self.[<$name _callback_fn>](move |$($arg),*| hook.[<call_ $name>]($($arg),*))
+        }
+    }
+});
+
+impl<'a> Builder<'a> {
+    /// Creates a new `RepoFilter` builder with no callbacks, other than those
+    /// given in the options. The input and output are set by the options.
     #[inline]
-    pub fn new(py: Python<'py>, args: TODO) -> Self {
+    pub fn new(args: FilteringOptions) -> Self {
+        let input = if args.stdin {
+            Input::Stdin
+        } else {
+            Input::Repo(args.source.clone().unwrap_or_else(|| ".".into()))
+        };
+        // Without --target, the output is the source repo, which is only
+        // allowed with --in-place.
+        let output = Output::Repo(
+            args.target
+                .clone()
+                .or_else(|| args.source.clone())
+                .unwrap_or_else(|| ".".into()),
+        );
         Builder {
-            py,
             args,
-            filename_callback: None,
-            message_callback: None,
-            name_callback: None,
-            email_callback: None,
-            refname_callback: None,
-            blob_callback: None,
-            commit_callback: None,
-            tag_callback: None,
-            reset_callback: None,
-            done_callback: None,
+            filename_callback: Callback::None,
+            message_callback: Callback::None,
+            name_callback: Callback::None,
+            email_callback: Callback::None,
+            refname_callback: Callback::None,
+            blob_callback: Callback::None,
+            commit_callback: Callback::None,
+            tag_callback: Callback::None,
+            reset_callback: Callback::None,
+            done_callback: Callback::None,
+            input,
+            output,
+            progress_events: None,
+            new_marks: NewMarks::new(),
+            #[cfg(feature = "python")]
             code_buf: String::new(),
+            #[cfg(feature = "lua")]
+            lua: None,
         }
     }
 
-    /// Builds a `RepoFilter` with the current configuration.
+    /// Builds a `RepoFilter` with the current configuration. Callbacks given
+    /// in the options are compiled here and conflict with those set on the
+    /// builder.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._handle_arg_callbacks`.
+    #[cfg_attr(not(any(feature = "python", feature = "lua")), allow(unused_mut))]
+    pub fn build(mut self) -> Result<RepoFilter<'a>> {
+        // Untagged callbacks are Python, like in filter-repo, and others are
+        // tagged with their language, like `--commit-callback:lua`.
+        macro_rules! handle(($($name:ident),*) => {
+            paste! {
+                $(
+                    let name = stringify!($name);
+                    let python = arg_callback(
+                        name,
+                        Language::Python,
+                        self.args.[<$name _callback>].as_deref(),
+                        self.[<$name _callback>].is_some(),
+                    )?;
+                    let lua = arg_callback(
+                        name,
+                        Language::Lua,
+                        self.args.[<$name _callback_lua>].as_deref(),
+                        self.[<$name _callback>].is_some(),
+                    )?;
+                    if python.is_some() && lua.is_some() {
+                        bail!("Cannot pass both --{name}-callback and --{name}-callback:lua");
+                    }
+                    #[cfg(feature = "python")]
+                    if let Some(callback) = python {
+                        self.code_buf.clear();
+                        self.[<$name _callback>] = Callback::Python(parse_callback(
+                            &mut callback.code.as_bytes(),
+                            name,
+                            callback.path.as_deref(),
+                            &mut self.code_buf,
+                        )?);
+                    }
+                    #[cfg(feature = "lua")]
+                    if let Some(callback) = lua {
+                        self.[<$name _callback>] = Callback::Lua(LuaCallback::load(
+                            &self.lua(),
+                            name,
+                            &callback.code,
+                            callback.path.as_deref(),
+                        )?);
+                    }
+                )*
+            }
+        });
+        handle!(filename, message, name, email, refname, blob, commit, tag, reset);
+
+        #[cfg(feature = "python")]
+        macro_rules! handle_hook(($($name:ident),*) => {
+            paste! {
+                $(if let Some(code) = self.args.[<$name _hook>].take() {
+                    if self.args.[<$name _callback>].is_some() {
+                        bail!(
+                            "Cannot pass both --{0}-callback and --{0}-hook",
+                            stringify!($name),
+                        );
+                    } else if self.args.[<$name _callback_lua>].is_some() {
+                        bail!(
+                            "Cannot pass both --{0}-callback:lua and --{0}-hook",
+                            stringify!($name),
+                        );
+                    } else if self.[<$name _callback>].is_some() {
+                        bail!(
+                            "Cannot pass a {0}_callback to RepoFilter AND pass --{0}-hook",
+                            stringify!($name),
+                        );
+                    }
+                    // Like for callbacks, the argument is a file, if one
+                    // exists with that name.
+                    let path = Path::new(&code);
+                    if path.exists() {
+                        self.[<$name _hook>](path)?;
+                    } else {
+                        self.[<$name _hook>](code.as_str())?;
+                    }
+                })*
+            }
+        });
+        #[cfg(not(feature = "python"))]
+        macro_rules! handle_hook(($($name:ident),*) => {
+            paste! {
+                $(if self.args.[<$name _hook>].is_some() {
+                    bail!(
+                        "--{}-hook requires Python, which is not enabled in this build",
+                        stringify!($name),
+                    );
+                })*
+            }
+        });
+        handle_hook!(blob, commit, tag, reset);
+        Ok(self.into())
+    }
+
+    /// Sets where the fast-export stream is read from. It defaults to the repo
+    /// in the current directory.
     #[inline]
-    pub fn build(self) -> RepoFilter<'py> {
-        self.into()
-    }
-
-    callback!(filename, "filenames");
-    callback!(message, "messages (both commit messages and tag messages)");
-    callback!(name, "names of people");
-    callback!(email, "email addresses");
-    callback!(refname, "refnames");
-    callback!(blob, "blob objects");
-    callback!(commit, "commit objects");
-    callback!(tag, "tag objects");
-    callback!(reset, "reset objects");
-    callback!(done, "the end of the stream");
-}
+    pub fn input(&mut self, input: Input) -> &mut Self {
+        self.input = input;
+        self
+    }
 
-impl Clone for Builder<'_> {
+    /// Sets where the filtered fast-import stream is written to. It defaults to
+    /// `--target` or, with `--in-place`, the source repo.
     #[inline]
-    fn clone(&self) -> Self {
-        Builder {
-            py: self.py,
-            args: TODO,
-            filename_callback: self.filename_callback,
-            message_callback: self.message_callback,
-            name_callback: self.name_callback,
-            email_callback: self.email_callback,
-            refname_callback: self.refname_callback,
-            blob_callback: self.blob_callback,
-            commit_callback: self.commit_callback,
-            tag_callback: self.tag_callback,
-            reset_callback: self.reset_callback,
-            done_callback: self.done_callback,
-            code_buf: String::new(),
-        }
+    pub fn output(&mut self, output: Output) -> &mut Self {
+        self.output = output;
+        self
+    }
+
+    /// Sets what to do with the signatures of commits and tags, which rewriting
+    /// invalidated. It defaults to `--signatures`.
+    #[inline]
+    pub fn signature_policy(&mut self, policy: SignaturePolicy) -> &mut Self {
+        self.args.signatures = policy;
+        self
+    }
+
+    /// Sets how commits with an `encoding` header are handled. It defaults to
+    /// `--reencode`, or `no` with `--preserve-commit-encoding`.
+    #[inline]
+    pub fn reencode(&mut self, mode: Reencode) -> &mut Self {
+        self.args.reencode = mode;
+        self
+    }
+
+    /// Sets the encodings tried, in order, for commits and tags without an
+    /// `encoding` header, which are not valid UTF-8. It defaults to
+    /// `--fallback-encoding`.
+    #[inline]
+    pub fn fallback_encodings(&mut self, charsets: Vec<Charset>) -> &mut Self {
+        self.args.fallback_encodings = charsets;
+        self
+    }
+
+    /// Sets whether to accept the malformed input, which fast-import of a Git
+    /// version accepts: signs, leading whitespace, junk, and other radixes in
+    /// integers, `mark :0`, and, before 2.45, invalidly quoted paths. It
+    /// defaults to `--fast-import-compat`, or strict parsing without it.
+    #[inline]
+    pub fn compat(&mut self, compat: Compat) -> &mut Self {
+        self.args.compat = compat;
+        self
+    }
+
+    /// Returns the Lua state for callbacks, which is created on first use.
+    #[cfg(feature = "lua")]
+    fn lua(&mut self) -> Rc<Lua> {
+        self.lua.get_or_insert_with(lua_api::new_lua).clone()
     }
+
+    /// Sets a channel, to which structured progress events are sent, for
+    /// callers which display progress themselves. Progress is still printed to
+    /// stderr, unless `--quiet`.
+    #[inline]
+    pub fn progress_events(&mut self, events: Sender<ProgressEvent>) -> &mut Self {
+        self.progress_events = Some(events);
+        self
+    }
+
+    callback!(
+        filename,
+        "filenames",
+        (&mut Bytes<'_>, FileContents<'_>) -> bool
+    );
+    callback!(
+        message,
+        "messages (both commit messages and tag messages)",
+        (&mut Bytes<'_>) -> ()
+    );
+    callback!(name, "names of people", (&mut Bytes<'_>) -> ());
+    callback!(email, "email addresses", (&mut Bytes<'_>) -> ());
+    callback!(refname, "refnames", (&mut Bytes<'_>) -> ());
+    callback!(blob, "blob objects", (&mut BlobObject<'_>) -> bool);
+    callback!(
+        commit,
+        "commit objects",
+        for<'c> (&mut Commit<Bytes<'c>>, &mut Vec<FileChange<'c>>, &CommitMetadata<'_>) -> bool
+    );
+    callback!(tag, "tag objects", (&mut Tag<Bytes<'_>>) -> bool);
+    callback!(reset, "reset objects", (&mut Reset<Bytes<'_>>) -> bool);
+    callback!(done, "the end of the stream", () -> ());
+
+    hook!(blob, "blob objects", (blob));
+    hook!(commit, "commit objects", (commit, changes, metadata));
+    hook!(tag, "tag objects", (tag));
+    hook!(reset, "reset objects", (reset));
 }
 
 impl Debug for Builder<'_> {
@@ -128,6 +392,9 @@ impl Debug for Builder<'_> {
             .field("tag_callback", &self.tag_callback)
             .field("reset_callback", &self.reset_callback)
             .field("done_callback", &self.done_callback)
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .field("progress_events", &self.progress_events)
             .finish()
     }
 }
@@ -135,99 +402,313 @@ impl Debug for Builder<'_> {
 /// A type that can be converted to a Python callback.
 ///
 /// This allows for overloading of the callback setters in `Builder`.
-pub trait ToCallback<'py> {
-    fn to_callback(
-        self,
-        py: Python<'py>,
-        name: &str,
-        code_buf: &mut String,
-    ) -> Result<&'py PyFunction>;
+#[cfg(feature = "python")]
+pub trait ToCallback {
+    fn to_callback(self, name: &str, code_buf: &mut String) -> Result<Py<PyFunction>>;
 }
 
-impl<'py> ToCallback<'py> for &str {
+#[cfg(feature = "python")]
+impl ToCallback for &str {
     #[inline]
-    fn to_callback(
-        self,
-        py: Python<'py>,
-        name: &str,
-        code_buf: &mut String,
-    ) -> Result<&'py PyFunction> {
-        parse_callback(py, &mut self.as_bytes(), name, code_buf)
+    fn to_callback(self, name: &str, code_buf: &mut String) -> Result<Py<PyFunction>> {
+        parse_callback(&mut self.as_bytes(), name, None, code_buf)
     }
 }
 
-impl<'py> ToCallback<'py> for &Path {
+#[cfg(feature = "python")]
+impl ToCallback for &Path {
     #[inline]
-    fn to_callback(
-        self,
-        py: Python<'py>,
-        name: &str,
-        code_buf: &mut String,
-    ) -> Result<&'py PyFunction> {
+    fn to_callback(self, name: &str, code_buf: &mut String) -> Result<Py<PyFunction>> {
         let mut f = BufReader::new(File::open(self)?);
-        parse_callback(py, &mut f, name, code_buf)
+        parse_callback(&mut f, name, Some(self), code_buf)
     }
 }
 
-impl<'py, T: BufRead> ToCallback<'py> for &mut T {
+#[cfg(feature = "python")]
+impl<T: BufRead> ToCallback for &mut T {
     #[inline]
-    fn to_callback(
-        self,
-        py: Python<'py>,
-        name: &str,
-        code_buf: &mut String,
-    ) -> Result<&'py PyFunction> {
-        parse_callback(py, self, name, code_buf)
+    fn to_callback(self, name: &str, code_buf: &mut String) -> Result<Py<PyFunction>> {
+        parse_callback(self, name, None, code_buf)
     }
 }
 
-impl<'py> ToCallback<'py> for &'py PyFunction {
+#[cfg(feature = "python")]
+impl ToCallback for &PyFunction {
     #[inline]
-    fn to_callback(
-        self,
-        _py: Python<'py>,
-        _name: &str,
-        _buf: &mut String,
-    ) -> Result<&'py PyFunction> {
-        Ok(self)
+    fn to_callback(self, _name: &str, _buf: &mut String) -> Result<Py<PyFunction>> {
+        Ok(self.into())
     }
 }
 
-fn parse_callback<'py>(
-    py: Python<'py>,
+/// A type that can be converted to a Python hook.
+///
+/// This allows for overloading of the hook setters in `Builder`.
+#[cfg(feature = "python")]
+pub trait ToHook {
+    fn to_hook(self, name: &str, code_buf: &mut String) -> Result<py_api::Hook>;
+}
+
+#[cfg(feature = "python")]
+impl ToHook for &str {
+    #[inline]
+    fn to_hook(self, name: &str, code_buf: &mut String) -> Result<py_api::Hook> {
+        parse_hook(&mut self.as_bytes(), name, None, code_buf)
+    }
+}
+
+#[cfg(feature = "python")]
+impl ToHook for &Path {
+    #[inline]
+    fn to_hook(self, name: &str, code_buf: &mut String) -> Result<py_api::Hook> {
+        let mut f = BufReader::new(File::open(self)?);
+        parse_hook(&mut f, name, Some(self), code_buf)
+    }
+}
+
+#[cfg(feature = "python")]
+impl<T: BufRead> ToHook for &mut T {
+    #[inline]
+    fn to_hook(self, name: &str, code_buf: &mut String) -> Result<py_api::Hook> {
+        parse_hook(self, name, None, code_buf)
+    }
+}
+
+/// A type that can be converted to a Lua callback.
+///
+/// This allows for overloading of the Lua callback setters in `Builder`.
+#[cfg(feature = "lua")]
+pub trait ToLuaCallback {
+    fn to_lua_callback(self, name: &str, lua: &Rc<Lua>) -> Result<LuaCallback>;
+}
+
+#[cfg(feature = "lua")]
+impl ToLuaCallback for &str {
+    #[inline]
+    fn to_lua_callback(self, name: &str, lua: &Rc<Lua>) -> Result<LuaCallback> {
+        LuaCallback::load(lua, name, self, None)
+    }
+}
+
+#[cfg(feature = "lua")]
+impl ToLuaCallback for &Path {
+    #[inline]
+    fn to_lua_callback(self, name: &str, lua: &Rc<Lua>) -> Result<LuaCallback> {
+        let code = fs::read_to_string(self)?;
+        LuaCallback::load(lua, name, &code, Some(self))
+    }
+}
+
+#[cfg(feature = "lua")]
+impl<T: BufRead> ToLuaCallback for &mut T {
+    #[inline]
+    fn to_lua_callback(self, name: &str, lua: &Rc<Lua>) -> Result<LuaCallback> {
+        let mut code = String::new();
+        self.read_to_string(&mut code)?;
+        LuaCallback::load(lua, name, &code, None)
+    }
+}
+
+/// A language, in which callbacks can be written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Language {
+    Python,
+    Lua,
+}
+
+impl Language {
+    /// Returns the tag for the language, which is appended to the option
+    /// name. Python is untagged, like in filter-repo, though `:py` is
+    /// accepted as an alias.
+    fn option_suffix(self) -> &'static str {
+        match self {
+            Language::Python => "",
+            Language::Lua => ":lua",
+        }
+    }
+
+    /// Returns whether support for the language is enabled in this build.
+    fn is_enabled(self) -> bool {
+        match self {
+            Language::Python => cfg!(feature = "python"),
+            Language::Lua => cfg!(feature = "lua"),
+        }
+    }
+}
+
+impl Display for Language {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Language::Python => "Python",
+            Language::Lua => "Lua",
+        })
+    }
+}
+
+/// The code of a callback, which was passed as an option.
+#[cfg_attr(not(any(feature = "python", feature = "lua")), allow(dead_code))]
+struct ArgCallback {
+    code: String,
+    /// The file it was read from, if any.
+    path: Option<PathBuf>,
+}
+
+/// Reads the code of a callback, which was passed as an option, and checks
+/// that it can be used.
+fn arg_callback(
+    name: &str,
+    language: Language,
+    code: Option<&str>,
+    has_callback: bool,
+) -> Result<Option<ArgCallback>> {
+    let Some(code) = code else {
+        return Ok(None);
+    };
+    let option = format!("--{name}-callback{}", language.option_suffix());
+    if !language.is_enabled() {
+        bail!("{option} requires {language}, which is not enabled in this build");
+    }
+    if has_callback {
+        bail!("Cannot pass a {name}_callback to RepoFilter AND pass {option}");
+    }
+    // Like filter-repo, the argument is a file, if one exists with that name.
+    let path = Path::new(code);
+    let (code, path) = if path.exists() {
+        (fs::read_to_string(path)?, Some(path.to_owned()))
+    } else {
+        (code.to_owned(), None)
+    };
+    if !code.contains("return ") && !matches!(name, "blob" | "commit" | "tag" | "reset") {
+        bail!("{option} should have a return statement");
+    }
+    Ok(Some(ArgCallback { code, path }))
+}
+
+/// Compiles a callback. `filename` is the file it was read from, if any, for
+/// tracebacks.
+#[cfg(feature = "python")]
+fn parse_callback(
     callback: &mut dyn BufRead,
     name: &str,
+    filename: Option<&Path>,
     code_buf: &mut String,
-) -> Result<&'py PyFunction> {
+) -> Result<Py<PyFunction>> {
     // Since callbacks can contain `return`, they need to be wrapped in a
-    // function. Otherwise, I could invoke `Py_CompileString` without
-    // `PyEval_EvalCode` and keep the same source positions for error messages.
+    // function. The positions in the compiled code are shifted back to those
+    // in the unwrapped code, so that tracebacks match what the user wrote.
     // If they were changed incompatibly from filter-repo to use setters instead
-    // of `return`, this would be possible.
+    // of `return`, this would not be needed.
     code_buf.push_str("def callback(");
     code_buf.push_str(name);
     code_buf.push_str(", _do_not_use_this_var = None):");
-    for line in callback.lines() {
+    push_body(callback, code_buf)?;
+
+    // The interpreter is initialized by the first Python callback, so that it
+    // is not started when unused.
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let filename = wrapped_filename(filename, name, "callback");
+        let code = compile_wrapped(py, code_buf, &filename)?;
+        let globals = new_py_globals(py)?;
+        if name == "filename" {
+            globals.set_item("file_data", wrap_pyfunction!(py_api::file_data, py)?)?;
+        }
+        let locals = PyDict::new(py);
+        py.import("builtins")?
+            .getattr("exec")?
+            .call1((code, globals, locals))?;
+        let callback = locals.get_item("callback")?.unwrap();
+        Ok(callback.extract::<&PyFunction>()?.into())
+    })
+}
+
+/// Compiles a hook. Unlike callbacks, it takes no parameters, and instead
+/// accesses the object through functions in its globals.
+#[cfg(feature = "python")]
+fn parse_hook(
+    hook: &mut dyn BufRead,
+    name: &str,
+    filename: Option<&Path>,
+    code_buf: &mut String,
+) -> Result<py_api::Hook> {
+    code_buf.push_str("def hook():");
+    push_body(hook, code_buf)?;
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let filename = wrapped_filename(filename, name, "hook");
+        let code = compile_wrapped(py, code_buf, &filename)?;
+        let globals = new_py_globals(py)?;
+        Ok(py_api::Hook::from_code(py, name, code, globals)?)
+    })
+}
+
+/// Appends the code as the indented body of a function.
+#[cfg(feature = "python")]
+fn push_body(code: &mut dyn BufRead, code_buf: &mut String) -> Result<()> {
+    for line in code.lines() {
         code_buf.push_str("\n  ");
         code_buf.push_str(&line?);
     }
     code_buf.push('\n');
+    Ok(())
+}
 
-    // TODO: Specialize `Python::run`, so I can pass a custom filename like
-    // `<commit_callback>` instead of `<string>`.
-    let globals = new_py_globals(py)?;
-    let locals = PyDict::new(py);
-    py.run(code_buf, Some(globals), Some(locals))?;
-    let callback = locals.get_item("callback")?.unwrap();
-    Ok(callback.extract()?)
+/// Returns the filename for tracebacks of a callback or hook, which is the
+/// file it was read from, or else like `<commit_callback>`.
+#[cfg(feature = "python")]
+fn wrapped_filename(filename: Option<&Path>, name: &str, kind: &str) -> String {
+    match filename {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => format!("<{name}_{kind}>"),
+    }
 }
 
+/// Compiles code, which `push_body` wrapped in a function, with the positions
+/// of the body shifted back to those in the unwrapped code. Code, which was
+/// not read from a file, is registered with `linecache`, so that tracebacks
+/// show its lines.
+#[cfg(feature = "python")]
+fn compile_wrapped<'py>(py: Python<'py>, code: &str, filename: &str) -> PyResult<&'py PyAny> {
+    const COMPILE_WRAPPED: &str = r#"
+import ast, linecache
+
+def compile_wrapped(source, filename):
+    try:
+        tree = ast.parse(source, filename)
+    except SyntaxError as e:
+        if e.lineno is not None and e.lineno > 1:
+            e.lineno -= 1
+            if e.end_lineno is not None:
+                e.end_lineno -= 1
+            if e.offset is not None:
+                e.offset = max(e.offset - 2, 1)
+            if e.end_offset is not None:
+                e.end_offset = max(e.end_offset - 2, 1)
+            if e.text is not None:
+                e.text = e.text[2:]
+        raise
+    for node in ast.walk(tree):
+        if not hasattr(node, 'lineno'):
+            continue
+        # The first line is the wrapper and the rest are indented by 2.
+        if node.lineno > 1:
+            node.col_offset -= 2
+        if getattr(node, 'end_lineno', None) is not None and node.end_lineno > 1:
+            node.end_col_offset -= 2
+    ast.increment_lineno(tree, -1)
+    if filename.startswith('<'):
+        lines = [line[2:] + '\n' for line in source.splitlines()[1:]]
+        linecache.cache[filename] = (len(source), None, lines, filename)
+    return compile(tree, filename, 'exec')
+"#;
+    let module = PyModule::from_code(py, COMPILE_WRAPPED, "<compile_wrapped>", "compile_wrapped")?;
+    module.getattr("compile_wrapped")?.call1((code, filename))
+}
+
+#[cfg(feature = "python")]
 fn new_py_globals<'py>(py: Python<'py>) -> PyResult<&'py PyDict> {
     // git-filter-repo uses `globals()`, which leaks many internal details. It
     // was probably only intended to expose imports and the public API
     // (`__all__`).
-    //
-    // TODO: Recreate the public library API in Rust and expose it to callbacks.
 
     let globals = PyDict::new(py);
 
@@ -253,28 +734,11 @@ fn new_py_globals<'py>(py: Python<'py>) -> PyResult<&'py PyDict> {
     globals.set_item("timedelta", datetime.getattr("timedelta")?)?;
     globals.set_item("datetime", datetime.getattr("datetime")?)?;
 
-    globals.set_item(
-        "__all__",
-        PyList::new(
-            py,
-            [
-                "Blob",
-                "Reset",
-                "FileChange",
-                "Commit",
-                "Tag",
-                "Progress",
-                "Checkpoint",
-                "FastExportParser",
-                "ProgressWriter",
-                "string_to_date",
-                "date_to_string",
-                "record_id_rename",
-                "GitUtils",
-                "FilteringOptions",
-                "RepoFilter",
-            ],
-        ),
-    )?;
+    // Like `from git_filter_repo import *`.
+    let api = py_api::module(py)?;
+    for name in py_api::NAMES {
+        globals.set_item(name, api.getattr(name)?)?;
+    }
+    globals.set_item("__all__", PyList::new(py, py_api::NAMES))?;
     Ok(globals)
 }
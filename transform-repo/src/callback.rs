@@ -0,0 +1,318 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Callbacks, which are either Rust closures, Python functions, or Lua chunks.
+//!
+//! Rust closures receive the parsed values directly, without converting them,
+//! borrowing from the parser's buffer for the command they are in.
+//! Python functions receive them wrapped in the `git_filter_repo` API, like in
+//! filter-repo. Lua chunks receive userdata with the same fields.
+
+use std::fmt::{self, Debug, Formatter};
+
+use anyhow::{Context, Result};
+use bstr::ByteSlice;
+use fast_export::command::{Commit, Commitish, Mark, OriginalOid, Reset, Tag};
+#[cfg(feature = "python")]
+use pyo3::{types::PyFunction, Py};
+
+#[cfg(feature = "lua")]
+use crate::lua_api::{self, LuaCallback};
+#[cfg(feature = "python")]
+use crate::py_api;
+use crate::{
+    blob_store::{BlobStore, FileContents},
+    parser::{BlobObject, Bytes, FileChange},
+};
+
+/// A callback for processing values of some kind in the stream.
+pub enum Callback<F: ?Sized> {
+    /// A Rust closure.
+    Rust(Box<F>),
+    /// A Python function.
+    #[cfg(feature = "python")]
+    Python(Py<PyFunction>),
+    /// A Lua chunk.
+    #[cfg(feature = "lua")]
+    Lua(LuaCallback),
+    /// No callback.
+    None,
+}
+
+/// Maps a filename in place. It returns `false` to remove the file. The
+/// contents of the file can be read on demand.
+pub type FilenameCallback<'a> =
+    Callback<dyn FnMut(&mut Bytes<'_>, FileContents<'_>) -> Result<bool> + 'a>;
+/// Maps a message, name, email, or refname in place.
+pub type BytesCallback<'a> = Callback<dyn FnMut(&mut Bytes<'_>) -> Result<()> + 'a>;
+/// Modifies a blob. It returns `false` to strip the blob.
+pub type BlobCallback<'a> = Callback<dyn FnMut(&mut BlobObject<'_>) -> Result<bool> + 'a>;
+/// Modifies a commit and its file changes, which borrow from the same command.
+/// It returns `false` to skip the commit, in which case references to it are
+/// rewritten to its first parent.
+pub type CommitCallback<'a> = Callback<
+    dyn for<'c> FnMut(
+            &mut Commit<Bytes<'c>>,
+            &mut Vec<FileChange<'c>>,
+            &CommitMetadata<'_>,
+        ) -> Result<bool>
+        + 'a,
+>;
+/// Modifies an annotated tag. It returns `false` to skip the tag.
+pub type TagCallback<'a> = Callback<dyn FnMut(&mut Tag<Bytes<'_>>) -> Result<bool> + 'a>;
+/// Modifies a reset. It returns `false` to skip the reset.
+pub type ResetCallback<'a> = Callback<dyn FnMut(&mut Reset<Bytes<'_>>) -> Result<bool> + 'a>;
+/// Called at the end of the stream.
+pub type DoneCallback<'a> = Callback<dyn FnMut() -> Result<()> + 'a>;
+
+/// Information about a commit before it was filtered, which is given to
+/// commit callbacks, and access to the contents of its files.
+///
+// Corresponds to the `aux_info` parameter of commit callbacks in filter-repo.
+#[derive(Clone, Copy, Debug)]
+pub struct CommitMetadata<'a> {
+    /// The parents of the commit in the original stream.
+    pub orig_parents: &'a [Commitish<Bytes<'a>>],
+    /// Whether the commit had file changes in the original stream.
+    pub had_file_changes: bool,
+    /// The store, from which the contents of file modifications can be read.
+    pub blobs: &'a BlobStore,
+}
+
+/// What a commit callback decided to do with a commit.
+pub(crate) enum CommitAction<'c> {
+    /// Keep the commit. When it is known that the callback could have changed
+    /// the file changes, the changes from before the callback are included.
+    Keep {
+        orig_changes: Option<Vec<FileChange<'c>>>,
+    },
+    /// Skip the commit and rewrite references to it.
+    Skip {
+        rewrite_to: Option<Commitish<Bytes<'c>>>,
+    },
+}
+
+impl<F: ?Sized> Callback<F> {
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        matches!(self, Callback::None)
+    }
+
+    #[inline]
+    pub fn is_some(&self) -> bool {
+        !self.is_none()
+    }
+}
+
+impl<F: ?Sized> Default for Callback<F> {
+    #[inline]
+    fn default() -> Self {
+        Callback::None
+    }
+}
+
+impl<F: ?Sized> Debug for Callback<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Callback::Rust(_) => f.write_str("Rust(..)"),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => f.debug_tuple("Python").field(callback).finish(),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => f.debug_tuple("Lua").field(callback).finish(),
+            Callback::None => f.write_str("None"),
+        }
+    }
+}
+
+impl FilenameCallback<'_> {
+    /// Maps a filename, returning `false` when it should be removed.
+    pub(crate) fn call(
+        &mut self,
+        filename: &mut Bytes<'_>,
+        contents: FileContents<'_>,
+    ) -> Result<bool> {
+        match self {
+            Callback::Rust(callback) => callback(filename, contents),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => {
+                match py_api::call_filename(callback, filename, contents)? {
+                    Some(new) => {
+                        *filename = new.into();
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_filename(callback, filename, contents),
+            Callback::None => Ok(true),
+        }
+        .with_context(|| format!("filename callback failed on {}", filename.as_bstr()))
+    }
+}
+
+impl BytesCallback<'_> {
+    /// Maps a value in place. For errors, `name` names the kind of value and
+    /// `object` describes the object, which it is in.
+    pub(crate) fn call(
+        &mut self,
+        name: &str,
+        value: &mut Bytes<'_>,
+        object: impl FnOnce() -> String,
+    ) -> Result<()> {
+        match self {
+            Callback::Rust(callback) => callback(value),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => {
+                *value = py_api::call_bytes(callback, name, value)?.into();
+                Ok(())
+            }
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_bytes(callback, name, value),
+            Callback::None => Ok(()),
+        }
+        .with_context(|| format!("{name} callback failed on {}", object()))
+    }
+}
+
+impl BlobCallback<'_> {
+    /// Modifies a blob, returning whether it is kept.
+    pub(crate) fn call(&mut self, blob: &mut BlobObject<'_>) -> Result<bool> {
+        match self {
+            Callback::Rust(callback) => callback(blob),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => py_api::call_blob(callback, blob),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_blob(callback, blob),
+            Callback::None => Ok(true),
+        }
+        .with_context(|| {
+            let object = describe("blob", blob.mark, blob.original_oid.as_ref(), None);
+            format!("blob callback failed on {object}")
+        })
+    }
+}
+
+impl CommitCallback<'_> {
+    /// Modifies a commit. When `record_changes`, the file changes from before
+    /// the callback are returned, if it could have changed them.
+    pub(crate) fn call<'c>(
+        &mut self,
+        commit: &mut Commit<Bytes<'c>>,
+        changes: &mut Vec<FileChange<'c>>,
+        metadata: &CommitMetadata<'_>,
+        record_changes: bool,
+    ) -> Result<CommitAction<'c>> {
+        match self {
+            Callback::Rust(callback) => {
+                let orig_changes = record_changes.then(|| changes.clone());
+                let keep = callback(commit, changes, metadata);
+                keep.map(|keep| {
+                    if keep {
+                        CommitAction::Keep { orig_changes }
+                    } else {
+                        CommitAction::Skip {
+                            rewrite_to: commit.from.clone(),
+                        }
+                    }
+                })
+            }
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => py_api::call_commit(callback, commit, changes, metadata),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => {
+                lua_api::call_commit(callback, commit, changes, metadata, record_changes)
+            }
+            Callback::None => Ok(CommitAction::Keep { orig_changes: None }),
+        }
+        .with_context(|| {
+            let branch = ("branch", &commit.branch.branch);
+            let object = describe(
+                "commit",
+                commit.mark,
+                commit.original_oid.as_ref(),
+                Some(branch),
+            );
+            format!("commit callback failed on {object}")
+        })
+    }
+}
+
+impl TagCallback<'_> {
+    /// Modifies a tag, returning whether it is kept.
+    pub(crate) fn call(&mut self, tag: &mut Tag<Bytes<'_>>) -> Result<bool> {
+        match self {
+            Callback::Rust(callback) => callback(tag),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => py_api::call_tag(callback, tag),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_tag(callback, tag),
+            Callback::None => Ok(true),
+        }
+        .with_context(|| {
+            let name = ("name", &tag.name.name);
+            let object = describe("tag", tag.mark, tag.original_oid.as_ref(), Some(name));
+            format!("tag callback failed on {object}")
+        })
+    }
+}
+
+impl ResetCallback<'_> {
+    /// Modifies a reset, returning whether it is kept.
+    pub(crate) fn call(&mut self, reset: &mut Reset<Bytes<'_>>) -> Result<bool> {
+        match self {
+            Callback::Rust(callback) => callback(reset),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => py_api::call_reset(callback, reset),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_reset(callback, reset),
+            Callback::None => Ok(true),
+        }
+        .with_context(|| {
+            let branch = ("branch", &reset.branch.branch);
+            let object = describe("reset", None, None, Some(branch));
+            format!("reset callback failed on {object}")
+        })
+    }
+}
+
+impl DoneCallback<'_> {
+    pub(crate) fn call(&mut self) -> Result<()> {
+        match self {
+            Callback::Rust(callback) => callback(),
+            #[cfg(feature = "python")]
+            Callback::Python(callback) => py_api::call_done(callback),
+            #[cfg(feature = "lua")]
+            Callback::Lua(callback) => lua_api::call_done(callback),
+            Callback::None => Ok(()),
+        }
+        .context("done callback failed")
+    }
+}
+
+/// Describes an object in the stream for errors from callbacks, so that a
+/// failure can be traced back to the object, which caused it.
+pub(crate) fn describe(
+    kind: &str,
+    mark: Option<Mark>,
+    original_oid: Option<&OriginalOid<Bytes<'_>>>,
+    name: Option<(&str, &Bytes<'_>)>,
+) -> String {
+    let mut s = kind.to_owned();
+    if let Some(mark) = mark {
+        s.push_str(&format!(" :{}", mark.mark));
+    }
+    let mut details = Vec::new();
+    if let Some(original_oid) = original_oid {
+        details.push(format!("original-oid {}", original_oid.oid.as_bstr()));
+    }
+    if let Some((field, value)) = name {
+        details.push(format!("{field} {}", value.as_bstr()));
+    }
+    if !details.is_empty() {
+        s.push_str(&format!(" ({})", details.join(", ")));
+    }
+    s
+}
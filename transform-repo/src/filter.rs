@@ -6,71 +6,109 @@
 #![allow(dead_code)]
 
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
 };
 
-use pyo3::{types::PyFunction, Python};
-use regex::Regex;
-
-use crate::builder::Builder;
-
-pub struct TODO;
-
-pub struct FastExportParser {}
-
-/// A tuple of (depth, list-of-ancestors). Commits and ancestors are identified
-/// by their id (their `mark` in fast-export or fast-import speak). The depth of
-/// a commit is one more than the max depth of any of its ancestors.
-pub struct AncestryGraph {}
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+use fast_export::{
+    command::{
+        Branch, Commit, Commitish, GetMark, Ls, MapBytes, Mark, Mode, Objectish, PersonIdent,
+        Reset, Tag, Treeish,
+    },
+    parse::{
+        Change, DataRef, FileCopyChange, FileDeleteChange, FileModifyChange, FileRenameChange,
+        NoteModifyChange,
+    },
+    Dump,
+};
+#[cfg(feature = "python")]
+use pyo3::{types::PyList, Py, Python};
+use regex::bytes::Regex;
 
-pub struct ProgressWriter {}
+use crate::{
+    ancestry::AncestryGraph,
+    blob_store::{BlobStore, FileContents},
+    builder::Builder,
+    callback::{
+        describe, BlobCallback, BytesCallback, CommitAction, CommitCallback, CommitMetadata,
+        DoneCallback, FilenameCallback, ResetCallback, TagCallback,
+    },
+    mailmap::MailmapInfo,
+    notes::{is_notes_ref, NotesRewriter},
+    options::{FilteringOptions, PruneMode, ReplaceRefs},
+    parser::{
+        owned, BlobObject, Bytes, CommitObject, FastExportParser, FileChange, Handler, NewMarks,
+        RefSet, StreamWriter,
+    },
+    paths,
+    progress::{ProgressStats, ProgressWriter, Verbosity},
+    reencode::Reencoder,
+    renames::{CommitRenames, Renamed},
+    sanity,
+    signature::SignaturePolicy,
+    stream::{self, Input, Output},
+};
 
 pub struct Oid {}
 
-pub struct RepoFilter<'py> {
-    args: TODO,
+pub struct RepoFilter<'a> {
+    args: FilteringOptions,
 
     /// Repo we are exporting.
     repo_working_dir: Option<PathBuf>,
 
     // Convenience callbacks.
     /// Callback for acting on filenames from commits.
-    filename_callback: Option<&'py PyFunction>,
+    filename_callback: FilenameCallback<'a>,
     /// Callback for acting on commit and tag messages.
-    message_callback: Option<&'py PyFunction>,
+    message_callback: BytesCallback<'a>,
     /// Callback for acting on author, committer, and tagger names.
-    name_callback: Option<&'py PyFunction>,
+    name_callback: BytesCallback<'a>,
     /// Callback for acting on author, committer, and tagger names.
-    email_callback: Option<&'py PyFunction>,
+    email_callback: BytesCallback<'a>,
     /// Callback for acting on ref names from commit, tag, and reset commands.
-    refname_callback: Option<&'py PyFunction>,
+    refname_callback: BytesCallback<'a>,
 
     /// Callbacks for acting on raw objects printed by `FastExport`.
-    blob_callback: Option<&'py PyFunction>,
-    commit_callback: Option<&'py PyFunction>,
-    tag_callback: Option<&'py PyFunction>,
-    reset_callback: Option<&'py PyFunction>,
-    done_callback: Option<&'py PyFunction>,
+    blob_callback: BlobCallback<'a>,
+    commit_callback: CommitCallback<'a>,
+    tag_callback: TagCallback<'a>,
+    reset_callback: ResetCallback<'a>,
+    done_callback: DoneCallback<'a>,
+    /// Objects which a library script inserted with `RepoFilter.insert`, to be
+    /// written before the next object from the stream.
+    #[cfg(feature = "python")]
+    pub(crate) insert_queue: Option<Py<PyList>>,
 
-    input: Option<TODO>,
+    input: Input,
     /// The fast-export process.
-    fe_process: Option<TODO>,
+    fe_process: Option<Child>,
     /// Path to where the original fast-export output is stored (usually
     /// `.git/filter-repo/fast-export.original`).
     fe_orig: Option<PathBuf>,
     /// Path to where the filtered fast-export output is stored (usually
     /// `.git/filter-repo/fast-export.filtered`).
     fe_filt: Option<PathBuf>,
-    /// The `FastExportParser` object we are working with.
-    parser: Option<FastExportParser>,
+    /// Refs which were received from the export and which were sent to the
+    /// import, recorded by `FastExportParser`.
+    exported_refs: RefSet,
+    imported_refs: RefSet,
 
-    output: Option<TODO>,
+    output: Output,
     /// The fast-import process.
-    fi_process: Option<TODO>,
-    import_pipes: Option<TODO>,
+    fi_process: Option<Child>,
+    /// The stdout of fast-import, from which responses to `get-mark` commands
+    /// are read.
+    import_pipes: Option<BufReader<ChildStdout>>,
     managed_output: bool,
 
+    /// The ancestry of commits in the filtered repo.
     graph: AncestryGraph,
     /// The ancestry of commits in the original repo.
     orig_graph: AncestryGraph,
@@ -83,20 +121,30 @@ pub struct RepoFilter<'py> {
     /// commits but due to filtering were turned into non-merge commits. The
     /// commits probably have suboptimal commit messages (e.g., "Merge branch
     /// feature into main").
-    commits_no_longer_merges: Vec<(Oid, Oid)>,
+    /// The new hash is `None` until it has been read from fast-import.
+    commits_no_longer_merges: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+
+    /// Commits which were pruned, by mark, mapped to the commit each was
+    /// rewritten to, or `None`, when none of its ancestors remain.
+    skipped_commits: HashMap<Mark, Option<Commitish<Bytes<'static>>>>,
+    /// Original IDs of commits, by mark.
+    commit_oids: HashMap<Mark, Vec<u8>>,
+    /// Marks of blobs, by original ID.
+    blob_marks: HashMap<Vec<u8>, Mark>,
 
     /// A dict of original_ids to new_ids; filtering commits means getting new
     /// commit hash (sha1sums), and we record the mapping both for diagnostic
     /// purposes and so we can rewrite commit messages. Note that the new_id can
     /// be None rather than a commit hash if the original commit became empty
     /// and was pruned or was otherwise dropped.
-    commit_renames: HashMap<TODO, TODO>,
+    commit_renames: CommitRenames,
+    /// Marks of commits, by original ID, for remapping the commits annotated
+    /// by notes.
+    commit_marks: HashMap<Vec<u8>, Mark>,
 
-    /// A set of original_ids for which we have not yet gotten the new_ids; we
-    /// use OrderedDict because we need to know the order of insertion, but the
-    /// values are always ignored (and set to None). If there was an OrderedSet
-    /// class, I'd use it instead.
-    pending_renames: TODO,
+    /// The original_ids for which we have not yet gotten the new_ids, in the
+    /// order their `get-mark` commands were sent to fast-import.
+    pending_renames: VecDeque<Vec<u8>>,
 
     /// A map from abbreviated commit hashes to the set of hashes with that
     /// prefix.
@@ -104,40 +152,98 @@ pub struct RepoFilter<'py> {
     /// It's common for commit messages to refer to commits by abbreviated
     /// commit hashes, as short as 7 characters. To facilitate translating such
     /// short hashes, we have a mapping of prefixes to full old hashes.
-    commit_short_old_hashes: HashMap<[u8; 7], HashSet<Oid>>,
+    commit_short_old_hashes: HashMap<[u8; 7], HashSet<Vec<u8>>>,
 
     /// A set of commit hash references appearing in commit messages which
     /// mapped to a valid commit that was removed entirely in the filtering
     /// process. The commit message will continue to reference the now-missing
     /// commit hash, since there was nothing to map it to.
-    commits_referenced_but_removed: HashSet<TODO>,
+    commits_referenced_but_removed: HashSet<Vec<u8>>,
+    /// Abbreviated new commit hashes, which were written to commit messages,
+    /// so that they can be checked for ambiguity with commits rewritten later.
+    abbreviated_hashes: Vec<Vec<u8>>,
+    /// A `git cat-file --batch-check` process in the source repo, which is
+    /// spawned on the first abbreviation of a commit hash.
+    batch_check: Option<BatchCheck>,
 
     /// Progress handling (number of commits parsed, etc.).
     progress_writer: ProgressWriter,
-    num_commits: usize,
 
-    /// Size of blobs in the repo.
-    unpacked_size: HashMap<Oid, usize>,
+    /// Size of blobs in the repo, by original ID.
+    unpacked_size: HashMap<Vec<u8>, u64>,
+    /// Marks of blobs which were stripped, so that changes referring to them
+    /// can be removed.
+    stripped_blobs: HashSet<Mark>,
+    /// Marks of blobs, commits, and tags which were rewritten, so that the
+    /// objects which refer to them are also treated as rewritten and have their
+    /// signatures stripped.
+    rewritten_marks: HashSet<Mark>,
 
     /// Other vars.
     sanity_checks_handled: bool,
     finalize_handled: bool,
-    orig_refs: Option<TODO>,
-    new_names: HashMap<TODO, Vec<u8>>,
+    /// Refs in the source repo before filtering, mapped to their IDs.
+    orig_refs: Option<HashMap<Vec<u8>, Vec<u8>>>,
+    /// The branch which `HEAD` in the source repo pointed to.
+    orig_head: Option<Vec<u8>>,
+    /// Original IDs of commits, in stream order, with the original IDs of
+    /// their parents.
+    orig_commit_parents: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    /// Cache of paths mapped by the path filters and filename callback.
+    new_names: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// The original file changes of each commit, by mark, which are tracked
+    /// when paths are filtered, so that renames and copies from paths which
+    /// are filtered out can be replaced with their contents.
+    orig_commits: HashMap<Mark, OrigCommit>,
+    /// The mark of the latest original commit on each branch, which is the
+    /// parent of the next commit to it without `from`.
+    orig_branch_tips: HashMap<Vec<u8>, Mark>,
+    /// The contents of blobs, which commit and filename callbacks can read.
+    blob_store: BlobStore,
+    /// The counter of marks for objects created by callbacks.
+    new_marks: NewMarks,
+
+    /// Re-encoding of commit and tag metadata to UTF-8.
+    reencoder: Reencoder,
+    /// What to do with signatures invalidated by rewriting.
+    signature_policy: SignaturePolicy,
 
     /// Cached pattern.
     hash_re: Regex,
 }
 
-impl<'py> RepoFilter<'py> {
+/// The file changes of a commit in the original history, relative to its
+/// first parent.
+#[derive(Clone, Debug)]
+struct OrigCommit {
+    /// The first parent, or `None`, when it is not in the stream.
+    parent: Option<Mark>,
+    changes: Vec<FileChange<'static>>,
+}
+
+impl<'a> RepoFilter<'a> {
     #[inline]
-    pub fn builder(py: Python<'py>, args: TODO) -> Builder {
-        Builder::new(py, args)
+    pub fn builder(args: FilteringOptions) -> Builder<'a> {
+        Builder::new(args)
     }
 }
 
-impl<'py> From<Builder<'py>> for RepoFilter<'py> {
-    fn from(b: Builder<'py>) -> Self {
+impl<'a> From<Builder<'a>> for RepoFilter<'a> {
+    fn from(b: Builder<'a>) -> Self {
+        let verbosity = if b.args.quiet {
+            Verbosity::Quiet
+        } else if b.args.debug {
+            Verbosity::Debug
+        } else {
+            Verbosity::Normal
+        };
+        let mut progress_writer = ProgressWriter::new(verbosity);
+        if let Some(events) = b.progress_events {
+            progress_writer.set_events(events);
+        }
+        let reencoder =
+            Reencoder::new(b.args.reencode).fallbacks(b.args.fallback_encodings.clone());
+        let signature_policy = b.args.signatures;
         RepoFilter {
             args: b.args,
             repo_working_dir: None,
@@ -151,12 +257,15 @@ impl<'py> From<Builder<'py>> for RepoFilter<'py> {
             tag_callback: b.tag_callback,
             reset_callback: b.reset_callback,
             done_callback: b.done_callback,
-            input: None,
+            #[cfg(feature = "python")]
+            insert_queue: None,
+            input: b.input,
             fe_process: None,
             fe_orig: None,
             fe_filt: None,
-            parser: None,
-            output: None,
+            exported_refs: HashSet::new(),
+            imported_refs: HashSet::new(),
+            output: b.output,
             fi_process: None,
             import_pipes: None,
             managed_output: true,
@@ -164,57 +273,3537 @@ impl<'py> From<Builder<'py>> for RepoFilter<'py> {
             orig_graph: AncestryGraph::new(),
             files_tweaked: HashSet::new(),
             commits_no_longer_merges: Vec::new(),
-            commit_renames: HashMap::new(),
-            pending_renames: TODO,
+            skipped_commits: HashMap::new(),
+            commit_oids: HashMap::new(),
+            blob_marks: HashMap::new(),
+            commit_renames: CommitRenames::new(),
+            commit_marks: HashMap::new(),
+            pending_renames: VecDeque::new(),
             commit_short_old_hashes: HashMap::new(),
             commits_referenced_but_removed: HashSet::new(),
-            progress_writer: ProgressWriter::new(),
-            num_commits: 0,
+            abbreviated_hashes: Vec::new(),
+            batch_check: None,
+            progress_writer,
             unpacked_size: HashMap::new(),
+            stripped_blobs: HashSet::new(),
+            rewritten_marks: HashSet::new(),
             sanity_checks_handled: false,
             finalize_handled: false,
             orig_refs: None,
+            orig_head: None,
+            orig_commit_parents: Vec::new(),
             new_names: HashMap::new(),
-            hash_re: Regex::new(r"(\b[0-9a-f]{7,40}\b)").unwrap(),
+            orig_commits: HashMap::new(),
+            orig_branch_tips: HashMap::new(),
+            blob_store: BlobStore::new(None, false, b.new_marks.clone()),
+            new_marks: b.new_marks,
+            reencoder,
+            signature_policy,
+            hash_re: Regex::new(r"(?-u)\b[0-9a-f]{7,40}\b").unwrap(),
+        }
+    }
+}
+
+impl RepoFilter<'_> {
+    /// Runs the filter: exports the input, passes it through the callbacks,
+    /// and imports the result into the output.
+    pub fn run(&mut self) -> Result<()> {
+        if let Some(option) = self.args.unsupported_option() {
+            bail!("{option} is not yet supported");
+        }
+        self.sanity_checks()?;
+
+        if self.args.use_mailmap {
+            let repo = match &self.input {
+                Input::Repo(repo) => repo.as_path(),
+                Input::Stream(_) | Input::Stdin => Path::new("."),
+            };
+            self.args.mailmap = Some(MailmapInfo::from_head(repo)?);
+        }
+
+        if let Input::Repo(source) = &self.input {
+            self.orig_refs = Some(sanity::refs(source)?);
+            let head = sanity::git(source, &["symbolic-ref", "-q", "HEAD"]).ok();
+            self.orig_head = head.map(|head| head.trim_end().to_vec());
+        }
+
+        // Blobs are only saved for the callbacks, which can read them. Blobs
+        // which filtering did not change are read back from the source repo.
+        let save_blobs = self.commit_callback.is_some() || self.filename_callback.is_some();
+        let repo = match &self.input {
+            Input::Repo(source) => Some(source.clone()),
+            Input::Stream(_) | Input::Stdin => None,
+        };
+        self.blob_store = BlobStore::new(repo, save_blobs, self.new_marks.clone());
+
+        // Like filter-repo, blobs are left out of the stream, when nothing
+        // reads or rewrites them and the target already has them, so commits
+        // refer to them by ID. Sizes are only known from the stream, so they
+        // are kept for --strip-blobs-bigger-than.
+        let no_data = self.blob_callback.is_none()
+            && self.args.replace_text.is_none()
+            && self.args.strip_blobs_bigger_than.is_none()
+            && matches!(&self.output, Output::Repo(target) if self.is_in_place(target));
+        let (input, fe_process) = self.input.open(&self.args, no_data)?;
+        self.fe_process = fe_process;
+        let (output, fi_process) = match self.output.open() {
+            Ok(output) => output,
+            Err(err) => {
+                self.kill_processes();
+                return Err(err);
+            }
+        };
+        self.fi_process = fi_process;
+        self.import_pipes = self
+            .fi_process
+            .as_mut()
+            .and_then(|fi| fi.stdout.take())
+            .map(BufReader::new);
+
+        let total_commits = self.count_commits();
+        self.progress_writer.start(total_commits);
+
+        let mut parser = FastExportParser::with_compat(input, output, self.args.compat);
+        if let Err(err) = parser.run(self) {
+            // Kill fast-import before the stream is closed, so that it does not
+            // import a partial history.
+            self.kill_processes();
+            return Err(err);
+        }
+        (self.exported_refs, self.imported_refs) = parser.finish()?;
+        self.progress_writer.finish();
+        self.flush_renames(None, 0)?;
+        self.import_pipes = None;
+        for (old_hash, new_hash) in &mut self.commits_no_longer_merges {
+            if let Renamed::Rewritten(new) = self.commit_renames.get(old_hash) {
+                *new_hash = Some(new.to_vec());
+            }
+        }
+        self.report_commits_referenced_but_removed();
+
+        stream::wait(self.fe_process.take(), "fast-export")?;
+        stream::wait(self.fi_process.take(), "fast-import")?;
+
+        self.finalize()
+    }
+
+    /// Cleans up the output repo after importing: deletes refs which were not
+    /// imported, writes the metadata, updates `HEAD`, and, unless `--no-gc` or
+    /// `--partial`, expires reflogs and prunes the old objects.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter.finish`.
+    fn finalize(&mut self) -> Result<()> {
+        if self.finalize_handled {
+            return Ok(());
+        }
+        self.finalize_handled = true;
+        let Output::Repo(target) = &self.output else {
+            return Ok(());
+        };
+        let target = &target.clone();
+
+        self.ref_update(target)?;
+        let metadata_dir = sanity::git_dir(target)?.join("filter-repo");
+        fs::create_dir_all(&metadata_dir)
+            .with_context(|| format!("could not create {}", metadata_dir.display()))?;
+        self.record_metadata(&metadata_dir)?;
+        self.update_head(target)?;
+
+        let repack = !self.args.no_gc && !self.args.partial;
+        let reset = sanity::git(target, &["rev-parse", "--is-bare-repository"])?.trim() != b"true";
+        RepoFilter::cleanup(target, repack, reset, self.progress_writer.verbosity())
+    }
+
+    /// Deletes refs which were exported, but not imported, such as those whose
+    /// history was entirely pruned or which were renamed. Nothing is deleted
+    /// with `--partial`. Then, replace refs are deleted or added for the
+    /// rewritten commits, as chosen by `--replace-refs`.
+    ///
+    /// # Differences from filter-repo
+    ///
+    /// Without `--replace-refs`, no replace refs are added, like
+    /// `update-no-add`. filter-repo instead defaults to `update-and-add`, or to
+    /// `update-or-add` when filtering again.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._ref_update`.
+    fn ref_update(&self, target: &Path) -> Result<()> {
+        let mut updates = Vec::new();
+        if !self.args.partial {
+            let mut refs_to_nuke = self
+                .exported_refs
+                .difference(&self.imported_refs)
+                .collect::<Vec<_>>();
+            refs_to_nuke.sort();
+            for refname in refs_to_nuke {
+                updates.extend_from_slice(&[b"delete ", &refname[..], b"\n"].concat());
+            }
+        }
+
+        // Updating the replace refs themselves is handled by fast-export, so
+        // only deleting and adding remain.
+        let replace_refs = self
+            .orig_refs
+            .iter()
+            .flatten()
+            .filter(|(refname, _)| refname.starts_with(b"refs/replace/"))
+            .collect::<Vec<_>>();
+        let actual_renames = self
+            .orig_commit_parents
+            .iter()
+            .filter_map(|(old_hash, _)| match self.commit_renames.get(old_hash) {
+                Renamed::Rewritten(new_hash) if new_hash != old_hash => {
+                    Some((&old_hash[..], new_hash))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let replace_ref = |old_hash: &[u8]| [&b"refs/replace/"[..], old_hash].concat();
+        let (delete, add) = match self.args.replace_refs {
+            Some(ReplaceRefs::DeleteNoAdd) => (true, false),
+            Some(ReplaceRefs::DeleteAndAdd) => (true, true),
+            Some(ReplaceRefs::UpdateOrAdd | ReplaceRefs::UpdateAndAdd) => (false, true),
+            Some(ReplaceRefs::UpdateNoAdd) | None => (false, false),
+        };
+        if delete {
+            let mut replace_refs_to_nuke = replace_refs
+                .iter()
+                .map(|(refname, _)| refname.as_slice())
+                .collect::<Vec<_>>();
+            // update-ref does not allow updating a ref twice, so refs which are
+            // added again are not deleted.
+            if add {
+                let added = actual_renames
+                    .iter()
+                    .map(|(old_hash, _)| replace_ref(old_hash))
+                    .collect::<HashSet<_>>();
+                replace_refs_to_nuke.retain(|refname| !added.contains(*refname));
+            }
+            replace_refs_to_nuke.sort();
+            for refname in replace_refs_to_nuke {
+                updates.extend_from_slice(&[b"delete ", refname, b"\n"].concat());
+            }
+        }
+        if add {
+            // With update-or-add, commits which replace another are not
+            // replaced themselves.
+            let update_only = self.args.replace_refs == Some(ReplaceRefs::UpdateOrAdd);
+            let replacements = replace_refs
+                .iter()
+                .map(|(_, oid)| oid.as_slice())
+                .collect::<HashSet<_>>();
+            for (old_hash, new_hash) in &actual_renames {
+                if !(update_only && replacements.contains(old_hash)) {
+                    updates.extend_from_slice(
+                        &[
+                            b"update ",
+                            &replace_ref(old_hash)[..],
+                            b" ",
+                            new_hash,
+                            b"\n",
+                        ]
+                        .concat(),
+                    );
+                }
+            }
+        }
+
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(target)
+            .args(["update-ref", "--no-deref", "--stdin"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("could not run git update-ref")?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&updates)?;
+        drop(stdin);
+        stream::wait(Some(child), "update-ref")
+    }
+
+    /// Points `HEAD` in the target at the branch which `HEAD` in the source
+    /// pointed to, after renaming, so that it follows renamed branches and a
+    /// new target repo checks out the same branch as the source.
+    fn update_head(&mut self, target: &Path) -> Result<()> {
+        let Some(orig_head) = &self.orig_head else {
+            return Ok(());
+        };
+        let mut head = Cow::Owned(orig_head.clone());
+        self.tweak_refname(&mut head, || "HEAD".to_owned())?;
+        if !self.imported_refs.contains(&*head) {
+            return Ok(());
+        }
+        let current = Command::new("git")
+            .arg("-C")
+            .arg(target)
+            .args(["symbolic-ref", "-q", "HEAD"])
+            .output()
+            .context("could not run git symbolic-ref")?;
+        if current.stdout.trim_end() != &*head {
+            sanity::git(target, &["symbolic-ref", "HEAD", head.to_str()?])?;
+        }
+        Ok(())
+    }
+
+    /// Cleans up a repo after filtering. When `repack`, reflogs are expired and
+    /// unreachable objects are pruned, so that the old history does not linger.
+    /// When `reset`, the working tree is reset to the new `HEAD`.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter.cleanup`.
+    pub fn cleanup(repo: &Path, repack: bool, reset: bool, verbosity: Verbosity) -> Result<()> {
+        let quiet = verbosity == Verbosity::Quiet;
+        if repack && verbosity == Verbosity::Normal {
+            eprintln!("Repacking your repo and cleaning out old unneeded objects");
+        }
+        let mut cleanup_cmds: Vec<&[&str]> = Vec::new();
+        // An unborn HEAD has nothing to reset to.
+        let has_head = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["rev-parse", "-q", "--verify", "HEAD"])
+            .stdout(Stdio::null())
+            .status()
+            .context("could not run git rev-parse")?
+            .success();
+        if reset && has_head {
+            cleanup_cmds.push(if quiet {
+                &["reset", "--quiet", "--hard"]
+            } else {
+                &["reset", "--hard"]
+            });
+        }
+        if repack {
+            cleanup_cmds.push(&["reflog", "expire", "--expire=now", "--all"]);
+            cleanup_cmds.push(if quiet {
+                &["gc", "--quiet", "--prune=now"]
+            } else {
+                &["gc", "--prune=now"]
+            });
+        }
+        for cmd in cleanup_cmds {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(repo)
+                .args(cmd)
+                .stdout(if quiet {
+                    Stdio::null()
+                } else {
+                    Stdio::inherit()
+                })
+                .status()
+                .with_context(|| format!("could not run git {}", cmd[0]))?;
+            if !status.success() {
+                bail!("git {} failed: {status}", cmd.join(" "));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the metadata files, which describe the results of filtering, to
+    /// the metadata directory (usually `.git/filter-repo/`):
+    ///
+    /// - `commit-map`: The original ID of each commit and its new ID, or the
+    ///   null ID, when it was pruned.
+    /// - `ref-map`: The original and new ID of each ref, which was exported.
+    /// - `suboptimal-issues`: Merges which became non-merges and references in
+    ///   messages to commits which were removed.
+    /// - `first-changed-commits`: The original and new IDs of the commits
+    ///   which changed, while all of their parents did not.
+    /// - `already_ran`: A marker, which allows filtering again without
+    ///   `--force`, as long as no work in the working tree would be lost.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._record_metadata`.
+    fn record_metadata(&self, metadata_dir: &Path) -> Result<()> {
+        let deleted_hash = &[b'0'; 40][..];
+        let new_hash = |old_hash: &[u8]| match self.commit_renames.get(old_hash) {
+            Renamed::Rewritten(new_hash) => Some(new_hash),
+            Renamed::Pruned => Some(deleted_hash),
+            Renamed::Unknown => None,
+        };
+        let write = |name: &str, contents: &[u8]| {
+            let path = metadata_dir.join(name);
+            fs::write(&path, contents)
+                .with_context(|| format!("could not write {}", path.display()))
+        };
+
+        let mut commit_map = format!("{:<40} {}\n", "old", "new").into_bytes();
+        for (old_hash, _) in &self.orig_commit_parents {
+            if let Some(new_hash) = new_hash(old_hash) {
+                commit_map.extend_from_slice(&[&old_hash[..], b" ", new_hash, b"\n"].concat());
+            }
+        }
+        write("commit-map", &commit_map)?;
+
+        if let (Some(orig_refs), Output::Repo(target)) = (&self.orig_refs, &self.output) {
+            let new_refs = sanity::refs(target)?;
+            let mut ref_map = format!("{:<40} {:<40} {}\n", "old", "new", "ref").into_bytes();
+            let mut sorted_refs = orig_refs.iter().collect::<Vec<_>>();
+            sorted_refs.sort();
+            for (refname, old_hash) in sorted_refs {
+                if !self.exported_refs.contains(refname) {
+                    continue;
+                }
+                // Annotated tags and refs whose tip was pruned are not in the
+                // commit renames, so are read from the target.
+                let new_hash = match self.commit_renames.get(old_hash) {
+                    Renamed::Rewritten(new_hash) if self.imported_refs.contains(refname) => {
+                        new_hash
+                    }
+                    _ => new_refs.get(refname).map_or(deleted_hash, Vec::as_slice),
+                };
+                ref_map.extend_from_slice(
+                    &[&old_hash[..], b" ", new_hash, b" ", refname, b"\n"].concat(),
+                );
+            }
+            if !self.is_in_place(target) {
+                let mut new_refs = new_refs.iter().collect::<Vec<_>>();
+                new_refs.sort();
+                for (refname, new_hash) in new_refs {
+                    if !orig_refs.contains_key(refname) && !refname.starts_with(b"refs/replace/") {
+                        let old_hash = vec![b'0'; new_hash.len()];
+                        ref_map.extend_from_slice(
+                            &[&old_hash[..], b" ", new_hash, b" ", refname, b"\n"].concat(),
+                        );
+                    }
+                }
+            }
+            write("ref-map", &ref_map)?;
+        }
+
+        let mut issues = String::new();
+        if !self.commits_no_longer_merges.is_empty() {
+            issues.push_str(
+                "The following commits used to be merge commits but due to filtering\n\
+                 are now regular commits; they likely have suboptimal commit messages\n\
+                 (e.g. \"Merge branch next into master\").  Original commit hash on the\n\
+                 left, commit hash after filtering/rewriting on the right:\n",
+            );
+            for (old_hash, new_hash) in &self.commits_no_longer_merges {
+                let new_hash = new_hash.as_deref().unwrap_or(deleted_hash);
+                issues.push_str(&format!(
+                    "  {} {}\n",
+                    old_hash.as_bstr(),
+                    new_hash.as_bstr()
+                ));
+            }
+            issues.push('\n');
+        }
+        if !self.commits_referenced_but_removed.is_empty() {
+            issues.push_str(
+                "The following commits were filtered out, but referenced in another\n\
+                 commit message.  The reference to the now-nonexistent commit hash\n\
+                 (or a substring thereof) was left as-is in any commit messages:\n",
+            );
+            let mut removed = self
+                .commits_referenced_but_removed
+                .iter()
+                .collect::<Vec<_>>();
+            removed.sort();
+            for old_hash in removed {
+                issues.push_str(&format!("  {}\n", old_hash.as_bstr()));
+            }
+            issues.push('\n');
+        }
+        let mut ambiguous = self
+            .abbreviated_hashes
+            .iter()
+            .filter(|abbrev| self.commit_renames.is_ambiguous(abbrev))
+            .collect::<Vec<_>>();
+        if !ambiguous.is_empty() {
+            issues.push_str(
+                "The following abbreviated commit hashes were written to commit\n\
+                 messages, but became ambiguous with commits rewritten later; they\n\
+                 may need to be lengthened:\n",
+            );
+            ambiguous.sort();
+            ambiguous.dedup();
+            for abbrev in ambiguous {
+                issues.push_str(&format!("  {}\n", abbrev.as_bstr()));
+            }
+            issues.push('\n');
+        }
+        if issues.is_empty() {
+            issues.push_str("No filtering problems encountered.\n");
+        }
+        write("suboptimal-issues", issues.as_bytes())?;
+
+        let is_changed = |old_hash: &[u8]| new_hash(old_hash).is_some_and(|new| new != old_hash);
+        let mut first_changed = format!("{:<40} {}\n", "old", "new").into_bytes();
+        for (old_hash, parents) in &self.orig_commit_parents {
+            if is_changed(old_hash) && !parents.iter().any(|parent| is_changed(parent)) {
+                let new_hash = new_hash(old_hash).unwrap();
+                first_changed.extend_from_slice(&[&old_hash[..], b" ", new_hash, b"\n"].concat());
+            }
+        }
+        write("first-changed-commits", &first_changed)?;
+
+        write(
+            "already_ran",
+            b"This file exists to allow you to filter again without --force.\n",
+        )
+    }
+
+    /// Checks that writing the output will not destroy any work. The source
+    /// repo is only rewritten with `--in-place` and, unless `--force`, when it
+    /// looks like a fresh clone. Otherwise, the output must be a new or empty
+    /// repo.
+    fn sanity_checks(&mut self) -> Result<()> {
+        if self.sanity_checks_handled {
+            return Ok(());
+        }
+        self.sanity_checks_handled = true;
+        let Output::Repo(target) = &self.output else {
+            return Ok(());
+        };
+        if self.is_in_place(target) {
+            if !self.args.in_place {
+                bail!(
+                    "Refusing to rewrite the source repo {} without --in-place. Use --target to \
+                     write the filtered history to a separate repo.",
+                    target.display(),
+                );
+            }
+            if !self.args.force {
+                sanity::check_fresh_clone(target)?;
+            }
+        } else {
+            sanity::prepare_target(target, self.args.force)?;
+        }
+        self.repo_working_dir = Some(target.clone());
+        Ok(())
+    }
+
+    /// Returns whether the target repo is rewritten in place. It is, when it is
+    /// the source repo or, for a stream, when `--in-place` is given, so that
+    /// the stream is imported into the existing history instead of a new repo.
+    fn is_in_place(&self, target: &Path) -> bool {
+        match &self.input {
+            Input::Repo(source) => sanity::is_same_repo(source, target),
+            Input::Stream(_) | Input::Stdin => self.args.in_place,
+        }
+    }
+
+    /// Counts the commits which will be exported, for estimating the time
+    /// remaining. This is only known when exporting from a repo.
+    fn count_commits(&self) -> Option<u64> {
+        let Input::Repo(repo) = &self.input else {
+            return None;
+        };
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["rev-list", "--count"])
+            .args(&self.args.refs)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        output.stdout.trim().to_str().ok()?.parse().ok()
+    }
+
+    /// Gets the counters of what has been processed.
+    #[inline]
+    pub fn progress(&self) -> ProgressStats {
+        self.progress_writer.stats()
+    }
+
+    fn kill_processes(&mut self) {
+        for mut child in [self.fe_process.take(), self.fi_process.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// Gets the original commit hashes, which are referenced in commit
+    /// messages, but were removed by filtering.
+    #[inline]
+    pub fn commits_referenced_but_removed(&self) -> &HashSet<Vec<u8>> {
+        &self.commits_referenced_but_removed
+    }
+
+    fn report_commits_referenced_but_removed(&self) {
+        if self.args.quiet || self.commits_referenced_but_removed.is_empty() {
+            return;
+        }
+        let mut removed = self
+            .commits_referenced_but_removed
+            .iter()
+            .collect::<Vec<_>>();
+        removed.sort();
+        eprintln!(
+            "NOTE: Commit messages reference commits which were removed by filtering, so \
+             these references were not rewritten:"
+        );
+        for oid in removed {
+            eprintln!("  {}", oid.as_bstr());
+        }
+    }
+
+    /// Gets the pairs of original and new hashes of commits, which were merges,
+    /// but became non-merges by filtering.
+    #[inline]
+    pub fn commits_no_longer_merges(&self) -> &[(Vec<u8>, Option<Vec<u8>>)] {
+        &self.commits_no_longer_merges
+    }
+
+    /// Gets the refs which were received from the export.
+    #[inline]
+    pub fn exported_refs(&self) -> &RefSet {
+        &self.exported_refs
+    }
+
+    /// Gets the refs which were sent to the import.
+    #[inline]
+    pub fn imported_refs(&self) -> &RefSet {
+        &self.imported_refs
+    }
+
+    // Corresponds to the mailmap and name and email callbacks in
+    // `git-filter-repo:RepoFilter._tweak_commit` and `RepoFilter._tweak_tag`.
+    fn tweak_ident(
+        &mut self,
+        ident: &mut PersonIdent<Bytes<'_>>,
+        object: impl Fn() -> String,
+    ) -> Result<()> {
+        if let Some(mailmap) = &self.args.mailmap {
+            let (name, email) = mailmap.translate(&ident.name, &ident.email);
+            if (name, email) != (&*ident.name, &*ident.email) {
+                let (name, email) = (name.to_vec(), email.to_vec());
+                ident.name = name.into();
+                ident.email = email.into();
+            }
+        }
+        self.name_callback.call("name", &mut ident.name, &object)?;
+        self.email_callback
+            .call("email", &mut ident.email, &object)?;
+        Ok(())
+    }
+
+    fn tweak_refname(
+        &mut self,
+        refname: &mut Bytes<'_>,
+        object: impl FnOnce() -> String,
+    ) -> Result<()> {
+        if let Some((old, new)) = &self.args.tag_rename {
+            if let Some(renamed) = do_tag_rename(old, new, refname) {
+                *refname = renamed.into();
+            }
+        }
+        self.refname_callback.call("refname", refname, object)
+    }
+
+    /// Requests the new ID of a commit from fast-import after it has been
+    /// written, to later translate references to it in commit messages.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._record_remapping`.
+    fn record_remapping(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<()> {
+        let (Some(original_oid), Some(mark), Some(_)) =
+            (&commit.original_oid, commit.mark, &self.import_pipes)
+        else {
+            return Ok(());
+        };
+        writeln!(output, "get-mark :{}", mark.mark)?;
+        output.flush()?;
+        // Note that we have queued up an id for later reading; flush a few of
+        // the older ones if we have too many queued up.
+        self.pending_renames.push_back(original_oid.oid.to_vec());
+        self.flush_renames(None, 40)
+    }
+
+    /// Reads the responses to pending `get-mark` commands, until the new ID
+    /// of `old_hash` is found, or, when `limit` is non-zero, until fewer than
+    /// `limit` are pending. When fewer than `2 * limit` are pending, nothing
+    /// is read, so that reads are batched.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._flush_renames`.
+    fn flush_renames(&mut self, old_hash: Option<&[u8]>, limit: usize) -> Result<()> {
+        if limit != 0 && self.pending_renames.len() < 2 * limit {
+            return Ok(());
+        }
+        let Some(fi_output) = &mut self.import_pipes else {
+            return Ok(());
+        };
+        let mut new_id = Vec::new();
+        while let Some(orig_id) = self.pending_renames.pop_front() {
+            new_id.clear();
+            fi_output.read_until(b'\n', &mut new_id)?;
+            let new_id = new_id.trim_end();
+            if new_id.is_empty() {
+                bail!(
+                    "fast-import exited before reporting the new ID of {}",
+                    orig_id.as_bstr(),
+                );
+            }
+            self.commit_renames.insert(&orig_id, Some(new_id));
+            if old_hash == Some(&orig_id) {
+                break;
+            }
+            if limit != 0 && self.pending_renames.len() < limit {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the new ID of a commit, reading it from fast-import if it is
+    /// still pending.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._get_rename`.
+    fn get_rename(&mut self, old_hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !matches!(self.commit_renames.get(old_hash), Renamed::Rewritten(_))
+            && self.pending_renames.iter().any(|oid| oid == old_hash)
+        {
+            self.flush_renames(Some(old_hash), 0)?;
+        }
+        Ok(match self.commit_renames.get(old_hash) {
+            Renamed::Rewritten(new_hash) => Some(new_hash.to_vec()),
+            Renamed::Unknown | Renamed::Pruned => None,
+        })
+    }
+
+    /// Returns the full original hashes of commits, which start with the
+    /// abbreviated hash.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._find_full_hash`.
+    fn find_full_hash(&self, short_hash: &[u8]) -> Vec<Vec<u8>> {
+        let Some(prefix) = short_hash
+            .get(..7)
+            .and_then(|p| <[u8; 7]>::try_from(p).ok())
+        else {
+            return Vec::new();
+        };
+        self.commit_short_old_hashes
+            .get(&prefix)
+            .map(|hashes| {
+                hashes
+                    .iter()
+                    .filter(|hash| hash.starts_with(short_hash))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Translates a possibly abbreviated reference to an original commit to
+    /// the new commit, keeping its length, unless that would be ambiguous.
+    /// References to unknown commits, such as non-commit hex words, or to
+    /// ambiguous abbreviations, are left as-is.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._translate_commit_hash`.
+    fn translate_commit_hash(&mut self, old_hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let [full_hash] = &self.find_full_hash(old_hash)[..] else {
+            return Ok(None);
+        };
+        match self.get_rename(full_hash)? {
+            Some(new_hash) => {
+                // Besides the new commits, the abbreviation must not be
+                // ambiguous with the objects in the source repo, most of which
+                // remain, such as unchanged blobs and trees.
+                let (input, batch_check) = (&self.input, &mut self.batch_check);
+                let abbrev =
+                    self.commit_renames
+                        .abbreviate(&new_hash, old_hash.len(), |prefix| {
+                            let Input::Repo(repo) = input else {
+                                return Ok(false);
+                            };
+                            let batch_check = match batch_check {
+                                Some(batch_check) => batch_check,
+                                None => batch_check.insert(BatchCheck::new(repo)?),
+                            };
+                            batch_check.is_ambiguous(prefix, &new_hash)
+                        })?;
+                if abbrev.len() < new_hash.len() {
+                    self.abbreviated_hashes.push(abbrev.to_vec());
+                }
+                Ok(Some(abbrev.to_vec()))
+            }
+            None => {
+                if self.commit_renames.get(full_hash) == Renamed::Pruned {
+                    self.commits_referenced_but_removed
+                        .insert(full_hash.clone());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Rewrites references to original commits in a commit message.
+    fn translate_commit_hashes(&mut self, message: &mut Bytes<'_>) -> Result<()> {
+        let hash_re = self.hash_re.clone();
+        let mut translated = Vec::new();
+        let mut last = 0;
+        for m in hash_re.find_iter(message) {
+            if let Some(new_hash) = self.translate_commit_hash(m.as_bytes())? {
+                translated.extend_from_slice(&message[last..m.start()]);
+                translated.extend_from_slice(&new_hash);
+                last = m.end();
+            }
+        }
+        if last != 0 {
+            translated.extend_from_slice(&message[last..]);
+            *message = translated.into();
+        }
+        Ok(())
+    }
+
+    /// Strips blobs from and filters the paths of file changes. The changes
+    /// of notes commits instead have their paths remapped to the rewritten
+    /// commits, which they annotate.
+    fn filter_changes(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        changes: &mut Vec<FileChange<'_>>,
+    ) -> Result<()> {
+        if is_notes_ref(&commit.branch.branch) {
+            let rewriter = NotesRewriter::new(&self.commit_renames, &self.commit_marks);
+            changes.retain_mut(|change| rewriter.rewrite_change(&mut change.change));
+            return Ok(());
+        }
+        if self.args.strip_blobs_bigger_than.is_some() || !self.args.strip_blobs_with_ids.is_empty()
+        {
+            self.strip_blob_changes(changes);
+        }
+        if self.filters_paths() {
+            self.filter_files(commit, changes)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the parent refers to a commit which was pruned.
+    fn is_skipped(&self, parent: &Commitish<Bytes<'_>>) -> bool {
+        matches!(parent.commit, Objectish::Mark(mark) if self.skipped_commits.contains_key(&mark))
+    }
+
+    /// Translates a reference to a commit, which may have been pruned, to the
+    /// commit it was rewritten to, or `None`, when none of its ancestors
+    /// remain.
+    fn translate_commit<'c>(&self, commit: &Commitish<Bytes<'c>>) -> Option<Commitish<Bytes<'c>>> {
+        match commit.commit {
+            Objectish::Mark(mark) => match self.skipped_commits.get(&mark) {
+                Some(rewritten) => rewritten.clone(),
+                None => Some(commit.clone()),
+            },
+            Objectish::BranchOrOid(_) => Some(commit.clone()),
+        }
+    }
+
+    /// Removes parents which were pruned without any remaining ancestor and,
+    /// unless `--prune-degenerate never`, parents which became redundant by
+    /// pruning: duplicates, which arise when both sides of a merge were pruned
+    /// back to the same commit, and ancestors of other parents. Returns the
+    /// remaining parents and, when a merge became degenerate, its new first
+    /// parent.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._trim_extra_parents`.
+    fn trim_extra_parents<'c>(
+        &mut self,
+        orig_parents: &[Commitish<Bytes<'_>>],
+        parents: Vec<Option<Commitish<Bytes<'c>>>>,
+    ) -> (Vec<Commitish<Bytes<'c>>>, Option<Commitish<Bytes<'c>>>) {
+        let always_prune = self.args.prune_degenerate == PruneMode::Always;
+        // Remove all parents rewritten to None, and keep track of which
+        // parents were rewritten to an ancestor.
+        let parents = parents
+            .into_iter()
+            .zip(orig_parents)
+            .filter_map(|(parent, orig)| {
+                Some((parent?, orig, always_prune || self.is_skipped(orig)))
+            })
+            .collect::<Vec<_>>();
+        if parents.len() < 2 || self.args.prune_degenerate == PruneMode::Never {
+            return (
+                parents.into_iter().map(|(parent, _, _)| parent).collect(),
+                None,
+            );
+        }
+
+        // Remove duplicate parents, but only those which were rewritten by
+        // pruning, since intentional duplicates in the original are kept.
+        let mut uniq: Vec<(Commitish<Bytes<'c>>, &Commitish<Bytes<'_>>, bool)> =
+            Vec::with_capacity(parents.len());
+        for (parent, orig, is_rewritten) in parents {
+            if !is_rewritten || !uniq.iter().any(|(p, _, _)| *p == parent) {
+                uniq.push((parent, orig, is_rewritten));
+            }
+        }
+
+        // Flatten unnecessary merges. If one side of history is entirely
+        // empty commits that were pruned, a commit may end up merged with its
+        // ancestor, so remove parents that are an ancestor of another parent.
+        if uniq.len() >= 2 {
+            let mut to_remove = Vec::new();
+            for cur in 0..uniq.len() {
+                if !uniq[cur].2 {
+                    continue;
+                }
+                for other in 0..uniq.len() {
+                    if cur == other
+                        || !self
+                            .graph
+                            .is_ancestor(&uniq[cur].0.commit, &uniq[other].0.commit)
+                    {
+                        continue;
+                    }
+                    // The parent seems redundant, but if it was intentionally
+                    // redundant in the original (e.g., a no-ff merge), keep it.
+                    if !always_prune
+                        && self
+                            .orig_graph
+                            .is_ancestor(&uniq[cur].1.commit, &uniq[other].1.commit)
+                    {
+                        continue;
+                    }
+                    // Some folks want all first parents to be merge commits,
+                    // so never remove the first parent with `--no-ff`.
+                    if self.args.no_ff && cur == 0 {
+                        continue;
+                    }
+                    to_remove.push(cur);
+                    break;
+                }
+            }
+            for cur in to_remove.into_iter().rev() {
+                uniq.remove(cur);
+            }
+        }
+
+        let parents = uniq
+            .into_iter()
+            .map(|(parent, _, _)| parent)
+            .collect::<Vec<_>>();
+        if parents.len() < 2 {
+            let new_1st_parent = parents[0].clone();
+            return (parents, Some(new_1st_parent));
+        }
+        (parents, None)
+    }
+
+    /// Returns the changes from the original of `parent` to the original of
+    /// `commit`, by diffing them in the source repo.
+    ///
+    // Corresponds to `git-filter-repo:GitUtils.get_file_changes`.
+    fn get_file_changes(
+        &self,
+        parent: &Commitish<Bytes<'_>>,
+        commit: &Commit<Bytes<'_>>,
+    ) -> Result<Vec<FileChange<'static>>> {
+        let Input::Repo(repo) = &self.input else {
+            bail!(
+                "Cannot compute the file changes of a commit relative to a new first parent \
+                 without a source repo"
+            );
+        };
+        let parent_oid = match &parent.commit {
+            Objectish::Mark(mark) => self
+                .commit_oids
+                .get(mark)
+                .with_context(|| format!("no original ID for commit :{}", mark.mark))?,
+            Objectish::BranchOrOid(oid) => &**oid,
+        };
+        let commit_oid = &commit
+            .original_oid
+            .as_ref()
+            .context("no original ID for commit")?
+            .oid;
+        diff_tree(repo, parent_oid, commit_oid, |oid| {
+            match self.blob_marks.get(oid) {
+                Some(&mark) => DataRef::Mark(mark),
+                None => DataRef::Oid(Cow::Owned(oid.to_vec())),
+            }
+        })
+    }
+
+    /// Records the paths of changes which were modified by filtering, since
+    /// they could lead to subsequent commits being empty.
+    fn record_tweaked_files(
+        &mut self,
+        orig_changes: &[FileChange<'_>],
+        changes: &[FileChange<'_>],
+    ) {
+        let all = self.args.replace_text.is_some();
+        fn by_path<'a, 'c>(
+            changes: &'a [FileChange<'c>],
+        ) -> HashMap<&'a [u8], Vec<&'a FileChange<'c>>> {
+            let mut by_path: HashMap<&[u8], Vec<&FileChange<'c>>> = HashMap::new();
+            for change in changes {
+                if let Some(path) = change_path(&change.change) {
+                    by_path.entry(path).or_default().push(change);
+                }
+            }
+            by_path
+        }
+        let (orig_by_path, final_by_path) = (by_path(orig_changes), by_path(changes));
+        for (a, b) in [
+            (&orig_by_path, &final_by_path),
+            (&final_by_path, &orig_by_path),
+        ] {
+            for (path, changes) in a {
+                let differs = || match b.get(path) {
+                    Some(other) => changes.iter().any(|change| !other.contains(change)),
+                    None => true,
+                };
+                if all || differs() {
+                    self.files_tweaked.insert(path.to_vec());
+                }
+            }
+        }
+    }
+
+    /// Returns whether a commit is empty after filtering and should be pruned.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._prunable`.
+    fn prunable(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        changes: &[FileChange<'_>],
+        new_1st_parent: Option<&Commitish<Bytes<'_>>>,
+        had_file_changes: bool,
+        orig_parents: &[Commitish<Bytes<'_>>],
+        output: &mut StreamWriter<'_>,
+    ) -> Result<bool> {
+        let parents = commit.from.iter().chain(&commit.merge).collect::<Vec<_>>();
+
+        if self.args.prune_empty == PruneMode::Never {
+            return Ok(false);
+        }
+        let always_prune = self.args.prune_empty == PruneMode::Always;
+
+        // For merge commits, unless there are prunable (redundant) parents, we
+        // do not want to prune.
+        if parents.len() >= 2 && new_1st_parent.is_none() {
+            return Ok(false);
+        }
+
+        if parents.len() < 2 {
+            // Special logic for commits that started empty.
+            if !had_file_changes && !always_prune {
+                let had_parents_pruned = parents.len() < orig_parents.len()
+                    || (orig_parents.len() == 1 && self.is_skipped(&orig_parents[0]));
+                // If the commit remains empty and had parents which were
+                // pruned, then prune this commit; otherwise, retain it.
+                return Ok(changes.is_empty() && had_parents_pruned);
+            }
+            // We can only get here if the commit didn't start empty, so if
+            // it's empty now, it obviously became empty.
+            if changes.is_empty() {
+                return Ok(true);
+            }
+        }
+
+        // If there are no parents of this commit and we didn't match the case
+        // above, then this commit cannot be pruned. Similarly, we cannot
+        // handle the hard cases without responses from fast-import.
+        let Some(parent) = new_1st_parent.or(parents.first().copied()) else {
+            return Ok(false);
+        };
+        if self.import_pipes.is_none() {
+            return Ok(false);
+        }
+
+        // If no commits have been pruned, then a non-merge commit can only be
+        // empty if filtering caused all of its changes to match the parent.
+        if self.skipped_commits.is_empty()
+            && orig_parents.len() < 2
+            && changes.iter().any(|change| {
+                change_path(&change.change).is_some_and(|path| !self.files_tweaked.contains(path))
+            })
+        {
+            return Ok(false);
+        }
+
+        // Finally, the hard case: compare the contents of this commit to its
+        // (remaining) first parent, by asking fast-import.
+        self.flush_renames(None, 0)?;
+        let root = match &parent.commit {
+            Objectish::Mark(mark) => Treeish::Mark(*mark),
+            Objectish::BranchOrOid(oid) => Treeish::Oid(oid.clone()),
+        };
+        for change in changes {
+            let (path, expected) = match &change.change {
+                Change::FileDelete(FileDeleteChange { path }) => (path, None),
+                Change::FileModify(FileModifyChange {
+                    data_ref,
+                    mode,
+                    path,
+                }) => {
+                    let oid = match data_ref {
+                        DataRef::Mark(mark) => {
+                            GetMark { mark: *mark }.dump(output)?;
+                            output.flush()?;
+                            self.read_response()?
+                        }
+                        DataRef::Oid(oid) => oid.to_vec(),
+                        DataRef::Inline(_) => return Ok(false),
+                    };
+                    (path, Some((*mode, oid)))
+                }
+                _ => return Ok(false),
+            };
+            Ls {
+                root: root.clone(),
+                path: path.clone(),
+            }
+            .dump(output)?;
+            output.flush()?;
+            let parent_version = self.read_response()?;
+            let parent_version = match parent_version.split_once_str(b"\t") {
+                Some((info, _)) => match info.fields().collect::<Vec<_>>()[..] {
+                    [mode, _, oid] => {
+                        let mode = u16::from_str_radix(mode.to_str()?, 8)?;
+                        Some((Mode::canonicalize(mode), oid.to_vec()))
+                    }
+                    _ => bail!("unexpected ls response: {}", parent_version.as_bstr()),
+                },
+                None if parent_version.starts_with(b"missing ") => None,
+                None => bail!("unexpected ls response: {}", parent_version.as_bstr()),
+            };
+            if parent_version != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Drops a commit from the output. References to it are rewritten to
+    /// `rewrite_to` and its branch is reset to it or, when `None`, deleted.
+    fn skip_commit(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        rewrite_to: Option<Commitish<Bytes<'_>>>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<()> {
+        if let Some(mark) = commit.mark {
+            self.skipped_commits
+                .insert(mark, rewrite_to.clone().map_bytes(&mut owned));
+            self.commit_renames.insert_pruned_mark(mark);
+        }
+        if let Some(original_oid) = &commit.original_oid {
+            self.commit_renames.insert(&original_oid.oid, None);
+        }
+        let branch = Branch {
+            branch: commit.branch.branch.clone(),
+        };
+        match rewrite_to {
+            Some(from) => output.insert_reset(&Reset {
+                branch,
+                from: Some(from),
+            })?,
+            None => Reset {
+                branch,
+                from: Some(deleted_commit()),
+            }
+            .dump(output)?,
+        }
+        Ok(())
+    }
+
+    /// Writes the objects, which a library script inserted, to the stream.
+    #[cfg(feature = "python")]
+    fn write_inserted(&mut self, output: &mut StreamWriter<'_>) -> Result<()> {
+        let Some(queue) = &self.insert_queue else {
+            return Ok(());
+        };
+        Python::with_gil(|py| {
+            let queue = queue.as_ref(py);
+            while !queue.is_empty() {
+                let obj = queue.get_item(0)?;
+                queue.del_item(0)?;
+                crate::py_api::insert_object(obj, output)?;
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(not(feature = "python"))]
+    #[inline]
+    fn write_inserted(&mut self, _output: &mut StreamWriter<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads a line of response from fast-import.
+    fn read_response(&mut self) -> Result<Vec<u8>> {
+        let fi_output = self
+            .import_pipes
+            .as_mut()
+            .context("no responses from fast-import")?;
+        let mut line = Vec::new();
+        fi_output.read_until(b'\n', &mut line)?;
+        if line.pop() != Some(b'\n') {
+            bail!("fast-import exited before responding");
+        }
+        Ok(line)
+    }
+
+    /// Returns whether a blob should be stripped by its size or original ID.
+    fn should_strip_blob(&self, original_oid: Option<&[u8]>, len: Option<u64>) -> bool {
+        let too_big = matches!(
+            (self.args.strip_blobs_bigger_than, len),
+            (Some(max), Some(len)) if len > max,
+        );
+        too_big || original_oid.is_some_and(|oid| self.args.strip_blobs_with_ids.contains(oid))
+    }
+
+    /// Removes file changes which refer to stripped blobs, either by mark or
+    /// by the ID of a blob outside the exported range.
+    fn strip_blob_changes(&self, changes: &mut Vec<FileChange<'_>>) {
+        changes.retain(|change| match &change.change {
+            Change::FileModify(fm) if fm.mode != Mode::GitLink => match &fm.data_ref {
+                DataRef::Mark(mark) => !self.stripped_blobs.contains(mark),
+                DataRef::Oid(oid) => {
+                    let len = self.unpacked_size.get(&**oid).copied();
+                    !self.should_strip_blob(Some(oid), len)
+                }
+                DataRef::Inline(_) => {
+                    let len = change.inline_data.as_ref().map(|data| data.len() as u64);
+                    !self.should_strip_blob(None, len)
+                }
+            },
+            _ => true,
+        });
+    }
+
+    /// Maps a path through the path filters and the filename callback.
+    /// Returns `None`, when the path is not wanted. The callback can read the
+    /// contents of `change`, if it is a modification.
+    fn newname(&mut self, path: &[u8], change: Option<&FileChange<'_>>) -> Result<Option<Vec<u8>>> {
+        if let Some(new) = self.new_names.get(path) {
+            return Ok(new.clone());
+        }
+        let mut new = paths::newname(
+            &self.args.path_changes,
+            path,
+            self.args.use_base_name,
+            self.args.inclusive,
+        );
+        let reads = self.blob_store.reads();
+        if let Some(name) = &mut new {
+            let store = self.blob_store.clone();
+            let contents = FileContents {
+                store: &store,
+                change,
+            };
+            if !self.filename_callback.call(name, contents)? {
+                new = None;
+            }
+        }
+        let new = new.filter(|new| !new.is_empty()).map(Cow::into_owned);
+        // Names which depend on the contents of the file are not cached.
+        if self.blob_store.reads() == reads {
+            self.new_names.insert(path.to_vec(), new.clone());
+        }
+        Ok(new)
+    }
+
+    /// Returns whether paths are filtered or renamed, by the path filters or
+    /// the filename callback.
+    fn filters_paths(&self) -> bool {
+        !self.args.path_changes.is_empty() || self.filename_callback.is_some()
+    }
+
+    /// Records the original file changes of a commit. Renames and copies from
+    /// paths which are filtered out are replaced with modifications of the
+    /// destination, since their contents would otherwise be lost with the
+    /// source.
+    fn track_files(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        changes: &mut [FileChange<'_>],
+    ) -> Result<()> {
+        // Without `from`, the commit continues its branch.
+        let parent = match &commit.from {
+            Some(from) => match from.commit {
+                Objectish::Mark(mark) => Some(mark),
+                Objectish::BranchOrOid(_) => None,
+            },
+            None => self.orig_branch_tips.get(&*commit.branch.branch).copied(),
+        };
+        let orig_changes = changes
+            .iter()
+            .cloned()
+            .map(FileChange::into_owned)
+            .collect::<Vec<_>>();
+        for (i, change) in changes.iter_mut().enumerate() {
+            let (Change::FileRename(FileRenameChange { source, dest })
+            | Change::FileCopy(FileCopyChange { source, dest })) = &change.change
+            else {
+                continue;
+            };
+            if self.newname(source, None)?.is_some() {
+                continue;
+            }
+            let Some(mut modify) = self.orig_file(&orig_changes[..i], parent, source) else {
+                continue;
+            };
+            if let Change::FileModify(FileModifyChange { path, .. }) = &mut modify.change {
+                *path = dest.clone();
+            }
+            *change = modify;
+        }
+        match commit.mark {
+            Some(mark) => {
+                let orig = OrigCommit {
+                    parent,
+                    changes: orig_changes,
+                };
+                self.orig_commits.insert(mark, orig);
+                self.orig_branch_tips
+                    .insert(commit.branch.branch.to_vec(), mark);
+            }
+            None => {
+                self.orig_branch_tips.remove(&*commit.branch.branch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the modification, which set a path in the original tree after
+    /// the changes of a commit with the given first parent. Returns `None`,
+    /// when the path is not in that tree or the tree is unknown.
+    fn orig_file<'c>(
+        &'c self,
+        mut changes: &'c [FileChange<'static>],
+        mut parent: Option<Mark>,
+        path: &[u8],
+    ) -> Option<FileChange<'static>> {
+        let mut path = path.to_vec();
+        loop {
+            for change in changes.iter().rev() {
+                match &change.change {
+                    Change::FileModify(FileModifyChange { path: p, .. }) if **p == *path => {
+                        return Some(change.clone());
+                    }
+                    Change::FileDelete(FileDeleteChange { path: p }) if **p == *path => {
+                        return None;
+                    }
+                    Change::FileRename(FileRenameChange { source, dest })
+                    | Change::FileCopy(FileCopyChange { source, dest })
+                        if **dest == *path =>
+                    {
+                        path = source.to_vec();
+                    }
+                    Change::FileRename(FileRenameChange { source, .. }) if **source == *path => {
+                        return None;
+                    }
+                    Change::FileDeleteAll => return None,
+                    _ => {}
+                }
+            }
+            let orig = self.orig_commits.get(&parent?)?;
+            changes = &orig.changes;
+            parent = orig.parent;
+        }
+    }
+
+    /// Filters and renames the paths of the file changes in a commit.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter._filter_files`.
+    fn filter_files(
+        &mut self,
+        commit: &Commit<Bytes<'_>>,
+        changes: &mut Vec<FileChange<'_>>,
+    ) -> Result<()> {
+        let commit_id = || match (&commit.original_oid, commit.mark) {
+            (Some(oid), _) => oid.oid.to_str_lossy().into_owned(),
+            (None, Some(mark)) => format!(":{}", mark.mark),
+            (None, None) => "(unknown)".to_owned(),
+        };
+        let mut kept: Vec<FileChange<'_>> = Vec::with_capacity(changes.len());
+        let mut by_path: HashMap<Vec<u8>, usize> = HashMap::new();
+        for mut change in changes.drain(..) {
+            match &mut change.change {
+                Change::FileModify(_) | Change::FileDelete(_) => {
+                    let path = change_path(&change.change).unwrap();
+                    let Some(new) = self.newname(path, Some(&change))? else {
+                        continue;
+                    };
+                    if let Change::FileModify(FileModifyChange { path, .. })
+                    | Change::FileDelete(FileDeleteChange { path }) = &mut change.change
+                    {
+                        *path = new.into();
+                    }
+                }
+                Change::FileRename(FileRenameChange { source, dest })
+                | Change::FileCopy(FileCopyChange { source, dest }) => {
+                    match (self.newname(source, None)?, self.newname(dest, None)?) {
+                        (Some(new_source), Some(new_dest)) => {
+                            *source = new_source.into();
+                            *dest = new_dest.into();
+                        }
+                        (None, None) => continue,
+                        // The file moved out of the filtered paths.
+                        (Some(new_source), None) => {
+                            if !matches!(change.change, Change::FileRename(_)) {
+                                continue;
+                            }
+                            change.change = Change::FileDelete(FileDeleteChange {
+                                path: new_source.into(),
+                            });
+                        }
+                        // The file moved into the filtered paths, but its
+                        // contents are only known by its old path, which is
+                        // not in the tracked original tree.
+                        (None, Some(_)) => {
+                            let (kind, source, dest) = match &change.change {
+                                Change::FileRename(r) => ("rename", &r.source, &r.dest),
+                                Change::FileCopy(c) => ("copy", &c.source, &c.dest),
+                                _ => unreachable!(),
+                            };
+                            bail!(
+                                "Cannot filter {kind} of {} to {}, because its source is filtered \
+                                 out and its contents are unknown; export without rename or copy \
+                                 detection\n  Commit: {}",
+                                source.as_bstr(),
+                                dest.as_bstr(),
+                                commit_id(),
+                            );
+                        }
+                    }
+                }
+                Change::FileDeleteAll
+                | Change::NoteModify(_)
+                | Change::Ls(_)
+                | Change::CatBlob(_) => {}
+            }
+
+            let path = match &change.change {
+                Change::FileModify(FileModifyChange { path, .. })
+                | Change::FileDelete(FileDeleteChange { path }) => path.to_vec(),
+                _ => {
+                    kept.push(change);
+                    continue;
+                }
+            };
+            if let Some(&i) = by_path.get(&path) {
+                // Renaming caused two paths to collide. This is allowed, when
+                // either is a deletion or they are identical.
+                match (&change.change, &kept[i].change) {
+                    (Change::FileDelete(_), _) => continue,
+                    (Change::FileModify(a), Change::FileModify(b))
+                        if a.mode == b.mode
+                            && a.data_ref == b.data_ref
+                            && change.inline_data == kept[i].inline_data =>
+                    {
+                        continue
+                    }
+                    (_, Change::FileDelete(_)) => {
+                        kept[i] = change;
+                        continue;
+                    }
+                    _ => bail!(
+                        "File renaming caused colliding pathnames!\n  Commit: {}\n  Filename: {}",
+                        commit_id(),
+                        path.as_bstr(),
+                    ),
+                }
+            }
+            by_path.insert(path, kept.len());
+            kept.push(change);
+        }
+        *changes = kept;
+        Ok(())
+    }
+}
+
+impl Handler for RepoFilter<'_> {
+    // Corresponds to `git-filter-repo:RepoFilter._tweak_blob`.
+    fn strip_blob(
+        &mut self,
+        mark: Option<Mark>,
+        original_oid: Option<&[u8]>,
+        len: Option<u64>,
+    ) -> Result<bool> {
+        if let (Some(oid), Some(len)) = (original_oid, len) {
+            self.unpacked_size.insert(oid.to_vec(), len);
+        }
+        let strip = self.should_strip_blob(original_oid, len);
+        if strip {
+            self.progress_writer.blob(len.unwrap_or(0));
+            if let Some(mark) = mark {
+                self.stripped_blobs.insert(mark);
+            }
+        }
+        Ok(strip)
+    }
+
+    // Corresponds to `git-filter-repo:RepoFilter._tweak_blob`.
+    fn blob(&mut self, blob: &mut BlobObject<'_>, output: &mut StreamWriter<'_>) -> Result<bool> {
+        self.write_inserted(output)?;
+        // Delimited data could not be sized from its header, so is checked
+        // after reading.
+        let len = blob.data.len() as u64;
+        self.progress_writer.blob(len);
+        if let Some(oid) = &blob.original_oid {
+            self.unpacked_size.insert(oid.oid.to_vec(), len);
         }
+        if self.should_strip_blob(None, Some(len)) {
+            if let Some(mark) = blob.mark {
+                self.stripped_blobs.insert(mark);
+            }
+            return Ok(false);
+        }
+
+        // Like filter-repo, leave binary blobs, which have a NUL byte in the
+        // first 8KiB, alone.
+        let mut rewritten = false;
+        if let Some(replace_text) = &self.args.replace_text {
+            if !blob.data[..blob.data.len().min(8192)].contains(&0) {
+                rewritten = replace_text.replace_vec(&mut blob.data);
+            }
+        }
+
+        if self.blob_callback.is_some() {
+            let orig_data = (!rewritten).then(|| blob.data.clone());
+            let keep = self.blob_callback.call(blob)?;
+            self.write_inserted(output)?;
+            if !keep {
+                if let Some(mark) = blob.mark {
+                    self.stripped_blobs.insert(mark);
+                }
+                return Ok(false);
+            }
+            rewritten |= orig_data.is_some_and(|data| data != blob.data);
+        }
+        if let (true, Some(mark)) = (rewritten, blob.mark) {
+            self.rewritten_marks.insert(mark);
+        }
+        if let Some(mark) = blob.mark {
+            let original_oid = blob.original_oid.as_ref().filter(|_| !rewritten);
+            self.blob_store
+                .save(mark, original_oid.map(|oid| &*oid.oid), &blob.data)?;
+        }
+        Ok(true)
+    }
+
+    // Corresponds to `git-filter-repo:RepoFilter._tweak_commit`.
+    fn commit(
+        &mut self,
+        commit: &mut CommitObject<'_>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<bool> {
+        self.progress_writer.commit();
+        self.write_inserted(output)?;
+        let CommitObject { commit, changes } = commit;
+        let had_file_changes = !changes.is_empty();
+        // The commit is kept as it was, to later check whether it changed,
+        // unless it is already known to be rewritten, because a parent was.
+        let parent_rewritten = commit.from.iter().chain(&commit.merge).any(|parent| {
+            matches!(parent.commit, Objectish::Mark(mark) if self.rewritten_marks.contains(&mark))
+        });
+        let unfiltered = (!parent_rewritten).then(|| (commit.clone(), changes.clone()));
+        if let (Some(mark), Some(original_oid)) = (commit.mark, &commit.original_oid) {
+            self.commit_oids.insert(mark, original_oid.oid.to_vec());
+            self.commit_marks.insert(original_oid.oid.to_vec(), mark);
+        }
+        if let Some(original_oid) = &commit.original_oid {
+            let parents = commit
+                .from
+                .iter()
+                .chain(&commit.merge)
+                .filter_map(|parent| match &parent.commit {
+                    Objectish::Mark(mark) => self.commit_oids.get(mark).cloned(),
+                    Objectish::BranchOrOid(oid) => Some(oid.to_vec()),
+                })
+                .collect();
+            self.orig_commit_parents
+                .push((original_oid.oid.to_vec(), parents));
+        }
+
+        // Parents which were pruned are rewritten to their nearest remaining
+        // ancestor.
+        let orig_parents = commit
+            .from
+            .iter()
+            .chain(&commit.merge)
+            .cloned()
+            .collect::<Vec<_>>();
+        let parents = orig_parents
+            .iter()
+            .map(|parent| self.translate_commit(parent))
+            .collect::<Vec<_>>();
+        if let Some(mark) = commit.mark {
+            let id = Objectish::Mark(mark);
+            self.graph
+                .add_commit_and_parents(&id, parents.iter().flatten().map(|parent| &parent.commit));
+            self.orig_graph
+                .add_commit_and_parents(&id, orig_parents.iter().map(|parent| &parent.commit));
+        }
+
+        self.reencoder.reencode_commit(commit)?;
+        if let Some(original_oid) = &commit.original_oid {
+            if let Some(prefix) = original_oid.oid.get(..7) {
+                self.commit_short_old_hashes
+                    .entry(prefix.try_into().unwrap())
+                    .or_default()
+                    .insert(original_oid.oid.to_vec());
+            }
+        }
+        if !self.args.preserve_commit_hashes {
+            self.translate_commit_hashes(&mut commit.message)?;
+        }
+        if let Some(replace_message) = &self.args.replace_message {
+            replace_message.replace_bytes(&mut commit.message);
+        }
+        let object = || {
+            let branch = ("branch", &commit.branch.branch);
+            describe(
+                "commit",
+                commit.mark,
+                commit.original_oid.as_ref(),
+                Some(branch),
+            )
+        };
+        self.message_callback
+            .call("message", &mut commit.message, object)?;
+        if let Some(author) = &mut commit.author {
+            self.tweak_ident(author, object)?;
+        }
+        self.tweak_ident(&mut commit.committer, object)?;
+
+        let tweaks_files = self.args.strip_blobs_bigger_than.is_some()
+            || !self.args.strip_blobs_with_ids.is_empty()
+            || !self.args.path_changes.is_empty()
+            || self.filename_callback.is_some();
+        let mut orig_changes =
+            (tweaks_files && self.args.prune_empty != PruneMode::Never).then(|| changes.clone());
+        if self.filters_paths() && !is_notes_ref(&commit.branch.branch) {
+            self.track_files(commit, changes)?;
+        }
+        self.filter_changes(commit, changes)?;
+
+        // Prune parents (due to pruning of empty commits) if relevant.
+        let old_1st_parent = parents.first().cloned().flatten();
+        let (parents, new_1st_parent) = self.trim_extra_parents(&orig_parents, parents);
+        let mut parents_iter = parents.iter().cloned();
+        commit.from = parents_iter.next();
+        commit.merge = parents_iter.collect();
+
+        // If parents were pruned, then the file changes need to be relative
+        // to the new first parent.
+        if let Some(first) = parents.first() {
+            if old_1st_parent.as_ref() != Some(first) {
+                *changes = self.get_file_changes(first, commit)?;
+                orig_changes = Some(changes.clone());
+                self.filter_changes(commit, changes)?;
+            }
+        }
+        if let Some(orig_changes) = &orig_changes {
+            self.record_tweaked_files(orig_changes, changes);
+        } else if self.args.replace_text.is_some() {
+            self.record_tweaked_files(changes, changes);
+        }
+
+        let object = || describe("commit", commit.mark, commit.original_oid.as_ref(), None);
+        self.tweak_refname(&mut commit.branch.branch, object)?;
+
+        if self.commit_callback.is_some() {
+            let blobs = self.blob_store.clone();
+            let metadata = CommitMetadata {
+                orig_parents: &orig_parents,
+                had_file_changes,
+                blobs: &blobs,
+            };
+            let record_changes = self.args.prune_empty != PruneMode::Never;
+            let action = self
+                .commit_callback
+                .call(commit, changes, &metadata, record_changes)?;
+            self.write_inserted(output)?;
+            // Blobs with contents replaced by the callback are written before
+            // the commit, which refers to them.
+            for blob in self.blob_store.take_added() {
+                blob.dump(output)?;
+            }
+            match action {
+                CommitAction::Keep { orig_changes } => {
+                    if let Some(orig_changes) = &orig_changes {
+                        self.record_tweaked_files(orig_changes, changes);
+                    }
+                }
+                CommitAction::Skip { rewrite_to } => {
+                    let rewrite_to = rewrite_to.and_then(|to| self.translate_commit(&to));
+                    self.skip_commit(commit, rewrite_to, output)?;
+                    return Ok(false);
+                }
+            }
+        }
+        let parents = commit
+            .from
+            .iter()
+            .chain(&commit.merge)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if self.prunable(
+            commit,
+            changes,
+            new_1st_parent.as_ref(),
+            had_file_changes,
+            &orig_parents,
+            output,
+        )? {
+            let rewrite_to = new_1st_parent.or_else(|| parents.first().cloned());
+            self.skip_commit(commit, rewrite_to, output)?;
+            return Ok(false);
+        }
+
+        if orig_parents.len() >= 2 && parents.len() < 2 {
+            if let Some(original_oid) = &commit.original_oid {
+                self.commits_no_longer_merges
+                    .push((original_oid.oid.to_vec(), None));
+            }
+        }
+
+        let rewritten = match &unfiltered {
+            Some((orig_commit, orig_changes)) => {
+                !same_commit_contents(orig_commit, commit)
+                    || orig_changes != changes
+                    || changes.iter().any(|change| match &change.change {
+                        Change::FileModify(FileModifyChange {
+                            data_ref: DataRef::Mark(mark),
+                            ..
+                        })
+                        | Change::NoteModify(NoteModifyChange {
+                            data_ref: DataRef::Mark(mark),
+                            ..
+                        }) => self.rewritten_marks.contains(mark),
+                        _ => false,
+                    })
+            }
+            None => true,
+        };
+        if let (true, Some(mark)) = (rewritten, commit.mark) {
+            self.rewritten_marks.insert(mark);
+        }
+        if let Some(warning) = self.signature_policy.apply_commit(commit, rewritten) {
+            eprintln!("WARNING: {warning}");
+        }
+        Ok(true)
+    }
+
+    fn commit_written(
+        &mut self,
+        commit: &CommitObject<'_>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<()> {
+        self.record_remapping(&commit.commit, output)
+    }
+
+    // Corresponds to `git-filter-repo:RepoFilter._tweak_tag`.
+    fn tag(&mut self, tag: &mut Tag<Bytes<'_>>, output: &mut StreamWriter<'_>) -> Result<bool> {
+        self.write_inserted(output)?;
+        let orig_tag = tag.clone();
+        if let Objectish::Mark(mark) = tag.from {
+            match self.skipped_commits.get(&mark) {
+                Some(Some(rewritten)) => tag.from = rewritten.commit.clone(),
+                // None of the history of the tagged commit remains.
+                Some(None) => return Ok(false),
+                None => {}
+            }
+        }
+        self.reencoder.reencode_tag(tag)?;
+        if let Some(replace_message) = &self.args.replace_message {
+            replace_message.replace_bytes(&mut tag.message);
+        }
+        let object = || {
+            let name = ("name", &tag.name.name);
+            describe("tag", tag.mark, tag.original_oid.as_ref(), Some(name))
+        };
+        self.message_callback
+            .call("message", &mut tag.message, object)?;
+        if let Some(tagger) = &mut tag.tagger {
+            self.tweak_ident(tagger, object)?;
+        }
+        // The refname callback acts on the full ref.
+        let mut fullref = b"refs/tags/".to_vec();
+        fullref.extend_from_slice(&tag.name.name);
+        let mut fullref = Bytes::from(fullref);
+        self.tweak_refname(&mut fullref, object)?;
+        tag.name.name = match fullref.strip_prefix(b"refs/tags/") {
+            Some(name) => name.to_vec().into(),
+            None => bail!(
+                "fast-import requires tags to be in refs/tags/ namespace.\n       \
+                 refs/tags/{} renamed to {}",
+                tag.name.name.as_bstr(),
+                fullref.as_bstr(),
+            ),
+        };
+        if self.tag_callback.is_some() {
+            let keep = self.tag_callback.call(tag)?;
+            self.write_inserted(output)?;
+            if !keep {
+                return Ok(false);
+            }
+        }
+        let rewritten = tag.name != orig_tag.name
+            || tag.from != orig_tag.from
+            || tag.tagger != orig_tag.tagger
+            || tag.message != orig_tag.message
+            || matches!(tag.from, Objectish::Mark(mark) if self.rewritten_marks.contains(&mark));
+        if let (true, Some(mark)) = (rewritten, tag.mark) {
+            self.rewritten_marks.insert(mark);
+        }
+        if let Some(warning) = self.signature_policy.apply_tag(tag, rewritten) {
+            eprintln!("WARNING: {warning}");
+        }
+        Ok(true)
+    }
+
+    // Corresponds to `git-filter-repo:RepoFilter._tweak_reset`.
+    fn reset(
+        &mut self,
+        reset: &mut Reset<Bytes<'_>>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<bool> {
+        self.write_inserted(output)?;
+        if self.filters_paths() {
+            match reset.from.as_ref().map(|from| &from.commit) {
+                Some(&Objectish::Mark(mark)) => {
+                    self.orig_branch_tips
+                        .insert(reset.branch.branch.to_vec(), mark);
+                }
+                _ => {
+                    self.orig_branch_tips.remove(&*reset.branch.branch);
+                }
+            }
+        }
+        if let Some(from) = &reset.from {
+            reset.from = Some(self.translate_commit(from).unwrap_or_else(deleted_commit));
+        }
+        let branch = reset.branch.branch.clone();
+        let object = || describe("reset", None, None, Some(("branch", &branch)));
+        self.tweak_refname(&mut reset.branch.branch, object)?;
+        if self.reset_callback.is_some() {
+            let keep = self.reset_callback.call(reset)?;
+            self.write_inserted(output)?;
+            return Ok(keep);
+        }
+        Ok(true)
+    }
+
+    fn done(&mut self) -> Result<()> {
+        self.done_callback.call()
+    }
+}
+
+/// A `git cat-file --batch-check` process, which checks whether abbreviated
+/// IDs are ambiguous with the objects in a repo.
+struct BatchCheck {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl BatchCheck {
+    fn new(repo: &Path) -> Result<Self> {
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["cat-file", "--batch-check"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("could not run git cat-file")?;
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(BatchCheck {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Returns whether an object other than `oid` starts with `prefix`.
+    fn is_ambiguous(&mut self, prefix: &[u8], oid: &[u8]) -> Result<bool> {
+        let stdin = self.stdin.as_mut().unwrap();
+        stdin.write_all(&[prefix, b"\n"].concat())?;
+        stdin.flush()?;
+        let mut line = Vec::new();
+        self.stdout.read_until(b'\n', &mut line)?;
+        // The line is `<oid> <type> <size>`, `<prefix> ambiguous`, or
+        // `<prefix> missing`.
+        Ok(
+            match line.trim_end().split_str(" ").collect::<Vec<_>>()[..] {
+                [_, b"missing"] => false,
+                [_, b"ambiguous"] => true,
+                [found, _, _] => found != oid,
+                _ => bail!("unexpected output from git cat-file: {}", line.as_bstr()),
+            },
+        )
+    }
+}
+
+impl Drop for BatchCheck {
+    fn drop(&mut self) {
+        // Closing stdin ends the batch.
+        self.stdin = None;
+        let _ = self.child.wait();
     }
 }
 
-impl AncestryGraph {
-    pub fn new() -> Self {
-        AncestryGraph {}
+/// Returns the changes from `parent` to `commit`, by diffing them in a repo.
+/// The blob of each modification is referred to by `data_ref`.
+///
+// Corresponds to `git-filter-repo:GitUtils.get_file_changes`.
+pub(crate) fn diff_tree(
+    repo: &Path,
+    parent: &[u8],
+    commit: &[u8],
+    data_ref: impl Fn(&[u8]) -> DataRef<Bytes<'static>>,
+) -> Result<Vec<FileChange<'static>>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["diff-tree", "-r", "-z"])
+        .arg(parent.to_str()?)
+        .arg(commit.to_str()?)
+        .output()
+        .context("could not run git diff-tree")?;
+    if !output.status.success() {
+        bail!("git diff-tree failed: {}", output.status);
     }
+
+    let mut file_changes = Vec::new();
+    let mut fields = output.stdout.split_str(b"\0");
+    while let (Some(info), Some(path)) = (fields.next(), fields.next()) {
+        let info = info.strip_prefix(b":").unwrap_or(info);
+        let [_, mode, _, new_hash, change_type] = info.fields().collect::<Vec<_>>()[..] else {
+            bail!("Unknown change for line {}", info.as_bstr());
+        };
+        let path = Cow::Owned(path.to_vec());
+        let change = match change_type {
+            b"D" => Change::FileDelete(FileDeleteChange { path }),
+            b"A" | b"M" | b"T" => {
+                let mode = u16::from_str_radix(mode.to_str()?, 8)?;
+                let data_ref = data_ref(new_hash);
+                Change::FileModify(FileModifyChange {
+                    data_ref,
+                    mode: Mode::canonicalize(mode),
+                    path,
+                })
+            }
+            _ => bail!("Unknown change type for line {}", info.as_bstr()),
+        };
+        file_changes.push(FileChange {
+            change,
+            inline_data: None,
+        });
+    }
+    Ok(file_changes)
 }
 
-impl ProgressWriter {
-    pub fn new() -> Self {
-        ProgressWriter {}
+/// The null commit, which deletes a branch when it is reset to it.
+fn deleted_commit<'a>() -> Commitish<Bytes<'a>> {
+    Commitish {
+        commit: Objectish::BranchOrOid(Cow::Borrowed(&[b'0'; 40])),
     }
 }
 
+/// Returns the path which a file change modifies.
+fn change_path<B: AsRef<[u8]>>(change: &Change<B>) -> Option<&[u8]> {
+    match change {
+        Change::FileModify(FileModifyChange { path, .. })
+        | Change::FileDelete(FileDeleteChange { path })
+        | Change::FileRename(FileRenameChange { dest: path, .. })
+        | Change::FileCopy(FileCopyChange { dest: path, .. }) => Some(path.as_ref()),
+        Change::FileDeleteAll | Change::NoteModify(_) | Change::Ls(_) | Change::CatBlob(_) => None,
+    }
+}
+
+/// Renames a ref in `refs/tags/` with the prefix `old` to have the prefix `new`.
+///
+// Corresponds to `git-filter-repo:RepoFilter._do_tag_rename`.
+fn do_tag_rename(old: &[u8], new: &[u8], refname: &[u8]) -> Option<Vec<u8>> {
+    let rest = refname.strip_prefix(b"refs/tags/")?.strip_prefix(old)?;
+    let mut renamed = b"refs/tags/".to_vec();
+    renamed.extend_from_slice(new);
+    renamed.extend_from_slice(rest);
+    Some(renamed)
+}
+
+/// Returns whether two commits have the same contents, apart from their
+/// signatures and the branch they are on, which are not part of what is signed.
+fn same_commit_contents(a: &Commit<Bytes<'_>>, b: &Commit<Bytes<'_>>) -> bool {
+    a.author == b.author
+        && a.committer == b.committer
+        && a.encoding == b.encoding
+        && a.message == b.message
+        && a.from == b.from
+        && a.merge == b.merge
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{borrow::Cow, fs};
+
+    use anyhow::Result;
+    use bstr::ByteSlice;
+    use fast_export::parse::{Change, FileModifyChange};
+    #[cfg(feature = "python")]
     use pyo3::{
         types::{PyDict, PyString},
         Python,
     };
 
-    use crate::filter::{RepoFilter, TODO};
+    #[cfg(feature = "python")]
+    use crate::callback::Callback;
+    use crate::{
+        filter::{BatchCheck, RepoFilter},
+        options::FilteringOptions,
+        stream::{Input, Output},
+        test_util::{git, init_repo},
+    };
 
+    #[cfg(feature = "python")]
     #[test]
     fn parse_and_call_callback() {
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.filename_callback("return f\"Hello, {filename}!\"")
+            .unwrap();
+        let filter = b.build().unwrap();
+        let Callback::Python(callback) = &filter.filename_callback else {
+            panic!("not a Python callback");
+        };
         Python::with_gil(|py| {
-            let mut b = RepoFilter::builder(py, TODO);
-            b.filename_callback("return f\"Hello, {filename}!\"")
-                .unwrap();
-            let filter = b.build();
-            let res = filter
-                .filename_callback
-                .unwrap()
-                .call(("world", PyDict::new(py)), None)
-                .unwrap();
-            let s = res.downcast::<PyString>().unwrap().to_str().unwrap();
+            let res = callback.call(py, ("world", PyDict::new(py)), None).unwrap();
+            let s = res.downcast::<PyString>(py).unwrap().to_str().unwrap();
             assert_eq!(s, "Hello, world!");
         });
     }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn run_stream_to_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        fs::write(
+            &input,
+            b"feature done\n\
+              blob\n\
+              mark :1\n\
+              original-oid 1111111111111111111111111111111111111111\n\
+              data 6\n\
+              hello\n\
+              \n\
+              reset refs/heads/main\n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              original-oid 2222222222222222222222222222222222222222\n\
+              author A U Thor <author@example.com> 1700000000 +0000\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 src/a.txt\n\
+              M 100644 :1 drop.txt\n\
+              M 100644 inline src/b.txt\n\
+              data 3\n\
+              hi\n\
+              \n\
+              reset refs/heads/main\n\
+              from :2\n\
+              \n\
+              done\n",
+        )
+        .unwrap();
+
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.input(Input::Stream(input))
+            .output(Output::Stream(output.clone()))
+            .filename_callback(
+                "return None if filename == b'drop.txt' else filename.replace(b'src/', b'lib/')",
+            )
+            .unwrap()
+            .message_callback("return message.upper()")
+            .unwrap();
+        let mut filter = b.build().unwrap();
+        filter.run().unwrap();
+        assert!(filter.exported_refs().contains(&b"refs/heads/main"[..]));
+        assert!(filter.imported_refs().contains(&b"refs/heads/main"[..]));
+
+        assert_eq!(
+            fs::read(&output).unwrap(),
+            b"feature done\n\
+              blob\n\
+              mark :1\n\
+              original-oid 1111111111111111111111111111111111111111\n\
+              data 6\n\
+              hello\n\
+              \n\
+              reset refs/heads/main\n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              original-oid 2222222222222222222222222222222222222222\n\
+              author A U Thor <author@example.com> 1700000000 +0000\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              INITIAL\n\
+              \n\
+              M 100644 :1 lib/a.txt\n\
+              M 100644 inline lib/b.txt\n\
+              data 3\n\
+              hi\n\
+              \n\
+              \n\
+              reset refs/heads/main\n\
+              from :2\n\
+              done\n",
+        );
+    }
+
+    #[test]
+    fn rust_callbacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        fs::write(
+            &input,
+            b"blob\n\
+              mark :1\n\
+              data 6\n\
+              hello\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 src/a.txt\n\
+              M 100644 :1 drop.txt\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :3\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 5\n\
+              Skip\n\
+              from :2\n\
+              M 100644 :1 b.txt\n\
+              \n\
+              done\n",
+        )
+        .unwrap();
+
+        let mut had_file_changes = Vec::new();
+        {
+            let mut b = RepoFilter::builder(FilteringOptions::default());
+            b.input(Input::Stream(input))
+                .output(Output::Stream(output.clone()))
+                .filename_callback_fn(|filename, contents| {
+                    // The contents are read after the blob callback.
+                    if let Some(data) = contents.read()? {
+                        assert_eq!(data, b"HELLO\n");
+                    }
+                    if let Some(rest) = filename.strip_prefix(b"src/") {
+                        *filename = [&b"lib/"[..], rest].concat().into();
+                    }
+                    Ok(**filename != *b"drop.txt")
+                })
+                .unwrap()
+                .message_callback_fn(|message| {
+                    // Values are borrowed from the parser, until modified.
+                    assert!(matches!(message, Cow::Borrowed(_)));
+                    message.to_mut().make_ascii_uppercase();
+                    Ok(())
+                })
+                .unwrap()
+                .blob_callback_fn(|blob| {
+                    blob.data.make_ascii_uppercase();
+                    Ok(true)
+                })
+                .unwrap()
+                .commit_callback_fn(|commit, changes, metadata| {
+                    had_file_changes.push(metadata.had_file_changes);
+                    for change in changes {
+                        let data = metadata.blobs.read_change(change)?;
+                        assert_eq!(data.as_deref(), Some(&b"HELLO\n"[..]));
+                        if let Change::FileModify(FileModifyChange { path, .. }) =
+                            &mut change.change
+                        {
+                            path.to_mut().extend_from_slice(b".orig");
+                        }
+                    }
+                    Ok(&*commit.message != b"SKIP\n")
+                })
+                .unwrap();
+            b.build().unwrap().run().unwrap();
+        }
+        assert_eq!(had_file_changes, [true, true]);
+
+        assert_eq!(
+            fs::read(&output).unwrap().as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 6\n\
+              HELLO\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              INITIAL\n\
+              \n\
+              M 100644 :1 lib/a.txt.orig\n\
+              \n\
+              reset refs/heads/main\n\
+              from :2\n\
+              done\n"
+                .as_bstr(),
+        );
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn hooks() {
+        let input = b"blob\n\
+              mark :1\n\
+              data 6\n\
+              hello\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 a.txt\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :3\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 5\n\
+              Skip\n\
+              from :2\n\
+              M 100644 :1 b.txt\n\
+              \n\
+              done\n";
+        let commit_hook = "\
+            assert had_file_changes()\n\
+            if message() == b'Skip\\n':\n  drop()\n\
+            else:\n  message(set=message().upper())";
+        let output = filter_stream(
+            &[
+                "--blob-hook",
+                "data(set=data().upper())",
+                "--on-commit",
+                commit_hook,
+            ],
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 6\n\
+              HELLO\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              INITIAL\n\
+              \n\
+              M 100644 :1 a.txt\n\
+              \n\
+              reset refs/heads/main\n\
+              from :2\n\
+              done\n"
+                .as_bstr(),
+        );
+
+        let err = filter_stream(
+            &["--commit-callback", "pass", "--commit-hook", "pass"],
+            input,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot pass both --commit-callback and --commit-hook",
+        );
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn callback_errors() {
+        let input = b"commit refs/heads/main\n\
+              mark :1\n\
+              original-oid 0123456789abcdef0123456789abcdef01234567\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              \n\
+              done\n";
+        let commit_callback = "\
+            if commit.message:\n  \
+              raise ValueError('bad commit')";
+        let err = filter_stream(&["--commit-callback", commit_callback], input).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(
+            err.starts_with(
+                "commit callback failed on commit :1 (original-oid \
+                 0123456789abcdef0123456789abcdef01234567, branch refs/heads/main): ",
+            ),
+            "{err}",
+        );
+        assert!(err.contains("File \"<commit_callback>\", line 2"), "{err}");
+        assert!(err.contains("raise ValueError('bad commit')"), "{err}");
+        assert!(err.ends_with("ValueError: bad commit"), "{err}");
+    }
+
+    #[test]
+    fn message_callback_error() {
+        let input = b"commit refs/heads/main\n\
+              mark :1\n\
+              original-oid 0123456789abcdef0123456789abcdef01234567\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              \n\
+              tag v1\n\
+              mark :2\n\
+              from :1\n\
+              tagger T A Gger <tagger@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Release\n\
+              \n\
+              done\n";
+        let filter = |fail_on: &'static [u8]| {
+            let dir = tempfile::tempdir().unwrap();
+            let input_path = dir.path().join("input");
+            fs::write(&input_path, input).unwrap();
+            let mut b = RepoFilter::builder(FilteringOptions::default());
+            b.input(Input::Stream(input_path))
+                .output(Output::Stream(dir.path().join("output")))
+                .message_callback_fn(move |message| {
+                    if **message == *fail_on {
+                        anyhow::bail!("bad message");
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            let err = b.build().unwrap().run().unwrap_err();
+            format!("{err:#}")
+        };
+
+        assert_eq!(
+            filter(b"Initial\n"),
+            "message callback failed on commit :1 (original-oid \
+             0123456789abcdef0123456789abcdef01234567, branch refs/heads/main): bad message",
+        );
+        assert_eq!(
+            filter(b"Release\n"),
+            "message callback failed on tag :2 (name v1): bad message",
+        );
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn lua_callbacks() {
+        let input = b"blob\n\
+              mark :1\n\
+              data 6\n\
+              hello\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 a.txt\n\
+              M 100644 inline b.txt\n\
+              data 5\n\
+              TODO\n\
+              \n\
+              done\n";
+        let commit_callback = "\
+            for i = #commit.file_changes, 1, -1 do\n  \
+              if commit:file_data(commit.file_changes[i]) == 'TODO\\n' then\n    \
+                table.remove(commit.file_changes, i)\n  \
+              end\n\
+            end\n\
+            commit.author_name = 'A U Thor'\n\
+            commit.message = commit.message:upper()";
+        let output = filter_stream(
+            &[
+                "--blob-callback:lua",
+                "blob.data = blob.data:upper()",
+                "--commit-callback:lua",
+                commit_callback,
+                "--refname-callback:lua",
+                "return (refname:gsub('main', 'trunk'))",
+            ],
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 6\n\
+              HELLO\n\
+              \n\
+              commit refs/heads/trunk\n\
+              mark :2\n\
+              author A U Thor <committer@example.com> 1700000000 +0000\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              INITIAL\n\
+              \n\
+              M 100644 :1 a.txt\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+
+        let err = filter_stream(
+            &["--commit-callback:lua", "local x = 1\nerror('bad commit')"],
+            input,
+        )
+        .unwrap_err();
+        let err = format!("{err:#}");
+        assert!(
+            err.starts_with(
+                "commit callback failed on commit :2 (branch refs/heads/main): \
+                 runtime error: <commit_callback>:2: bad commit",
+            ),
+            "{err}",
+        );
+
+        let err = filter_stream(
+            &["--commit-callback", "pass", "--commit-callback:lua", "pass"],
+            input,
+        )
+        .unwrap_err();
+        let expected = if cfg!(feature = "python") {
+            "Cannot pass both --commit-callback and --commit-callback:lua"
+        } else {
+            "--commit-callback requires Python, which is not enabled in this build"
+        };
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn replace_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        fs::write(
+            &input,
+            b"blob\n\
+              mark :1\n\
+              data 9\n\
+              (c) 2023\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 a.txt\n\
+              M 100644 :1 b.txt\n\
+              \n\
+              done\n",
+        )
+        .unwrap();
+
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.input(Input::Stream(input))
+            .output(Output::Stream(output.clone()))
+            .commit_callback_fn(|_, changes, metadata| {
+                let change = &mut changes[0];
+                let data = metadata.blobs.read_change(change)?.unwrap();
+                let data = data.replace(b"2023", b"2024");
+                metadata.blobs.set_contents(change, data)?;
+                Ok(true)
+            })
+            .unwrap();
+        b.build().unwrap().run().unwrap();
+
+        assert_eq!(
+            fs::read(&output).unwrap().as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 9\n\
+              (c) 2023\n\
+              \n\
+              blob\n\
+              mark :1099511627776\n\
+              data 9\n\
+              (c) 2024\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              \n\
+              M 100644 :1099511627776 a.txt\n\
+              M 100644 :1 b.txt\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn blob_contents() {
+        let input = b"blob\n\
+              mark :1\n\
+              data 7\n\
+              secret\n\
+              \n\
+              blob\n\
+              mark :2\n\
+              data 6\n\
+              hello\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :3\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 8\n\
+              Initial\n\
+              M 100644 :1 a.txt\n\
+              M 100644 :2 b.txt\n\
+              M 100644 inline c.txt\n\
+              data 3\n\
+              hi\n\
+              D d.txt\n\
+              \n\
+              done\n";
+        let output = filter_stream(
+            &[
+                "--blob-callback",
+                "blob.data = blob.data.replace(b'hello', b'world')",
+                "--filename-callback",
+                "return None if file_data() == b'secret\\n' else filename",
+                "--commit-callback",
+                "commit.message += b''.join(c.data() or b'-\\n' for c in commit.file_changes)",
+            ],
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 7\n\
+              secret\n\
+              \n\
+              blob\n\
+              mark :2\n\
+              data 6\n\
+              world\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :3\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 19\n\
+              Initial\n\
+              world\n\
+              hi\n\
+              -\n\
+              \n\
+              M 100644 :2 b.txt\n\
+              M 100644 inline c.txt\n\
+              data 3\n\
+              hi\n\
+              \n\
+              D d.txt\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+    }
+
+    fn filter_stream(args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("input");
+        let output_path = dir.path().join("output");
+        fs::write(&input_path, input).unwrap();
+        let args = FilteringOptions::parse_args(["filter-repo"].iter().chain(args)).unwrap();
+        let mut b = RepoFilter::builder(args);
+        b.input(Input::Stream(input_path))
+            .output(Output::Stream(output_path.clone()));
+        b.build()?.run()?;
+        Ok(fs::read(&output_path).unwrap())
+    }
+
+    #[test]
+    fn filter_paths() {
+        let input = b"commit refs/heads/main\n\
+              mark :1\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              M 100644 0000000000000000000000000000000000000001 src/a.rs\n\
+              M 100644 0000000000000000000000000000000000000002 README\n\
+              D src/old.rs\n\
+              R src/b.rs src/c.rs\n\
+              R src/d.rs docs/d.rs\n\
+              \n\
+              done\n";
+        let output = filter_stream(&["--subdirectory-filter", "src"], input).unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"commit refs/heads/main\n\
+              mark :1\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              \n\
+              M 100644 0000000000000000000000000000000000000001 a.rs\n\
+              D old.rs\n\
+              R b.rs c.rs\n\
+              D d.rs\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+
+        let output = filter_stream(&["--path", "docs/", "--invert-paths"], input).unwrap();
+        assert!(!output.contains_str("R src/d.rs docs/d.rs"));
+        assert!(output.contains_str("D src/d.rs\n"));
+        assert!(output.contains_str("M 100644 0000000000000000000000000000000000000002 README\n"));
+
+        let err = filter_stream(&["--path", "docs/"], input).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Cannot filter rename of src/d.rs to docs/d.rs"));
+
+        let err = filter_stream(&["--path-rename", "README:src/a.rs"], input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "File renaming caused colliding pathnames!\n  Commit: :1\n  Filename: src/a.rs",
+        );
+        let output = filter_stream(&["--path-rename", "src/old.rs:README"], input).unwrap();
+        assert!(!output.contains_str("D README"));
+    }
+
+    #[test]
+    fn replace_text_and_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let expressions = dir.path().join("expressions");
+        fs::write(&expressions, "hunter2\nregex:key-[0-9]+==>key-XXX\n").unwrap();
+        let expressions = expressions.to_str().unwrap();
+        let input = b"blob\n\
+              mark :1\n\
+              data 26\n\
+              password=hunter2 key-1234\n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 16\n\
+              Rotate key-1234\n\
+              M 100644 :1 config\n\
+              \n\
+              done\n";
+        let output = filter_stream(
+            &[
+                "--replace-text",
+                expressions,
+                "--replace-message",
+                expressions,
+            ],
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"blob\n\
+              mark :1\n\
+              data 31\n\
+              password=***REMOVED*** key-XXX\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 15\n\
+              Rotate key-XXX\n\
+              \n\
+              M 100644 :1 config\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+
+        // A commit is rewritten, when only the contents of its files are.
+        let input = b"blob\n\
+              mark :1\n\
+              data 16\n\
+              password=hunter2\n\
+              commit refs/heads/main\n\
+              mark :2\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              gpgsig sha1 openpgp\n\
+              data 3\n\
+              sig\n\
+              data 4\n\
+              Add\n\
+              M 100644 :1 config\n\
+              \n\
+              done\n";
+        let output = filter_stream(&["--replace-text", expressions], input).unwrap();
+        assert!(!output.contains_str("gpgsig"), "{}", output.as_bstr());
+    }
+
+    #[test]
+    fn strip_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let ids = dir.path().join("ids");
+        fs::write(&ids, "2222222222222222222222222222222222222222\n").unwrap();
+        let input = b"blob\n\
+              mark :1\n\
+              original-oid 1111111111111111111111111111111111111111\n\
+              data 12\n\
+              big content\n\
+              blob\n\
+              mark :2\n\
+              original-oid 2222222222222222222222222222222222222222\n\
+              data 4\n\
+              key\n\
+              blob\n\
+              mark :3\n\
+              data <<END\n\
+              large content\n\
+              END\n\
+              blob\n\
+              mark :4\n\
+              data 3\n\
+              ok\n\
+              commit refs/heads/main\n\
+              mark :5\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              M 100644 :1 big\n\
+              M 100644 :2 key\n\
+              M 100644 :3 large\n\
+              M 100644 :4 ok\n\
+              M 100644 2222222222222222222222222222222222222222 key2\n\
+              M 160000 2222222222222222222222222222222222222222 submodule\n\
+              \n\
+              done\n";
+        let output = filter_stream(
+            &[
+                "--strip-blobs-bigger-than",
+                "10",
+                "--strip-blobs-with-ids",
+                ids.to_str().unwrap(),
+            ],
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"blob\n\
+              mark :4\n\
+              data 3\n\
+              ok\n\
+              \n\
+              commit refs/heads/main\n\
+              mark :5\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              \n\
+              M 100644 :4 ok\n\
+              M 160000 2222222222222222222222222222222222222222 submodule\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+    }
+
+    #[test]
+    fn mailmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mailmap = dir.path().join("mailmap");
+        fs::write(
+            &mailmap,
+            "Jane Doe <jane@example.com> <jdoe@old.example.com>\n\
+             C O Mitter <committer@example.com>\n",
+        )
+        .unwrap();
+        let input = b"commit refs/heads/main\n\
+              mark :1\n\
+              author jdoe <JDoe@old.example.com> 1700000000 +0000\n\
+              committer committer <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              \n\
+              tag v1\n\
+              from :1\n\
+              tagger jdoe <jdoe@old.example.com> 1700000000 +0000\n\
+              data 0\n\
+              done\n";
+        let output = filter_stream(&["--mailmap", mailmap.to_str().unwrap()], input).unwrap();
+        assert_eq!(
+            output.as_bstr(),
+            b"commit refs/heads/main\n\
+              mark :1\n\
+              author Jane Doe <jane@example.com> 1700000000 +0000\n\
+              committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+              data 0\n\
+              \n\
+              \n\
+              tag v1\n\
+              from :1\n\
+              tagger Jane Doe <jane@example.com> 1700000000 +0000\n\
+              data 0\n\
+              \n\
+              done\n"
+                .as_bstr(),
+        );
+    }
+
+    #[test]
+    fn rename_into_kept_path() {
+        let commit = |branch: &str, mark: u32, parents: &str, changes: &str| {
+            format!(
+                "commit refs/heads/{branch}\nmark :{mark}\n\
+                 committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+                 data 2\nc{mark}\n{parents}{changes}\n"
+            )
+        };
+        let filter = |commits: &[String]| {
+            let input = ["blob\nmark :1\ndata 2\na\n\n", &commits.concat(), "done\n"].concat();
+            filter_stream(&["--path", "new/"], input.as_bytes())
+        };
+
+        let output = filter(&[
+            commit(
+                "main",
+                2,
+                "",
+                "M 100644 :1 old/a.txt\nM 100644 :1 old/b.txt\n",
+            ),
+            commit("main", 3, "", "R old/a.txt new/a.txt\n"),
+            commit("side", 4, "from :3\n", "C old/b.txt new/b.txt\n"),
+        ])
+        .unwrap();
+        let output = output.to_str().unwrap();
+        assert!(output.contains("c3\nM 100644 :1 new/a.txt\n"), "{output}");
+        assert!(
+            output.contains("c4\nfrom :3\nM 100644 :1 new/b.txt\n"),
+            "{output}"
+        );
+
+        // The tree is tracked at commits which are no longer a branch tip and
+        // across merges, which are relative to their first parent.
+        let output = filter(&[
+            commit(
+                "main",
+                2,
+                "",
+                "M 100644 :1 old/a.txt\nM 100644 :1 new/c.txt\n",
+            ),
+            commit(
+                "main",
+                3,
+                "from :2\n",
+                "M 100644 :1 old/b.txt\nM 100644 :1 new/d.txt\n",
+            ),
+            commit("side", 4, "from :2\n", "R old/a.txt new/a.txt\n"),
+            commit("main", 5, "from :3\nmerge :4\n", "R old/b.txt new/b.txt\n"),
+        ])
+        .unwrap();
+        let output = output.to_str().unwrap();
+        assert!(
+            output.contains("c4\nfrom :2\nM 100644 :1 new/a.txt\n"),
+            "{output}"
+        );
+        assert!(
+            output.contains("merge :4\nM 100644 :1 new/b.txt\n"),
+            "{output}"
+        );
+
+        // The tree of a parent outside of the stream is unknown.
+        let err = filter(&[commit(
+            "main",
+            2,
+            "from 1111111111111111111111111111111111111111\n",
+            "R old/a.txt new/a.txt\n",
+        )])
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Cannot filter rename of old/a.txt to new/a.txt"),
+            "{err:#}"
+        );
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn run_repo_to_repo() {
+        let src = init_repo();
+        let dst = init_repo();
+        fs::write(src.path().join("a.txt"), "a\n").unwrap();
+        fs::write(src.path().join("secret.txt"), "secret\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Add files"]);
+        git(src.path(), &["tag", "-a", "-m", "Version 1", "v1"]);
+
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()))
+            .filename_callback("return None if filename == b'secret.txt' else filename")
+            .unwrap()
+            .refname_callback("return refname.replace(b'main', b'trunk')")
+            .unwrap();
+        b.build().unwrap().run().unwrap();
+
+        assert_eq!(
+            git(
+                dst.path(),
+                &["ls-tree", "-r", "--name-only", "refs/heads/trunk"]
+            ),
+            "a.txt\n",
+        );
+        assert_eq!(
+            git(dst.path(), &["log", "--format=%s", "refs/tags/v1"]),
+            "Add files\n",
+        );
+    }
+
+    #[test]
+    fn notes() {
+        let src = init_repo();
+        let dst = init_repo();
+        for file in ["a.txt", "b.txt"] {
+            fs::write(src.path().join(file), file).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", &format!("Add {file}")]);
+            git(src.path(), &["notes", "add", "-m", &format!("Note {file}")]);
+        }
+
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()))
+            .message_callback_fn(|message| {
+                message.to_mut().make_ascii_uppercase();
+                Ok(())
+            })
+            .unwrap();
+        b.build().unwrap().run().unwrap();
+
+        assert_eq!(
+            git(dst.path(), &["log", "--format=%s: %N", "main"]),
+            "ADD B.TXT: Note b.txt\n\nADD A.TXT: Note a.txt\n\n",
+        );
+        assert_eq!(git(dst.path(), &["notes", "list"]).lines().count(), 2);
+    }
+
+    #[test]
+    fn notes_of_pruned_commits() {
+        let src = init_repo();
+        let dst = init_repo();
+        let commit = |file: &str, contents: &str, message: &str| {
+            fs::write(src.path().join(file), contents).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", message]);
+            git(src.path(), &["notes", "add", "-m", &format!("Note {file}")]);
+        };
+        commit("a.txt", "a\n", "Add a");
+        commit("b.txt", "b\n", "Add b");
+        commit("a.txt", "z\n", "Change a");
+        git(src.path(), &["notes", "remove", "main~2"]);
+        git(src.path(), &["notes", "add", "-m", "New note", "main~2"]);
+
+        let args = FilteringOptions::parse_args(["filter-repo", "--path", "a.txt"]).unwrap();
+        let mut b = RepoFilter::builder(args);
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()));
+        b.build().unwrap().run().unwrap();
+
+        // The note of the pruned commit is dropped.
+        assert_eq!(
+            git(dst.path(), &["log", "--format=%s: %N", "main"]),
+            "Change a: Note a.txt\n\nAdd a: New note\n\n",
+        );
+        assert_eq!(git(dst.path(), &["notes", "list"]).lines().count(), 2);
+    }
+
+    #[test]
+    fn signatures() {
+        let commit = |mark: u32, from: Option<u32>, encoding: &str, message: &[u8]| {
+            let from = from
+                .map(|from| format!("from :{from}\n"))
+                .unwrap_or_default();
+            let mut commit = format!(
+                "commit refs/heads/main\nmark :{mark}\n\
+                 committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+                 gpgsig sha1 openpgp\ndata 4\nsig{mark}\n{encoding}data {}\n",
+                message.len(),
+            )
+            .into_bytes();
+            commit.extend_from_slice(message);
+            commit.extend_from_slice(format!("{from}\n").as_bytes());
+            commit
+        };
+        let tag = |name: &str, from: u32| {
+            let message =
+                "Release\n-----BEGIN PGP SIGNATURE-----\nsig\n-----END PGP SIGNATURE-----\n";
+            format!(
+                "tag {name}\nfrom :{from}\n\
+                 tagger T A Gger <tagger@example.com> 1700000000 +0000\n\
+                 data {}\n{message}\n",
+                message.len(),
+            )
+            .into_bytes()
+        };
+        let input = [
+            commit(1, None, "", b"c1\n"),
+            // Only this commit changes, but its descendants are rewritten too.
+            commit(2, Some(1), "encoding iso-8859-1\n", b"caf\xe9\n"),
+            commit(3, Some(2), "", b"c3\n"),
+            tag("v1", 1),
+            tag("v2", 3),
+            b"done\n".to_vec(),
+        ]
+        .concat();
+        let signed = |output: &[u8]| {
+            let output = output.to_str().unwrap();
+            let sigs = ["sig1", "sig2", "sig3"]
+                .into_iter()
+                .filter(|sig| output.contains(&format!("\n{sig}\n")))
+                .collect::<Vec<_>>();
+            (sigs, output.matches("BEGIN PGP SIGNATURE").count())
+        };
+
+        let output = filter_stream(&["--force"], &input).unwrap();
+        assert_eq!(signed(&output), (vec!["sig1"], 1));
+        let output = filter_stream(&["--signatures", "keep"], &input).unwrap();
+        assert_eq!(signed(&output), (vec!["sig1", "sig2", "sig3"], 2));
+        let output = filter_stream(&["--signatures", "warn"], &input).unwrap();
+        assert_eq!(signed(&output), (vec!["sig1"], 1));
+    }
+
+    #[test]
+    fn unchanged_signed_tag() {
+        let src = init_repo();
+        let dst = tempfile::tempdir().unwrap();
+        let commit = |file: &str| {
+            fs::write(src.path().join(file), file).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", file]);
+            git(src.path(), &["rev-parse", "HEAD"]).trim().to_owned()
+        };
+        // Signatures are not verified, so a fake one is enough.
+        let signed_tag = |name: &str, commit: &str| {
+            let tag = format!(
+                "object {commit}\ntype commit\ntag {name}\n\
+                 tagger T A Gger <tagger@example.com> 1700000000 +0000\n\n\
+                 Release\n-----BEGIN PGP SIGNATURE-----\nsig\n-----END PGP SIGNATURE-----\n"
+            );
+            fs::write(src.path().join(".git/tag"), tag).unwrap();
+            let oid = git(src.path(), &["hash-object", "-t", "tag", "-w", ".git/tag"]);
+            let tag_ref = format!("refs/tags/{name}");
+            git(src.path(), &["update-ref", &tag_ref, oid.trim()]);
+        };
+        let c1 = commit("a.txt");
+        signed_tag("v1", &c1);
+        let c2 = commit("b.txt");
+        signed_tag("v2", &c2);
+
+        let args = FilteringOptions::parse_args(["filter-repo", "--path", "a.txt"]).unwrap();
+        let mut b = RepoFilter::builder(args);
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()));
+        b.build().unwrap().run().unwrap();
+
+        // The tag of the unchanged commit keeps its signature, but the tag
+        // moved to it from the pruned commit loses its own.
+        let v1 = git(src.path(), &["rev-parse", "v1"]);
+        assert_eq!(git(dst.path(), &["rev-parse", "v1"]), v1);
+        let v2 = git(dst.path(), &["cat-file", "tag", "v2"]);
+        assert!(!v2.contains("BEGIN PGP SIGNATURE"), "{v2}");
+        assert_eq!(git(dst.path(), &["rev-parse", "v2^{commit}"]).trim(), c1);
+    }
+
+    #[test]
+    fn fast_import_compat() {
+        let input = b"blob\nmark :0\ndata 2\na\n\n\
+                      commit refs/heads/main\nmark :1\n\
+                      committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+                      data  2\nc\nM 100644 inline a\ndata 2\na\n\ndone\n";
+        let err = filter_stream(&["--force"], input).unwrap_err();
+        assert!(format!("{err:#}").contains("mark"), "{err:#}");
+        let output = filter_stream(&["--fast-import-compat", "2.44"], input).unwrap();
+        assert_eq!(
+            output.to_str().unwrap(),
+            "blob\ndata 2\na\n\n\
+             commit refs/heads/main\nmark :1\n\
+             committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+             data 2\nc\n\nM 100644 inline a\ndata 2\na\n\n\ndone\n",
+        );
+    }
+
+    #[test]
+    fn reencode() {
+        let commit = |encoding: &str, message: &[u8]| {
+            let mut commit = format!(
+                "commit refs/heads/main\nmark :1\n\
+                 committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+                 {encoding}data {}\n",
+                message.len(),
+            )
+            .into_bytes();
+            commit.extend_from_slice(message);
+            commit.extend_from_slice(b"\ndone\n");
+            commit
+        };
+        let expected = |message: &str| {
+            format!(
+                "commit refs/heads/main\nmark :1\n\
+                 committer C O Mitter <committer@example.com> 1700000000 +0000\n\
+                 data {}\n{message}\n\ndone\n",
+                message.len(),
+            )
+        };
+
+        let latin1 = commit("encoding iso-8859-1\n", b"caf\xe9\n");
+        let output = filter_stream(&["--force"], &latin1).unwrap();
+        assert_eq!(output.to_str().unwrap(), expected("café\n"));
+        let output = filter_stream(&["--preserve-commit-encoding"], &latin1).unwrap();
+        // Only the optional LF after the data is added.
+        assert_eq!(output, [&latin1[..latin1.len() - 5], b"\ndone\n"].concat());
+        let err = filter_stream(&["--reencode", "abort"], &latin1).unwrap_err();
+        assert!(format!("{err:#}").contains("--reencode=abort"), "{err:#}");
+
+        let args = ["--fallback-encoding", "shift_jis,latin1"];
+        let sjis = commit("", b"\x82\xa0\n");
+        let output = filter_stream(&args, &sjis).unwrap();
+        assert_eq!(output.to_str().unwrap(), expected("あ\n"));
+        let latin1 = commit("", b"caf\xe9\n");
+        let output = filter_stream(&args, &latin1).unwrap();
+        assert_eq!(output.to_str().unwrap(), expected("café\n"));
+    }
+
+    #[test]
+    fn translate_commit_hashes() {
+        let src = init_repo();
+        let dst = init_repo();
+        fs::write(src.path().join("a.txt"), "a\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Add a"]);
+        let old = git(src.path(), &["rev-parse", "HEAD"]).trim().to_owned();
+        let message = format!("Fix {}\n\nSee {old} and deadbeef0", &old[..9]);
+        fs::write(src.path().join("a.txt"), "b\n").unwrap();
+        git(src.path(), &["commit", "-q", "-a", "-m", &message]);
+
+        let args = FilteringOptions::parse_args(["filter-repo", "--to-subdirectory-filter", "sub"])
+            .unwrap();
+        let mut b = RepoFilter::builder(args);
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()));
+        b.build().unwrap().run().unwrap();
+
+        let new = git(dst.path(), &["rev-parse", "main~1"]).trim().to_owned();
+        assert_ne!(new, old);
+        assert_eq!(
+            git(dst.path(), &["log", "-1", "--format=%B", "main"]),
+            format!("Fix {}\n\nSee {new} and deadbeef0\n\n", &new[..9]),
+        );
+    }
+
+    #[test]
+    fn batch_check() {
+        let repo = init_repo();
+        fs::write(repo.path().join("a"), "a\n").unwrap();
+        let blob = git(repo.path(), &["hash-object", "-w", "a"]);
+        let blob = blob.trim_end().as_bytes();
+        let other = [b'0'; 40];
+        let mut batch_check = BatchCheck::new(repo.path()).unwrap();
+        assert!(batch_check.is_ambiguous(&blob[..7], &other).unwrap());
+        assert!(!batch_check.is_ambiguous(&blob[..7], blob).unwrap());
+        assert!(!batch_check.is_ambiguous(&other[..7], &other).unwrap());
+    }
+
+    #[test]
+    fn prune_empty() {
+        let src = init_repo();
+        let dst = init_repo();
+        let commit = |file: &str, contents: &str, message: &str| {
+            fs::write(src.path().join(file), contents).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", message]);
+        };
+        commit("a.txt", "a\n", "Add a");
+        commit("b.txt", "b\n", "Add b");
+        git(src.path(), &["checkout", "-q", "-b", "side", "main~1"]);
+        commit("c.txt", "c\n", "Add c");
+        git(src.path(), &["checkout", "-q", "main"]);
+        git(
+            src.path(),
+            &["merge", "-q", "--no-ff", "--no-commit", "side"],
+        );
+        commit("a.txt", "m\n", "Merge side");
+        let old_merge = git(src.path(), &["rev-parse", "HEAD"]).trim().to_owned();
+        commit("b.txt", "bb\n", "Change b");
+        commit("a.txt", "z\n", "Change a");
+
+        let args = FilteringOptions::parse_args(["filter-repo", "--path", "a.txt"]).unwrap();
+        let mut b = RepoFilter::builder(args);
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()));
+        let mut filter = b.build().unwrap();
+        filter.run().unwrap();
+
+        assert_eq!(
+            git(dst.path(), &["log", "--format=%s", "main"]),
+            "Change a\nMerge side\nAdd a\n",
+        );
+        assert_eq!(git(dst.path(), &["rev-list", "--merges", "main"]), "");
+        let new_merge = git(dst.path(), &["rev-parse", "main~1"]).trim().to_owned();
+        assert_eq!(
+            filter.commits_no_longer_merges(),
+            [(old_merge.into_bytes(), Some(new_merge.into_bytes()))],
+        );
+        assert_eq!(git(dst.path(), &["show", "main~1:a.txt"]), "m\n");
+    }
+
+    #[test]
+    fn read_blobs_from_repo() {
+        let src = init_repo();
+        let dst = init_repo();
+        fs::write(src.path().join("a.txt"), "unchanged\n").unwrap();
+        fs::write(src.path().join("b.txt"), "changed\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Initial"]);
+
+        let mut contents = Vec::new();
+        let mut b = RepoFilter::builder(FilteringOptions::default());
+        b.input(Input::Repo(src.path().to_owned()))
+            .output(Output::Repo(dst.path().to_owned()))
+            .blob_callback_fn(|blob| {
+                if blob.data == b"changed\n" {
+                    blob.data.make_ascii_uppercase();
+                }
+                Ok(true)
+            })
+            .unwrap()
+            .commit_callback_fn(|_, changes, metadata| {
+                for change in changes {
+                    contents.push(metadata.blobs.read_change(change)?.unwrap());
+                }
+                Ok(true)
+            })
+            .unwrap();
+        let mut filter = b.build().unwrap();
+        filter.run().unwrap();
+        // Only the changed blob was saved and the other was read from the repo.
+        let store = format!("{:?}", filter.blob_store);
+        assert!(store.contains("saved: 1, unchanged: 1"), "{store}");
+        drop(filter);
+        assert_eq!(contents, [&b"unchanged\n"[..], b"CHANGED\n"]);
+    }
+
+    #[test]
+    fn replace_refs() {
+        let src = init_repo();
+        let commit = |file: &str, contents: &str, message: &str| {
+            fs::write(src.path().join(file), contents).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", message]);
+            git(src.path(), &["rev-parse", "HEAD"])
+                .trim_end()
+                .to_owned()
+        };
+        // Only the commits which changed are replaced.
+        commit("a.txt", "a\n", "Add a");
+        commit("b.txt", "b\n", "Add b");
+        let c3 = commit("a.txt", "z\n", "Change a");
+        // A commit outside of the branches, which is replaced by another.
+        let commit_tree = |message: &str| {
+            git(src.path(), &["commit-tree", "-m", message, "main^{tree}"])
+                .trim_end()
+                .to_owned()
+        };
+        let (orig, replacement) = (commit_tree("Original"), commit_tree("Replacement"));
+        git(
+            src.path(),
+            &["update-ref", &format!("refs/replace/{orig}"), &replacement],
+        );
+
+        let filter = |mode: Option<&str>| {
+            let dst = init_repo();
+            let mut args = vec!["filter-repo", "--path", "a.txt"];
+            args.extend(mode.map(|mode| ["--replace-refs", mode]).iter().flatten());
+            let args = FilteringOptions::parse_args(args).unwrap();
+            let mut b = RepoFilter::builder(args);
+            b.input(Input::Repo(src.path().to_owned()))
+                .output(Output::Repo(dst.path().to_owned()));
+            b.build().unwrap().run().unwrap();
+            let refs = git(
+                dst.path(),
+                &[
+                    "for-each-ref",
+                    "--format=%(refname:strip=2)",
+                    "refs/replace/",
+                ],
+            );
+            let mut refs = refs.lines().map(str::to_owned).collect::<Vec<_>>();
+            refs.sort();
+            (dst, refs)
+        };
+        let sorted = |refs: &[&String]| {
+            let mut refs = refs.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            refs.sort();
+            refs
+        };
+
+        assert_eq!(filter(None).1, sorted(&[&orig]));
+        assert_eq!(filter(Some("update-no-add")).1, sorted(&[&orig]));
+        assert_eq!(filter(Some("delete-no-add")).1, sorted(&[]));
+        assert_eq!(
+            filter(Some("delete-and-add")).1,
+            sorted(&[&c3, &replacement]),
+        );
+        assert_eq!(filter(Some("update-or-add")).1, sorted(&[&c3, &orig]));
+        let (dst, refs) = filter(Some("update-and-add"));
+        assert_eq!(refs, sorted(&[&c3, &orig, &replacement]));
+        assert_eq!(
+            git(dst.path(), &["rev-parse", &format!("refs/replace/{c3}")]),
+            git(dst.path(), &["rev-parse", "main"]),
+        );
+    }
+
+    #[test]
+    fn flatten_merges() {
+        let src = init_repo();
+        let commit = |file: &str, contents: &str, message: &str| {
+            fs::write(src.path().join(file), contents).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", message]);
+        };
+        commit("a.txt", "a\n", "Add a");
+        git(src.path(), &["checkout", "-q", "-b", "side"]);
+        commit("b.txt", "b\n", "Add b");
+        git(src.path(), &["checkout", "-q", "main"]);
+        commit("a.txt", "aa\n", "Change a");
+        git(
+            src.path(),
+            &["merge", "-q", "--no-ff", "-m", "Merge side", "side"],
+        );
+        git(src.path(), &["checkout", "-q", "-b", "topic"]);
+        commit("b.txt", "bb\n", "Change b");
+        git(src.path(), &["checkout", "-q", "main"]);
+        git(
+            src.path(),
+            &["merge", "-q", "--no-ff", "--no-commit", "topic"],
+        );
+        commit("a.txt", "m\n", "Merge topic");
+
+        let filter = |args: &[&str]| {
+            let dst = init_repo();
+            let args = FilteringOptions::parse_args(["filter-repo"].iter().chain(args)).unwrap();
+            let mut b = RepoFilter::builder(args);
+            b.input(Input::Repo(src.path().to_owned()))
+                .output(Output::Repo(dst.path().to_owned()));
+            b.build().unwrap().run().unwrap();
+            git(dst.path(), &["log", "--format=%p%x09%s", "main"])
+                .lines()
+                .map(|line| line.split_once('\t').unwrap())
+                .map(|(parents, subject)| (parents.split_whitespace().count(), subject.to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        // "Merge side" merges "Change a" with its ancestor "Add a", so becomes
+        // empty and is pruned. "Merge topic" has changes, so is kept, but its
+        // second parent is redundant.
+        assert_eq!(
+            filter(&["--path", "a.txt"]),
+            [
+                (1, "Merge topic".to_owned()),
+                (1, "Change a".to_owned()),
+                (0, "Add a".to_owned()),
+            ],
+        );
+        assert_eq!(
+            filter(&["--path", "a.txt", "--prune-degenerate", "never"]),
+            [
+                (2, "Merge topic".to_owned()),
+                (2, "Merge side".to_owned()),
+                (1, "Change a".to_owned()),
+                (0, "Add a".to_owned()),
+            ],
+        );
+    }
+
+    #[test]
+    fn in_place() {
+        let src = init_repo();
+        fs::write(src.path().join("a.txt"), "a\n").unwrap();
+        fs::write(src.path().join("b.txt"), "b\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Add a and b"]);
+
+        let filter = |args: &[&str]| {
+            let args = ["filter-repo", "--source"]
+                .into_iter()
+                .chain([src.path().to_str().unwrap()])
+                .chain(args.iter().copied());
+            let args = FilteringOptions::parse_args(args).unwrap();
+            RepoFilter::builder(args).build()?.run()
+        };
+        let err = filter(&["--path", "a.txt"]).unwrap_err().to_string();
+        assert!(err.contains("without --in-place"), "{err}");
+        let err = filter(&["--path", "a.txt", "--in-place"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("does not look like a fresh clone"), "{err}");
+        filter(&["--path", "a.txt", "--in-place", "--force"]).unwrap();
+        assert_eq!(
+            git(src.path(), &["ls-tree", "--name-only", "main"]),
+            "a.txt\n"
+        );
+    }
+
+    #[test]
+    fn in_place_stream() {
+        let src = init_repo();
+        fs::write(src.path().join("a.txt"), "a\n").unwrap();
+        fs::write(src.path().join("b.txt"), "b\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Add a and b"]);
+        let stream = src.path().join("stream");
+        let export = git(src.path(), &["fast-export", "--all"]);
+        fs::write(&stream, export).unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let clone = dst.path().join("clone");
+        let url = format!("file://{}", src.path().display());
+        git(dst.path(), &["clone", "-q", &url, "clone"]);
+
+        let filter = |args: &[&str]| {
+            let args = FilteringOptions::parse_args(
+                ["filter-repo", "--path", "a.txt"]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            )
+            .unwrap();
+            let mut b = RepoFilter::builder(args);
+            b.input(Input::Stream(stream.clone()))
+                .output(Output::Repo(clone.clone()));
+            b.build()?.run()
+        };
+        let err = filter(&[]).unwrap_err().to_string();
+        assert!(err.contains("not empty"), "{err}");
+        filter(&["--in-place"]).unwrap();
+        assert_eq!(git(&clone, &["ls-tree", "--name-only", "main"]), "a.txt\n");
+    }
+
+    #[test]
+    fn metadata() {
+        let src = init_repo();
+        let dst = tempfile::tempdir().unwrap();
+        let commit = |file: &str, message: &str| {
+            fs::write(src.path().join(file), message).unwrap();
+            git(src.path(), &["add", "."]);
+            git(src.path(), &["commit", "-q", "-m", message]);
+            git(src.path(), &["rev-parse", "HEAD"]).trim().to_owned()
+        };
+        let c1 = commit("a.txt", "Add a");
+        let c2 = commit("b.txt", "Add b");
+        git(src.path(), &["tag", "v1"]);
+        let c3 = commit("a.txt", &format!("Revert {c2}"));
+
+        let run = || {
+            let args = FilteringOptions::parse_args(["filter-repo", "--path", "a.txt"]).unwrap();
+            let mut b = RepoFilter::builder(args);
+            b.input(Input::Repo(src.path().to_owned()))
+                .output(Output::Repo(dst.path().to_owned()));
+            b.build()?.run()
+        };
+        run().unwrap();
+
+        let new_main = git(dst.path(), &["rev-parse", "main"]).trim().to_owned();
+        let new_c1 = git(dst.path(), &["rev-parse", "main~1"]).trim().to_owned();
+        assert_eq!(new_c1, c1);
+        let zero = "0".repeat(40);
+        let metadata = dst.path().join(".git/filter-repo");
+        let read = |name: &str| fs::read_to_string(metadata.join(name)).unwrap();
+        assert_eq!(
+            read("commit-map"),
+            format!("old{:38}new\n{c1} {c1}\n{c2} {zero}\n{c3} {new_main}\n", ""),
+        );
+        assert_eq!(
+            read("ref-map"),
+            format!(
+                "old{:38}new{:38}ref\n\
+                 {c3} {new_main} refs/heads/main\n\
+                 {c2} {c1} refs/tags/v1\n",
+                "", "",
+            ),
+        );
+        assert_eq!(
+            read("suboptimal-issues"),
+            format!(
+                "The following commits were filtered out, but referenced in another\n\
+                 commit message.  The reference to the now-nonexistent commit hash\n\
+                 (or a substring thereof) was left as-is in any commit messages:\n  \
+                 {c2}\n\n"
+            ),
+        );
+        assert_eq!(
+            read("first-changed-commits"),
+            format!("old{:38}new\n{c2} {zero}\n", ""),
+        );
+
+        // Filtering again into the same repo does not need --force.
+        run().unwrap();
+        fs::remove_file(metadata.join("already_ran")).unwrap();
+        let err = run().unwrap_err().to_string();
+        assert!(err.contains("since it is not empty"), "{err}");
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn finalize() {
+        let src = init_repo();
+        fs::write(src.path().join("a.txt"), "a\n").unwrap();
+        fs::write(src.path().join("secret.txt"), "hunter2\n").unwrap();
+        git(src.path(), &["add", "."]);
+        git(src.path(), &["commit", "-q", "-m", "Add a and a secret"]);
+        git(src.path(), &["checkout", "-q", "--orphan", "secrets"]);
+        git(src.path(), &["rm", "-q", "--cached", "a.txt"]);
+        git(src.path(), &["commit", "-q", "-m", "Add only a secret"]);
+        git(src.path(), &["checkout", "-q", "-f", "main"]);
+        let secret = git(src.path(), &["rev-parse", "main:secret.txt"]);
+
+        let args = FilteringOptions::parse_args([
+            "filter-repo",
+            "--source",
+            src.path().to_str().unwrap(),
+            "--in-place",
+            "--force",
+            "--quiet",
+            "--invert-paths",
+            "--path",
+            "secret.txt",
+            "--refname-callback",
+            "return refname.replace(b'main', b'trunk')",
+        ])
+        .unwrap();
+        RepoFilter::builder(args).build().unwrap().run().unwrap();
+
+        assert_eq!(
+            git(src.path(), &["for-each-ref", "--format=%(refname)"]),
+            "refs/heads/trunk\n",
+        );
+        assert_eq!(
+            git(src.path(), &["symbolic-ref", "HEAD"]),
+            "refs/heads/trunk\n"
+        );
+        assert!(!src.path().join("secret.txt").exists());
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(src.path())
+            .args(["cat-file", "-e", secret.trim()])
+            .status()
+            .unwrap();
+        assert!(!status.success());
+    }
 }
@@ -3,10 +3,30 @@
 // This file is part of git-transform-repo, distributed under the GPL 2.0 with a
 // linking exception. For the full terms, see the included COPYING file.
 
+pub mod ancestry;
+pub mod blob_store;
 pub mod builder;
+pub mod callback;
 pub mod filter;
+#[cfg(feature = "lua")]
+pub mod lua_api;
+pub mod mailmap;
+pub mod notes;
+pub mod options;
 pub mod parser;
-#[allow(dead_code)]
+pub mod paths;
+pub mod progress;
+#[cfg(feature = "python")]
+pub mod py_api;
+#[cfg(feature = "python")]
 pub(crate) mod py_bytes;
+pub mod reencode;
+pub mod renames;
+pub mod replace;
+pub mod sanity;
+pub mod signature;
+pub mod stream;
+#[cfg(test)]
+mod test_util;
 
 pub use filter::RepoFilter;
@@ -0,0 +1,834 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Callbacks in Lua, with an embedded interpreter, for hosts without a
+//! matching libpython.
+//!
+//! A callback is the body of a Lua chunk, which receives the same parameters
+//! as the Python function, such as `commit` and `metadata`. Fields are
+//! named like in the Python API, but objects are userdata, which read and
+//! write the Rust values in place, so nothing is copied until it is accessed.
+//! Lua strings hold arbitrary bytes, so messages, names, and paths are passed
+//! as-is, without decoding them.
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    fmt::{self, Debug, Formatter},
+    mem,
+    num::NonZeroU64,
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{bail, Result};
+use bstr::ByteSlice;
+use fast_export::{
+    command::{
+        Commit, Commitish, DataHeader, Encoding, Mark, Mode, Objectish, OriginalOid, PersonIdent,
+        Reset, Tag,
+    },
+    parse::{
+        Change, DataRef, FileCopyChange, FileDeleteChange, FileModifyChange, FileRenameChange,
+    },
+};
+use mlua::{
+    ExternalError, Function, Lua, RegistryKey, Table, UserData, UserDataFields, UserDataMethods,
+    Value,
+};
+
+use crate::{
+    blob_store::{BlobStore, FileContents},
+    callback::{CommitAction, CommitMetadata},
+    parser::{BlobObject, Bytes, FileChange},
+};
+
+/// A compiled Lua callback. Callbacks from the same builder share a Lua
+/// state, so they can share globals.
+pub struct LuaCallback {
+    lua: Rc<Lua>,
+    function: RegistryKey,
+    chunk_name: String,
+}
+
+impl LuaCallback {
+    /// Compiles the body of a callback. `filename` is the file it was read
+    /// from, if any, for error messages.
+    ///
+    /// The parameters are declared on the same line as the first line of the
+    /// body, so that line numbers in errors match what the user wrote.
+    pub(crate) fn load(
+        lua: &Rc<Lua>,
+        name: &str,
+        code: &str,
+        filename: Option<&Path>,
+    ) -> Result<Self> {
+        let params = match name {
+            "filename" => "filename, file_data",
+            "commit" => "commit, metadata",
+            "done" => "",
+            _ => name,
+        };
+        let source = if params.is_empty() {
+            code.to_owned()
+        } else {
+            format!("local {params} = ... {code}")
+        };
+        // Lua prefixes chunk names with `@` for files and `=` for names shown
+        // verbatim.
+        let chunk_name = match filename {
+            Some(path) => format!("@{}", path.display()),
+            None => format!("=<{name}_callback>"),
+        };
+        let function = lua.load(source).set_name(&chunk_name).into_function()?;
+        let function = lua.create_registry_value(function)?;
+        Ok(LuaCallback {
+            lua: lua.clone(),
+            function,
+            chunk_name,
+        })
+    }
+
+    fn function(&self) -> mlua::Result<Function<'_>> {
+        self.lua.registry_value(&self.function)
+    }
+}
+
+impl Debug for LuaCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LuaCallback")
+            .field(&self.chunk_name[1..].to_owned())
+            .finish()
+    }
+}
+
+/// Creates a Lua state for callbacks, with the safe standard libraries.
+pub(crate) fn new_lua() -> Rc<Lua> {
+    Rc::new(Lua::new())
+}
+
+/// Calls a message, name, email, or refname callback, which maps a string to
+/// a string.
+pub(crate) fn call_bytes(callback: &LuaCallback, name: &str, value: &mut Bytes<'_>) -> Result<()> {
+    let lua = &*callback.lua;
+    let new: Option<mlua::String<'_>> = callback.function()?.call(lua.create_string(&**value)?)?;
+    match new {
+        Some(new) => *value = to_bytes(&new),
+        None => bail!("{name} callback returned nil"),
+    }
+    Ok(())
+}
+
+/// Calls a filename callback, which maps a filename to a filename or `nil`,
+/// to remove the file. The contents of the file can be read with the
+/// `file_data()` parameter.
+pub(crate) fn call_filename(
+    callback: &LuaCallback,
+    filename: &mut Bytes<'_>,
+    contents: FileContents<'_>,
+) -> Result<bool> {
+    let lua = &*callback.lua;
+    let function = callback.function()?;
+    let new = lua.scope(|scope| {
+        let file_data = scope.create_function(|lua, ()| {
+            match contents.read().map_err(|err| err.into_lua_err())? {
+                Some(data) => lua.create_string(data).map(Some),
+                None => Ok(None),
+            }
+        })?;
+        let new: Option<mlua::String<'_>> =
+            function.call((lua.create_string(&*filename)?, file_data))?;
+        Ok(new.map(|new| to_bytes(&new)))
+    })?;
+    match new {
+        Some(new) => {
+            *filename = new;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Calls a blob callback, returning whether the blob is kept.
+pub(crate) fn call_blob(callback: &LuaCallback, blob: &mut BlobObject<'_>) -> Result<bool> {
+    let lua = &*callback.lua;
+    let function = callback.function()?;
+    let skipped = Cell::new(false);
+    lua.scope(|scope| {
+        let obj = scope.create_nonstatic_userdata(LuaBlob {
+            blob,
+            skipped: &skipped,
+        })?;
+        function.call::<_, ()>(obj)
+    })?;
+    Ok(!skipped.get())
+}
+
+/// Calls a commit callback. Like in Python, the file changes from before the
+/// callback are only returned when it accessed them.
+pub(crate) fn call_commit<'c>(
+    callback: &LuaCallback,
+    commit: &mut Commit<Bytes<'c>>,
+    changes: &mut Vec<FileChange<'c>>,
+    metadata: &CommitMetadata<'_>,
+    record_changes: bool,
+) -> Result<CommitAction<'c>> {
+    let lua = &*callback.lua;
+    let function = callback.function()?;
+    let state = CommitState::default();
+    let had_author = commit.author.is_some();
+    let metadata_table = lua.create_table()?;
+    metadata_table.set(
+        "orig_parents",
+        commitishes_to_lua(lua, metadata.orig_parents)?,
+    )?;
+    metadata_table.set("had_file_changes", metadata.had_file_changes)?;
+    lua.scope(|scope| {
+        let obj = scope.create_nonstatic_userdata(LuaCommit {
+            commit: &mut *commit,
+            changes: &mut *changes,
+            blobs: metadata.blobs,
+            state: &state,
+        })?;
+        function.call::<_, ()>((obj, metadata_table))
+    })?;
+
+    if !had_author && commit.author.as_ref() == Some(&commit.committer) {
+        commit.author = None;
+    }
+    if let Some(key) = state.parents.take() {
+        let mut parents = commitishes_from_lua(lua.registry_value(&key)?)?.into_iter();
+        commit.from = parents.next();
+        commit.merge = parents.collect();
+        lua.remove_registry_value(key)?;
+    }
+    let mut orig_changes = None;
+    if let Some(key) = state.file_changes.take() {
+        let table: Table<'_> = lua.registry_value(&key)?;
+        let mut new = table
+            .sequence_values::<Table<'_>>()
+            .map(|change| change_from_lua(&change?))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        let orig = mem::take(changes);
+        new.extend(orig.iter().filter(|change| !change.is_modeled()).cloned());
+        *changes = new;
+        orig_changes = record_changes.then_some(orig);
+        lua.remove_registry_value(key)?;
+    }
+
+    if state.skipped.get() {
+        let rewrite_to = state.skipped_to.take().map(|commit| Commitish { commit });
+        return Ok(CommitAction::Skip { rewrite_to });
+    }
+    Ok(CommitAction::Keep { orig_changes })
+}
+
+/// Calls a tag callback, returning whether the tag is kept.
+pub(crate) fn call_tag(callback: &LuaCallback, tag: &mut Tag<Bytes<'_>>) -> Result<bool> {
+    let lua = &*callback.lua;
+    let function = callback.function()?;
+    let skipped = Cell::new(false);
+    lua.scope(|scope| {
+        let obj = scope.create_nonstatic_userdata(LuaTag {
+            tag,
+            skipped: &skipped,
+        })?;
+        function.call::<_, ()>(obj)
+    })?;
+    Ok(!skipped.get())
+}
+
+/// Calls a reset callback, returning whether the reset is kept.
+pub(crate) fn call_reset(callback: &LuaCallback, reset: &mut Reset<Bytes<'_>>) -> Result<bool> {
+    let lua = &*callback.lua;
+    let function = callback.function()?;
+    let skipped = Cell::new(false);
+    lua.scope(|scope| {
+        let obj = scope.create_nonstatic_userdata(LuaReset {
+            reset,
+            skipped: &skipped,
+        })?;
+        function.call::<_, ()>(obj)
+    })?;
+    Ok(!skipped.get())
+}
+
+pub(crate) fn call_done(callback: &LuaCallback) -> Result<()> {
+    callback.function()?.call::<_, ()>(())?;
+    Ok(())
+}
+
+/// A blob, as given to blob callbacks.
+struct LuaBlob<'a, 'c> {
+    blob: &'a mut BlobObject<'c>,
+    skipped: &'a Cell<bool>,
+}
+
+/// A commit, as given to commit callbacks. The file changes and parents are
+/// converted to Lua tables when first accessed, so that they can be modified
+/// in place, and are converted back after the callback.
+struct LuaCommit<'a, 'c> {
+    commit: &'a mut Commit<Bytes<'c>>,
+    changes: &'a mut Vec<FileChange<'c>>,
+    blobs: &'a BlobStore,
+    state: &'a CommitState,
+}
+
+#[derive(Default)]
+struct CommitState {
+    parents: RefCell<Option<RegistryKey>>,
+    file_changes: RefCell<Option<RegistryKey>>,
+    skipped: Cell<bool>,
+    /// The commit, which references to this skipped commit are rewritten to.
+    skipped_to: RefCell<Option<Objectish<Bytes<'static>>>>,
+}
+
+/// An annotated tag, as given to tag callbacks. `ref` is the name of the
+/// tag, without `refs/tags/`.
+struct LuaTag<'a, 'c> {
+    tag: &'a mut Tag<Bytes<'c>>,
+    skipped: &'a Cell<bool>,
+}
+
+/// A reset, as given to reset callbacks.
+struct LuaReset<'a, 'c> {
+    reset: &'a mut Reset<Bytes<'c>>,
+    skipped: &'a Cell<bool>,
+}
+
+/// Adds a getter and setter for a string field.
+macro_rules! bytes_field(($fields:ident, $name:literal, |$this:ident| $get:expr, $set:expr) => {
+    $fields.add_field_method_get($name, |lua, $this| lua.create_string(&*$get));
+    $fields.add_field_method_set($name, |_, $this, value: mlua::String<'_>| {
+        *$set = to_bytes(&value);
+        Ok(())
+    });
+});
+
+/// Adds a getter and setter for an optional string field, which is `nil`
+/// when absent.
+macro_rules! optional_bytes_field((
+    $fields:ident, $name:literal, |$this:ident| $field:expr, $wrapper:ident { $inner:ident }
+) => {
+    $fields.add_field_method_get($name, |lua, $this| match &$field {
+        Some(value) => lua.create_string(&*value.$inner).map(Some),
+        None => Ok(None),
+    });
+    $fields.add_field_method_set($name, |_, $this, value: Option<mlua::String<'_>>| {
+        $field = value.map(|value| $wrapper { $inner: to_bytes(&value) });
+        Ok(())
+    });
+});
+
+impl UserData for LuaBlob<'_, '_> {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field("type", "blob");
+        fields.add_field_method_get("id", |_, this| Ok(this.blob.mark.map(mark_to_lua)));
+        optional_bytes_field!(
+            fields,
+            "original_id",
+            |this| this.blob.original_oid,
+            OriginalOid { oid }
+        );
+        fields.add_field_method_get("data", |lua, this| lua.create_string(&this.blob.data));
+        fields.add_field_method_set("data", |_, this, data: mlua::String<'_>| {
+            this.blob.data = data.as_bytes().to_vec();
+            Ok(())
+        });
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("skip", |_, this, ()| {
+            this.skipped.set(true);
+            Ok(())
+        });
+    }
+}
+
+impl UserData for LuaCommit<'_, '_> {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field("type", "commit");
+        fields.add_field_method_get("id", |_, this| Ok(this.commit.mark.map(mark_to_lua)));
+        bytes_field!(
+            fields,
+            "branch",
+            |this| this.commit.branch.branch,
+            &mut this.commit.branch.branch
+        );
+        // The author is omitted when it is the same as the committer, so it
+        // is copied from the committer when first set.
+        bytes_field!(
+            fields,
+            "author_name",
+            |this| this
+                .commit
+                .author
+                .as_ref()
+                .unwrap_or(&this.commit.committer)
+                .name,
+            &mut author(this.commit).name
+        );
+        bytes_field!(
+            fields,
+            "author_email",
+            |this| this
+                .commit
+                .author
+                .as_ref()
+                .unwrap_or(&this.commit.committer)
+                .email,
+            &mut author(this.commit).email
+        );
+        bytes_field!(
+            fields,
+            "author_date",
+            |this| this
+                .commit
+                .author
+                .as_ref()
+                .unwrap_or(&this.commit.committer)
+                .date,
+            &mut author(this.commit).date
+        );
+        bytes_field!(
+            fields,
+            "committer_name",
+            |this| this.commit.committer.name,
+            &mut this.commit.committer.name
+        );
+        bytes_field!(
+            fields,
+            "committer_email",
+            |this| this.commit.committer.email,
+            &mut this.commit.committer.email
+        );
+        bytes_field!(
+            fields,
+            "committer_date",
+            |this| this.commit.committer.date,
+            &mut this.commit.committer.date
+        );
+        bytes_field!(
+            fields,
+            "message",
+            |this| this.commit.message,
+            &mut this.commit.message
+        );
+        optional_bytes_field!(
+            fields,
+            "original_id",
+            |this| this.commit.original_oid,
+            OriginalOid { oid }
+        );
+        optional_bytes_field!(
+            fields,
+            "encoding",
+            |this| this.commit.encoding,
+            Encoding { encoding }
+        );
+        fields.add_field_method_get("parents", |lua, this| {
+            let mut key = this.state.parents.borrow_mut();
+            if let Some(key) = &*key {
+                return lua.registry_value::<Table<'_>>(key);
+            }
+            let parents = this.commit.from.iter().chain(&this.commit.merge);
+            let table = commitishes_to_lua(lua, parents)?;
+            *key = Some(lua.create_registry_value(table.clone())?);
+            Ok(table)
+        });
+        fields.add_field_method_set("parents", |lua, this, parents: Table<'_>| {
+            *this.state.parents.borrow_mut() = Some(lua.create_registry_value(parents)?);
+            Ok(())
+        });
+        fields.add_field_method_get("file_changes", |lua, this| {
+            let mut key = this.state.file_changes.borrow_mut();
+            if let Some(key) = &*key {
+                return lua.registry_value::<Table<'_>>(key);
+            }
+            let table = lua.create_table()?;
+            for change in this.changes.iter() {
+                if let Some(change) = change_to_lua(lua, change)? {
+                    table.push(change)?;
+                }
+            }
+            *key = Some(lua.create_registry_value(table.clone())?);
+            Ok(table)
+        });
+        fields.add_field_method_set("file_changes", |lua, this, changes: Table<'_>| {
+            *this.state.file_changes.borrow_mut() = Some(lua.create_registry_value(changes)?);
+            Ok(())
+        });
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // Drops this commit, so that references to it refer to `new_id` or,
+        // when it is `nil`, to nothing.
+        methods.add_method("skip", |_, this, new_id: Option<Value<'_>>| {
+            this.state.skipped.set(true);
+            *this.state.skipped_to.borrow_mut() = new_id.map(objectish_from_lua).transpose()?;
+            Ok(())
+        });
+        // Returns the contents of a modification, which are read on demand,
+        // or `nil` for other changes and submodules.
+        methods.add_method("file_data", |lua, this, change: Table<'_>| {
+            let change = change_from_lua(&change)?;
+            match this
+                .blobs
+                .read_change(&change)
+                .map_err(|err| err.into_lua_err())?
+            {
+                Some(data) => lua.create_string(data).map(Some),
+                None => Ok(None),
+            }
+        });
+        // Replaces the contents of a modification with a new blob, which is
+        // written before the commit, and updates its `blob_id`.
+        methods.add_method(
+            "set_file_data",
+            |_, this, (change, data): (Table<'_>, mlua::String<'_>)| {
+                let mut new = change_from_lua(&change)?;
+                this.blobs
+                    .set_contents(&mut new, data.as_bytes().to_vec())
+                    .map_err(|err| err.into_lua_err())?;
+                if let Change::FileModify(FileModifyChange {
+                    data_ref: DataRef::Mark(mark),
+                    ..
+                }) = new.change
+                {
+                    change.set("blob_id", mark_to_lua(mark))?;
+                    change.set("data", Value::Nil)?;
+                }
+                Ok(())
+            },
+        );
+    }
+}
+
+impl UserData for LuaTag<'_, '_> {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field("type", "tag");
+        fields.add_field_method_get("id", |_, this| Ok(this.tag.mark.map(mark_to_lua)));
+        bytes_field!(
+            fields,
+            "ref",
+            |this| this.tag.name.name,
+            &mut this.tag.name.name
+        );
+        fields.add_field_method_get("from_ref", |lua, this| {
+            objectish_to_lua(lua, &this.tag.from)
+        });
+        fields.add_field_method_set("from_ref", |_, this, from: Value<'_>| {
+            this.tag.from = objectish_from_lua(from)?;
+            Ok(())
+        });
+        // The tagger can be modified, but not added or removed.
+        for (name, field) in [("tagger_name", 0), ("tagger_email", 1), ("tagger_date", 2)] {
+            fields.add_field_method_get(name, move |lua, this| match &this.tag.tagger {
+                Some(tagger) => {
+                    let value = [&tagger.name, &tagger.email, &tagger.date][field];
+                    lua.create_string(&**value).map(Some)
+                }
+                None => Ok(None),
+            });
+            fields.add_field_method_set(name, move |_, this, value: mlua::String<'_>| {
+                let Some(tagger) = &mut this.tag.tagger else {
+                    return Err(mlua::Error::runtime("the tag has no tagger"));
+                };
+                *[&mut tagger.name, &mut tagger.email, &mut tagger.date][field] = to_bytes(&value);
+                Ok(())
+            });
+        }
+        bytes_field!(
+            fields,
+            "message",
+            |this| this.tag.message,
+            &mut this.tag.message
+        );
+        optional_bytes_field!(
+            fields,
+            "original_id",
+            |this| this.tag.original_oid,
+            OriginalOid { oid }
+        );
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("skip", |_, this, ()| {
+            this.skipped.set(true);
+            Ok(())
+        });
+    }
+}
+
+impl UserData for LuaReset<'_, '_> {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field("type", "reset");
+        bytes_field!(
+            fields,
+            "ref",
+            |this| this.reset.branch.branch,
+            &mut this.reset.branch.branch
+        );
+        fields.add_field_method_get("from_ref", |lua, this| match &this.reset.from {
+            Some(from) => objectish_to_lua(lua, &from.commit),
+            None => Ok(Value::Nil),
+        });
+        fields.add_field_method_set("from_ref", |_, this, from: Value<'_>| {
+            this.reset.from = match from {
+                Value::Nil => None,
+                from => Some(Commitish {
+                    commit: objectish_from_lua(from)?,
+                }),
+            };
+            Ok(())
+        });
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("skip", |_, this, ()| {
+            this.skipped.set(true);
+            Ok(())
+        });
+    }
+}
+
+/// Returns the author of a commit, which is first copied from the committer,
+/// if it was omitted.
+fn author<'a, 'c>(commit: &'a mut Commit<Bytes<'c>>) -> &'a mut PersonIdent<Bytes<'c>> {
+    commit
+        .author
+        .get_or_insert_with(|| commit.committer.clone())
+}
+
+#[inline]
+fn to_bytes(s: &mlua::String<'_>) -> Bytes<'static> {
+    Cow::Owned(s.as_bytes().to_vec())
+}
+
+/// Returns the ID, under which Lua sees a mark.
+#[inline]
+fn mark_to_lua(mark: Mark) -> i64 {
+    mark.mark.get() as i64
+}
+
+/// Converts a mark, as an integer, or a hash, as a string.
+fn objectish_to_lua<'lua>(
+    lua: &'lua Lua,
+    objectish: &Objectish<Bytes<'_>>,
+) -> mlua::Result<Value<'lua>> {
+    match objectish {
+        Objectish::Mark(mark) => Ok(Value::Integer(mark_to_lua(*mark))),
+        Objectish::BranchOrOid(oid) => lua.create_string(&**oid).map(Value::String),
+    }
+}
+
+fn objectish_from_lua(value: Value<'_>) -> mlua::Result<Objectish<Bytes<'static>>> {
+    match value {
+        Value::Integer(id) => u64::try_from(id)
+            .ok()
+            .and_then(NonZeroU64::new)
+            .map(|mark| Objectish::Mark(Mark { mark }))
+            .ok_or_else(|| mlua::Error::runtime("object IDs must be positive")),
+        Value::String(oid) => Ok(Objectish::BranchOrOid(to_bytes(&oid))),
+        value => Err(mlua::Error::runtime(format!(
+            "expected an object ID or hash, got {}",
+            value.type_name(),
+        ))),
+    }
+}
+
+fn commitishes_to_lua<'lua, 'a, 'c: 'a>(
+    lua: &'lua Lua,
+    commitishes: impl IntoIterator<Item = &'a Commitish<Bytes<'c>>>,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    for commitish in commitishes {
+        table.push(objectish_to_lua(lua, &commitish.commit)?)?;
+    }
+    Ok(table)
+}
+
+fn commitishes_from_lua(table: Table<'_>) -> mlua::Result<Vec<Commitish<Bytes<'static>>>> {
+    table
+        .sequence_values::<Value<'_>>()
+        .map(|commit| {
+            Ok(Commitish {
+                commit: objectish_from_lua(commit?)?,
+            })
+        })
+        .collect()
+}
+
+/// Converts a file change to a table like the Python `FileChange`, unless it
+/// is of a type which filter-repo does not model, such as a note. For
+/// modifications, `blob_id` is the ID of the blob with the contents, or its
+/// hash, or, for inline data, `data` is the contents. For renames and copies,
+/// `filename` is the source and `blob_id` is the destination.
+fn change_to_lua<'lua>(
+    lua: &'lua Lua,
+    change: &FileChange<'_>,
+) -> mlua::Result<Option<Table<'lua>>> {
+    let table = lua.create_table()?;
+    match &change.change {
+        Change::FileModify(FileModifyChange {
+            data_ref,
+            mode,
+            path,
+        }) => {
+            table.set("type", "M")?;
+            table.set("filename", lua.create_string(&**path)?)?;
+            table.set("mode", format!("{:06o}", *mode as u16))?;
+            match data_ref {
+                DataRef::Mark(mark) => table.set("blob_id", mark_to_lua(*mark))?,
+                DataRef::Oid(oid) => table.set("blob_id", lua.create_string(&**oid)?)?,
+                DataRef::Inline(_) => {
+                    if let Some(data) = &change.inline_data {
+                        table.set("data", lua.create_string(data)?)?;
+                    }
+                }
+            }
+        }
+        Change::FileDelete(FileDeleteChange { path }) => {
+            table.set("type", "D")?;
+            table.set("filename", lua.create_string(&**path)?)?;
+        }
+        Change::FileRename(FileRenameChange { source, dest })
+        | Change::FileCopy(FileCopyChange { source, dest }) => {
+            let kind = if matches!(change.change, Change::FileRename(_)) {
+                "R"
+            } else {
+                "C"
+            };
+            table.set("type", kind)?;
+            table.set("filename", lua.create_string(&**source)?)?;
+            table.set("blob_id", lua.create_string(&**dest)?)?;
+        }
+        Change::FileDeleteAll => table.set("type", "DELETEALL")?,
+        Change::NoteModify(_) | Change::Ls(_) | Change::CatBlob(_) => return Ok(None),
+    }
+    Ok(Some(table))
+}
+
+fn change_from_lua(table: &Table<'_>) -> mlua::Result<FileChange<'static>> {
+    let kind: mlua::String<'_> = table.get("type")?;
+    let kind = kind.as_bytes();
+    let invalid =
+        || mlua::Error::runtime(format!("incomplete file change of type {}", kind.as_bstr(),));
+    let path = || -> mlua::Result<Bytes<'static>> {
+        let path: Option<mlua::String<'_>> = table.get("filename")?;
+        path.map(|path| to_bytes(&path)).ok_or_else(invalid)
+    };
+    let dest = || -> mlua::Result<Bytes<'static>> {
+        let dest: Option<mlua::String<'_>> = table.get("blob_id")?;
+        dest.map(|dest| to_bytes(&dest)).ok_or_else(invalid)
+    };
+    let mut inline_data = None;
+    let change = match kind {
+        b"M" => {
+            let mode: Option<mlua::String<'_>> = table.get("mode")?;
+            let mode = mode.ok_or_else(invalid)?;
+            let mode = mode
+                .to_str()
+                .ok()
+                .and_then(|mode| u16::from_str_radix(mode, 8).ok())
+                .ok_or_else(|| {
+                    mlua::Error::runtime(format!("invalid mode {}", mode.as_bytes().as_bstr()))
+                })?;
+            let blob_id: Value<'_> = table.get("blob_id")?;
+            let data: Option<mlua::String<'_>> = table.get("data")?;
+            let data_ref = match (blob_id, data) {
+                (Value::Nil, Some(data)) => {
+                    inline_data = Some(data.as_bytes().to_vec());
+                    DataRef::Inline(DataHeader::Counted {
+                        len: data.as_bytes().len() as u64,
+                    })
+                }
+                (Value::Nil, None) => return Err(invalid()),
+                (blob_id, _) => match objectish_from_lua(blob_id)? {
+                    Objectish::Mark(mark) => DataRef::Mark(mark),
+                    Objectish::BranchOrOid(oid) => DataRef::Oid(oid),
+                },
+            };
+            Change::FileModify(FileModifyChange {
+                data_ref,
+                mode: Mode::canonicalize(mode),
+                path: path()?,
+            })
+        }
+        b"D" => Change::FileDelete(FileDeleteChange { path: path()? }),
+        b"R" => Change::FileRename(FileRenameChange {
+            source: path()?,
+            dest: dest()?,
+        }),
+        b"C" => Change::FileCopy(FileCopyChange {
+            source: path()?,
+            dest: dest()?,
+        }),
+        b"DELETEALL" => Change::FileDeleteAll,
+        _ => return Err(invalid()),
+    };
+    Ok(FileChange {
+        change,
+        inline_data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_round_trip() {
+        let lua = Lua::new();
+        let path = |path: &'static str| Cow::Borrowed(path.as_bytes());
+        let changes = [
+            Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Mark(Mark {
+                    mark: NonZeroU64::new(1).unwrap(),
+                }),
+                mode: Mode::Exe,
+                path: path("run.sh"),
+            }),
+            Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Oid(path("0123456789abcdef0123456789abcdef01234567")),
+                mode: Mode::File,
+                path: path("a"),
+            }),
+            Change::FileDelete(FileDeleteChange { path: path("b") }),
+            Change::FileRename(FileRenameChange {
+                source: path("c"),
+                dest: path("d"),
+            }),
+            Change::FileCopy(FileCopyChange {
+                source: path("e"),
+                dest: path("f"),
+            }),
+            Change::FileDeleteAll,
+        ];
+        for change in changes {
+            let change = FileChange {
+                change,
+                inline_data: None,
+            };
+            let table = change_to_lua(&lua, &change).unwrap().unwrap();
+            assert_eq!(change_from_lua(&table).unwrap(), change);
+        }
+
+        let inline = FileChange {
+            change: Change::FileModify(FileModifyChange {
+                data_ref: DataRef::Inline(DataHeader::Counted { len: 2 }),
+                mode: Mode::File,
+                path: path("g"),
+            }),
+            inline_data: Some(b"hi".to_vec()),
+        };
+        let table = change_to_lua(&lua, &inline).unwrap().unwrap();
+        assert_eq!(table.get::<_, String>("data").unwrap(), "hi");
+        assert_eq!(change_from_lua(&table).unwrap(), inline);
+
+        table.set("mode", "banana").unwrap();
+        assert!(change_from_lua(&table).is_err());
+    }
+}
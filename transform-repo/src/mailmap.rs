@@ -0,0 +1,202 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Mapping of author, committer, and tagger identities with a mailmap.
+
+use std::{path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+use bstr::{BString, ByteSlice};
+use regex::bytes::Regex;
+use thiserror::Error;
+
+/// Rules for mapping names and emails, parsed from a mailmap file. See
+/// gitmailmap(5) for the format.
+///
+// Corresponds to `git-filter-repo:MailmapInfo`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailmapInfo {
+    /// The rules in the order they were first given, as pairs of
+    /// `(commit_name, commit_email)` and `(proper_name, proper_email)`. A
+    /// missing or empty commit name or email matches any. An empty proper name
+    /// or email keeps the original.
+    changes: Vec<(Matcher, Identity)>,
+}
+
+type Matcher = (Option<Vec<u8>>, Option<Vec<u8>>);
+type Identity = (Vec<u8>, Vec<u8>);
+
+/// An error from parsing a mailmap file. The message matches git-filter-repo.
+#[derive(Debug, Error)]
+#[error("Unparseable mailmap file: line #{line} is bad: {content:?}")]
+pub struct MailmapError {
+    line: usize,
+    content: BString,
+}
+
+impl MailmapInfo {
+    /// Parses a mailmap file. All four forms of entries are accepted:
+    ///
+    /// ```text
+    /// Proper Name <commit@email.xx>
+    /// <proper@email.xx> <commit@email.xx>
+    /// Proper Name <proper@email.xx> <commit@email.xx>
+    /// Proper Name <proper@email.xx> Commit Name <commit@email.xx>
+    /// ```
+    ///
+    // Corresponds to `git-filter-repo:MailmapInfo._parse_file`.
+    pub fn parse(contents: &[u8]) -> Result<Self, MailmapError> {
+        let name_and_email_re = Regex::new(r"(?-u)^(.*?)\s*<([^>]*)>\s*").unwrap();
+        let mut mailmap = MailmapInfo::default();
+        for (i, line) in contents.lines_with_terminator().enumerate() {
+            let err = || MailmapError {
+                line: i + 1,
+                content: line.into(),
+            };
+            // Remove comments and surrounding whitespace.
+            let content = line.find_byte(b'#').map_or(line, |j| &line[..j]).trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let m = name_and_email_re.captures(content).ok_or_else(err)?;
+            let proper = (m[1].to_vec(), m[2].to_vec());
+            let end = m.get(0).unwrap().end();
+            if end == content.len() {
+                mailmap.insert((None, Some(proper.1.clone())), proper);
+                continue;
+            }
+            let rest = &content[end..];
+            let commit = match name_and_email_re.captures(rest) {
+                Some(m) => {
+                    if m.get(0).unwrap().end() != rest.len() {
+                        return Err(err());
+                    }
+                    (Some(m[1].to_vec()), Some(m[2].to_vec()))
+                }
+                None => (Some(rest.to_vec()), None),
+            };
+            mailmap.insert(commit, proper);
+        }
+        Ok(mailmap)
+    }
+
+    /// Reads the `.mailmap` file at `HEAD` in a repo, for `--use-mailmap`.
+    pub fn from_head(repo: &Path) -> Result<Self> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["cat-file", "blob", "HEAD:.mailmap"])
+            .output()
+            .context("could not run git cat-file")?;
+        if !output.status.success() {
+            bail!("Cannot read .mailmap at HEAD in {}", repo.display());
+        }
+        Ok(MailmapInfo::parse(&output.stdout)?)
+    }
+
+    /// Records a rule. A later rule for the same commit identity replaces the
+    /// earlier one, but keeps its precedence.
+    fn insert(&mut self, commit: Matcher, proper: Identity) {
+        match self.changes.iter_mut().find(|(old, _)| *old == commit) {
+            Some((_, new)) => *new = proper,
+            None => self.changes.push((commit, proper)),
+        }
+    }
+
+    /// Returns whether there are no rules.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the new name and email for an identity, per the first rule
+    /// which matches it, or the given name and email, when none match. Emails
+    /// are compared case-insensitively and names exactly.
+    ///
+    // Corresponds to `git-filter-repo:MailmapInfo.translate`.
+    pub fn translate<'a>(&'a self, name: &'a [u8], email: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        for ((old_name, old_email), (new_name, new_email)) in &self.changes {
+            let email_matches = old_email
+                .as_ref()
+                .is_none_or(|old_email| email.eq_ignore_ascii_case(old_email));
+            let name_matches = old_name
+                .as_ref()
+                .is_none_or(|old_name| old_name.is_empty() || name == &old_name[..]);
+            if email_matches && name_matches {
+                let name = if new_name.is_empty() { name } else { new_name };
+                let email = if new_email.is_empty() {
+                    email
+                } else {
+                    new_email
+                };
+                return (name, email);
+            }
+        }
+        (name, email)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_forms() {
+        let mailmap = MailmapInfo::parse(
+            b"# Contributors\n\
+              Jane Doe <jane@example.com>\n\
+              <joe@example.com> <joe@old.example.com>  # moved\n\
+              Ann Lee <ann@example.com> <ann@old.example.com>\n\
+              Bob Roe <bob@example.com> Robert <BOB@old.example.com>\n\
+              \n\
+              Sam <sam@example.com> Sammy\n",
+        )
+        .unwrap();
+        let translate = |name: &'static str, email: &'static str| {
+            let (name, email) = mailmap.translate(name.as_bytes(), email.as_bytes());
+            (name.to_str().unwrap(), email.to_str().unwrap())
+        };
+        assert_eq!(
+            translate("jane", "Jane@Example.com"),
+            ("Jane Doe", "jane@example.com")
+        );
+        assert_eq!(
+            translate("Joe", "joe@old.example.com"),
+            ("Joe", "joe@example.com")
+        );
+        assert_eq!(
+            translate("A. Lee", "ann@old.example.com"),
+            ("Ann Lee", "ann@example.com")
+        );
+        assert_eq!(
+            translate("Robert", "bob@old.example.com"),
+            ("Bob Roe", "bob@example.com")
+        );
+        assert_eq!(
+            translate("Bob", "bob@old.example.com"),
+            ("Bob", "bob@old.example.com")
+        );
+        assert_eq!(
+            translate("Sammy", "any@example.com"),
+            ("Sam", "sam@example.com")
+        );
+        assert_eq!(
+            translate("Other", "other@example.com"),
+            ("Other", "other@example.com")
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = MailmapInfo::parse(b"Jane <jane@example.com>\nno email\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Unparseable mailmap file: line #2 is bad: "no email\n""#
+        );
+        let err = MailmapInfo::parse(b"A <a@x> B <b@x> C\n").unwrap_err();
+        assert_eq!(err.line, 1);
+    }
+}
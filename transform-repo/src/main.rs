@@ -0,0 +1,83 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+use std::process::ExitCode;
+#[cfg(feature = "python")]
+use std::{ffi::OsString, path::PathBuf};
+
+use anyhow::Result;
+#[cfg(feature = "python")]
+use clap::{value_parser, Arg};
+use clap::{ArgMatches, Command};
+#[cfg(feature = "python")]
+use pyo3::Python;
+#[cfg(feature = "python")]
+use transform_repo::py_api;
+use transform_repo::{
+    options::{self, FilteringOptions, OptionsError},
+    RepoFilter,
+};
+
+fn main() -> ExitCode {
+    let command = Command::new("git-transform-repo")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(options::command());
+    #[cfg(feature = "python")]
+    let command = command.subcommand(
+        Command::new("run-script")
+            .about("Run a git-filter-repo library script, which imports git_filter_repo")
+            .arg(
+                Arg::new("script")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                Arg::new("args")
+                    .num_args(0..)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true)
+                    .value_parser(value_parser!(OsString)),
+            ),
+    );
+    let matches = command.get_matches();
+    let res = match matches.subcommand() {
+        Some(("filter-repo", matches)) => filter_repo(matches),
+        #[cfg(feature = "python")]
+        Some(("run-script", matches)) => run_script(matches),
+        _ => unreachable!(),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if let Some(OptionsError::Clap(err)) = err.downcast_ref() {
+                err.exit();
+            }
+            eprintln!("Error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the filter-repo compatibility mode.
+fn filter_repo(matches: &ArgMatches) -> Result<()> {
+    let args = FilteringOptions::from_arg_matches(matches)?;
+    RepoFilter::builder(args).build()?.run()
+}
+
+/// Runs a library script with the `git_filter_repo` API.
+#[cfg(feature = "python")]
+fn run_script(matches: &ArgMatches) -> Result<()> {
+    let script = matches.get_one::<PathBuf>("script").unwrap();
+    let args = matches
+        .get_many::<OsString>("args")
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| py_api::run_script(py, script, &args))
+}
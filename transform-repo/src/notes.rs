@@ -0,0 +1,209 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Rewriting of notes trees onto rewritten commits.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use fast_export::{
+    command::{Commitish, Mark, Objectish},
+    parse::{Change, DataRef, FileDeleteChange, FileModifyChange, NoteModifyChange},
+};
+
+use crate::renames::{CommitRenames, Renamed};
+
+/// Returns whether the ref is a notes ref, i.e., in `refs/notes/`.
+#[inline]
+pub fn is_notes_ref(refname: &[u8]) -> bool {
+    refname.starts_with(b"refs/notes/")
+}
+
+/// Remaps the commits annotated by notes onto the commits they were rewritten
+/// to.
+///
+/// Notes which annotate a commit by mark follow it automatically, since
+/// fast-import resolves the mark to the rewritten commit. Notes which annotate
+/// a commit by ID are remapped with the commit renames. In both cases, notes
+/// for commits that were pruned are dropped.
+///
+/// fast-export writes notes commits as ordinary file changes, with the ID of
+/// the annotated commit as the path, split into fanout directories. Those
+/// which annotate a commit in the stream are turned into note changes by the
+/// mark of that commit, so that fast-import writes them at the path of the
+/// rewritten commit.
+#[derive(Clone, Copy, Debug)]
+pub struct NotesRewriter<'r> {
+    renames: &'r CommitRenames,
+    /// Marks of commits, by original ID.
+    marks: &'r HashMap<Vec<u8>, Mark>,
+}
+
+impl<'r> NotesRewriter<'r> {
+    #[inline]
+    pub fn new(renames: &'r CommitRenames, marks: &'r HashMap<Vec<u8>, Mark>) -> Self {
+        NotesRewriter { renames, marks }
+    }
+
+    /// Remaps a file change in a notes commit in place. Returns `false`, when
+    /// the change should be dropped.
+    pub fn rewrite_change(&self, change: &mut Change<Cow<'_, [u8]>>) -> bool {
+        let (data_ref, path) = match change {
+            Change::NoteModify(note) => return self.rewrite(note),
+            Change::FileModify(FileModifyChange { data_ref, path, .. }) => {
+                (Some(data_ref.clone()), path)
+            }
+            Change::FileDelete(FileDeleteChange { path }) => (None, path),
+            _ => return true,
+        };
+        let Some(oid) = annotated_oid(path) else {
+            return true;
+        };
+        let commit = match self.marks.get(&oid) {
+            Some(&mark) if self.renames.is_pruned_mark(mark) => return false,
+            Some(&mark) => Objectish::Mark(mark),
+            None => match self.renames.get(&oid) {
+                Renamed::Unknown => return true,
+                Renamed::Rewritten(new) => Objectish::BranchOrOid(Cow::Owned(new.to_owned())),
+                Renamed::Pruned => return false,
+            },
+        };
+        // fast-import removes a note, when its blob is the null ID.
+        let data_ref = data_ref.unwrap_or_else(|| DataRef::Oid(vec![b'0'; oid.len()].into()));
+        *change = Change::NoteModify(NoteModifyChange {
+            data_ref,
+            commit: Commitish { commit },
+        });
+        true
+    }
+
+    /// Remaps the annotated commit of a note in place. Returns `false`, when
+    /// the note should be dropped.
+    pub fn rewrite(&self, note: &mut NoteModifyChange<Cow<'_, [u8]>>) -> bool {
+        let Commitish { commit } = &mut note.commit;
+        match commit {
+            Objectish::Mark(mark) => !self.renames.is_pruned_mark(*mark),
+            Objectish::BranchOrOid(oid) => match self.renames.get(oid) {
+                Renamed::Unknown => true,
+                Renamed::Rewritten(new) => {
+                    *oid = Cow::Owned(new.to_owned());
+                    true
+                }
+                Renamed::Pruned => false,
+            },
+        }
+    }
+}
+
+/// Returns the ID of the commit annotated by the note at this path in a notes
+/// tree, or `None`, when it is not a note.
+fn annotated_oid(path: &[u8]) -> Option<Vec<u8>> {
+    let oid = path
+        .iter()
+        .copied()
+        .filter(|&b| b != b'/')
+        .collect::<Vec<_>>();
+    let is_oid = (oid.len() == 40 || oid.len() == 64)
+        && oid.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    is_oid.then_some(oid)
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_export::command::Mode;
+
+    use super::*;
+
+    fn note(commit: Objectish<Cow<'static, [u8]>>) -> NoteModifyChange<Cow<'static, [u8]>> {
+        NoteModifyChange {
+            data_ref: DataRef::Mark(Mark::new(1).unwrap()),
+            commit: Commitish { commit },
+        }
+    }
+
+    fn oid(oid: &'static [u8]) -> Objectish<Cow<'static, [u8]>> {
+        Objectish::BranchOrOid(Cow::Borrowed(oid))
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut renames = CommitRenames::new();
+        renames.insert(b"aaaa", Some(b"bbbb"));
+        renames.insert(b"cccc", None);
+        renames.insert_pruned_mark(Mark::new(3).unwrap());
+        let marks = HashMap::new();
+        let rewriter = NotesRewriter::new(&renames, &marks);
+
+        let mut n = note(oid(b"aaaa"));
+        assert!(rewriter.rewrite(&mut n));
+        assert_eq!(n, note(oid(b"bbbb")));
+
+        let mut n = note(oid(b"dddd"));
+        assert!(rewriter.rewrite(&mut n));
+        assert_eq!(n, note(oid(b"dddd")));
+
+        assert!(!rewriter.rewrite(&mut note(oid(b"cccc"))));
+        assert!(rewriter.rewrite(&mut note(Objectish::Mark(Mark::new(2).unwrap()))));
+        assert!(!rewriter.rewrite(&mut note(Objectish::Mark(Mark::new(3).unwrap()))));
+    }
+
+    #[test]
+    fn rewrite_change() {
+        let a = "a".repeat(40);
+        let (b, c) = ("b".repeat(40), "c".repeat(40));
+        let mut renames = CommitRenames::new();
+        renames.insert(c.as_bytes(), None);
+        renames.insert_pruned_mark(Mark::new(2).unwrap());
+        let marks = HashMap::from([
+            (a.clone().into_bytes(), Mark::new(1).unwrap()),
+            (b.clone().into_bytes(), Mark::new(2).unwrap()),
+        ]);
+        let rewriter = NotesRewriter::new(&renames, &marks);
+        let blob = DataRef::Mark(Mark::new(5).unwrap());
+        let modify = |path: String| {
+            Change::FileModify(FileModifyChange {
+                data_ref: blob.clone(),
+                mode: Mode::File,
+                path: path.into_bytes().into(),
+            })
+        };
+
+        let mut change = modify(format!("{}/{}", &a[..2], &a[2..]));
+        assert!(rewriter.rewrite_change(&mut change));
+        assert_eq!(
+            change,
+            Change::NoteModify(NoteModifyChange {
+                data_ref: blob.clone(),
+                commit: Commitish {
+                    commit: Objectish::Mark(Mark::new(1).unwrap()),
+                },
+            }),
+        );
+        let mut change = Change::FileDelete(FileDeleteChange {
+            path: a.clone().into_bytes().into(),
+        });
+        assert!(rewriter.rewrite_change(&mut change));
+        assert!(matches!(
+            change,
+            Change::NoteModify(NoteModifyChange {
+                data_ref: DataRef::Oid(oid),
+                ..
+            }) if *oid == [b'0'; 40],
+        ));
+        assert!(!rewriter.rewrite_change(&mut modify(b)));
+        assert!(!rewriter.rewrite_change(&mut modify(c)));
+        let mut change = modify("d".repeat(40));
+        assert!(rewriter.rewrite_change(&mut change));
+        assert_eq!(change, modify("d".repeat(40)));
+        let mut change = modify("README".to_owned());
+        assert!(rewriter.rewrite_change(&mut change));
+        assert_eq!(change, modify("README".to_owned()));
+    }
+
+    #[test]
+    fn notes_ref() {
+        assert!(is_notes_ref(b"refs/notes/commits"));
+        assert!(!is_notes_ref(b"refs/heads/notes"));
+    }
+}
@@ -0,0 +1,1258 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Options for filtering, parsed compatibly with git-filter-repo.
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use bstr::{ByteSlice, ByteVec};
+use clap::{ArgMatches, Args, Command, FromArgMatches, ValueEnum};
+use fast_export::parse::{Compat, GitVersion};
+use regex::bytes::Regex;
+use thiserror::Error;
+
+use crate::{
+    mailmap::{MailmapError, MailmapInfo},
+    paths::{python_replacement, Glob},
+    reencode::{Charset, Reencode},
+    replace::ReplaceText,
+    signature::SignaturePolicy,
+};
+
+/// The options for a filter, which correspond to the command-line options of
+/// git-filter-repo.
+///
+// Corresponds to `git-filter-repo:FilteringOptions`.
+#[derive(Clone, Debug)]
+pub struct FilteringOptions {
+    // Analysis
+    pub analyze: bool,
+    pub report_dir: Option<PathBuf>,
+
+    // Filtering based on paths
+    pub invert_paths: bool,
+    /// Path filters and renames in the order they were given.
+    pub path_changes: Vec<PathChange>,
+    /// Whether paths which match a filter are kept, rather than dropped. It is
+    /// false when there are no filters, so that no paths are dropped.
+    pub inclusive: bool,
+    pub use_base_name: bool,
+
+    // Content editing filters
+    pub replace_text: Option<ReplaceText>,
+    pub strip_blobs_bigger_than: Option<u64>,
+    /// Original IDs of blobs to strip.
+    pub strip_blobs_with_ids: HashSet<Vec<u8>>,
+
+    // Renaming of refs
+    pub tag_rename: Option<(Vec<u8>, Vec<u8>)>,
+
+    // Filtering of commit messages
+    pub replace_message: Option<ReplaceText>,
+    pub preserve_commit_hashes: bool,
+    /// How commits with an `encoding` header are handled.
+    pub reencode: Reencode,
+    /// The encodings tried, in order, for commits and tags without an
+    /// `encoding` header, which are not valid UTF-8.
+    pub fallback_encodings: Vec<Charset>,
+
+    // Signatures
+    pub signatures: SignaturePolicy,
+
+    // Filtering of names and emails
+    pub mailmap: Option<MailmapInfo>,
+    /// Read the mailmap from `.mailmap` at `HEAD` in the source repo.
+    pub use_mailmap: bool,
+
+    // Parent rewriting
+    pub replace_refs: Option<ReplaceRefs>,
+    pub prune_empty: PruneMode,
+    pub prune_degenerate: PruneMode,
+    pub no_ff: bool,
+
+    // Generic callback code snippets
+    pub filename_callback: Option<String>,
+    pub message_callback: Option<String>,
+    pub name_callback: Option<String>,
+    pub email_callback: Option<String>,
+    pub refname_callback: Option<String>,
+    pub blob_callback: Option<String>,
+    pub commit_callback: Option<String>,
+    pub tag_callback: Option<String>,
+    pub reset_callback: Option<String>,
+    pub filename_callback_lua: Option<String>,
+    pub message_callback_lua: Option<String>,
+    pub name_callback_lua: Option<String>,
+    pub email_callback_lua: Option<String>,
+    pub refname_callback_lua: Option<String>,
+    pub blob_callback_lua: Option<String>,
+    pub commit_callback_lua: Option<String>,
+    pub tag_callback_lua: Option<String>,
+    pub reset_callback_lua: Option<String>,
+
+    // Hook code snippets
+    pub blob_hook: Option<String>,
+    pub commit_hook: Option<String>,
+    pub tag_hook: Option<String>,
+    pub reset_hook: Option<String>,
+
+    // Location to filter from and to
+    pub source: Option<PathBuf>,
+    pub target: Option<PathBuf>,
+    pub in_place: bool,
+
+    // Miscellaneous
+    pub date_order: bool,
+    pub refs: Vec<OsString>,
+    pub dry_run: bool,
+    pub debug: bool,
+    pub stdin: bool,
+    /// How permissively the fast-export stream is parsed.
+    pub compat: Compat,
+    pub quiet: bool,
+    pub force: bool,
+    pub partial: bool,
+    pub no_gc: bool,
+    pub state_branch: Option<OsString>,
+}
+
+/// A filter or rename of paths.
+#[derive(Clone, Debug)]
+pub enum PathChange {
+    /// Keep paths which match.
+    Filter(PathMatch),
+    /// Rename paths which match to the replacement. For a regex, the
+    /// replacement uses the syntax of [`Regex::replace_all`].
+    Rename(PathMatch, Vec<u8>),
+}
+
+/// A pattern for matching paths.
+#[derive(Clone, Debug)]
+pub enum PathMatch {
+    /// Matches the exact path or a directory prefix.
+    Literal(Vec<u8>),
+    Glob(Glob),
+    Regex(Regex),
+}
+
+/// How to handle commits which become empty or merges which become
+/// degenerate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum PruneMode {
+    Always,
+    #[default]
+    Auto,
+    Never,
+}
+
+/// How to handle replace refs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum ReplaceRefs {
+    DeleteNoAdd,
+    DeleteAndAdd,
+    UpdateNoAdd,
+    UpdateOrAdd,
+    UpdateAndAdd,
+}
+
+/// An error from parsing options. The messages match git-filter-repo.
+#[derive(Debug, Error)]
+pub enum OptionsError {
+    #[error(transparent)]
+    Clap(#[from] clap::Error),
+    #[error("No arguments specified.")]
+    NoArguments,
+    #[error("--analyze is incompatible with --path* flags; it's a read-only operation.")]
+    AnalyzeWithPaths,
+    #[error("--analyze is incompatible with --stdin.")]
+    AnalyzeWithStdin,
+    #[error("--use-base-name and --path-rename are incompatible.")]
+    BaseNameWithRename,
+    #[error("--path-rename expects one colon in its argument: <old_name:new_name>.")]
+    PathRenameColon,
+    #[error(
+        "With --path-rename, if OLD_NAME and NEW_NAME are both non-empty and either ends with a \
+         slash then both must."
+    )]
+    PathRenameSlash,
+    #[error(
+        "When rename directories, if OLDNAME and NEW_NAME are both non-empty and either ends with \
+         a slash then both must."
+    )]
+    RenameDirSlash,
+    #[error("In {file}, 'glob:' and '==>' are incompatible (renaming globs makes no sense)")]
+    GlobRename { file: String },
+    #[error("Pathnames cannot begin with a '/'")]
+    AbsolutePath,
+    #[error("Invalid path component '{component}' found in '{path}'")]
+    InvalidPathComponent { component: String, path: String },
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    Mailmap(#[from] MailmapError),
+    #[error("Could not parse --strip-blobs-bigger-than argument {0}")]
+    StripBlobsSize(String),
+    #[error("--tag-rename expects one colon in its argument: <old_name:new_name>.")]
+    TagRenameColon,
+    #[error("argument is not valid UTF-8: {0:?}")]
+    NonUtf8(OsString),
+    #[error("could not read {path}: {err}")]
+    ReadFile { path: String, err: io::Error },
+}
+
+/// The raw arguments of the `filter-repo` subcommand.
+#[derive(Args, Debug)]
+struct FilterRepoArgs {
+    /// Analyze repository history and create a report that may be useful in
+    /// determining what to filter in a subsequent run.
+    #[arg(long, help_heading = "Analysis")]
+    analyze: bool,
+    /// Directory to write report, defaults to GIT_DIR/filter_repo/analysis.
+    #[arg(long, value_name = "DIR_OR_FILE", help_heading = "Analysis")]
+    report_dir: Option<PathBuf>,
+
+    /// Invert the selection of files from the specified --path-{match,glob,regex}
+    /// options below.
+    #[arg(long, help_heading = "Filtering based on paths")]
+    invert_paths: bool,
+    /// Exactly match filename or directory to keep.
+    #[arg(
+        long,
+        value_name = "DIR_OR_FILE",
+        help_heading = "Filtering based on paths"
+    )]
+    path: Vec<OsString>,
+    #[arg(long, value_name = "DIR_OR_FILE", hide = true)]
+    path_match: Vec<OsString>,
+    /// Glob of paths to keep.
+    #[arg(long, value_name = "GLOB", help_heading = "Filtering based on paths")]
+    path_glob: Vec<OsString>,
+    /// Regex of paths to keep.
+    #[arg(long, value_name = "REGEX", help_heading = "Filtering based on paths")]
+    path_regex: Vec<OsString>,
+    /// Match on file base name instead of full path from the top of the repo.
+    #[arg(long, help_heading = "Filtering based on paths")]
+    use_base_name: bool,
+    /// Path to rename; if filename or directory matches OLD_NAME rename to
+    /// NEW_NAME.
+    #[arg(
+        long,
+        value_name = "OLD_NAME:NEW_NAME",
+        help_heading = "Renaming based on paths"
+    )]
+    path_rename: Vec<OsString>,
+    #[arg(long, value_name = "OLD_NAME:NEW_NAME", hide = true)]
+    path_rename_match: Vec<OsString>,
+    /// Specify several path filtering and renaming directives, one per line.
+    #[arg(
+        long,
+        value_name = "FILENAME",
+        help_heading = "Filtering based on paths"
+    )]
+    paths_from_file: Vec<PathBuf>,
+    /// Only look at history that touches the given subdirectory and treat that
+    /// directory as the project root.
+    #[arg(long, value_name = "DIRECTORY", help_heading = "Shortcuts")]
+    subdirectory_filter: Vec<OsString>,
+    /// Treat the project root as instead being under DIRECTORY.
+    #[arg(long, value_name = "DIRECTORY", help_heading = "Shortcuts")]
+    to_subdirectory_filter: Vec<OsString>,
+
+    /// A file with expressions that, if found, will be replaced.
+    #[arg(
+        long,
+        value_name = "EXPRESSIONS_FILE",
+        help_heading = "Content editing filters"
+    )]
+    replace_text: Option<PathBuf>,
+    /// Strip blobs (files) bigger than specified size (e.g. '5M', '2G', etc).
+    #[arg(long, value_name = "SIZE", help_heading = "Content editing filters")]
+    strip_blobs_bigger_than: Option<String>,
+    /// Read git object ids from each line of the given file, and strip all of
+    /// them from history.
+    #[arg(
+        long,
+        value_name = "BLOB_ID_FILENAME",
+        help_heading = "Content editing filters"
+    )]
+    strip_blobs_with_ids: Option<PathBuf>,
+
+    /// Rename tags starting with OLD to start with NEW.
+    #[arg(long, value_name = "OLD:NEW", help_heading = "Renaming of refs")]
+    tag_rename: Option<OsString>,
+
+    /// A file with expressions that, if found in commit or tag messages, will
+    /// be replaced.
+    #[arg(
+        long,
+        value_name = "EXPRESSIONS_FILE",
+        help_heading = "Filtering of commit messages"
+    )]
+    replace_message: Option<PathBuf>,
+    /// By default, since commits are rewritten and thus gain new hashes,
+    /// references to old commit hashes in commit messages are replaced with new
+    /// commit hashes. This turns that off.
+    #[arg(long, help_heading = "Filtering of commit messages")]
+    preserve_commit_hashes: bool,
+    /// Do not reencode commit messages into UTF-8.
+    #[arg(long, help_heading = "Filtering of commit messages")]
+    preserve_commit_encoding: bool,
+    /// How to handle commits with an encoding header: 'yes' reencodes them
+    /// into UTF-8, 'no' keeps them as they are, and 'abort' fails. Defaults to
+    /// 'yes'.
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = Reencode::from_str,
+        conflicts_with = "preserve_commit_encoding",
+        help_heading = "Filtering of commit messages"
+    )]
+    reencode: Option<Reencode>,
+    /// Encodings to try, in order, for commit messages, names, and emails,
+    /// which have no encoding header, but are not valid UTF-8. Each commit or
+    /// tag uses the first which decodes all of its text, so one which decodes
+    /// anything, like latin1, should be last. Can be given multiple times or
+    /// separated by commas.
+    #[arg(
+        long,
+        value_name = "ENCODING",
+        value_parser = Charset::from_str,
+        value_delimiter = ',',
+        help_heading = "Filtering of commit messages"
+    )]
+    fallback_encoding: Vec<Charset>,
+
+    /// What to do with the signatures of commits and tags, which rewriting
+    /// invalidated. Signatures of objects which did not change, along with
+    /// everything they refer to, are always kept.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value_t,
+        help_heading = "Signatures"
+    )]
+    signatures: SignaturePolicy,
+
+    /// Use specified mailmap file for mapping author, committer, and tagger
+    /// names and emails.
+    #[arg(
+        long,
+        value_name = "FILENAME",
+        help_heading = "Filtering of names & emails"
+    )]
+    mailmap: Option<PathBuf>,
+    /// Same as: '--mailmap .mailmap', but reads it from HEAD.
+    #[arg(
+        long,
+        conflicts_with = "mailmap",
+        help_heading = "Filtering of names & emails"
+    )]
+    use_mailmap: bool,
+
+    /// Replace refs (see git-replace(1)) are used to rewrite parents, unless
+    /// turned off by the usual git mechanism; this flag specifies what do do
+    /// with those refs afterward. Replace refs can either be deleted or updated
+    /// to point at new commit hashes. Also, new replace refs can be added for
+    /// each commit rewrite. With 'update-or-add', new replace refs are only
+    /// added for commit rewrites that aren't used to update an existing
+    /// replace ref. Defaults to 'update-no-add'.
+    #[arg(long, value_enum, help_heading = "Parent rewriting")]
+    replace_refs: Option<ReplaceRefs>,
+    /// Whether to prune empty commits.
+    #[arg(long, value_enum, default_value_t, help_heading = "Parent rewriting")]
+    prune_empty: PruneMode,
+    /// Since merge commits are needed for history topology, they are typically
+    /// exempt from pruning. This flag controls that.
+    #[arg(long, value_enum, default_value_t, help_heading = "Parent rewriting")]
+    prune_degenerate: PruneMode,
+    /// Even if the first parent is or becomes an ancestor of another parent,
+    /// do not prune it.
+    #[arg(long, help_heading = "Parent rewriting")]
+    no_ff: bool,
+
+    /// Python code body for processing filenames.
+    #[arg(
+        long,
+        visible_alias = "filename-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    filename_callback: Option<String>,
+    /// Python code body for processing messages (both commit messages and tag
+    /// messages).
+    #[arg(
+        long,
+        visible_alias = "message-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    message_callback: Option<String>,
+    /// Python code body for processing names of people.
+    #[arg(
+        long,
+        visible_alias = "name-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    name_callback: Option<String>,
+    /// Python code body for processing email addresses.
+    #[arg(
+        long,
+        visible_alias = "email-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    email_callback: Option<String>,
+    /// Python code body for processing refnames.
+    #[arg(
+        long,
+        visible_alias = "refname-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    refname_callback: Option<String>,
+    /// Python code body for processing blob objects.
+    #[arg(
+        long,
+        visible_alias = "blob-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    blob_callback: Option<String>,
+    /// Python code body for processing commit objects.
+    #[arg(
+        long,
+        visible_alias = "commit-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    commit_callback: Option<String>,
+    /// Python code body for processing tag objects.
+    #[arg(
+        long,
+        visible_alias = "tag-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    tag_callback: Option<String>,
+    /// Python code body for processing reset objects.
+    #[arg(
+        long,
+        visible_alias = "reset-callback:py",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    reset_callback: Option<String>,
+    /// Lua code body for processing filenames.
+    #[arg(
+        long = "filename-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    filename_callback_lua: Option<String>,
+    /// Lua code body for processing messages (both commit messages and tag
+    /// messages).
+    #[arg(
+        long = "message-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    message_callback_lua: Option<String>,
+    /// Lua code body for processing names of people.
+    #[arg(
+        long = "name-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    name_callback_lua: Option<String>,
+    /// Lua code body for processing email addresses.
+    #[arg(
+        long = "email-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    email_callback_lua: Option<String>,
+    /// Lua code body for processing refnames.
+    #[arg(
+        long = "refname-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    refname_callback_lua: Option<String>,
+    /// Lua code body for processing blob objects.
+    #[arg(
+        long = "blob-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    blob_callback_lua: Option<String>,
+    /// Lua code body for processing commit objects.
+    #[arg(
+        long = "commit-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    commit_callback_lua: Option<String>,
+    /// Lua code body for processing tag objects.
+    #[arg(
+        long = "tag-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    tag_callback_lua: Option<String>,
+    /// Lua code body for processing reset objects.
+    #[arg(
+        long = "reset-callback:lua",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Generic callback code snippets"
+    )]
+    reset_callback_lua: Option<String>,
+
+    /// Python code body for processing blob objects with getter and setter
+    /// functions, like `message()` and `message(set=...)`, instead of object
+    /// properties.
+    #[arg(
+        long,
+        visible_alias = "on-blob",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Hook code snippets"
+    )]
+    blob_hook: Option<String>,
+    /// Python code body for processing commit objects with getter and setter
+    /// functions, like `message()` and `message(set=...)`, instead of object
+    /// properties.
+    #[arg(
+        long,
+        visible_alias = "on-commit",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Hook code snippets"
+    )]
+    commit_hook: Option<String>,
+    /// Python code body for processing tag objects with getter and setter
+    /// functions, like `message()` and `message(set=...)`, instead of object
+    /// properties.
+    #[arg(
+        long,
+        visible_alias = "on-tag",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Hook code snippets"
+    )]
+    tag_hook: Option<String>,
+    /// Python code body for processing reset objects with getter and setter
+    /// functions, like `message()` and `message(set=...)`, instead of object
+    /// properties.
+    #[arg(
+        long,
+        visible_alias = "on-reset",
+        value_name = "FUNCTION_BODY_OR_FILE",
+        help_heading = "Hook code snippets"
+    )]
+    reset_hook: Option<String>,
+
+    /// Git repository to read from.
+    #[arg(long, help_heading = "Location to filter from/to")]
+    source: Option<PathBuf>,
+    /// Git repository to write the filtered history to. It is created, if it
+    /// does not exist, and must not have any refs, unless --force.
+    #[arg(long, help_heading = "Location to filter from/to")]
+    target: Option<PathBuf>,
+    /// Overwrite the source repository with the filtered history, instead of
+    /// writing to a separate --target.
+    #[arg(
+        long,
+        conflicts_with = "target",
+        help_heading = "Location to filter from/to"
+    )]
+    in_place: bool,
+
+    /// Processes commits in commit timestamp order.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    date_order: bool,
+    /// Limit history rewriting to the specified refs. Implies --partial.
+    #[arg(
+        long,
+        num_args = 1..,
+        allow_hyphen_values = true,
+        help_heading = "Miscellaneous options"
+    )]
+    refs: Vec<OsString>,
+    /// Do not change the repository. Run `git fast-export` and filter its
+    /// output, and save both the original and the filtered version for
+    /// comparison.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    dry_run: bool,
+    /// Print additional information about operations being performed and
+    /// commands being run.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    debug: bool,
+    /// Instead of running `git fast-export` and filtering its output, filter
+    /// the fast-export stream from stdin.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    stdin: bool,
+    /// Accept any stream which fast-import from the given version of Git
+    /// accepts, warning about each input which is otherwise rejected as
+    /// malformed.
+    #[arg(
+        long,
+        value_name = "GIT_VERSION",
+        value_parser = GitVersion::from_str,
+        help_heading = "Miscellaneous options"
+    )]
+    fast_import_compat: Option<GitVersion>,
+    /// Pass --quiet to other git commands called.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    quiet: bool,
+    /// Rewrite repository history even if the source repo does not look like a
+    /// fresh clone, when --in-place, or if the target repo is not empty.
+    #[arg(short, long, help_heading = "Miscellaneous options")]
+    force: bool,
+    /// Do a partial history rewrite, resulting in the mixture of old and new
+    /// history.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    partial: bool,
+    /// Do not run 'git gc' after filtering.
+    #[arg(long, help_heading = "Miscellaneous options")]
+    no_gc: bool,
+    /// Enables incremental filtering by saving the mapping of old to new
+    /// objects to the specified branch upon exit, and loading that mapping
+    /// from that branch (if it exists) upon startup.
+    #[arg(
+        long,
+        value_name = "BRANCH_NAME",
+        help_heading = "Miscellaneous options"
+    )]
+    state_branch: Option<OsString>,
+}
+
+/// The arguments which change paths, in the order they are handled.
+const PATH_ARGS: &[&str] = &[
+    "path",
+    "path_match",
+    "path_glob",
+    "path_regex",
+    "path_rename",
+    "path_rename_match",
+    "paths_from_file",
+    "subdirectory_filter",
+    "to_subdirectory_filter",
+];
+
+/// Creates the `filter-repo` subcommand.
+pub fn command() -> Command {
+    FilterRepoArgs::augment_args(
+        Command::new("filter-repo")
+            .about("Rewrite repository history, compatibly with git-filter-repo"),
+    )
+}
+
+impl FilteringOptions {
+    /// Parses options from command-line arguments, including the program name.
+    pub fn parse_args<I, T>(args: I) -> Result<Self, OptionsError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = command().try_get_matches_from(args)?;
+        FilteringOptions::from_arg_matches(&matches)
+    }
+
+    /// Converts options from the matches of the `filter-repo` subcommand.
+    ///
+    // Corresponds to `git-filter-repo:FilteringOptions.parse_args`.
+    pub fn from_arg_matches(matches: &ArgMatches) -> Result<Self, OptionsError> {
+        if matches.ids().all(|id| {
+            matches.value_source(id.as_str()) != Some(clap::parser::ValueSource::CommandLine)
+        }) {
+            return Err(OptionsError::NoArguments);
+        }
+        let args = FilterRepoArgs::from_arg_matches(matches)?;
+
+        // Path changes are order-dependent, so they are collected in the order
+        // they were given, instead of by option.
+        let mut path_args = Vec::new();
+        for &id in PATH_ARGS {
+            if let (Some(indices), Some(values)) = (matches.indices_of(id), matches.get_raw(id)) {
+                path_args.extend(indices.zip(values).map(|(i, value)| (i, id, value)));
+            }
+        }
+        path_args.sort_by_key(|&(i, _, _)| i);
+        let any_path_args = !path_args.is_empty();
+        let mut path_changes = Vec::new();
+        for (_, id, value) in path_args {
+            match id {
+                "path" | "path_match" => {
+                    append_filter(&mut path_changes, PathKind::Literal, &to_bytes(value)?)?
+                }
+                "path_glob" => append_filter(&mut path_changes, PathKind::Glob, &to_bytes(value)?)?,
+                "path_regex" => {
+                    append_filter(&mut path_changes, PathKind::Regex, &to_bytes(value)?)?
+                }
+                "path_rename" | "path_rename_match" => {
+                    append_rename(&mut path_changes, &to_bytes(value)?)?
+                }
+                "paths_from_file" => {
+                    path_changes.extend(get_paths_from_file(Path::new(value))?);
+                }
+                "subdirectory_filter" => {
+                    let dir = dir_with_slash(to_bytes(value)?);
+                    append_filter(&mut path_changes, PathKind::Literal, &dir)?;
+                    let mut rename = dir;
+                    rename.push(b':');
+                    append_rename(&mut path_changes, &rename)?;
+                }
+                "to_subdirectory_filter" => {
+                    let mut rename = b":".to_vec();
+                    rename.extend_from_slice(&dir_with_slash(to_bytes(value)?));
+                    append_rename(&mut path_changes, &rename)?;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        // Corresponds to `git-filter-repo:FilteringOptions.sanity_check_args`.
+        if args.analyze && any_path_args {
+            return Err(OptionsError::AnalyzeWithPaths);
+        }
+        if args.analyze && args.stdin {
+            return Err(OptionsError::AnalyzeWithStdin);
+        }
+        // Without any path filters, no path is filtered out.
+        let inclusive = !args.invert_paths
+            && path_changes
+                .iter()
+                .any(|change| matches!(change, PathChange::Filter(_)));
+        if args.use_base_name
+            && path_changes
+                .iter()
+                .any(|change| matches!(change, PathChange::Rename(..)))
+        {
+            return Err(OptionsError::BaseNameWithRename);
+        }
+
+        let strip_blobs_bigger_than = args
+            .strip_blobs_bigger_than
+            .map(|size| parse_size(&size).ok_or(OptionsError::StripBlobsSize(size)))
+            .transpose()?;
+        let strip_blobs_with_ids = match &args.strip_blobs_with_ids {
+            Some(path) => read_file(path)?.fields().map(|oid| oid.to_vec()).collect(),
+            None => HashSet::new(),
+        };
+        let replace_text = args
+            .replace_text
+            .map(|path| get_replace_text(&path))
+            .transpose()?;
+        let replace_message = args
+            .replace_message
+            .map(|path| get_replace_text(&path))
+            .transpose()?;
+        let mailmap = args
+            .mailmap
+            .map(|path| Ok::<_, OptionsError>(MailmapInfo::parse(&read_file(&path)?)?))
+            .transpose()?;
+        let tag_rename = args
+            .tag_rename
+            .map(|rename| {
+                let rename = to_bytes(&rename)?;
+                match rename.split_once_str(b":") {
+                    Some((old, new)) => Ok((old.to_vec(), new.to_vec())),
+                    None => Err(OptionsError::TagRenameColon),
+                }
+            })
+            .transpose()?;
+        // --refs implies --partial.
+        let partial = args.partial || !args.refs.is_empty();
+        let refs = if args.refs.is_empty() {
+            vec![OsString::from("--all")]
+        } else {
+            args.refs
+        };
+
+        Ok(FilteringOptions {
+            analyze: args.analyze,
+            report_dir: args.report_dir,
+            invert_paths: args.invert_paths,
+            path_changes,
+            inclusive,
+            use_base_name: args.use_base_name,
+            replace_text,
+            strip_blobs_bigger_than,
+            strip_blobs_with_ids,
+            tag_rename,
+            replace_message,
+            preserve_commit_hashes: args.preserve_commit_hashes,
+            reencode: if args.preserve_commit_encoding {
+                Reencode::No
+            } else {
+                args.reencode.unwrap_or_default()
+            },
+            fallback_encodings: args.fallback_encoding,
+            signatures: args.signatures,
+            mailmap,
+            use_mailmap: args.use_mailmap,
+            replace_refs: args.replace_refs,
+            prune_empty: args.prune_empty,
+            prune_degenerate: args.prune_degenerate,
+            no_ff: args.no_ff,
+            filename_callback: args.filename_callback,
+            message_callback: args.message_callback,
+            name_callback: args.name_callback,
+            email_callback: args.email_callback,
+            refname_callback: args.refname_callback,
+            blob_callback: args.blob_callback,
+            commit_callback: args.commit_callback,
+            tag_callback: args.tag_callback,
+            reset_callback: args.reset_callback,
+            filename_callback_lua: args.filename_callback_lua,
+            message_callback_lua: args.message_callback_lua,
+            name_callback_lua: args.name_callback_lua,
+            email_callback_lua: args.email_callback_lua,
+            refname_callback_lua: args.refname_callback_lua,
+            blob_callback_lua: args.blob_callback_lua,
+            commit_callback_lua: args.commit_callback_lua,
+            tag_callback_lua: args.tag_callback_lua,
+            reset_callback_lua: args.reset_callback_lua,
+            blob_hook: args.blob_hook,
+            commit_hook: args.commit_hook,
+            tag_hook: args.tag_hook,
+            reset_hook: args.reset_hook,
+            source: args.source,
+            target: args.target,
+            in_place: args.in_place,
+            date_order: args.date_order,
+            refs,
+            dry_run: args.dry_run,
+            debug: args.debug,
+            stdin: args.stdin,
+            compat: args
+                .fast_import_compat
+                .map_or(Compat::Strict, Compat::FastImport),
+            quiet: args.quiet,
+            force: args.force,
+            partial,
+            no_gc: args.no_gc,
+            state_branch: args.state_branch,
+        })
+    }
+
+    /// Returns the first given option, which is accepted for compatibility,
+    /// but not yet implemented.
+    pub(crate) fn unsupported_option(&self) -> Option<&'static str> {
+        let unsupported = [
+            (self.analyze, "--analyze"),
+            (self.report_dir.is_some(), "--report-dir"),
+            (self.dry_run, "--dry-run"),
+            (self.debug, "--debug"),
+            (self.state_branch.is_some(), "--state-branch"),
+        ];
+        unsupported
+            .into_iter()
+            .find_map(|(given, option)| given.then_some(option))
+    }
+}
+
+impl Default for FilteringOptions {
+    fn default() -> Self {
+        FilteringOptions {
+            analyze: false,
+            report_dir: None,
+            invert_paths: false,
+            path_changes: Vec::new(),
+            inclusive: false,
+            use_base_name: false,
+            replace_text: None,
+            strip_blobs_bigger_than: None,
+            strip_blobs_with_ids: HashSet::new(),
+            tag_rename: None,
+            replace_message: None,
+            preserve_commit_hashes: false,
+            reencode: Reencode::Yes,
+            fallback_encodings: Vec::new(),
+            signatures: SignaturePolicy::Strip,
+            mailmap: None,
+            use_mailmap: false,
+            replace_refs: None,
+            prune_empty: PruneMode::Auto,
+            prune_degenerate: PruneMode::Auto,
+            no_ff: false,
+            filename_callback: None,
+            message_callback: None,
+            name_callback: None,
+            email_callback: None,
+            refname_callback: None,
+            blob_callback: None,
+            commit_callback: None,
+            tag_callback: None,
+            reset_callback: None,
+            filename_callback_lua: None,
+            message_callback_lua: None,
+            name_callback_lua: None,
+            email_callback_lua: None,
+            refname_callback_lua: None,
+            blob_callback_lua: None,
+            commit_callback_lua: None,
+            tag_callback_lua: None,
+            reset_callback_lua: None,
+            blob_hook: None,
+            commit_hook: None,
+            tag_hook: None,
+            reset_hook: None,
+            source: None,
+            target: None,
+            in_place: false,
+            date_order: false,
+            refs: vec![OsString::from("--all")],
+            dry_run: false,
+            debug: false,
+            stdin: false,
+            compat: Compat::Strict,
+            quiet: false,
+            force: false,
+            partial: false,
+            no_gc: false,
+            state_branch: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PathKind {
+    Literal,
+    Glob,
+    Regex,
+}
+
+/// Appends a path filter.
+///
+// Corresponds to `git-filter-repo:FilteringOptions.AppendFilter`.
+fn append_filter(
+    path_changes: &mut Vec<PathChange>,
+    kind: PathKind,
+    path: &[u8],
+) -> Result<(), OptionsError> {
+    if path.starts_with(b"/") {
+        return Err(OptionsError::AbsolutePath);
+    }
+    check_components(path, path)?;
+    match kind {
+        PathKind::Literal => {
+            path_changes.push(PathChange::Filter(PathMatch::Literal(path.to_vec())));
+        }
+        PathKind::Glob => {
+            path_changes.push(PathChange::Filter(PathMatch::Glob(Glob::new(path)?)));
+            // A glob also matches the contents of the directories it matches.
+            if !path.ends_with(b"*") {
+                let mut glob = path.to_vec();
+                glob.extend_from_slice(if path.ends_with(b"/") { b"*" } else { b"/*" });
+                path_changes.push(PathChange::Filter(PathMatch::Glob(Glob::new(&glob)?)));
+            }
+        }
+        PathKind::Regex => {
+            let regex = Regex::new(&path.to_str_lossy())?;
+            path_changes.push(PathChange::Filter(PathMatch::Regex(regex)));
+        }
+    }
+    Ok(())
+}
+
+/// Appends a path rename of the form `OLD_NAME:NEW_NAME`.
+///
+// Corresponds to `git-filter-repo:FilteringOptions.AppendFilter`.
+fn append_rename(path_changes: &mut Vec<PathChange>, rename: &[u8]) -> Result<(), OptionsError> {
+    let mut parts = rename.split_str(b":");
+    let (Some(old), Some(new), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(OptionsError::PathRenameColon);
+    };
+    if !old.is_empty() && !new.is_empty() && old.ends_with(b"/") != new.ends_with(b"/") {
+        return Err(OptionsError::PathRenameSlash);
+    }
+    if old.starts_with(b"/") || new.starts_with(b"/") {
+        return Err(OptionsError::AbsolutePath);
+    }
+    check_components(old, rename)?;
+    check_components(new, rename)?;
+    path_changes.push(PathChange::Rename(
+        PathMatch::Literal(old.to_vec()),
+        new.to_vec(),
+    ));
+    Ok(())
+}
+
+fn check_components(path: &[u8], user_path: &[u8]) -> Result<(), OptionsError> {
+    for component in path.split_str(b"/") {
+        if component == b"." || component == b".." {
+            return Err(OptionsError::InvalidPathComponent {
+                component: component.to_str_lossy().into_owned(),
+                path: user_path.to_str_lossy().into_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads path filters and renames from a file. Each line is a path, optionally
+/// prefixed with `literal:`, `glob:`, or `regex:`, and optionally followed by
+/// `==>` and its replacement.
+///
+// Corresponds to `git-filter-repo:FilteringOptions.get_paths_from_file`.
+fn get_paths_from_file(filename: &Path) -> Result<Vec<PathChange>, OptionsError> {
+    let contents = read_file(filename)?;
+    let mut path_changes = Vec::new();
+    for line in contents.lines() {
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let (line, repl) = match line.rfind(b"==>") {
+            Some(i) => (&line[..i], Some(&line[i + 3..])),
+            None => (line, None),
+        };
+        let (pattern, is_glob) = if let Some(regex) = line.strip_prefix(b"regex:") {
+            (PathMatch::Regex(Regex::new(&regex.to_str_lossy())?), false)
+        } else if let Some(glob) = line.strip_prefix(b"glob:") {
+            if repl.is_some() {
+                return Err(OptionsError::GlobRename {
+                    file: filename.display().to_string(),
+                });
+            }
+            (PathMatch::Glob(Glob::new(glob)?), true)
+        } else {
+            let literal = line.strip_prefix(b"literal:").unwrap_or(line);
+            if let Some(repl) = repl {
+                if !literal.is_empty()
+                    && !repl.is_empty()
+                    && literal.ends_with(b"/") != repl.ends_with(b"/")
+                {
+                    return Err(OptionsError::RenameDirSlash);
+                }
+            }
+            (PathMatch::Literal(literal.to_vec()), false)
+        };
+        match repl {
+            Some(repl) => {
+                let repl = match pattern {
+                    PathMatch::Regex(_) => python_replacement(repl),
+                    _ => repl.to_vec(),
+                };
+                path_changes.push(PathChange::Rename(pattern, repl));
+            }
+            None => {
+                let extension = match &pattern {
+                    PathMatch::Glob(glob) if is_glob && !glob.as_bytes().ends_with(b"*") => {
+                        let mut glob = glob.as_bytes().to_vec();
+                        glob.extend_from_slice(if glob.ends_with(b"/") { b"*" } else { b"/*" });
+                        Some(Glob::new(&glob)?)
+                    }
+                    _ => None,
+                };
+                path_changes.push(PathChange::Filter(pattern));
+                if let Some(glob) = extension {
+                    path_changes.push(PathChange::Filter(PathMatch::Glob(glob)));
+                }
+            }
+        }
+    }
+    Ok(path_changes)
+}
+
+/// Parses a size with an optional `K`, `M`, or `G` suffix.
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, multiplier) = match size.as_bytes().last()? {
+        b'K' => (&size[..size.len() - 1], 1 << 10),
+        b'M' => (&size[..size.len() - 1], 1 << 20),
+        b'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn dir_with_slash(mut dir: Vec<u8>) -> Vec<u8> {
+    if !dir.ends_with(b"/") {
+        dir.push(b'/');
+    }
+    dir
+}
+
+// Corresponds to `git-filter-repo:FilteringOptions.get_replace_text`.
+fn get_replace_text(filename: &Path) -> Result<ReplaceText, OptionsError> {
+    Ok(ReplaceText::parse(&read_file(filename)?)?)
+}
+
+fn to_bytes(arg: &OsStr) -> Result<Vec<u8>, OptionsError> {
+    Vec::from_os_string(arg.to_owned()).map_err(OptionsError::NonUtf8)
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<Vec<u8>, OptionsError> {
+    fs::read(path).map_err(|err| OptionsError::ReadFile {
+        path: path.display().to_string(),
+        err,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<FilteringOptions, OptionsError> {
+        FilteringOptions::parse_args(["filter-repo"].iter().chain(args))
+    }
+
+    fn describe(change: &PathChange) -> String {
+        let pattern = |m: &PathMatch| match m {
+            PathMatch::Literal(path) => format!("literal {}", path.as_bstr()),
+            PathMatch::Glob(glob) => format!("glob {}", glob.as_bytes().as_bstr()),
+            PathMatch::Regex(regex) => format!("regex {regex}"),
+        };
+        match change {
+            PathChange::Filter(m) => format!("filter {}", pattern(m)),
+            PathChange::Rename(m, new) => format!("rename {} to {}", pattern(m), new.as_bstr()),
+        }
+    }
+
+    #[test]
+    fn path_changes_in_order() {
+        let args = parse(&[
+            "--path-glob",
+            "src",
+            "--path",
+            "README.md",
+            "--subdirectory-filter",
+            "lib",
+            "--path-rename",
+            "a/:b/",
+            "--path",
+            "Makefile",
+        ])
+        .unwrap();
+        let changes = args.path_changes.iter().map(describe).collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                "filter glob src",
+                "filter glob src/*",
+                "filter literal README.md",
+                "filter literal lib/",
+                "rename literal lib/ to ",
+                "rename literal a/ to b/",
+                "filter literal Makefile",
+            ],
+        );
+        assert!(args.inclusive);
+        assert_eq!(args.refs, [OsString::from("--all")]);
+    }
+
+    #[test]
+    fn paths_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("paths");
+        fs::write(
+            &file,
+            "# comment\n\nsrc/\nglob:*.c\nregex:^doc/(.*)\\.txt$==>docs/\\1.md\nliteral:old==>new\n",
+        )
+        .unwrap();
+        let args = parse(&["--paths-from-file", file.to_str().unwrap()]).unwrap();
+        let changes = args.path_changes.iter().map(describe).collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                "filter literal src/",
+                "filter glob *.c",
+                "filter glob *.c/*",
+                "rename regex ^doc/(.*)\\.txt$ to docs/${1}.md",
+                "rename literal old to new",
+            ],
+        );
+
+        fs::write(&file, "glob:*.c==>src/\n").unwrap();
+        let err = parse(&["--paths-from-file", file.to_str().unwrap()]).unwrap_err();
+        assert!(matches!(err, OptionsError::GlobRename { .. }), "{err}");
+    }
+
+    #[test]
+    fn errors() {
+        let err = |args: &[&str]| parse(args).unwrap_err().to_string();
+        assert_eq!(err(&[]), "No arguments specified.");
+        assert_eq!(
+            err(&["--path-rename", "a:b:c"]),
+            "--path-rename expects one colon in its argument: <old_name:new_name>.",
+        );
+        assert_eq!(
+            err(&["--path-rename", "a/:b"]),
+            "With --path-rename, if OLD_NAME and NEW_NAME are both non-empty and either ends \
+             with a slash then both must.",
+        );
+        assert_eq!(
+            err(&["--path", "/etc"]),
+            "Pathnames cannot begin with a '/'"
+        );
+        assert_eq!(
+            err(&["--path", "a/../b"]),
+            "Invalid path component '..' found in 'a/../b'",
+        );
+        assert_eq!(
+            err(&["--analyze", "--path", "a"]),
+            "--analyze is incompatible with --path* flags; it's a read-only operation.",
+        );
+        assert_eq!(
+            err(&["--use-base-name", "--path-rename", "a:b"]),
+            "--use-base-name and --path-rename are incompatible.",
+        );
+        assert_eq!(
+            err(&["--strip-blobs-bigger-than", "5X"]),
+            "Could not parse --strip-blobs-bigger-than argument 5X",
+        );
+        assert_eq!(
+            err(&["--tag-rename", "v1"]),
+            "--tag-rename expects one colon in its argument: <old_name:new_name>.",
+        );
+    }
+
+    #[test]
+    fn values() {
+        let args = parse(&[
+            "--strip-blobs-bigger-than",
+            "5M",
+            "--tag-rename",
+            "v:release-",
+            "--prune-empty",
+            "never",
+            "--signatures",
+            "verbatim",
+            "--fast-import-compat",
+            "2.44",
+            "--reencode",
+            "abort",
+            "--fallback-encoding",
+            "sjis,latin1",
+            "-f",
+            "--refs",
+            "main",
+            "--branches",
+        ])
+        .unwrap();
+        assert_eq!(args.strip_blobs_bigger_than, Some(5 << 20));
+        assert_eq!(args.tag_rename, Some((b"v".to_vec(), b"release-".to_vec())));
+        assert_eq!(args.prune_empty, PruneMode::Never);
+        assert_eq!(args.signatures, SignaturePolicy::Keep);
+        assert_eq!(args.compat, Compat::FastImport(GitVersion::new(2, 44, 0)));
+        assert_eq!(args.reencode, Reencode::Abort);
+        assert_eq!(
+            args.fallback_encodings,
+            [Charset::Whatwg(encoding_rs::SHIFT_JIS), Charset::Latin1]
+        );
+        assert_eq!(
+            args.refs,
+            [OsString::from("main"), OsString::from("--branches")]
+        );
+        assert!(args.force);
+        assert!(args.partial);
+        assert!(!args.inclusive);
+    }
+}
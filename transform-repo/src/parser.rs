@@ -3,16 +3,26 @@
 // This file is part of git-transform-repo, distributed under the GPL 2.0 with a
 // linking exception. For the full terms, see the included COPYING file.
 
-#![allow(dead_code)]
-
 use std::{
+    borrow::Cow,
+    cell::Cell,
     collections::{HashMap, HashSet},
-    io::{BufRead, Write},
+    io::{self, BufRead, Read, Write},
+    num::NonZeroU64,
+    rc::Rc,
 };
 
-use pyo3::types::PyFunction;
+use anyhow::Result;
+use fast_export::{
+    command::{
+        Command, Commit, Commitish, DataHeader, MapBytes, Mark, Objectish, OriginalOid, Reset, Tag,
+    },
+    dump_data,
+    parse::{Change, Compat, DataRef, Parser},
+    Dump,
+};
 
-use crate::filter::{Oid, TODO};
+use crate::notes::is_notes_ref;
 
 /// A class for parsing and handling the output from fast-export. This class
 /// allows the user to register callbacks when various types of data are
@@ -21,28 +31,24 @@ use crate::filter::{Oid, TODO};
 /// it encounters them, the user gets to use/modify these objects via callbacks,
 /// and finally `FastExportParser` outputs the modified objects in fast-import
 /// format (presumably so they can be used to create a new repo).
-pub struct FastExportParser<'py, R: BufRead, W: Write> {
+pub struct FastExportParser<R: BufRead, W: Write> {
     /// A handle to the input source for the fast-export data.
-    input: R,
+    input: Parser<R>,
+    /// What is tracked across commands, which is kept apart from `input`, so
+    /// that it can be updated while a command borrows from the input.
+    state: ParserState<W>,
+}
 
+struct ParserState<W> {
     /// A handle to the output file for the output we generate (we call dump on
     /// many of the Git elements we create).
     output: W,
 
-    /// Callbacks for the various Git elements.
-    blob_callback: Option<&'py PyFunction>,
-    commit_callback: Option<&'py PyFunction>,
-    tag_callback: Option<&'py PyFunction>,
-    reset_callback: Option<&'py PyFunction>,
-    progress_callback: Option<&'py PyFunction>,
-    checkpoint_callback: Option<&'py PyFunction>,
-    done_callback: Option<&'py PyFunction>,
-
     /// Keep track of which refs appear from the export, and which make it to
     /// the import (pruning of empty commits, renaming of refs, and creating new
     /// manual objects and inserting them can cause these to differ).
-    exported_refs: HashSet<Oid>,
-    imported_refs: HashSet<Oid>,
+    exported_refs: RefSet,
+    imported_refs: RefSet,
 
     /// A list of the branches we've seen, plus the last known commit they
     /// pointed to. An entry in latest_commit/latest_orig_commit will be deleted
@@ -50,60 +56,517 @@ pub struct FastExportParser<'py, R: BufRead, W: Write> {
     /// fast-import's weird decision to allow having an implicit parent via
     /// naming the branch instead of requiring branches to be specified via
     /// `from` directives.
-    latest_commit: HashMap<TODO, TODO>,
-    latest_orig_commit: HashMap<TODO, TODO>,
+    latest_commit: HashMap<Vec<u8>, Mark>,
+    latest_orig_commit: HashMap<Vec<u8>, Vec<u8>>,
+
+    /// Commits and resets of notes refs, which are handled at the end of the
+    /// stream.
+    deferred_notes: Vec<DeferredNotes>,
+}
+
+/// A command to a notes ref, which was deferred until the end of the stream.
+enum DeferredNotes {
+    Commit(Box<CommitObject<'static>>),
+    Reset(Reset<Bytes<'static>>),
+}
+
+/// The callbacks for the objects in a fast-export stream. Each receives the
+/// object, which it may modify, and returns whether to keep it. Objects borrow
+/// from the parser's buffer, so handlers copy what they retain.
+pub trait Handler {
+    /// Called with the header of a blob before its data is read. Returns
+    /// whether to strip the blob, in which case its data is skipped unread.
+    /// The length is unknown for delimited data.
+    fn strip_blob(
+        &mut self,
+        mark: Option<Mark>,
+        original_oid: Option<&[u8]>,
+        len: Option<u64>,
+    ) -> Result<bool>;
+    /// Handles a blob. Commands written to the output are inserted before the
+    /// blob, as for the other objects.
+    fn blob(&mut self, blob: &mut BlobObject<'_>, output: &mut StreamWriter<'_>) -> Result<bool>;
+    /// Handles a commit. Commands written to the output are inserted before
+    /// the commit.
+    fn commit(
+        &mut self,
+        commit: &mut CommitObject<'_>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<bool>;
+    /// Called after a kept commit has been written to the output, so that
+    /// the handler can follow it with commands of its own.
+    fn commit_written(
+        &mut self,
+        commit: &CommitObject<'_>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<()>;
+    fn tag(&mut self, tag: &mut Tag<Bytes<'_>>, output: &mut StreamWriter<'_>) -> Result<bool>;
+    fn reset(
+        &mut self,
+        reset: &mut Reset<Bytes<'_>>,
+        output: &mut StreamWriter<'_>,
+    ) -> Result<bool>;
+    /// Called when the end of the stream is reached.
+    fn done(&mut self) -> Result<()>;
+}
+
+/// The output stream, into which a [`Handler`] may insert commands of its own.
+pub struct StreamWriter<'a> {
+    output: &'a mut dyn Write,
+    imported_refs: &'a mut RefSet,
+}
+
+impl StreamWriter<'_> {
+    /// Inserts a reset into the stream and records its branch as imported, as
+    /// if it had been kept from the input.
+    pub fn insert_reset(&mut self, reset: &Reset<Bytes<'_>>) -> io::Result<()> {
+        self.imported_refs.insert(reset.branch.branch.to_vec());
+        reset.dump(&mut self.output)
+    }
+
+    /// Inserts a commit into the stream and records its branch as imported.
+    pub fn insert_commit(&mut self, commit: &CommitObject<'_>) -> io::Result<()> {
+        self.imported_refs
+            .insert(commit.commit.branch.branch.to_vec());
+        commit.dump(&mut self.output)
+    }
+
+    /// Inserts a tag into the stream and records it as imported.
+    pub fn insert_tag(&mut self, tag: &Tag<Bytes<'_>>) -> io::Result<()> {
+        self.imported_refs.insert(tag_ref(&tag.name.name));
+        tag.dump(&mut self.output)
+    }
+}
+
+impl Write for StreamWriter<'_> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.output.write_all(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
 
-    /// Stores the contents of the current line of input being parsed.
-    current_line: Vec<u8>,
+/// A set of full refnames.
+pub type RefSet = HashSet<Vec<u8>>;
+
+/// Objects created by callbacks and scripts are given marks above those which
+/// fast-export assigns, so that they do not collide with marks in the stream.
+///
+/// # Differences from filter-repo
+///
+/// filter-repo renumbers every mark in the stream, so that new objects can
+/// take the next ID. Here, marks from the stream are kept as-is.
+const FIRST_NEW_MARK: u64 = 1 << 40;
+
+/// A counter of marks for new objects, which are not used in the stream. Clones
+/// share the same counter, so that objects created by callbacks and by the
+/// filter do not collide.
+#[derive(Clone, Debug)]
+pub struct NewMarks(Rc<Cell<u64>>);
+
+impl NewMarks {
+    #[inline]
+    pub fn new() -> Self {
+        NewMarks(Rc::new(Cell::new(FIRST_NEW_MARK)))
+    }
+
+    /// Returns a mark for a new object.
+    #[inline]
+    pub fn next(&self) -> Mark {
+        let mark = self.0.get();
+        self.0.set(mark + 1);
+        Mark {
+            mark: NonZeroU64::new(mark).unwrap(),
+        }
+    }
 }
 
-impl<'py, R: BufRead, W: Write> FastExportParser<'py, R, W> {
+impl Default for NewMarks {
+    #[inline]
+    fn default() -> Self {
+        NewMarks::new()
+    }
+}
+
+/// Bytes, which borrow from the parser's buffer, until they are replaced.
+///
+/// The parser reuses its buffer for each command, so objects are only valid
+/// for the command, in which they were parsed. Objects which are retained
+/// longer are copied with `into_owned`.
+pub type Bytes<'a> = Cow<'a, [u8]>;
+
+/// A blob with its contents read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobObject<'a> {
+    pub mark: Option<Mark>,
+    pub original_oid: Option<OriginalOid<Bytes<'a>>>,
+    pub data: Vec<u8>,
+}
+
+/// A commit with its file changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitObject<'a> {
+    pub commit: Commit<Bytes<'a>>,
+    pub changes: Vec<FileChange<'a>>,
+}
+
+/// A file change in a commit, with the contents of its inline data, if it has
+/// any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileChange<'a> {
+    pub change: Change<Bytes<'a>>,
+    pub inline_data: Option<Vec<u8>>,
+}
+
+impl BlobObject<'_> {
+    /// Copies the blob out of the parser's buffer.
+    #[inline]
+    pub fn into_owned(self) -> BlobObject<'static> {
+        BlobObject {
+            mark: self.mark,
+            original_oid: self.original_oid.map_bytes(&mut owned),
+            data: self.data,
+        }
+    }
+}
+
+impl CommitObject<'_> {
+    /// Copies the commit out of the parser's buffer.
+    #[inline]
+    pub fn into_owned(self) -> CommitObject<'static> {
+        CommitObject {
+            commit: self.commit.map_bytes(&mut owned),
+            changes: self
+                .changes
+                .into_iter()
+                .map(FileChange::into_owned)
+                .collect(),
+        }
+    }
+}
+
+impl FileChange<'_> {
+    /// Copies the file change out of the parser's buffer.
+    #[inline]
+    pub fn into_owned(self) -> FileChange<'static> {
+        FileChange {
+            change: self.change.map_bytes(&mut owned),
+            inline_data: self.inline_data,
+        }
+    }
+
+    /// Returns whether filter-repo models a file change. Others, such as
+    /// notes, are kept as-is by scripting APIs.
+    #[inline]
+    #[cfg_attr(not(any(feature = "python", feature = "lua")), allow(dead_code))]
+    pub(crate) fn is_modeled(&self) -> bool {
+        !matches!(
+            self.change,
+            Change::NoteModify(_) | Change::Ls(_) | Change::CatBlob(_)
+        )
+    }
+}
+
+impl<R: BufRead, W: Write> FastExportParser<R, W> {
     /// Creates a new `FastExportParser`.
     ///
     /// # Differences from filter-repo
     ///
-    /// The parameters here differ from filter-repo `FastExportParser.__init__`.
-    /// In filter-repo, `__init__` has a different order for its callback
-    /// keyword arguments, and `input` and `output` are assigned later by
-    /// `FastExportParser.run`.
-    pub fn new(
-        input: R,
-        output: W,
-        blob_callback: Option<&'py PyFunction>,
-        commit_callback: Option<&'py PyFunction>,
-        tag_callback: Option<&'py PyFunction>,
-        reset_callback: Option<&'py PyFunction>,
-        progress_callback: Option<&'py PyFunction>,
-        checkpoint_callback: Option<&'py PyFunction>,
-        done_callback: Option<&'py PyFunction>,
-    ) -> Self {
+    /// In filter-repo, `FastExportParser.__init__` takes the callbacks and
+    /// `input` and `output` are assigned later by `FastExportParser.run`. Here,
+    /// the callbacks are instead passed to `run` as a [`Handler`].
+    ///
+    /// fast-export may write notes commits before the commits they annotate,
+    /// since they are unrelated histories. Commits and resets of notes refs
+    /// are handled at the end of the stream instead, so that the annotated
+    /// commits have been rewritten by then. filter-repo does not rewrite notes.
+    pub fn new(input: R, output: W) -> Self {
+        FastExportParser::with_compat(input, output, Compat::Strict)
+    }
+
+    /// Creates a new `FastExportParser`, which parses the input as
+    /// permissively as `compat` specifies and warns about each quirk of
+    /// fast-import it accepts.
+    pub fn with_compat(input: R, output: W, compat: Compat) -> Self {
         FastExportParser {
-            input,
-            output,
-            blob_callback,
-            commit_callback,
-            tag_callback,
-            reset_callback,
-            progress_callback,
-            checkpoint_callback,
-            done_callback,
-            exported_refs: HashSet::new(),
-            imported_refs: HashSet::new(),
-            latest_commit: HashMap::new(),
-            latest_orig_commit: HashMap::new(),
-            current_line: Vec::new(),
+            input: Parser::with_compat(input, compat),
+            state: ParserState {
+                output,
+                exported_refs: HashSet::new(),
+                imported_refs: HashSet::new(),
+                latest_commit: HashMap::new(),
+                latest_orig_commit: HashMap::new(),
+                deferred_notes: Vec::new(),
+            },
+        }
+    }
+
+    /// Parses the fast-export stream, passes each object to the handler, and
+    /// writes the objects it keeps in order. Objects are passed to the handler
+    /// borrowing from the input, except for notes, which are deferred.
+    pub fn run<H: Handler>(&mut self, handler: &mut H) -> Result<()> {
+        loop {
+            self.warn_quirks();
+            let state = &mut self.state;
+            let (command, input) = self.input.next_shared()?;
+            match command {
+                Command::Blob(blob) => {
+                    let len = match blob.data_header {
+                        DataHeader::Counted { len } => Some(len),
+                        DataHeader::Delimited { .. } => None,
+                    };
+                    let original_oid = blob.original_oid.clone().map_bytes(&mut borrowed);
+                    let oid = original_oid.as_ref().map(|oid| &*oid.oid);
+                    if handler.strip_blob(blob.mark, oid, len)? {
+                        // The parser skips the unread data.
+                        continue;
+                    }
+                    let mut data = Vec::new();
+                    blob.open()?.read_to_end(&mut data)?;
+                    let mut blob = BlobObject {
+                        mark: blob.mark,
+                        original_oid,
+                        data,
+                    };
+                    if handler.blob(&mut blob, &mut state.writer())? {
+                        blob.dump(&mut state.output)?;
+                    }
+                }
+                Command::Commit(commit) => {
+                    let commit = CommitObject {
+                        commit: commit.map_bytes(&mut borrowed),
+                        changes: parse_changes(input)?,
+                    };
+                    if is_notes_ref(&commit.commit.branch.branch) {
+                        let commit = Box::new(commit.into_owned());
+                        state.deferred_notes.push(DeferredNotes::Commit(commit));
+                    } else {
+                        state.handle_commit(commit, handler)?;
+                    }
+                }
+                Command::Tag(tag) => {
+                    let mut tag = tag.map_bytes(&mut borrowed);
+                    state.exported_refs.insert(tag_ref(&tag.name.name));
+                    if handler.tag(&mut tag, &mut state.writer())? {
+                        state.imported_refs.insert(tag_ref(&tag.name.name));
+                        tag.dump(&mut state.output)?;
+                    }
+                }
+                Command::Reset(reset) => {
+                    let reset = reset.map_bytes(&mut borrowed);
+                    if is_notes_ref(&reset.branch.branch) {
+                        let reset = reset.map_bytes(&mut owned);
+                        state.deferred_notes.push(DeferredNotes::Reset(reset));
+                    } else {
+                        state.handle_reset(reset, handler)?;
+                    }
+                }
+                Command::Ls(ls) => ls.dump(&mut state.output)?,
+                Command::CatBlob(cat_blob) => cat_blob.dump(&mut state.output)?,
+                Command::GetMark(get_mark) => get_mark.dump(&mut state.output)?,
+                Command::Checkpoint => state.output.write_all(b"checkpoint\n")?,
+                Command::Done(done) => {
+                    self.warn_quirks();
+                    let state = &mut self.state;
+                    for deferred in std::mem::take(&mut state.deferred_notes) {
+                        match deferred {
+                            DeferredNotes::Commit(commit) => {
+                                state.handle_commit(*commit, handler)?
+                            }
+                            DeferredNotes::Reset(reset) => state.handle_reset(reset, handler)?,
+                        }
+                    }
+                    handler.done()?;
+                    done.dump(&mut state.output)?;
+                    break;
+                }
+                Command::Alias(alias) => alias.dump(&mut state.output)?,
+                Command::Progress(progress) => progress.dump(&mut state.output)?,
+                Command::Feature(feature) => feature.dump(&mut state.output)?,
+                Command::Option(option) => option.dump(&mut state.output)?,
+            }
+        }
+        self.state.output.flush()?;
+        Ok(())
+    }
+
+    /// Reports the inputs, which were accepted only for compatibility with
+    /// fast-import.
+    fn warn_quirks(&mut self) {
+        for quirk in self.input.take_quirks() {
+            eprintln!("WARNING: accepted for compatibility with fast-import at {quirk}");
         }
     }
 
     /// Gets the refs which were received from the export.
     #[inline]
-    pub fn get_exported_refs(&self) -> &HashSet<Oid> {
-        &self.exported_refs
+    pub fn get_exported_refs(&self) -> &RefSet {
+        &self.state.exported_refs
     }
 
     /// Gets the refs which were sent to the import.
     #[inline]
-    pub fn get_imported_refs(&self) -> &HashSet<Oid> {
-        &self.imported_refs
+    pub fn get_imported_refs(&self) -> &RefSet {
+        &self.state.imported_refs
+    }
+
+    /// Gets the mark of the latest commit to the branch, which is the implicit
+    /// parent of the next commit to it without a `from` directive.
+    #[inline]
+    pub fn get_latest_commit(&self, branch: &[u8]) -> Option<Mark> {
+        self.state.latest_commit.get(branch).copied()
+    }
+
+    /// Gets the original ID of the latest commit to the branch.
+    #[inline]
+    pub fn get_latest_orig_commit(&self, branch: &[u8]) -> Option<&[u8]> {
+        self.state.latest_orig_commit.get(branch).map(Vec::as_slice)
+    }
+
+    /// Flushes and closes the output, returning the exported and imported
+    /// refs.
+    pub fn finish(mut self) -> Result<(RefSet, RefSet)> {
+        self.state.output.flush()?;
+        Ok((self.state.exported_refs, self.state.imported_refs))
     }
 }
+
+impl<W: Write> ParserState<W> {
+    fn handle_commit<H: Handler>(
+        &mut self,
+        mut commit: CommitObject<'_>,
+        handler: &mut H,
+    ) -> Result<()> {
+        let branch = commit.commit.branch.branch.to_vec();
+        let original_oid = commit
+            .commit
+            .original_oid
+            .as_ref()
+            .map(|oid| oid.oid.to_vec());
+        self.exported_refs.insert(branch.clone());
+
+        let keep = handler.commit(&mut commit, &mut self.writer())?;
+
+        if let Some(original_oid) = original_oid {
+            self.latest_orig_commit.insert(branch.clone(), original_oid);
+        }
+        match commit.commit.mark {
+            Some(mark) if keep => {
+                self.latest_commit.insert(branch, mark);
+            }
+            _ => {}
+        }
+        if keep {
+            self.imported_refs
+                .insert(commit.commit.branch.branch.to_vec());
+            commit.dump(&mut self.output)?;
+            handler.commit_written(&commit, &mut self.writer())?;
+        }
+        Ok(())
+    }
+
+    fn handle_reset<H: Handler>(
+        &mut self,
+        mut reset: Reset<Bytes<'_>>,
+        handler: &mut H,
+    ) -> Result<()> {
+        let branch = reset.branch.branch.to_vec();
+        self.exported_refs.insert(branch.clone());
+        // The reset starts the branch over, so it has no implicit parent after
+        // it, unless it names one.
+        self.latest_commit.remove(&branch);
+        self.latest_orig_commit.remove(&branch);
+        if let Some(Commitish {
+            commit: Objectish::Mark(mark),
+        }) = reset.from
+        {
+            self.latest_commit.insert(branch, mark);
+        }
+        if handler.reset(&mut reset, &mut self.writer())? {
+            self.imported_refs.insert(reset.branch.branch.to_vec());
+            reset.dump(&mut self.output)?;
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> StreamWriter<'_> {
+        StreamWriter {
+            output: &mut self.output,
+            imported_refs: &mut self.imported_refs,
+        }
+    }
+}
+
+/// Parses the file changes of the current commit, which borrow from the input
+/// like the commit.
+fn parse_changes<R: BufRead>(input: &Parser<R>) -> Result<Vec<FileChange<'_>>> {
+    let mut changes = Vec::new();
+    let mut iter = input.changes()?;
+    while let Some(change) = iter.next()? {
+        let inline_data = match &change {
+            Change::FileModify(fm) if matches!(fm.data_ref, DataRef::Inline(_)) => true,
+            Change::NoteModify(nm) if matches!(nm.data_ref, DataRef::Inline(_)) => true,
+            _ => false,
+        };
+        let change = change.map_bytes(&mut borrowed);
+        let inline_data = if inline_data {
+            let mut data = Vec::new();
+            iter.open_data()?.read_to_end(&mut data)?;
+            Some(data)
+        } else {
+            None
+        };
+        changes.push(FileChange {
+            change,
+            inline_data,
+        });
+    }
+    Ok(changes)
+}
+
+impl Dump for BlobObject<'_> {
+    fn dump<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(b"blob\n")?;
+        self.mark.dump(w)?;
+        self.original_oid.dump(w)?;
+        dump_data(w, &self.data)
+    }
+}
+
+impl Dump for CommitObject<'_> {
+    fn dump<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.commit.dump(w)?;
+        for change in &self.changes {
+            change.change.dump(w)?;
+            if let Some(data) = &change.inline_data {
+                dump_data(w, data)?;
+            }
+        }
+        w.write_all(b"\n")
+    }
+}
+
+#[inline]
+fn borrowed(bytes: &[u8]) -> Bytes<'_> {
+    Cow::Borrowed(bytes)
+}
+
+#[inline]
+pub(crate) fn owned(bytes: Bytes<'_>) -> Bytes<'static> {
+    Cow::Owned(bytes.into_owned())
+}
+
+fn tag_ref(name: &[u8]) -> Vec<u8> {
+    let mut refname = b"refs/tags/".to_vec();
+    refname.extend_from_slice(name);
+    refname
+}
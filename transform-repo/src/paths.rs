@@ -0,0 +1,306 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Selection and renaming of paths in commits.
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug, Formatter},
+};
+
+use bstr::ByteSlice;
+use regex::bytes::Regex;
+
+use crate::options::{PathChange, PathMatch};
+
+/// A shell-style wildcard pattern, with the semantics of Python's `fnmatch`.
+/// Unlike gitignore patterns, `*` also matches `/`.
+#[derive(Clone)]
+pub struct Glob {
+    pattern: Vec<u8>,
+    regex: Regex,
+}
+
+impl Glob {
+    /// Compiles a glob pattern.
+    pub fn new(pattern: &[u8]) -> Result<Self, regex::Error> {
+        Ok(Glob {
+            pattern: pattern.to_vec(),
+            regex: Regex::new(&format!("(?s-u)^{}$", translate_glob(pattern)))?,
+        })
+    }
+
+    /// Returns whether the path matches the pattern in its entirety.
+    #[inline]
+    pub fn is_match(&self, path: &[u8]) -> bool {
+        self.regex.is_match(path)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pattern
+    }
+}
+
+impl Debug for Glob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Glob")
+            .field(&self.pattern.as_bstr())
+            .finish()
+    }
+}
+
+/// Translates a glob pattern to an unanchored regex, which matches bytes.
+///
+// Corresponds to `cpython/Lib/fnmatch.py:translate`.
+pub(crate) fn translate_glob(pattern: &[u8]) -> String {
+    let mut re = String::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        let b = pattern[i];
+        i += 1;
+        match b {
+            b'*' => {
+                // Consecutive stars are equivalent to one.
+                while pattern.get(i) == Some(&b'*') {
+                    i += 1;
+                }
+                re.push_str(".*");
+            }
+            b'?' => re.push('.'),
+            b'[' => {
+                let mut j = i;
+                if pattern.get(j) == Some(&b'!') {
+                    j += 1;
+                }
+                if pattern.get(j) == Some(&b']') {
+                    j += 1;
+                }
+                while j < pattern.len() && pattern[j] != b']' {
+                    j += 1;
+                }
+                if j >= pattern.len() {
+                    // An unclosed bracket is a literal.
+                    re.push_str(r"\[");
+                    continue;
+                }
+                let class = &pattern[i..j];
+                i = j + 1;
+                re.push('[');
+                let class = match class.strip_prefix(b"!") {
+                    Some(class) => {
+                        re.push('^');
+                        class
+                    }
+                    None => class,
+                };
+                for &b in class {
+                    match b {
+                        // Keep ranges, but escape everything else.
+                        b'-' => re.push('-'),
+                        _ => push_escaped(&mut re, b),
+                    }
+                }
+                re.push(']');
+            }
+            _ => push_escaped(&mut re, b),
+        }
+    }
+    re
+}
+
+fn push_escaped(re: &mut String, b: u8) {
+    if b.is_ascii_alphanumeric() {
+        re.push(b as char);
+    } else {
+        re.push_str(&format!(r"\x{b:02x}"));
+    }
+}
+
+/// Converts a replacement string for Python's `re.sub` to the syntax of
+/// `regex`. Python refers to groups with `\1`, `\g<1>`, or `\g<name>`, while
+/// `regex` uses `${1}` or `${name}` and treats `$` as special.
+pub(crate) fn python_replacement(repl: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(repl.len());
+    let mut i = 0;
+    while i < repl.len() {
+        let b = repl[i];
+        i += 1;
+        match b {
+            b'$' => out.extend_from_slice(b"$$"),
+            b'\\' if i < repl.len() => {
+                let next = repl[i];
+                if next.is_ascii_digit() {
+                    let end = repl[i..]
+                        .iter()
+                        .take(2)
+                        .take_while(|b| b.is_ascii_digit())
+                        .count();
+                    push_group(&mut out, &repl[i..i + end]);
+                    i += end;
+                } else if next == b'g' && repl.get(i + 1) == Some(&b'<') {
+                    match repl[i + 2..].find_byte(b'>') {
+                        Some(len) => {
+                            push_group(&mut out, &repl[i + 2..i + 2 + len]);
+                            i += len + 3;
+                        }
+                        None => out.push(b'\\'),
+                    }
+                } else {
+                    let escaped = match next {
+                        b'n' => Some(b'\n'),
+                        b't' => Some(b'\t'),
+                        b'r' => Some(b'\r'),
+                        b'\\' => Some(b'\\'),
+                        _ => None,
+                    };
+                    match escaped {
+                        Some(escaped) => {
+                            out.push(escaped);
+                            i += 1;
+                        }
+                        None => out.push(b'\\'),
+                    }
+                }
+            }
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+fn push_group(out: &mut Vec<u8>, group: &[u8]) {
+    out.extend_from_slice(b"${");
+    out.extend_from_slice(group);
+    out.push(b'}');
+}
+
+/// Returns whether `path_expr` matches `pathname` or a leading directory of
+/// it, allowing `path_expr` to not have a trailing slash, even if it is meant
+/// to match a leading directory.
+///
+// Corresponds to `git-filter-repo:RepoFilter._filter_files.filename_matches`.
+pub fn filename_matches(path_expr: &[u8], pathname: &[u8]) -> bool {
+    if path_expr.is_empty() {
+        return true;
+    }
+    let n = path_expr.len();
+    pathname.starts_with(path_expr)
+        && (path_expr.ends_with(b"/") || pathname.len() == n || pathname[n] == b'/')
+}
+
+/// Applies path filters and renames to a path. Returns `None`, when the path
+/// is not wanted, or its possibly renamed path.
+///
+/// When `use_base_name` is set, filters match only on the base name of the
+/// path. A path is wanted when whether it matched a filter equals `inclusive`.
+///
+// Corresponds to `git-filter-repo:RepoFilter._filter_files.newname`.
+pub fn newname<'a>(
+    path_changes: &[PathChange],
+    pathname: &'a [u8],
+    use_base_name: bool,
+    inclusive: bool,
+) -> Option<Cow<'a, [u8]>> {
+    let mut wanted = false;
+    let mut path = Cow::Borrowed(pathname);
+    for change in path_changes {
+        match change {
+            PathChange::Filter(m) => {
+                if wanted {
+                    continue;
+                }
+                let name = if use_base_name {
+                    path.rsplit_once_str(b"/").map_or(&*path, |(_, base)| base)
+                } else {
+                    &*path
+                };
+                wanted = match m {
+                    PathMatch::Literal(literal) => filename_matches(literal, name),
+                    PathMatch::Glob(glob) => glob.is_match(name),
+                    PathMatch::Regex(regex) => regex.is_match(name),
+                };
+            }
+            PathChange::Rename(m, repl) => match m {
+                PathMatch::Literal(literal) => {
+                    if filename_matches(literal, &path) {
+                        let mut renamed = repl.clone();
+                        renamed.extend_from_slice(&path[literal.len()..]);
+                        path = Cow::Owned(renamed);
+                    }
+                }
+                PathMatch::Regex(regex) => {
+                    if regex.find(&path).is_some_and(|m| m.start() == 0) {
+                        path = Cow::Owned(regex.replace_all(&path, repl.as_slice()).into_owned());
+                    }
+                }
+                PathMatch::Glob(_) => unreachable!("globs cannot be renamed"),
+            },
+        }
+    }
+    (wanted == inclusive).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str) -> Glob {
+        Glob::new(pattern.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob("*.c").is_match(b"src/main.c"));
+        assert!(!glob("*.c").is_match(b"main.h"));
+        assert!(glob("src/?.rs").is_match(b"src/a.rs"));
+        assert!(glob("[!a-c]x").is_match(b"dx"));
+        assert!(!glob("[!a-c]x").is_match(b"bx"));
+        assert!(glob("[]]").is_match(b"]"));
+        assert!(glob("a[b").is_match(b"a[b"));
+        assert!(glob("a.b+").is_match(b"a.b+"));
+        assert!(!glob("a.b").is_match(b"axb"));
+        assert!(Glob::new(b"\xff*").unwrap().is_match(b"\xff\xfe"));
+    }
+
+    #[test]
+    fn replacement() {
+        assert_eq!(python_replacement(br"docs/\1.md"), b"docs/${1}.md");
+        assert_eq!(python_replacement(br"\g<name>-\g<2>$"), b"${name}-${2}$$");
+        assert_eq!(python_replacement(br"a\\b\n"), b"a\\b\n");
+    }
+
+    #[test]
+    fn filter_and_rename() {
+        let changes = vec![
+            PathChange::Filter(PathMatch::Literal(b"lib/".to_vec())),
+            PathChange::Rename(PathMatch::Literal(b"lib/".to_vec()), b"".to_vec()),
+            PathChange::Filter(PathMatch::Literal(b"README".to_vec())),
+            PathChange::Rename(
+                PathMatch::Regex(Regex::new(r"^(.*)\.txt$").unwrap()),
+                b"${1}.md".to_vec(),
+            ),
+        ];
+        let rename = |path: &str| {
+            newname(&changes, path.as_bytes(), false, true)
+                .map(|path| path.to_str_lossy().into_owned())
+        };
+        assert_eq!(rename("lib/a.rs").as_deref(), Some("a.rs"));
+        assert_eq!(rename("lib/notes.txt").as_deref(), Some("notes.md"));
+        assert_eq!(rename("README").as_deref(), Some("README"));
+        assert_eq!(rename("README.md"), None);
+        assert_eq!(rename("library/a.rs"), None);
+
+        let inverted = |path: &str| newname(&changes, path.as_bytes(), false, false).is_some();
+        assert!(!inverted("lib/a.rs"));
+        assert!(inverted("src/a.rs"));
+
+        let base = [PathChange::Filter(PathMatch::Glob(
+            Glob::new(b"*.h").unwrap(),
+        ))];
+        assert!(newname(&base, b"include/a.h", true, true).is_some());
+        assert!(newname(&base, b"include.h/a.c", true, true).is_none());
+    }
+}
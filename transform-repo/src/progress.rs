@@ -0,0 +1,342 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Reporting of progress while filtering.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, IsTerminal, Write},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+/// Counters of what has been processed so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProgressStats {
+    /// The number of commits parsed.
+    pub commits: u64,
+    /// The number of blobs parsed, including those which were stripped.
+    pub blobs: u64,
+    /// The number of bytes of blob data parsed.
+    pub bytes: u64,
+    /// The number of commits to be exported, when known.
+    pub total_commits: Option<u64>,
+    /// The time since filtering started.
+    pub elapsed: Duration,
+}
+
+/// An event in the progress of filtering, for library callers which want to
+/// display progress themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Filtering started.
+    Started { total_commits: Option<u64> },
+    /// Counters were updated. These are throttled, like the display.
+    Update(ProgressStats),
+    /// Filtering finished with these final counters.
+    Finished(ProgressStats),
+}
+
+/// How much is printed to the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Print nothing, for `--quiet`.
+    Quiet,
+    /// Print progress.
+    Normal,
+    /// Print progress and the commands being run, for `--debug`.
+    Debug,
+}
+
+/// Displays live counters of the progress of filtering on stderr. On a
+/// terminal, the status line is redrawn in place with ANSI escape sequences;
+/// otherwise, it degrades to occasional plain lines.
+///
+// Corresponds to `git-filter-repo:ProgressWriter`.
+pub struct ProgressWriter {
+    verbosity: Verbosity,
+    output: Box<dyn Write>,
+    is_terminal: bool,
+    events: Option<Sender<ProgressEvent>>,
+    stats: ProgressStats,
+    start: Instant,
+    last_update: Option<Instant>,
+    /// Whether a status line has been drawn and not yet terminated.
+    drawn: bool,
+}
+
+impl ProgressWriter {
+    /// How often the status is redrawn on a terminal.
+    const TERMINAL_INTERVAL: Duration = Duration::from_millis(100);
+    /// How often a status line is printed, when not on a terminal.
+    const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Creates a progress writer, which writes to stderr.
+    pub fn new(verbosity: Verbosity) -> Self {
+        let is_terminal = io::stderr().is_terminal();
+        ProgressWriter::with_output(verbosity, Box::new(io::stderr()), is_terminal)
+    }
+
+    /// Creates a progress writer, which writes to the given output.
+    pub fn with_output(verbosity: Verbosity, output: Box<dyn Write>, is_terminal: bool) -> Self {
+        ProgressWriter {
+            verbosity,
+            output,
+            is_terminal,
+            events: None,
+            stats: ProgressStats::default(),
+            start: Instant::now(),
+            last_update: None,
+            drawn: false,
+        }
+    }
+
+    /// Sends progress events to the receiver, in addition to displaying them.
+    #[inline]
+    pub fn set_events(&mut self, events: Sender<ProgressEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Returns the verbosity.
+    #[inline]
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Returns the counters so far.
+    #[inline]
+    pub fn stats(&self) -> ProgressStats {
+        self.stats
+    }
+
+    /// Starts timing, with the number of commits to be exported, if known.
+    pub fn start(&mut self, total_commits: Option<u64>) {
+        self.start = Instant::now();
+        self.last_update = None;
+        self.stats = ProgressStats {
+            total_commits,
+            ..ProgressStats::default()
+        };
+        self.send(ProgressEvent::Started { total_commits });
+    }
+
+    /// Counts a parsed commit.
+    pub fn commit(&mut self) {
+        self.stats.commits += 1;
+        self.update();
+    }
+
+    /// Counts a parsed blob with its size.
+    pub fn blob(&mut self, len: u64) {
+        self.stats.blobs += 1;
+        self.stats.bytes += len;
+        self.update();
+    }
+
+    /// Redraws the status, if enough time has passed since it was last drawn.
+    ///
+    // Corresponds to `git-filter-repo:ProgressWriter.show`.
+    fn update(&mut self) {
+        let now = Instant::now();
+        let interval = if self.is_terminal {
+            ProgressWriter::TERMINAL_INTERVAL
+        } else {
+            ProgressWriter::PLAIN_INTERVAL
+        };
+        if self
+            .last_update
+            .is_some_and(|last| now.duration_since(last) < interval)
+        {
+            return;
+        }
+        self.last_update = Some(now);
+        self.stats.elapsed = now.duration_since(self.start);
+        self.send(ProgressEvent::Update(self.stats));
+        self.draw();
+    }
+
+    /// Draws the final status and terminates the status line.
+    ///
+    // Corresponds to `git-filter-repo:ProgressWriter.finish`.
+    pub fn finish(&mut self) {
+        self.stats.elapsed = self.start.elapsed();
+        self.send(ProgressEvent::Finished(self.stats));
+        if self.stats.commits != 0 || self.stats.blobs != 0 {
+            self.draw();
+        }
+        if self.drawn && self.is_terminal {
+            let _ = writeln!(self.output);
+        }
+        let _ = self.output.flush();
+        self.drawn = false;
+    }
+
+    fn draw(&mut self) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        // Errors writing progress are not worth failing the filter over.
+        let _ = if self.is_terminal {
+            // Return to the start of the line and clear it.
+            write!(self.output, "\r\x1b[K{}", self.stats)
+        } else {
+            writeln!(self.output, "{}", self.stats)
+        };
+        let _ = self.output.flush();
+        self.drawn = true;
+    }
+
+    fn send(&mut self, event: ProgressEvent) {
+        if let Some(events) = &self.events {
+            if events.send(event).is_err() {
+                // The receiver hung up, so stop sending.
+                self.events = None;
+            }
+        }
+    }
+}
+
+impl ProgressStats {
+    /// Estimates the time remaining from the rate of commits so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total_commits?;
+        if self.commits == 0 || self.commits > total {
+            return None;
+        }
+        let remaining = (total - self.commits) as f64;
+        let per_commit = self.elapsed.as_secs_f64() / self.commits as f64;
+        Some(Duration::from_secs_f64(remaining * per_commit))
+    }
+}
+
+impl Display for ProgressStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Parsed {}", self.commits)?;
+        if let Some(total) = self.total_commits {
+            write!(f, "/{total}")?;
+        }
+        write!(
+            f,
+            " commits, {} blobs ({})",
+            self.blobs,
+            ByteSize(self.bytes)
+        )?;
+        if let Some(eta) = self.eta() {
+            let secs = eta.as_secs();
+            write!(f, ", ETA {}:{:02}", secs / 60, secs % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a number of bytes with binary units.
+struct ByteSize(u64);
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, sync::mpsc};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn display() {
+        let stats = ProgressStats {
+            commits: 25,
+            blobs: 40,
+            bytes: 3 * 1024 * 1024 + 512 * 1024,
+            total_commits: Some(100),
+            elapsed: Duration::from_secs(10),
+        };
+        assert_eq!(
+            stats.to_string(),
+            "Parsed 25/100 commits, 40 blobs (3.5 MiB), ETA 0:30",
+        );
+        let stats = ProgressStats {
+            total_commits: None,
+            bytes: 100,
+            ..stats
+        };
+        assert_eq!(stats.to_string(), "Parsed 25 commits, 40 blobs (100 B)");
+    }
+
+    #[test]
+    fn output() {
+        let write = |verbosity, is_terminal| {
+            let buf = SharedBuf::default();
+            let mut progress =
+                ProgressWriter::with_output(verbosity, Box::new(buf.clone()), is_terminal);
+            progress.start(None);
+            progress.blob(2048);
+            progress.commit();
+            progress.finish();
+            let out = buf.0.borrow().clone();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            write(Verbosity::Normal, true),
+            "\r\x1b[KParsed 0 commits, 1 blobs (2.0 KiB)\
+             \r\x1b[KParsed 1 commits, 1 blobs (2.0 KiB)\n",
+        );
+        assert_eq!(
+            write(Verbosity::Normal, false),
+            "Parsed 0 commits, 1 blobs (2.0 KiB)\n\
+             Parsed 1 commits, 1 blobs (2.0 KiB)\n",
+        );
+        assert_eq!(write(Verbosity::Quiet, true), "");
+    }
+
+    #[test]
+    fn events() {
+        let (tx, rx) = mpsc::channel();
+        let mut progress =
+            ProgressWriter::with_output(Verbosity::Quiet, Box::new(io::sink()), false);
+        progress.set_events(tx);
+        progress.start(Some(2));
+        progress.commit();
+        progress.commit();
+        progress.finish();
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            events[0],
+            ProgressEvent::Started {
+                total_commits: Some(2)
+            }
+        );
+        assert!(matches!(events[1], ProgressEvent::Update(stats) if stats.commits == 1));
+        assert!(matches!(
+            events.last(),
+            Some(ProgressEvent::Finished(stats)) if stats.commits == 2 && stats.total_commits == Some(2)
+        ));
+    }
+}
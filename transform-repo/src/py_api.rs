@@ -0,0 +1,2868 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! The library API of git-filter-repo, recreated as Python classes backed by
+//! the Rust types, for callbacks and for scripts which import
+//! `git_filter_repo`.
+
+// The impls generated by `#[pymethods]` in pyo3 0.20 trigger this lint on
+// newer compilers.
+#![allow(non_local_definitions)]
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{Cursor, Write},
+    mem,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use bstr::ByteSlice;
+use fast_export::{
+    command::{
+        Branch, Commit as CommitCommand, CommitSignature, Commitish, DataHeader, Encoding,
+        MapBytes, Mark, Mode, Objectish, OriginalOid, PersonIdent, Reset as ResetCommand,
+        Tag as TagCommand, TagName,
+    },
+    parse::{
+        Change, DataRef, FileCopyChange, FileDeleteChange, FileModifyChange, FileRenameChange,
+    },
+    Dump,
+};
+use pyo3::{
+    exceptions::{PyAttributeError, PyRuntimeError, PySystemExit, PyTypeError, PyValueError},
+    prelude::*,
+    types::{IntoPyDict, PyBytes, PyDict, PyFunction, PyList, PyTuple},
+};
+
+use crate::{
+    blob_store::{BlobStore, FileContents},
+    callback::{CommitAction, CommitMetadata},
+    filter::{self, RepoFilter},
+    options::{FilteringOptions, OptionsError},
+    parser::{self, BlobObject, Bytes, CommitObject, FastExportParser, Handler, StreamWriter},
+    py_bytes::PyLazyBytes,
+    reencode::Reencode,
+    sanity,
+};
+
+thread_local! {
+    /// IDs which were renamed by `record_id_rename`.
+    static ID_RENAMES: RefCell<HashMap<u64, u64>> = RefCell::new(HashMap::new());
+    /// The counter of IDs for new objects, which is shared by every
+    /// `RepoFilter`, since objects may be created before the filter, which
+    /// inserts them.
+    static NEW_MARKS: parser::NewMarks = parser::NewMarks::new();
+    /// The blob store, while a callback is running, and the file change, whose
+    /// name a filename callback is processing.
+    static CALLBACK_BLOBS: RefCell<Option<(BlobStore, Option<parser::FileChange<'static>>)>> =
+        const { RefCell::new(None) };
+}
+
+/// Runs a callback with access to the contents of blobs.
+fn with_blobs<T>(
+    blobs: &BlobStore,
+    file: Option<&parser::FileChange<'_>>,
+    f: impl FnOnce() -> T,
+) -> T {
+    let file = file.map(|file| file.clone().into_owned());
+    let prev = CALLBACK_BLOBS.with(|cell| cell.replace(Some((blobs.clone(), file))));
+    let res = f();
+    CALLBACK_BLOBS.with(|cell| cell.replace(prev));
+    res
+}
+
+/// Reads the contents of a file modification from the blob store of the
+/// running callback.
+fn read_blob(change: Option<&parser::FileChange<'_>>) -> PyResult<Option<Vec<u8>>> {
+    CALLBACK_BLOBS.with(|cell| {
+        let cell = cell.borrow();
+        let Some((blobs, file)) = &*cell else {
+            return Err(PyRuntimeError::new_err(
+                "blob contents can only be read in callbacks",
+            ));
+        };
+        let Some(change) = change.or(file.as_ref()) else {
+            return Ok(None);
+        };
+        Ok(blobs.read_change(change)?)
+    })
+}
+
+/// Returns the contents of the file, whose name a filename callback is
+/// processing, or `None` for deletions, renames, and submodules.
+#[pyfunction]
+pub fn file_data(py: Python<'_>) -> PyResult<Option<Py<PyBytes>>> {
+    Ok(read_blob(None)?.map(|data| py_bytes(py, &data)))
+}
+
+/// Returns the ID for a new object, which is its mark.
+///
+// Corresponds to `git-filter-repo:_IDs.new`.
+fn new_id() -> u64 {
+    NEW_MARKS.with(|marks| marks.next().mark.get())
+}
+
+/// Returns the ID, under which callbacks see a mark.
+///
+// Corresponds to `git-filter-repo:_IDs.translate`.
+fn mark_id(mark: Mark) -> u64 {
+    let id = mark.mark.get();
+    ID_RENAMES.with(|renames| renames.borrow().get(&id).copied().unwrap_or(id))
+}
+
+fn to_mark(id: u64) -> PyResult<Mark> {
+    NonZeroU64::new(id)
+        .map(|mark| Mark { mark })
+        .ok_or_else(|| PyValueError::new_err("object IDs must be positive"))
+}
+
+/// Records that references to the object with ID `old_id` should instead
+/// refer to `new_id`. It only affects the IDs seen by callbacks.
+///
+// Corresponds to `git-filter-repo:record_id_rename`.
+#[pyfunction]
+pub fn record_id_rename(old_id: u64, new_id: u64) {
+    ID_RENAMES.with(|renames| {
+        let mut renames = renames.borrow_mut();
+        // Objects which were renamed to `old_id` follow it.
+        for id in renames.values_mut() {
+            if *id == old_id {
+                *id = new_id;
+            }
+        }
+        renames.insert(old_id, new_id);
+    });
+}
+
+#[inline]
+fn py_bytes(py: Python<'_>, bytes: &[u8]) -> Py<PyBytes> {
+    PyBytes::new(py, bytes).into()
+}
+
+#[inline]
+fn owned(py: Python<'_>, bytes: &Py<PyBytes>) -> Bytes<'static> {
+    Cow::Owned(bytes.as_bytes(py).to_vec())
+}
+
+pub(crate) fn objectish_to_py(py: Python<'_>, objectish: &Objectish<Bytes<'_>>) -> PyObject {
+    match objectish {
+        Objectish::Mark(mark) => mark_id(*mark).into_py(py),
+        Objectish::BranchOrOid(oid) => py_bytes(py, oid).into_py(py),
+    }
+}
+
+pub(crate) fn objectish_from_py(objectish: &PyAny) -> PyResult<Objectish<Bytes<'static>>> {
+    match objectish.extract::<u64>() {
+        Ok(id) => Ok(Objectish::Mark(to_mark(id)?)),
+        Err(_) => Ok(Objectish::BranchOrOid(Cow::Owned(
+            objectish.extract::<&[u8]>()?.to_vec(),
+        ))),
+    }
+}
+
+/// Writes the fast-import command for an object to a Python file.
+fn dump_to_file<C: Dump>(py: Python<'_>, command: &C, file: &PyAny) -> PyResult<()> {
+    let mut buf = Vec::new();
+    command.dump(&mut buf)?;
+    file.call_method1("write", (PyBytes::new(py, &buf),))?;
+    Ok(())
+}
+
+/// Moves bytes out of a Rust object, to be copied for Python when it reads
+/// them. Bytes borrowed from the parser are first copied out of its buffer.
+#[inline]
+fn lazy(bytes: &mut Bytes<'_>) -> PyLazyBytes {
+    PyLazyBytes::new(mem::take(bytes))
+}
+
+/// Gives bytes back to Rust, by moving them when `take`, or else by copying
+/// them.
+#[inline]
+fn lazy_to_rust(py: Python<'_>, bytes: &mut PyLazyBytes, take: bool) -> Bytes<'static> {
+    if take {
+        bytes.take(py)
+    } else {
+        bytes.to_bytes(py)
+    }
+}
+
+/// Returns whether Python holds no references to an object, which was given
+/// to a callback, so that its contents can be moved out instead of copied.
+#[inline]
+fn is_unshared<T>(obj: &Py<T>, py: Python<'_>) -> bool {
+    obj.get_refcnt(py) == 1
+}
+
+/// A blob, as given to blob callbacks or created to be inserted.
+///
+// Corresponds to `git-filter-repo:Blob`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Blob {
+    #[pyo3(get, set)]
+    pub id: u64,
+    #[pyo3(get, set)]
+    pub old_id: u64,
+    original_id: Option<PyLazyBytes>,
+    data: PyLazyBytes,
+    /// Whether the blob has been written or skipped, so it should not be
+    /// written again.
+    #[pyo3(get)]
+    pub dumped: bool,
+}
+
+#[pymethods]
+impl Blob {
+    #[new]
+    #[pyo3(signature = (data, original_id = None))]
+    fn new(data: Py<PyBytes>, original_id: Option<Py<PyBytes>>) -> Self {
+        let id = new_id();
+        Blob {
+            id,
+            old_id: id,
+            original_id: original_id.map(PyLazyBytes::from_python),
+            data: PyLazyBytes::from_python(data),
+            dumped: false,
+        }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "blob"
+    }
+
+    #[getter]
+    fn original_id(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.original_id.as_mut().map(|oid| oid.get(py))
+    }
+
+    #[setter]
+    fn set_original_id(&mut self, original_id: Option<Py<PyBytes>>) {
+        self.original_id = original_id.map(PyLazyBytes::from_python);
+    }
+
+    #[getter]
+    fn data(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.data.get(py)
+    }
+
+    #[setter]
+    fn set_data(&mut self, data: Py<PyBytes>) {
+        self.data.set(data);
+    }
+
+    // Corresponds to `git-filter-repo:_GitElementWithId.skip`.
+    #[pyo3(signature = (new_id = None))]
+    fn skip(&mut self, new_id: Option<u64>) {
+        self.dumped = true;
+        if let Some(new_id) = new_id {
+            record_id_rename(self.old_id, new_id);
+        }
+    }
+
+    fn dump(&mut self, py: Python<'_>, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        dump_to_file(py, &self.copy_out(py)?, file)
+    }
+}
+
+impl Blob {
+    /// Moves the contents of a blob into an object for a callback. They are
+    /// given back by `Blob::restore`.
+    pub(crate) fn from_rust(blob: &mut BlobObject<'_>) -> Self {
+        let id = blob.mark.map_or_else(new_id, mark_id);
+        Blob {
+            id,
+            old_id: id,
+            original_id: blob
+                .original_oid
+                .take()
+                .map(|oid| PyLazyBytes::new(oid.oid)),
+            data: PyLazyBytes::new(Cow::Owned(mem::take(&mut blob.data))),
+            dumped: false,
+        }
+    }
+
+    /// Converts a blob back after a callback.
+    pub(crate) fn restore(obj: &Py<Self>, py: Python<'_>) -> PyResult<BlobObject<'static>> {
+        let take = is_unshared(obj, py);
+        obj.borrow_mut(py).convert(py, take)
+    }
+
+    /// Copies the contents out, to write the object while Python holds it.
+    pub(crate) fn copy_out(&mut self, py: Python<'_>) -> PyResult<BlobObject<'static>> {
+        self.convert(py, false)
+    }
+
+    fn convert(&mut self, py: Python<'_>, take: bool) -> PyResult<BlobObject<'static>> {
+        let bytes = |bytes: &mut PyLazyBytes| lazy_to_rust(py, bytes, take);
+        Ok(BlobObject {
+            mark: Some(to_mark(self.id)?),
+            original_oid: self
+                .original_id
+                .as_mut()
+                .map(|oid| OriginalOid { oid: bytes(oid) }),
+            data: bytes(&mut self.data).into_owned(),
+        })
+    }
+}
+
+/// A change to a file in a commit. For modifications, `blob_id` is the ID of
+/// the blob with the contents, or its hash. For renames and copies,
+/// `filename` is the source and `blob_id` is the destination.
+///
+// Corresponds to `git-filter-repo:FileChange`.
+#[pyclass(module = "git_filter_repo")]
+pub struct FileChange {
+    #[pyo3(get, set)]
+    pub r#type: Py<PyBytes>,
+    #[pyo3(get, set)]
+    pub filename: Option<Py<PyBytes>>,
+    #[pyo3(get, set)]
+    pub mode: Option<Py<PyBytes>>,
+    #[pyo3(get, set)]
+    pub blob_id: Option<PyObject>,
+    /// The contents of a modification with inline data, for which `blob_id`
+    /// is `None`.
+    inline_data: Option<Vec<u8>>,
+}
+
+#[pymethods]
+impl FileChange {
+    #[new]
+    #[pyo3(signature = (type_, filename = None, id_ = None, mode = None))]
+    fn new(
+        py: Python<'_>,
+        type_: Py<PyBytes>,
+        filename: Option<Py<PyBytes>>,
+        id_: Option<PyObject>,
+        mode: Option<Py<PyBytes>>,
+    ) -> PyResult<Self> {
+        let fields = (filename.is_some(), id_.is_some(), mode.is_some());
+        let valid = match type_.as_bytes(py) {
+            b"M" => fields == (true, true, true),
+            b"D" => fields == (true, false, false),
+            b"R" | b"C" => fields == (true, true, false),
+            b"DELETEALL" => fields == (false, false, false),
+            _ => false,
+        };
+        if !valid {
+            return Err(PyValueError::new_err(format!(
+                "invalid FileChange of type {}",
+                type_.as_bytes(py).as_bstr(),
+            )));
+        }
+        Ok(FileChange {
+            r#type: type_,
+            filename,
+            mode,
+            blob_id: id_,
+            inline_data: None,
+        })
+    }
+
+    /// Returns the contents of a modification, which are read on demand, or
+    /// `None` for other changes and submodules. It can only be called in
+    /// callbacks.
+    ///
+    /// # Differences from filter-repo
+    ///
+    /// filter-repo does not give commit callbacks access to the contents of
+    /// files.
+    fn data(&self, py: Python<'_>) -> PyResult<Option<Py<PyBytes>>> {
+        let change = self.to_rust(py)?;
+        Ok(read_blob(Some(&change))?.map(|data| py_bytes(py, &data)))
+    }
+
+    /// Replaces the contents of a modification with a new blob, which is
+    /// written before the commit. It can only be called in commit callbacks.
+    ///
+    /// # Differences from filter-repo
+    ///
+    /// filter-repo does not allow commit callbacks to modify blobs.
+    fn set_data(&mut self, py: Python<'_>, data: &[u8]) -> PyResult<()> {
+        let mut change = self.to_rust(py)?;
+        CALLBACK_BLOBS.with(|cell| match &*cell.borrow() {
+            Some((blobs, None)) => Ok(blobs.set_contents(&mut change, data.to_vec())?),
+            _ => Err(PyRuntimeError::new_err(
+                "blob contents can only be replaced in commit callbacks",
+            )),
+        })?;
+        if let Change::FileModify(FileModifyChange {
+            data_ref: DataRef::Mark(mark),
+            ..
+        }) = change.change
+        {
+            self.blob_id = Some(mark_id(mark).into_py(py));
+            self.inline_data = None;
+        }
+        Ok(())
+    }
+}
+
+impl FileChange {
+    /// Converts a file change, unless it is of a type which filter-repo does
+    /// not model, such as a note.
+    pub(crate) fn from_rust(py: Python<'_>, change: &parser::FileChange<'_>) -> Option<Self> {
+        let (kind, filename, mode, blob_id) = match &change.change {
+            Change::FileModify(FileModifyChange {
+                data_ref,
+                mode,
+                path,
+            }) => {
+                let blob_id = match data_ref {
+                    DataRef::Mark(mark) => Some(mark_id(*mark).into_py(py)),
+                    DataRef::Oid(oid) => Some(py_bytes(py, oid).into_py(py)),
+                    DataRef::Inline(_) => None,
+                };
+                let mode = py_bytes(py, format!("{:06o}", *mode as u16).as_bytes());
+                (&b"M"[..], Some(path), Some(mode), blob_id)
+            }
+            Change::FileDelete(FileDeleteChange { path }) => (&b"D"[..], Some(path), None, None),
+            Change::FileRename(FileRenameChange { source, dest }) => (
+                &b"R"[..],
+                Some(source),
+                None,
+                Some(py_bytes(py, dest).into_py(py)),
+            ),
+            Change::FileCopy(FileCopyChange { source, dest }) => (
+                &b"C"[..],
+                Some(source),
+                None,
+                Some(py_bytes(py, dest).into_py(py)),
+            ),
+            Change::FileDeleteAll => (&b"DELETEALL"[..], None, None, None),
+            Change::NoteModify(_) | Change::Ls(_) | Change::CatBlob(_) => return None,
+        };
+        Some(FileChange {
+            r#type: py_bytes(py, kind),
+            filename: filename.map(|path| py_bytes(py, path)),
+            mode,
+            blob_id,
+            inline_data: change.inline_data.clone(),
+        })
+    }
+
+    pub(crate) fn to_rust(&self, py: Python<'_>) -> PyResult<parser::FileChange<'static>> {
+        let kind = self.r#type.as_bytes(py);
+        let invalid =
+            || PyValueError::new_err(format!("incomplete FileChange of type {}", kind.as_bstr()));
+        let path = || {
+            self.filename
+                .as_ref()
+                .map(|path| owned(py, path))
+                .ok_or_else(invalid)
+        };
+        let dest = || -> PyResult<Bytes<'static>> {
+            let dest = self.blob_id.as_ref().ok_or_else(invalid)?;
+            Ok(Cow::Owned(dest.extract::<&[u8]>(py)?.to_vec()))
+        };
+        let mut inline_data = None;
+        let change = match kind {
+            b"M" => {
+                let mode = self.mode.as_ref().ok_or_else(invalid)?.as_bytes(py);
+                let mode = mode
+                    .to_str()
+                    .ok()
+                    .and_then(|mode| u16::from_str_radix(mode, 8).ok())
+                    .ok_or_else(|| {
+                        PyValueError::new_err(format!("invalid mode {}", mode.as_bstr()))
+                    })?;
+                let data_ref = match (&self.blob_id, &self.inline_data) {
+                    (Some(blob_id), _) => match objectish_from_py(blob_id.as_ref(py))? {
+                        Objectish::Mark(mark) => DataRef::Mark(mark),
+                        Objectish::BranchOrOid(oid) => DataRef::Oid(oid),
+                    },
+                    (None, Some(data)) => {
+                        inline_data = Some(data.clone());
+                        DataRef::Inline(DataHeader::Counted {
+                            len: data.len() as u64,
+                        })
+                    }
+                    (None, None) => return Err(invalid()),
+                };
+                Change::FileModify(FileModifyChange {
+                    data_ref,
+                    mode: Mode::canonicalize(mode),
+                    path: path()?,
+                })
+            }
+            b"D" => Change::FileDelete(FileDeleteChange { path: path()? }),
+            b"R" => Change::FileRename(FileRenameChange {
+                source: path()?,
+                dest: dest()?,
+            }),
+            b"C" => Change::FileCopy(FileCopyChange {
+                source: path()?,
+                dest: dest()?,
+            }),
+            b"DELETEALL" => Change::FileDeleteAll,
+            _ => return Err(invalid()),
+        };
+        Ok(parser::FileChange {
+            change,
+            inline_data,
+        })
+    }
+}
+
+/// A commit, as given to commit callbacks or created to be inserted. Each
+/// parent is the ID of a commit in the stream or the hash of a commit outside
+/// it.
+///
+// Corresponds to `git-filter-repo:Commit`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Commit {
+    branch: PyLazyBytes,
+    author_name: PyLazyBytes,
+    author_email: PyLazyBytes,
+    author_date: PyLazyBytes,
+    committer_name: PyLazyBytes,
+    committer_email: PyLazyBytes,
+    committer_date: PyLazyBytes,
+    message: PyLazyBytes,
+    file_changes: LazyFileChanges,
+    #[pyo3(get, set)]
+    pub parents: Py<PyList>,
+    original_id: Option<PyLazyBytes>,
+    encoding: Option<PyLazyBytes>,
+    #[pyo3(get, set)]
+    pub id: u64,
+    #[pyo3(get, set)]
+    pub old_id: u64,
+    #[pyo3(get)]
+    pub dumped: bool,
+    /// The commit, which references to this skipped commit are rewritten to.
+    pub(crate) skipped_to: Option<PyObject>,
+    /// Whether the commit had an `author` command, which is omitted when the
+    /// author is the same as the committer.
+    has_author: bool,
+    signatures: Vec<CommitSignature<Bytes<'static>>>,
+}
+
+/// The file changes of a commit, which are converted to `FileChange` objects
+/// only when Python reads them.
+enum LazyFileChanges {
+    /// The changes from Rust, which Python has not accessed.
+    Rust(Vec<parser::FileChange<'static>>),
+    /// The changes as a Python list, once Python has read or assigned them,
+    /// and the changes from Rust, which they replaced. Changes which
+    /// filter-repo does not model, such as notes, are kept from the latter.
+    Python {
+        list: Py<PyList>,
+        orig: Vec<parser::FileChange<'static>>,
+    },
+}
+
+#[pymethods]
+impl Commit {
+    #[new]
+    #[pyo3(signature = (
+        branch,
+        author_name,
+        author_email,
+        author_date,
+        committer_name,
+        committer_email,
+        committer_date,
+        message,
+        file_changes,
+        parents,
+        original_id = None,
+        encoding = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        branch: Py<PyBytes>,
+        author_name: Py<PyBytes>,
+        author_email: Py<PyBytes>,
+        author_date: Py<PyBytes>,
+        committer_name: Py<PyBytes>,
+        committer_email: Py<PyBytes>,
+        committer_date: Py<PyBytes>,
+        message: Py<PyBytes>,
+        file_changes: Py<PyList>,
+        parents: Py<PyList>,
+        original_id: Option<Py<PyBytes>>,
+        encoding: Option<Py<PyBytes>>,
+    ) -> Self {
+        let id = new_id();
+        Commit {
+            branch: PyLazyBytes::from_python(branch),
+            author_name: PyLazyBytes::from_python(author_name),
+            author_email: PyLazyBytes::from_python(author_email),
+            author_date: PyLazyBytes::from_python(author_date),
+            committer_name: PyLazyBytes::from_python(committer_name),
+            committer_email: PyLazyBytes::from_python(committer_email),
+            committer_date: PyLazyBytes::from_python(committer_date),
+            message: PyLazyBytes::from_python(message),
+            file_changes: LazyFileChanges::Python {
+                list: file_changes,
+                orig: Vec::new(),
+            },
+            parents,
+            original_id: original_id.map(PyLazyBytes::from_python),
+            encoding: encoding.map(PyLazyBytes::from_python),
+            id,
+            old_id: id,
+            dumped: false,
+            skipped_to: None,
+            has_author: true,
+            signatures: Vec::new(),
+        }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "commit"
+    }
+
+    #[getter]
+    fn branch(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.branch.get(py)
+    }
+
+    #[setter]
+    fn set_branch(&mut self, branch: Py<PyBytes>) {
+        self.branch.set(branch);
+    }
+
+    #[getter]
+    fn author_name(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.author_name.get(py)
+    }
+
+    #[setter]
+    fn set_author_name(&mut self, author_name: Py<PyBytes>) {
+        self.author_name.set(author_name);
+    }
+
+    #[getter]
+    fn author_email(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.author_email.get(py)
+    }
+
+    #[setter]
+    fn set_author_email(&mut self, author_email: Py<PyBytes>) {
+        self.author_email.set(author_email);
+    }
+
+    #[getter]
+    fn author_date(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.author_date.get(py)
+    }
+
+    #[setter]
+    fn set_author_date(&mut self, author_date: Py<PyBytes>) {
+        self.author_date.set(author_date);
+    }
+
+    #[getter]
+    fn committer_name(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.committer_name.get(py)
+    }
+
+    #[setter]
+    fn set_committer_name(&mut self, committer_name: Py<PyBytes>) {
+        self.committer_name.set(committer_name);
+    }
+
+    #[getter]
+    fn committer_email(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.committer_email.get(py)
+    }
+
+    #[setter]
+    fn set_committer_email(&mut self, committer_email: Py<PyBytes>) {
+        self.committer_email.set(committer_email);
+    }
+
+    #[getter]
+    fn committer_date(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.committer_date.get(py)
+    }
+
+    #[setter]
+    fn set_committer_date(&mut self, committer_date: Py<PyBytes>) {
+        self.committer_date.set(committer_date);
+    }
+
+    #[getter]
+    fn message(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.message.get(py)
+    }
+
+    #[setter]
+    fn set_message(&mut self, message: Py<PyBytes>) {
+        self.message.set(message);
+    }
+
+    #[getter]
+    fn file_changes(&mut self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        if let LazyFileChanges::Rust(changes) = &mut self.file_changes {
+            let orig = mem::take(changes);
+            let list = PyList::empty(py);
+            for change in &orig {
+                if let Some(change) = FileChange::from_rust(py, change) {
+                    list.append(Py::new(py, change)?)?;
+                }
+            }
+            self.file_changes = LazyFileChanges::Python {
+                list: list.into(),
+                orig,
+            };
+        }
+        match &self.file_changes {
+            LazyFileChanges::Python { list, .. } => Ok(list.clone_ref(py)),
+            LazyFileChanges::Rust(_) => unreachable!(),
+        }
+    }
+
+    #[setter]
+    fn set_file_changes(&mut self, file_changes: Py<PyList>) {
+        let orig = match &mut self.file_changes {
+            LazyFileChanges::Rust(orig) | LazyFileChanges::Python { orig, .. } => mem::take(orig),
+        };
+        self.file_changes = LazyFileChanges::Python {
+            list: file_changes,
+            orig,
+        };
+    }
+
+    #[getter]
+    fn original_id(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.original_id.as_mut().map(|oid| oid.get(py))
+    }
+
+    #[setter]
+    fn set_original_id(&mut self, original_id: Option<Py<PyBytes>>) {
+        self.original_id = original_id.map(PyLazyBytes::from_python);
+    }
+
+    #[getter]
+    fn encoding(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.encoding.as_mut().map(|encoding| encoding.get(py))
+    }
+
+    #[setter]
+    fn set_encoding(&mut self, encoding: Option<Py<PyBytes>>) {
+        self.encoding = encoding.map(PyLazyBytes::from_python);
+    }
+
+    /// Returns the first parent, or `None` for a root commit.
+    fn first_parent(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let parents = self.parents.as_ref(py);
+        Ok(if parents.is_empty() {
+            None
+        } else {
+            Some(parents.get_item(0)?.into_py(py))
+        })
+    }
+
+    /// Drops this commit, so that references to it refer to `new_id` or, when
+    /// it is `None`, to nothing.
+    ///
+    // Corresponds to `git-filter-repo:Commit.skip`.
+    #[pyo3(signature = (new_id = None))]
+    fn skip(&mut self, new_id: Option<PyObject>) {
+        self.dumped = true;
+        self.skipped_to = new_id;
+    }
+
+    fn dump(&mut self, py: Python<'_>, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        dump_to_file(py, &self.copy_out(py)?, file)
+    }
+}
+
+impl Commit {
+    /// Moves the contents of a commit into an object for a callback. They are
+    /// given back by `Commit::restore`.
+    pub(crate) fn from_rust(
+        py: Python<'_>,
+        commit: &mut CommitCommand<Bytes<'_>>,
+        changes: &mut Vec<parser::FileChange<'_>>,
+    ) -> Self {
+        let id = commit.mark.map_or_else(new_id, mark_id);
+        let has_author = commit.author.is_some();
+        let author = commit
+            .author
+            .take()
+            .unwrap_or_else(|| commit.committer.clone());
+        let parents = PyList::new(
+            py,
+            commit
+                .from
+                .iter()
+                .chain(&commit.merge)
+                .map(|parent| objectish_to_py(py, &parent.commit))
+                .collect::<Vec<_>>(),
+        );
+        Commit {
+            branch: lazy(&mut commit.branch.branch),
+            author_name: PyLazyBytes::new(author.name),
+            author_email: PyLazyBytes::new(author.email),
+            author_date: PyLazyBytes::new(author.date),
+            committer_name: lazy(&mut commit.committer.name),
+            committer_email: lazy(&mut commit.committer.email),
+            committer_date: lazy(&mut commit.committer.date),
+            message: lazy(&mut commit.message),
+            file_changes: LazyFileChanges::Rust(
+                mem::take(changes)
+                    .into_iter()
+                    .map(parser::FileChange::into_owned)
+                    .collect(),
+            ),
+            parents: parents.into(),
+            original_id: commit
+                .original_oid
+                .take()
+                .map(|oid| PyLazyBytes::new(oid.oid)),
+            encoding: commit
+                .encoding
+                .take()
+                .map(|encoding| PyLazyBytes::new(encoding.encoding)),
+            id,
+            old_id: id,
+            dumped: false,
+            skipped_to: None,
+            has_author,
+            signatures: mem::take(&mut commit.signatures).map_bytes(&mut parser::owned),
+        }
+    }
+
+    /// Converts a commit back after a callback. When Python accessed the file
+    /// changes, the changes, which were given to it, are also returned.
+    pub(crate) fn restore(
+        obj: &Py<Self>,
+        py: Python<'_>,
+    ) -> PyResult<(
+        CommitObject<'static>,
+        Option<Vec<parser::FileChange<'static>>>,
+    )> {
+        let take = is_unshared(obj, py);
+        obj.borrow_mut(py).convert(py, take)
+    }
+
+    /// Copies the contents out, to write the object while Python holds it.
+    pub(crate) fn copy_out(&mut self, py: Python<'_>) -> PyResult<CommitObject<'static>> {
+        Ok(self.convert(py, false)?.0)
+    }
+
+    fn convert(
+        &mut self,
+        py: Python<'_>,
+        take: bool,
+    ) -> PyResult<(
+        CommitObject<'static>,
+        Option<Vec<parser::FileChange<'static>>>,
+    )> {
+        let bytes = |bytes: &mut PyLazyBytes| lazy_to_rust(py, bytes, take);
+        let same_author = self.author_name.as_bytes(py) == self.committer_name.as_bytes(py)
+            && self.author_email.as_bytes(py) == self.committer_email.as_bytes(py)
+            && self.author_date.as_bytes(py) == self.committer_date.as_bytes(py);
+        let author = (self.has_author || !same_author).then(|| PersonIdent {
+            name: bytes(&mut self.author_name),
+            email: bytes(&mut self.author_email),
+            date: bytes(&mut self.author_date),
+        });
+        let committer = PersonIdent {
+            name: bytes(&mut self.committer_name),
+            email: bytes(&mut self.committer_email),
+            date: bytes(&mut self.committer_date),
+        };
+        let mut parents = self
+            .parents
+            .as_ref(py)
+            .iter()
+            .map(|parent| {
+                Ok(Commitish {
+                    commit: objectish_from_py(parent)?,
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?
+            .into_iter();
+        let (changes, orig_changes) = match &mut self.file_changes {
+            LazyFileChanges::Rust(changes) if take => (mem::take(changes), None),
+            LazyFileChanges::Rust(changes) => (changes.clone(), None),
+            LazyFileChanges::Python { list, orig } => {
+                let mut changes = list
+                    .as_ref(py)
+                    .iter()
+                    .map(|change| change.extract::<PyRef<'_, FileChange>>()?.to_rust(py))
+                    .collect::<PyResult<Vec<_>>>()?;
+                changes.extend(orig.iter().filter(|change| !change.is_modeled()).cloned());
+                let orig = if take { mem::take(orig) } else { orig.clone() };
+                (changes, Some(orig))
+            }
+        };
+        let commit = CommitCommand {
+            branch: Branch {
+                branch: bytes(&mut self.branch),
+            },
+            mark: Some(to_mark(self.id)?),
+            original_oid: self
+                .original_id
+                .as_mut()
+                .map(|oid| OriginalOid { oid: bytes(oid) }),
+            author,
+            committer,
+            signatures: if take {
+                mem::take(&mut self.signatures)
+            } else {
+                self.signatures.clone()
+            },
+            encoding: self.encoding.as_mut().map(|encoding| Encoding {
+                encoding: bytes(encoding),
+            }),
+            message: bytes(&mut self.message),
+            from: parents.next(),
+            merge: parents.collect(),
+        };
+        Ok((CommitObject { commit, changes }, orig_changes))
+    }
+}
+
+/// An annotated tag, as given to tag callbacks or created to be inserted.
+/// `ref` is the name of the tag, without `refs/tags/`.
+///
+// Corresponds to `git-filter-repo:Tag`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Tag {
+    r#ref: PyLazyBytes,
+    #[pyo3(get, set)]
+    pub from_ref: PyObject,
+    tagger_name: Option<PyLazyBytes>,
+    tagger_email: Option<PyLazyBytes>,
+    tagger_date: Option<PyLazyBytes>,
+    message: PyLazyBytes,
+    original_id: Option<PyLazyBytes>,
+    #[pyo3(get, set)]
+    pub id: u64,
+    #[pyo3(get, set)]
+    pub old_id: u64,
+    #[pyo3(get)]
+    pub dumped: bool,
+    signature: Option<Bytes<'static>>,
+}
+
+#[pymethods]
+impl Tag {
+    #[new]
+    #[pyo3(signature = (
+        ref_,
+        from_ref,
+        tagger_name,
+        tagger_email,
+        tagger_date,
+        tag_msg,
+        original_id = None,
+    ))]
+    fn new(
+        ref_: Py<PyBytes>,
+        from_ref: PyObject,
+        tagger_name: Option<Py<PyBytes>>,
+        tagger_email: Option<Py<PyBytes>>,
+        tagger_date: Option<Py<PyBytes>>,
+        tag_msg: Py<PyBytes>,
+        original_id: Option<Py<PyBytes>>,
+    ) -> Self {
+        let id = new_id();
+        Tag {
+            r#ref: PyLazyBytes::from_python(ref_),
+            from_ref,
+            tagger_name: tagger_name.map(PyLazyBytes::from_python),
+            tagger_email: tagger_email.map(PyLazyBytes::from_python),
+            tagger_date: tagger_date.map(PyLazyBytes::from_python),
+            message: PyLazyBytes::from_python(tag_msg),
+            original_id: original_id.map(PyLazyBytes::from_python),
+            id,
+            old_id: id,
+            dumped: false,
+            signature: None,
+        }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "tag"
+    }
+
+    #[getter(r#ref)]
+    fn get_ref(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.r#ref.get(py)
+    }
+
+    #[setter(r#ref)]
+    fn set_ref(&mut self, r#ref: Py<PyBytes>) {
+        self.r#ref.set(r#ref);
+    }
+
+    #[getter]
+    fn tagger_name(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.tagger_name.as_mut().map(|name| name.get(py))
+    }
+
+    #[setter]
+    fn set_tagger_name(&mut self, tagger_name: Option<Py<PyBytes>>) {
+        self.tagger_name = tagger_name.map(PyLazyBytes::from_python);
+    }
+
+    #[getter]
+    fn tagger_email(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.tagger_email.as_mut().map(|email| email.get(py))
+    }
+
+    #[setter]
+    fn set_tagger_email(&mut self, tagger_email: Option<Py<PyBytes>>) {
+        self.tagger_email = tagger_email.map(PyLazyBytes::from_python);
+    }
+
+    #[getter]
+    fn tagger_date(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.tagger_date.as_mut().map(|date| date.get(py))
+    }
+
+    #[setter]
+    fn set_tagger_date(&mut self, tagger_date: Option<Py<PyBytes>>) {
+        self.tagger_date = tagger_date.map(PyLazyBytes::from_python);
+    }
+
+    #[getter]
+    fn message(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.message.get(py)
+    }
+
+    #[setter]
+    fn set_message(&mut self, message: Py<PyBytes>) {
+        self.message.set(message);
+    }
+
+    #[getter]
+    fn original_id(&mut self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.original_id.as_mut().map(|oid| oid.get(py))
+    }
+
+    #[setter]
+    fn set_original_id(&mut self, original_id: Option<Py<PyBytes>>) {
+        self.original_id = original_id.map(PyLazyBytes::from_python);
+    }
+
+    #[pyo3(signature = (new_id = None))]
+    fn skip(&mut self, new_id: Option<u64>) {
+        self.dumped = true;
+        if let Some(new_id) = new_id {
+            record_id_rename(self.old_id, new_id);
+        }
+    }
+
+    fn dump(&mut self, py: Python<'_>, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        dump_to_file(py, &self.copy_out(py)?, file)
+    }
+}
+
+impl Tag {
+    /// Moves the contents of a tag into an object for a callback. They are
+    /// given back by `Tag::restore`.
+    pub(crate) fn from_rust(py: Python<'_>, tag: &mut TagCommand<Bytes<'_>>) -> Self {
+        let id = tag.mark.map_or_else(new_id, mark_id);
+        let (tagger_name, tagger_email, tagger_date) = match tag.tagger.take() {
+            Some(tagger) => (
+                Some(PyLazyBytes::new(tagger.name)),
+                Some(PyLazyBytes::new(tagger.email)),
+                Some(PyLazyBytes::new(tagger.date)),
+            ),
+            None => (None, None, None),
+        };
+        Tag {
+            r#ref: lazy(&mut tag.name.name),
+            from_ref: objectish_to_py(py, &tag.from),
+            tagger_name,
+            tagger_email,
+            tagger_date,
+            message: lazy(&mut tag.message),
+            original_id: tag.original_oid.take().map(|oid| PyLazyBytes::new(oid.oid)),
+            id,
+            old_id: id,
+            dumped: false,
+            signature: tag.signature.take().map(parser::owned),
+        }
+    }
+
+    /// Converts a tag back after a callback.
+    pub(crate) fn restore(obj: &Py<Self>, py: Python<'_>) -> PyResult<TagCommand<Bytes<'static>>> {
+        let take = is_unshared(obj, py);
+        obj.borrow_mut(py).convert(py, take)
+    }
+
+    /// Copies the contents out, to write the object while Python holds it.
+    pub(crate) fn copy_out(&mut self, py: Python<'_>) -> PyResult<TagCommand<Bytes<'static>>> {
+        self.convert(py, false)
+    }
+
+    fn convert(&mut self, py: Python<'_>, take: bool) -> PyResult<TagCommand<Bytes<'static>>> {
+        let bytes = |bytes: &mut PyLazyBytes| lazy_to_rust(py, bytes, take);
+        let tagger = match (
+            &mut self.tagger_name,
+            &mut self.tagger_email,
+            &mut self.tagger_date,
+        ) {
+            (Some(name), Some(email), Some(date)) => Some(PersonIdent {
+                name: bytes(name),
+                email: bytes(email),
+                date: bytes(date),
+            }),
+            (None, None, None) => None,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "the tagger name, email, and date must be set together",
+                ))
+            }
+        };
+        Ok(TagCommand {
+            name: TagName {
+                name: bytes(&mut self.r#ref),
+            },
+            mark: Some(to_mark(self.id)?),
+            from: objectish_from_py(self.from_ref.as_ref(py))?,
+            original_oid: self
+                .original_id
+                .as_mut()
+                .map(|oid| OriginalOid { oid: bytes(oid) }),
+            tagger,
+            message: bytes(&mut self.message),
+            signature: if take {
+                self.signature.take()
+            } else {
+                self.signature.clone()
+            },
+        })
+    }
+}
+
+/// A reset of a ref to a commit, or, when `from_ref` is `None`, to start a
+/// new history.
+///
+// Corresponds to `git-filter-repo:Reset`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Reset {
+    r#ref: PyLazyBytes,
+    #[pyo3(get, set)]
+    pub from_ref: Option<PyObject>,
+    #[pyo3(get)]
+    pub dumped: bool,
+}
+
+#[pymethods]
+impl Reset {
+    #[new]
+    #[pyo3(signature = (ref_, from_ref = None))]
+    fn new(ref_: Py<PyBytes>, from_ref: Option<PyObject>) -> Self {
+        Reset {
+            r#ref: PyLazyBytes::from_python(ref_),
+            from_ref,
+            dumped: false,
+        }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "reset"
+    }
+
+    #[getter(r#ref)]
+    fn get_ref(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        self.r#ref.get(py)
+    }
+
+    #[setter(r#ref)]
+    fn set_ref(&mut self, r#ref: Py<PyBytes>) {
+        self.r#ref.set(r#ref);
+    }
+
+    fn skip(&mut self) {
+        self.dumped = true;
+    }
+
+    fn dump(&mut self, py: Python<'_>, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        dump_to_file(py, &self.copy_out(py)?, file)
+    }
+}
+
+impl Reset {
+    /// Moves the contents of a reset into an object for a callback. They are
+    /// given back by `Reset::restore`.
+    pub(crate) fn from_rust(py: Python<'_>, reset: &mut ResetCommand<Bytes<'_>>) -> Self {
+        Reset {
+            r#ref: lazy(&mut reset.branch.branch),
+            from_ref: reset
+                .from
+                .as_ref()
+                .map(|from| objectish_to_py(py, &from.commit)),
+            dumped: false,
+        }
+    }
+
+    /// Converts a reset back after a callback.
+    pub(crate) fn restore(
+        obj: &Py<Self>,
+        py: Python<'_>,
+    ) -> PyResult<ResetCommand<Bytes<'static>>> {
+        let take = is_unshared(obj, py);
+        obj.borrow_mut(py).convert(py, take)
+    }
+
+    /// Copies the contents out, to write the object while Python holds it.
+    pub(crate) fn copy_out(&mut self, py: Python<'_>) -> PyResult<ResetCommand<Bytes<'static>>> {
+        self.convert(py, false)
+    }
+
+    fn convert(&mut self, py: Python<'_>, take: bool) -> PyResult<ResetCommand<Bytes<'static>>> {
+        Ok(ResetCommand {
+            branch: Branch {
+                branch: lazy_to_rust(py, &mut self.r#ref, take),
+            },
+            from: match &self.from_ref {
+                Some(from) => Some(Commitish {
+                    commit: objectish_from_py(from.as_ref(py))?,
+                }),
+                None => None,
+            },
+        })
+    }
+}
+
+/// A progress message, to be printed by fast-import.
+///
+// Corresponds to `git-filter-repo:Progress`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Progress {
+    #[pyo3(get, set)]
+    pub message: Py<PyBytes>,
+    #[pyo3(get)]
+    pub dumped: bool,
+}
+
+#[pymethods]
+impl Progress {
+    #[new]
+    fn new(message: Py<PyBytes>) -> Self {
+        Progress {
+            message,
+            dumped: false,
+        }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "progress"
+    }
+
+    fn skip(&mut self) {
+        self.dumped = true;
+    }
+
+    fn dump(&mut self, py: Python<'_>, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        let progress = fast_export::command::Progress {
+            message: self.message.as_bytes(py),
+        };
+        dump_to_file(py, &progress, file)
+    }
+}
+
+/// A checkpoint, which makes fast-import write what it has imported so far.
+///
+// Corresponds to `git-filter-repo:Checkpoint`.
+#[pyclass(module = "git_filter_repo")]
+pub struct Checkpoint {
+    #[pyo3(get)]
+    pub dumped: bool,
+}
+
+#[pymethods]
+impl Checkpoint {
+    #[new]
+    fn new() -> Self {
+        Checkpoint { dumped: false }
+    }
+
+    #[getter(r#type)]
+    fn get_type(&self) -> &'static str {
+        "checkpoint"
+    }
+
+    fn skip(&mut self) {
+        self.dumped = true;
+    }
+
+    fn dump(&mut self, file: &PyAny) -> PyResult<()> {
+        self.dumped = true;
+        file.call_method1("write", (PyBytes::new(file.py(), b"checkpoint\n\n"),))?;
+        Ok(())
+    }
+}
+
+/// Writes an object, which a script inserted, to the stream, unless it was
+/// already written or skipped.
+pub(crate) fn insert_object(obj: &PyAny, output: &mut StreamWriter<'_>) -> anyhow::Result<()> {
+    let py = obj.py();
+    if let Ok(mut blob) = obj.extract::<PyRefMut<'_, Blob>>() {
+        if !blob.dumped {
+            blob.dumped = true;
+            blob.copy_out(py)?.dump(output)?;
+        }
+    } else if let Ok(mut commit) = obj.extract::<PyRefMut<'_, Commit>>() {
+        if !commit.dumped {
+            commit.dumped = true;
+            output.insert_commit(&commit.copy_out(py)?)?;
+        }
+    } else if let Ok(mut tag) = obj.extract::<PyRefMut<'_, Tag>>() {
+        if !tag.dumped {
+            tag.dumped = true;
+            output.insert_tag(&tag.copy_out(py)?)?;
+        }
+    } else if let Ok(mut reset) = obj.extract::<PyRefMut<'_, Reset>>() {
+        if !reset.dumped {
+            reset.dumped = true;
+            output.insert_reset(&reset.copy_out(py)?)?;
+        }
+    } else if let Ok(mut progress) = obj.extract::<PyRefMut<'_, Progress>>() {
+        if !progress.dumped {
+            progress.dumped = true;
+            fast_export::command::Progress {
+                message: progress.message.as_bytes(py),
+            }
+            .dump(output)?;
+        }
+    } else if let Ok(mut checkpoint) = obj.extract::<PyRefMut<'_, Checkpoint>>() {
+        if !checkpoint.dumped {
+            checkpoint.dumped = true;
+            output.write_all(b"checkpoint\n\n")?;
+        }
+    } else {
+        bail!("cannot insert {} into the stream", obj.get_type().name()?);
+    }
+    Ok(())
+}
+
+/// Calls a callback, which maps bytes to bytes.
+pub(crate) fn call_bytes(
+    callback: &Py<PyFunction>,
+    name: &str,
+    value: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    match call_optional_bytes(callback, value)? {
+        Some(value) => Ok(value),
+        None => bail!("{name} callback returned None"),
+    }
+}
+
+/// Calls a filename callback, which maps a filename to a filename or `None`.
+pub(crate) fn call_filename(
+    callback: &Py<PyFunction>,
+    filename: &[u8],
+    contents: FileContents<'_>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    with_blobs(contents.store, contents.change, || {
+        call_optional_bytes(callback, filename)
+    })
+}
+
+/// Calls a callback, which maps bytes to bytes or `None`.
+fn call_optional_bytes(callback: &Py<PyFunction>, value: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    Python::with_gil(|py| {
+        let res = callback
+            .call1(py, (PyBytes::new(py, value),))
+            .map_err(|err| callback_error(py, err))?;
+        if res.is_none(py) {
+            return Ok(None);
+        }
+        Ok(Some(res.extract::<&[u8]>(py)?.to_vec()))
+    })
+}
+
+/// Calls a blob callback, returning whether the blob is kept.
+pub(crate) fn call_blob(
+    callback: &Py<PyFunction>,
+    blob: &mut BlobObject<'_>,
+) -> anyhow::Result<bool> {
+    Python::with_gil(|py| {
+        let obj = Py::new(py, Blob::from_rust(blob))?;
+        // The object is restored before checking for an exception, so that
+        // the error can describe it.
+        let res = callback.call1(py, (obj.clone_ref(py), PyDict::new(py)));
+        *blob = Blob::restore(&obj, py)?;
+        res.map_err(|err| callback_error(py, err))?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    })
+}
+
+/// Converts an exception raised by a callback to an error, which includes
+/// its traceback. It is formatted by the `traceback` module instead of
+/// `PyTraceback::format`, because only the former uses `linecache`, where the
+/// sources of callbacks given as strings are registered.
+fn callback_error(py: Python<'_>, err: PyErr) -> anyhow::Error {
+    let format = || -> PyResult<String> {
+        let lines = py
+            .import("traceback")?
+            .getattr("format_exception")?
+            .call1((err.get_type(py), err.value(py), err.traceback(py)))?;
+        let lines = lines.extract::<Vec<String>>()?;
+        Ok(lines.concat().trim_end().to_owned())
+    };
+    match format() {
+        Ok(formatted) => anyhow::anyhow!("{formatted}"),
+        Err(_) => anyhow::anyhow!("{err}"),
+    }
+}
+
+/// Converts the metadata of a commit to the `aux_info` of commit callbacks.
+fn aux_info<'py>(py: Python<'py>, metadata: &CommitMetadata<'_>) -> PyResult<&'py PyDict> {
+    let aux_info = PyDict::new(py);
+    let orig_parents = metadata
+        .orig_parents
+        .iter()
+        .map(|parent| objectish_to_py(py, &parent.commit))
+        .collect::<Vec<_>>();
+    aux_info.set_item("orig_parents", PyList::new(py, orig_parents))?;
+    aux_info.set_item("had_file_changes", metadata.had_file_changes)?;
+    Ok(aux_info)
+}
+
+/// Calls a commit callback.
+pub(crate) fn call_commit<'c>(
+    callback: &Py<PyFunction>,
+    commit: &mut CommitCommand<Bytes<'c>>,
+    changes: &mut Vec<parser::FileChange<'c>>,
+    metadata: &CommitMetadata<'_>,
+) -> anyhow::Result<CommitAction<'c>> {
+    Python::with_gil(|py| {
+        let obj = Py::new(py, Commit::from_rust(py, commit, changes))?;
+        let aux_info = aux_info(py, metadata)?;
+        let res = with_blobs(metadata.blobs, None, || {
+            callback.call1(py, (obj.clone_ref(py), aux_info))
+        });
+        let (new, orig_changes) = Commit::restore(&obj, py)?;
+        *commit = new.commit;
+        *changes = new.changes;
+        res.map_err(|err| callback_error(py, err))?;
+        let obj = obj.borrow(py);
+        if obj.dumped {
+            let rewrite_to = match &obj.skipped_to {
+                Some(id) => Some(Commitish {
+                    commit: objectish_from_py(id.as_ref(py))?,
+                }),
+                None => None,
+            };
+            return Ok(CommitAction::Skip { rewrite_to });
+        }
+        Ok(CommitAction::Keep { orig_changes })
+    })
+}
+
+/// Calls a tag callback, returning whether the tag is kept.
+pub(crate) fn call_tag(
+    callback: &Py<PyFunction>,
+    tag: &mut TagCommand<Bytes<'_>>,
+) -> anyhow::Result<bool> {
+    Python::with_gil(|py| {
+        let obj = Py::new(py, Tag::from_rust(py, tag))?;
+        let res = callback.call1(py, (obj.clone_ref(py), PyDict::new(py)));
+        *tag = Tag::restore(&obj, py)?;
+        res.map_err(|err| callback_error(py, err))?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    })
+}
+
+/// Calls a reset callback, returning whether the reset is kept.
+pub(crate) fn call_reset(
+    callback: &Py<PyFunction>,
+    reset: &mut ResetCommand<Bytes<'_>>,
+) -> anyhow::Result<bool> {
+    Python::with_gil(|py| {
+        let obj = Py::new(py, Reset::from_rust(py, reset))?;
+        let res = callback.call1(py, (obj.clone_ref(py), PyDict::new(py)));
+        *reset = Reset::restore(&obj, py)?;
+        res.map_err(|err| callback_error(py, err))?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    })
+}
+
+pub(crate) fn call_done(callback: &Py<PyFunction>) -> anyhow::Result<()> {
+    Python::with_gil(|py| {
+        callback.call0(py).map_err(|err| callback_error(py, err))?;
+        Ok(())
+    })
+}
+
+/// The object, which a hook is currently processing, that its accessor
+/// functions read and write.
+#[derive(Default)]
+struct HookContext {
+    object: Option<PyObject>,
+    /// `orig_parents` and `had_file_changes` for commit hooks.
+    metadata: Option<Py<PyDict>>,
+}
+
+/// A getter and setter for a field of the object given to a hook, which is
+/// installed as a function in its globals. Called without arguments, it
+/// returns the value, which is only copied to Python then. Called with `set=`,
+/// it assigns the value. `drop()` skips the object.
+#[pyclass(module = "git_filter_repo", unsendable)]
+struct HookFunction {
+    context: Rc<RefCell<HookContext>>,
+    name: &'static str,
+}
+
+#[pymethods]
+impl HookFunction {
+    #[pyo3(signature = (**kwargs))]
+    fn __call__(&self, py: Python<'_>, kwargs: Option<&PyDict>) -> PyResult<PyObject> {
+        let (object, metadata) = {
+            let context = self.context.borrow();
+            let Some(object) = &context.object else {
+                return Err(PyRuntimeError::new_err(format!(
+                    "{}() called outside of a hook",
+                    self.name,
+                )));
+            };
+            let metadata = context.metadata.as_ref().map(|m| m.clone_ref(py));
+            (object.clone_ref(py), metadata)
+        };
+        let mut value = None;
+        for (key, v) in kwargs.into_iter().flatten() {
+            if key.extract::<&str>()? != "set" || self.name == "drop" {
+                return Err(PyTypeError::new_err(format!(
+                    "{}() got an unexpected keyword argument {key}",
+                    self.name,
+                )));
+            }
+            value = Some(v);
+        }
+        if self.name == "drop" {
+            object.call_method0(py, "skip")?;
+            return Ok(py.None());
+        }
+        if let Some(metadata) = metadata.as_ref().map(|m| m.as_ref(py)) {
+            if let Some(v) = metadata.get_item(self.name)? {
+                if value.is_some() {
+                    return Err(PyAttributeError::new_err(format!(
+                        "{} is read-only",
+                        self.name,
+                    )));
+                }
+                return Ok(v.into());
+            }
+        }
+        match value {
+            Some(value) => {
+                object.setattr(py, self.name, value)?;
+                Ok(py.None())
+            }
+            None => object.getattr(py, self.name),
+        }
+    }
+}
+
+/// Returns the accessor functions for hooks of a kind of object.
+fn hook_fields(kind: &str) -> &'static [&'static str] {
+    match kind {
+        "blob" => &["id", "original_id", "data"],
+        "commit" => &[
+            "id",
+            "branch",
+            "author_name",
+            "author_email",
+            "author_date",
+            "committer_name",
+            "committer_email",
+            "committer_date",
+            "message",
+            "file_changes",
+            "parents",
+            "original_id",
+            "encoding",
+            "orig_parents",
+            "had_file_changes",
+        ],
+        "tag" => &[
+            "id",
+            "ref",
+            "from_ref",
+            "tagger_name",
+            "tagger_email",
+            "tagger_date",
+            "message",
+            "original_id",
+        ],
+        "reset" => &["ref", "from_ref"],
+        _ => &[],
+    }
+}
+
+/// A hook, which processes objects through getter and setter functions in its
+/// globals, instead of through the properties of an object parameter like
+/// callbacks. Fields are only copied to Python when the hook reads them.
+pub struct Hook {
+    function: Py<PyFunction>,
+    context: Rc<RefCell<HookContext>>,
+}
+
+impl Hook {
+    /// Creates a hook from compiled code, which defines `hook()`. The accessor
+    /// functions for the kind of object are added to its globals.
+    pub(crate) fn from_code(
+        py: Python<'_>,
+        kind: &str,
+        code: &PyAny,
+        globals: &PyDict,
+    ) -> PyResult<Self> {
+        let context = Rc::new(RefCell::new(HookContext::default()));
+        for &name in hook_fields(kind).iter().chain(&["drop"]) {
+            let function = HookFunction {
+                context: context.clone(),
+                name,
+            };
+            globals.set_item(name, Py::new(py, function)?)?;
+        }
+        let locals = PyDict::new(py);
+        py.import("builtins")?
+            .getattr("exec")?
+            .call1((code, globals, locals))?;
+        let function = locals.get_item("hook")?.unwrap();
+        Ok(Hook {
+            function: function.extract::<&PyFunction>()?.into(),
+            context,
+        })
+    }
+
+    /// Calls the hook with the object installed for its accessors.
+    fn call(
+        &self,
+        py: Python<'_>,
+        object: PyObject,
+        metadata: Option<Py<PyDict>>,
+    ) -> anyhow::Result<()> {
+        *self.context.borrow_mut() = HookContext {
+            object: Some(object),
+            metadata,
+        };
+        let res = self.function.call0(py);
+        *self.context.borrow_mut() = HookContext::default();
+        res.map(drop).map_err(|err| callback_error(py, err))
+    }
+
+    /// Calls a blob hook, returning whether the blob is kept.
+    pub(crate) fn call_blob(&self, blob: &mut BlobObject<'_>) -> anyhow::Result<bool> {
+        Python::with_gil(|py| {
+            let obj = Py::new(py, Blob::from_rust(blob))?;
+            let res = self.call(py, obj.clone_ref(py).into_py(py), None);
+            *blob = Blob::restore(&obj, py)?;
+            res?;
+            let dumped = obj.borrow(py).dumped;
+            Ok(!dumped)
+        })
+    }
+
+    /// Calls a commit hook, returning whether the commit is kept. When it is
+    /// dropped, references to it are rewritten to its first parent.
+    pub(crate) fn call_commit(
+        &self,
+        commit: &mut CommitCommand<Bytes<'_>>,
+        changes: &mut Vec<parser::FileChange<'_>>,
+        metadata: &CommitMetadata<'_>,
+    ) -> anyhow::Result<bool> {
+        Python::with_gil(|py| {
+            let obj = Py::new(py, Commit::from_rust(py, commit, changes))?;
+            let aux_info = aux_info(py, metadata)?;
+            let res = with_blobs(metadata.blobs, None, || {
+                self.call(py, obj.clone_ref(py).into_py(py), Some(aux_info.into()))
+            });
+            let (new, _) = Commit::restore(&obj, py)?;
+            *commit = new.commit;
+            *changes = new.changes;
+            res?;
+            let dumped = obj.borrow(py).dumped;
+            Ok(!dumped)
+        })
+    }
+
+    /// Calls a tag hook, returning whether the tag is kept.
+    pub(crate) fn call_tag(&self, tag: &mut TagCommand<Bytes<'_>>) -> anyhow::Result<bool> {
+        Python::with_gil(|py| {
+            let obj = Py::new(py, Tag::from_rust(py, tag))?;
+            let res = self.call(py, obj.clone_ref(py).into_py(py), None);
+            *tag = Tag::restore(&obj, py)?;
+            res?;
+            let dumped = obj.borrow(py).dumped;
+            Ok(!dumped)
+        })
+    }
+
+    /// Calls a reset hook, returning whether the reset is kept.
+    pub(crate) fn call_reset(&self, reset: &mut ResetCommand<Bytes<'_>>) -> anyhow::Result<bool> {
+        Python::with_gil(|py| {
+            let obj = Py::new(py, Reset::from_rust(py, reset))?;
+            let res = self.call(py, obj.clone_ref(py).into_py(py), None);
+            *reset = Reset::restore(&obj, py)?;
+            res?;
+            let dumped = obj.borrow(py).dumped;
+            Ok(!dumped)
+        })
+    }
+}
+
+/// The options for filtering, as parsed from filter-repo arguments. Options
+/// can be read and set as attributes, like in filter-repo.
+///
+// Corresponds to `git-filter-repo:FilteringOptions`.
+#[pyclass(name = "FilteringOptions", module = "git_filter_repo")]
+#[derive(Clone)]
+pub struct PyFilteringOptions {
+    pub args: FilteringOptions,
+}
+
+macro_rules! bool_options(($($name:ident),* $(,)?) => {
+    fn bool_option<'a>(args: &'a mut FilteringOptions, name: &str) -> Option<&'a mut bool> {
+        match name {
+            $(stringify!($name) => Some(&mut args.$name),)*
+            _ => None,
+        }
+    }
+});
+
+bool_options!(
+    invert_paths,
+    inclusive,
+    use_base_name,
+    preserve_commit_hashes,
+    use_mailmap,
+    no_ff,
+    in_place,
+    date_order,
+    dry_run,
+    debug,
+    stdin,
+    quiet,
+    force,
+    partial,
+    no_gc,
+);
+
+/// Allows rewriting the source repo in place, when there is no target or it is
+/// the source, like filter-repo does for scripts.
+fn allow_in_place(args: &mut FilteringOptions) {
+    let source = args.source.as_deref().unwrap_or(Path::new("."));
+    if args
+        .target
+        .as_deref()
+        .is_none_or(|target| sanity::is_same_repo(source, target))
+    {
+        args.in_place = true;
+    }
+}
+
+#[pymethods]
+impl PyFilteringOptions {
+    /// Parses filter-repo arguments, without the program name.
+    ///
+    /// # Differences from filter-repo
+    ///
+    /// Like filter-repo, scripts rewrite the repo in place, unless `--target`
+    /// names another repo. The command line instead requires `--in-place` for
+    /// that.
+    #[staticmethod]
+    #[pyo3(signature = (input_args, error_on_empty = true))]
+    fn parse_args(input_args: Vec<OsString>, error_on_empty: bool) -> PyResult<Self> {
+        let args = std::iter::once(OsString::from("filter-repo")).chain(input_args);
+        let mut args = match FilteringOptions::parse_args(args) {
+            Ok(args) => args,
+            Err(OptionsError::NoArguments) if !error_on_empty => FilteringOptions::default(),
+            Err(OptionsError::Clap(err)) => {
+                let _ = err.print();
+                return Err(PySystemExit::new_err(err.exit_code()));
+            }
+            Err(err) => return Err(PySystemExit::new_err(err.to_string())),
+        };
+        allow_in_place(&mut args);
+        Ok(PyFilteringOptions { args })
+    }
+
+    /// Returns the options, as if no arguments were given.
+    #[staticmethod]
+    fn default_options() -> PyResult<Self> {
+        PyFilteringOptions::parse_args(Vec::new(), false)
+    }
+
+    fn __getattr__(&mut self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        if let Some(value) = bool_option(&mut self.args, name) {
+            return Ok(value.into_py(py));
+        }
+        Ok(match name {
+            "source" => self.args.source.as_deref().map(path_to_py).into_py(py),
+            "target" => self.args.target.as_deref().map(path_to_py).into_py(py),
+            "refs" => PyList::new(py, &self.args.refs).into_py(py),
+            "preserve_commit_encoding" => (self.args.reencode == Reencode::No).into_py(py),
+            _ => {
+                return Err(pyo3::exceptions::PyAttributeError::new_err(format!(
+                    "'FilteringOptions' object has no attribute '{name}'"
+                )))
+            }
+        })
+    }
+
+    fn __setattr__(&mut self, name: &str, value: &PyAny) -> PyResult<()> {
+        if let Some(option) = bool_option(&mut self.args, name) {
+            *option = value.is_true()?;
+            return Ok(());
+        }
+        match name {
+            "source" | "target" => {
+                let path = if value.is_none() {
+                    None
+                } else {
+                    Some(path_arg(value)?)
+                };
+                if name == "source" {
+                    self.args.source = path;
+                } else {
+                    self.args.target = path;
+                }
+                allow_in_place(&mut self.args);
+            }
+            "refs" => {
+                self.args.refs = value
+                    .iter()?
+                    .map(|refname| Ok(path_arg(refname?)?.into_os_string()))
+                    .collect::<PyResult<_>>()?;
+            }
+            "preserve_commit_encoding" => {
+                self.args.reencode = if value.is_true()? {
+                    Reencode::No
+                } else {
+                    Reencode::Yes
+                };
+            }
+            _ => {
+                return Err(pyo3::exceptions::PyAttributeError::new_err(format!(
+                    "cannot set option '{name}' on 'FilteringOptions'"
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a path from Python, which may be `str` or `bytes`.
+fn path_arg(path: &PyAny) -> PyResult<PathBuf> {
+    match path.extract::<&[u8]>() {
+        Ok(path) => Ok(path
+            .to_os_str()
+            .map_err(|_| PyValueError::new_err("path is not valid for this platform"))?
+            .into()),
+        Err(_) => path.extract(),
+    }
+}
+
+fn path_to_py(path: &Path) -> Vec<u8> {
+    <[u8]>::from_os_str(path.as_os_str())
+        .map(<[u8]>::to_vec)
+        .unwrap_or_else(|| path.to_string_lossy().into_owned().into_bytes())
+}
+
+/// Filters a repo with callbacks, which are Python functions, for library
+/// scripts.
+///
+// Corresponds to `git-filter-repo:RepoFilter`.
+#[pyclass(name = "RepoFilter", module = "git_filter_repo", unsendable)]
+pub struct PyRepoFilter {
+    args: FilteringOptions,
+    filename_callback: Option<Py<PyFunction>>,
+    message_callback: Option<Py<PyFunction>>,
+    name_callback: Option<Py<PyFunction>>,
+    email_callback: Option<Py<PyFunction>>,
+    refname_callback: Option<Py<PyFunction>>,
+    blob_callback: Option<Py<PyFunction>>,
+    commit_callback: Option<Py<PyFunction>>,
+    tag_callback: Option<Py<PyFunction>>,
+    reset_callback: Option<Py<PyFunction>>,
+    done_callback: Option<Py<PyFunction>>,
+    /// Objects passed to `insert`, which have not yet been written.
+    insert_queue: Py<PyList>,
+}
+
+#[pymethods]
+impl PyRepoFilter {
+    #[new]
+    #[pyo3(signature = (
+        args,
+        filename_callback = None,
+        message_callback = None,
+        name_callback = None,
+        email_callback = None,
+        refname_callback = None,
+        blob_callback = None,
+        commit_callback = None,
+        tag_callback = None,
+        reset_callback = None,
+        done_callback = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        args: &PyFilteringOptions,
+        filename_callback: Option<Py<PyFunction>>,
+        message_callback: Option<Py<PyFunction>>,
+        name_callback: Option<Py<PyFunction>>,
+        email_callback: Option<Py<PyFunction>>,
+        refname_callback: Option<Py<PyFunction>>,
+        blob_callback: Option<Py<PyFunction>>,
+        commit_callback: Option<Py<PyFunction>>,
+        tag_callback: Option<Py<PyFunction>>,
+        reset_callback: Option<Py<PyFunction>>,
+        done_callback: Option<Py<PyFunction>>,
+    ) -> Self {
+        PyRepoFilter {
+            args: args.args.clone(),
+            filename_callback,
+            message_callback,
+            name_callback,
+            email_callback,
+            refname_callback,
+            blob_callback,
+            commit_callback,
+            tag_callback,
+            reset_callback,
+            done_callback,
+            insert_queue: PyList::empty(py).into(),
+        }
+    }
+
+    /// Inserts an object into the stream. It is written before the object
+    /// being handled by the current callback.
+    ///
+    // Corresponds to `git-filter-repo:RepoFilter.insert`.
+    fn insert(&self, py: Python<'_>, obj: PyObject) -> PyResult<()> {
+        self.insert_queue.as_ref(py).append(obj)
+    }
+
+    fn run(&self, py: Python<'_>) -> PyResult<()> {
+        let mut b = RepoFilter::builder(self.args.clone());
+        b.new_marks = NEW_MARKS.with(Clone::clone);
+        macro_rules! set_callbacks(($($name:ident),*) => {
+            $(if let Some(callback) = &self.$name {
+                b.$name(callback.as_ref(py))?;
+            })*
+        });
+        set_callbacks!(
+            filename_callback,
+            message_callback,
+            name_callback,
+            email_callback,
+            refname_callback,
+            blob_callback,
+            commit_callback,
+            tag_callback,
+            reset_callback,
+            done_callback
+        );
+        let mut filter = b.build()?;
+        filter.insert_queue = Some(self.insert_queue.clone_ref(py));
+        filter.run()?;
+        Ok(())
+    }
+}
+
+/// Parses a fast-export stream from a Python file and writes the objects,
+/// after passing them to the callbacks, to another.
+///
+/// # Differences from filter-repo
+///
+/// Progress and checkpoint commands are passed through, so progress and
+/// checkpoint callbacks are not supported.
+///
+// Corresponds to `git-filter-repo:FastExportParser`.
+#[pyclass(name = "FastExportParser", module = "git_filter_repo", unsendable)]
+pub struct PyFastExportParser {
+    blob_callback: Option<PyObject>,
+    commit_callback: Option<PyObject>,
+    tag_callback: Option<PyObject>,
+    reset_callback: Option<PyObject>,
+    done_callback: Option<PyObject>,
+}
+
+#[pymethods]
+impl PyFastExportParser {
+    #[new]
+    #[pyo3(signature = (
+        blob_callback = None,
+        commit_callback = None,
+        tag_callback = None,
+        reset_callback = None,
+        progress_callback = None,
+        checkpoint_callback = None,
+        done_callback = None,
+    ))]
+    fn new(
+        blob_callback: Option<PyObject>,
+        commit_callback: Option<PyObject>,
+        tag_callback: Option<PyObject>,
+        reset_callback: Option<PyObject>,
+        progress_callback: Option<PyObject>,
+        checkpoint_callback: Option<PyObject>,
+        done_callback: Option<PyObject>,
+    ) -> PyResult<Self> {
+        if progress_callback.is_some() || checkpoint_callback.is_some() {
+            return Err(PyValueError::new_err(
+                "progress and checkpoint callbacks are not supported",
+            ));
+        }
+        Ok(PyFastExportParser {
+            blob_callback,
+            commit_callback,
+            tag_callback,
+            reset_callback,
+            done_callback,
+        })
+    }
+
+    fn run(&self, py: Python<'_>, input: &PyAny, output: &PyAny) -> PyResult<()> {
+        let data = input.call_method0("read")?;
+        let data = data.extract::<&[u8]>()?;
+        let mut filtered = Vec::new();
+        let mut parser = FastExportParser::new(Cursor::new(data), &mut filtered);
+        parser.run(&mut PyHandler { py, parser: self })?;
+        parser.finish()?;
+        output.call_method1("write", (PyBytes::new(py, &filtered),))?;
+        Ok(())
+    }
+}
+
+/// Passes the objects from a `FastExportParser` to its Python callbacks.
+struct PyHandler<'a, 'py> {
+    py: Python<'py>,
+    parser: &'a PyFastExportParser,
+}
+
+impl Handler for PyHandler<'_, '_> {
+    fn strip_blob(
+        &mut self,
+        _mark: Option<Mark>,
+        _original_oid: Option<&[u8]>,
+        _len: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn blob(
+        &mut self,
+        blob: &mut BlobObject<'_>,
+        _output: &mut StreamWriter<'_>,
+    ) -> anyhow::Result<bool> {
+        let (py, Some(callback)) = (self.py, &self.parser.blob_callback) else {
+            return Ok(true);
+        };
+        let obj = Py::new(py, Blob::from_rust(blob))?;
+        callback.call1(py, (obj.clone_ref(py),))?;
+        *blob = Blob::restore(&obj, py)?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    }
+
+    fn commit(
+        &mut self,
+        commit: &mut CommitObject<'_>,
+        _output: &mut StreamWriter<'_>,
+    ) -> anyhow::Result<bool> {
+        let (py, Some(callback)) = (self.py, &self.parser.commit_callback) else {
+            return Ok(true);
+        };
+        let had_file_changes = !commit.changes.is_empty();
+        let obj = Py::new(
+            py,
+            Commit::from_rust(py, &mut commit.commit, &mut commit.changes),
+        )?;
+        let aux_info = PyDict::new(py);
+        let orig_parents = obj.borrow(py).parents.clone_ref(py);
+        let orig_parents = orig_parents.as_ref(py).iter().collect::<Vec<_>>();
+        aux_info.set_item("orig_parents", PyList::new(py, orig_parents))?;
+        aux_info.set_item("had_file_changes", had_file_changes)?;
+        callback.call1(py, (obj.clone_ref(py), aux_info))?;
+        (*commit, _) = Commit::restore(&obj, py)?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    }
+
+    fn commit_written(
+        &mut self,
+        _commit: &CommitObject<'_>,
+        _output: &mut StreamWriter<'_>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn tag(
+        &mut self,
+        tag: &mut TagCommand<Bytes<'_>>,
+        _output: &mut StreamWriter<'_>,
+    ) -> anyhow::Result<bool> {
+        let (py, Some(callback)) = (self.py, &self.parser.tag_callback) else {
+            return Ok(true);
+        };
+        let obj = Py::new(py, Tag::from_rust(py, tag))?;
+        callback.call1(py, (obj.clone_ref(py),))?;
+        *tag = Tag::restore(&obj, py)?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    }
+
+    fn reset(
+        &mut self,
+        reset: &mut ResetCommand<Bytes<'_>>,
+        _output: &mut StreamWriter<'_>,
+    ) -> anyhow::Result<bool> {
+        let (py, Some(callback)) = (self.py, &self.parser.reset_callback) else {
+            return Ok(true);
+        };
+        let obj = Py::new(py, Reset::from_rust(py, reset))?;
+        callback.call1(py, (obj.clone_ref(py),))?;
+        *reset = Reset::restore(&obj, py)?;
+        let dumped = obj.borrow(py).dumped;
+        Ok(!dumped)
+    }
+
+    fn done(&mut self) -> anyhow::Result<()> {
+        if let Some(callback) = &self.parser.done_callback {
+            callback.call0(self.py)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows a message of progress on stdout, which is redrawn in place.
+///
+// Corresponds to `git-filter-repo:ProgressWriter`.
+#[pyclass(name = "ProgressWriter", module = "git_filter_repo")]
+pub struct PyProgressWriter {
+    last_update: Option<Instant>,
+    last_message: Option<String>,
+}
+
+#[pymethods]
+impl PyProgressWriter {
+    #[new]
+    fn new() -> Self {
+        PyProgressWriter {
+            last_update: Some(Instant::now()),
+            last_message: None,
+        }
+    }
+
+    fn show(&mut self, py: Python<'_>, msg: String) -> PyResult<()> {
+        let now = Instant::now();
+        let due = self
+            .last_update
+            .is_none_or(|last| now.duration_since(last).as_secs_f64() > 0.1);
+        if due {
+            self.last_update = Some(now);
+            let stdout = py.import("sys")?.getattr("stdout")?;
+            stdout.call_method1("write", (format!("\r{msg}"),))?;
+            stdout.call_method0("flush")?;
+        }
+        self.last_message = Some(msg);
+        Ok(())
+    }
+
+    fn finish(&mut self, py: Python<'_>) -> PyResult<()> {
+        self.last_update = None;
+        if let Some(msg) = self.last_message.clone() {
+            self.show(py, msg)?;
+        }
+        py.import("sys")?
+            .getattr("stdout")?
+            .call_method1("write", ("\n",))?;
+        Ok(())
+    }
+}
+
+/// Utilities for querying a repo.
+///
+// Corresponds to `git-filter-repo:GitUtils`.
+#[pyclass(module = "git_filter_repo")]
+pub struct GitUtils;
+
+#[pymethods]
+impl GitUtils {
+    /// Returns the number of commits in the revisions, or in all refs.
+    #[staticmethod]
+    #[pyo3(signature = (repo, *args))]
+    fn get_commit_count(repo: &PyAny, args: &PyTuple) -> PyResult<u64> {
+        let args = args.extract::<Vec<String>>()?;
+        let mut cmd = vec!["rev-list", "--count"];
+        if args.is_empty() {
+            cmd.push("--all");
+        }
+        cmd.extend(args.iter().map(String::as_str));
+        let count = sanity::git(&path_arg(repo)?, &cmd)?;
+        parse_count(&count)
+    }
+
+    /// Returns the number of objects, loose and packed, in the repo.
+    #[staticmethod]
+    fn get_total_objects(repo: &PyAny) -> PyResult<u64> {
+        let output = sanity::git(&path_arg(repo)?, &["count-objects", "-v"])?;
+        let mut total = 0;
+        for line in output.lines() {
+            if let Some((key, value)) = line.split_once_str(b": ") {
+                if key == b"count" || key == b"in-pack" {
+                    total += parse_count(value)?;
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    #[staticmethod]
+    fn is_repository_bare(repo_working_dir: &PyAny) -> PyResult<bool> {
+        let repo = path_arg(repo_working_dir)?;
+        let output = sanity::git(&repo, &["rev-parse", "--is-bare-repository"])?;
+        Ok(output.trim() == b"true")
+    }
+
+    #[staticmethod]
+    fn determine_git_dir(py: Python<'_>, repo_working_dir: &PyAny) -> PyResult<Py<PyBytes>> {
+        let git_dir = sanity::git_dir(&path_arg(repo_working_dir)?)?;
+        Ok(py_bytes(py, &path_to_py(&git_dir)))
+    }
+
+    /// Returns the refs of the repo, mapped to their hashes.
+    #[staticmethod]
+    fn get_refs<'py>(py: Python<'py>, repo_working_dir: &PyAny) -> PyResult<&'py PyDict> {
+        let refs = PyDict::new(py);
+        for (refname, oid) in sanity::refs(&path_arg(repo_working_dir)?)? {
+            refs.set_item(PyBytes::new(py, &refname), PyBytes::new(py, &oid))?;
+        }
+        Ok(refs)
+    }
+
+    /// Returns the unpacked and packed sizes of the blobs in the repo in the
+    /// current directory, by hash.
+    #[staticmethod]
+    #[pyo3(signature = (quiet = false))]
+    fn get_blob_sizes(py: Python<'_>, quiet: bool) -> PyResult<(&PyDict, &PyDict)> {
+        let _ = quiet;
+        let output = sanity::git(
+            Path::new("."),
+            &[
+                "cat-file",
+                "--batch-all-objects",
+                "--batch-check=%(objectname) %(objecttype) %(objectsize) %(objectsize:disk)",
+            ],
+        )?;
+        let (unpacked_size, packed_size) = (PyDict::new(py), PyDict::new(py));
+        for line in output.lines() {
+            let [oid, kind, size, disk_size] = line.fields().collect::<Vec<_>>()[..] else {
+                return Err(PyValueError::new_err(format!(
+                    "unexpected cat-file output: {}",
+                    line.as_bstr(),
+                )));
+            };
+            if kind == b"blob" {
+                let oid = PyBytes::new(py, oid);
+                unpacked_size.set_item(oid, parse_count(size)?)?;
+                packed_size.set_item(oid, parse_count(disk_size)?)?;
+            }
+        }
+        Ok((unpacked_size, packed_size))
+    }
+
+    /// Returns the changes from one commit to another.
+    #[staticmethod]
+    fn get_file_changes<'py>(
+        py: Python<'py>,
+        repo: &PyAny,
+        parent_hash: &[u8],
+        commit_hash: &[u8],
+    ) -> PyResult<&'py PyList> {
+        let changes = filter::diff_tree(&path_arg(repo)?, parent_hash, commit_hash, |oid| {
+            DataRef::Oid(Cow::Owned(oid.to_vec()))
+        })?;
+        let list = PyList::empty(py);
+        for change in &changes {
+            if let Some(change) = FileChange::from_rust(py, change) {
+                list.append(Py::new(py, change)?)?;
+            }
+        }
+        Ok(list)
+    }
+
+    #[staticmethod]
+    fn print_my_version() {
+        println!("git-transform-repo {}", env!("CARGO_PKG_VERSION"));
+    }
+}
+
+fn parse_count(count: &[u8]) -> PyResult<u64> {
+    count
+        .trim()
+        .to_str()
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| PyValueError::new_err(format!("invalid count {}", count.as_bstr())))
+}
+
+/// Converts a Git date, like `b"1700000000 +0100"`, to an aware `datetime`.
+///
+// Corresponds to `git-filter-repo:string_to_date`.
+#[pyfunction]
+pub fn string_to_date<'py>(py: Python<'py>, datestring: &[u8]) -> PyResult<&'py PyAny> {
+    let invalid = || PyValueError::new_err(format!("invalid date {}", datestring.as_bstr()));
+    let (timestamp, offset) = datestring
+        .to_str()
+        .ok()
+        .and_then(|date| date.split_once(' '))
+        .ok_or_else(invalid)?;
+    let timestamp = timestamp.parse::<i64>().map_err(|_| invalid())?;
+    let (sign, hours, minutes) = match offset.as_bytes() {
+        [sign @ (b'+' | b'-'), hh @ .., m1, m2] if hh.len() == 2 => {
+            let sign = if *sign == b'-' { -1 } else { 1 };
+            let hours = offset[1..3].parse::<i64>().map_err(|_| invalid())?;
+            let minutes = [*m1, *m2]
+                .to_str()
+                .ok()
+                .and_then(|minutes| minutes.parse::<i64>().ok())
+                .ok_or_else(invalid)?;
+            (sign, hours, minutes)
+        }
+        _ => return Err(invalid()),
+    };
+    let datetime = py.import("datetime")?;
+    let delta = datetime.getattr("timedelta")?.call(
+        (),
+        Some([("minutes", sign * (hours * 60 + minutes))].into_py_dict(py)),
+    )?;
+    let tz = datetime.getattr("timezone")?.call1((delta, offset))?;
+    datetime
+        .getattr("datetime")?
+        .call_method1("fromtimestamp", (timestamp, tz))
+}
+
+/// Converts an aware `datetime` to a Git date.
+///
+// Corresponds to `git-filter-repo:date_to_string`.
+#[pyfunction]
+pub fn date_to_string(py: Python<'_>, dateobj: &PyAny) -> PyResult<Py<PyBytes>> {
+    let timestamp = dateobj.call_method0("timestamp")?.extract::<f64>()?.floor() as i64;
+    let offset = dateobj.call_method0("utcoffset")?;
+    let offset = if offset.is_none() {
+        0
+    } else {
+        offset.call_method0("total_seconds")?.extract::<f64>()? as i64 / 60
+    };
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    let date = format!("{timestamp} {sign}{:02}{:02}", offset / 60, offset % 60);
+    Ok(py_bytes(py, date.as_bytes()))
+}
+
+/// The names of the library API, in the order filter-repo lists them in
+/// `__all__`.
+pub(crate) const NAMES: [&str; 15] = [
+    "Blob",
+    "Reset",
+    "FileChange",
+    "Commit",
+    "Tag",
+    "Progress",
+    "Checkpoint",
+    "FastExportParser",
+    "ProgressWriter",
+    "string_to_date",
+    "date_to_string",
+    "record_id_rename",
+    "GitUtils",
+    "FilteringOptions",
+    "RepoFilter",
+];
+
+/// Returns the `git_filter_repo` module, which provides the library API. It
+/// is created once and registered in `sys.modules`, so that scripts and
+/// callbacks can import it.
+pub fn module(py: Python<'_>) -> PyResult<&PyModule> {
+    let modules = py.import("sys")?.getattr("modules")?.downcast::<PyDict>()?;
+    if let Some(module) = modules.get_item("git_filter_repo")? {
+        return Ok(module.downcast()?);
+    }
+    let module = PyModule::new(py, "git_filter_repo")?;
+    module.add_class::<Blob>()?;
+    module.add_class::<Reset>()?;
+    module.add_class::<FileChange>()?;
+    module.add_class::<Commit>()?;
+    module.add_class::<Tag>()?;
+    module.add_class::<Progress>()?;
+    module.add_class::<Checkpoint>()?;
+    module.add_class::<PyFastExportParser>()?;
+    module.add_class::<PyProgressWriter>()?;
+    module.add_function(wrap_pyfunction!(string_to_date, module)?)?;
+    module.add_function(wrap_pyfunction!(date_to_string, module)?)?;
+    module.add_function(wrap_pyfunction!(record_id_rename, module)?)?;
+    module.add_class::<GitUtils>()?;
+    module.add_class::<PyFilteringOptions>()?;
+    module.add_class::<PyRepoFilter>()?;
+    module.add("__all__", NAMES.to_vec())?;
+    modules.set_item("git_filter_repo", module)?;
+    Ok(module)
+}
+
+/// Runs a git-filter-repo library script, for which `import git_filter_repo`
+/// provides this API, with the arguments in `sys.argv`.
+pub fn run_script(py: Python<'_>, script: &Path, args: &[OsString]) -> anyhow::Result<()> {
+    module(py)?;
+    let code = fs::read_to_string(script)
+        .with_context(|| format!("could not read {}", script.display()))?;
+    let argv = std::iter::once(script.as_os_str())
+        .chain(args.iter().map(OsString::as_os_str))
+        .collect::<Vec<_>>();
+    py.import("sys")?.setattr("argv", PyList::new(py, argv))?;
+
+    let builtins = py.import("builtins")?;
+    let globals = PyDict::new(py);
+    globals.set_item("__name__", "__main__")?;
+    globals.set_item("__file__", script)?;
+    globals.set_item("__builtins__", builtins)?;
+    let res = builtins
+        .getattr("compile")?
+        .call1((code, script, "exec"))
+        .and_then(|code| builtins.getattr("exec")?.call1((code, globals)));
+    match res {
+        Ok(_) => Ok(()),
+        Err(err) if err.is_instance_of::<PySystemExit>(py) => {
+            let status = err.value(py).getattr("code")?;
+            if status.is_none() || status.extract::<i64>().is_ok_and(|status| status == 0) {
+                Ok(())
+            } else {
+                bail!("{} exited with {status}", script.display());
+            }
+        }
+        Err(err) => {
+            err.print(py);
+            bail!("{} failed", script.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::{types::PyDict, Python};
+
+    use super::*;
+    use crate::test_util::{git, init_repo};
+
+    fn with_gil<R>(f: impl FnOnce(Python<'_>) -> R) -> R {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(f)
+    }
+
+    #[test]
+    fn dates() {
+        with_gil(|py| {
+            let date = string_to_date(py, b"1700000000 -0130").unwrap();
+            let iso = date.call_method0("isoformat").unwrap();
+            assert_eq!(iso.extract::<&str>().unwrap(), "2023-11-14T20:43:20-01:30");
+            let date = date_to_string(py, date).unwrap();
+            assert_eq!(date.as_bytes(py), b"1700000000 -0130");
+        });
+    }
+
+    #[test]
+    fn fast_export_parser() {
+        let stream = b"\
+blob
+mark :1
+data 6
+hello
+
+commit refs/heads/main
+mark :2
+author A U Thor <author@example.com> 1700000000 +0000
+committer A U Thor <author@example.com> 1700000000 +0000
+data 8
+message
+M 100644 :1 file.txt
+
+done
+";
+        let code = r#"
+import io
+import git_filter_repo as fr
+
+def blob_callback(blob):
+    blob.data = blob.data.upper()
+
+def commit_callback(commit, aux_info):
+    assert aux_info["had_file_changes"]
+    commit.message = b"rewritten\n"
+    commit.file_changes.append(fr.FileChange(b"D", b"gone.txt"))
+
+parser = fr.FastExportParser(blob_callback=blob_callback, commit_callback=commit_callback)
+output = io.BytesIO()
+parser.run(io.BytesIO(stream), output)
+result = output.getvalue()
+"#;
+        with_gil(|py| {
+            module(py).unwrap();
+            let globals = PyDict::new(py);
+            globals
+                .set_item("stream", PyBytes::new(py, stream))
+                .unwrap();
+            py.run(code, Some(globals), None).unwrap();
+            let result = globals.get_item("result").unwrap().unwrap();
+            assert_eq!(
+                result.extract::<&[u8]>().unwrap().as_bstr(),
+                b"\
+blob
+mark :1
+data 6
+HELLO
+
+commit refs/heads/main
+mark :2
+author A U Thor <author@example.com> 1700000000 +0000
+committer A U Thor <author@example.com> 1700000000 +0000
+data 10
+rewritten
+
+M 100644 :1 file.txt
+D gone.txt
+
+done
+"
+                .as_bstr(),
+            );
+        });
+    }
+
+    #[test]
+    fn repo_filter() {
+        let (repo, dir) = (init_repo(), tempfile::tempdir().unwrap());
+        let (source, target) = (repo.path(), dir.path().join("target"));
+        for (message, path, contents) in [
+            ("first", "a.txt", "secret one\n"),
+            ("skip me", "a.txt", "secret two\n"),
+            ("third", "b.txt", "b\n"),
+        ] {
+            fs::write(source.join(path), contents).unwrap();
+            git(source, &["add", path]);
+            git(source, &["commit", "-q", "-m", message]);
+        }
+
+        let code = r#"
+import git_filter_repo as fr
+
+def blob_callback(blob, metadata):
+    blob.data = blob.data.replace(b"secret", b"public")
+
+def commit_callback(commit, metadata):
+    if commit.message.startswith(b"skip"):
+        commit.skip(commit.first_parent())
+        return
+    commit.message = commit.message.upper()
+    if not commit.parents:
+        blob = fr.Blob(b"inserted\n")
+        repo_filter.insert(blob)
+        commit.file_changes.append(fr.FileChange(b"M", b"inserted.txt", blob.id, b"100644"))
+
+args = fr.FilteringOptions.parse_args(["--source", source, "--target", target, "--quiet"])
+repo_filter = fr.RepoFilter(args, blob_callback=blob_callback, commit_callback=commit_callback)
+repo_filter.run()
+"#;
+        with_gil(|py| {
+            module(py).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("source", source).unwrap();
+            globals.set_item("target", &target).unwrap();
+            py.run(code, Some(globals), None).unwrap();
+        });
+        assert_eq!(git(&target, &["log", "--format=%s"]), "THIRD\nFIRST\n");
+        assert_eq!(git(&target, &["show", "HEAD:a.txt"]), "public one\n");
+        assert_eq!(git(&target, &["show", "HEAD:inserted.txt"]), "inserted\n");
+    }
+
+    /// Runs `contrib/filter-repo-demos/insert-beginning` from filter-repo,
+    /// with the repo passed as `source` instead of the working directory.
+    #[test]
+    fn insert_beginning_demo() {
+        let dir = init_repo();
+        let repo = dir.path();
+        for (message, path) in [("first", "a.txt"), ("second", "b.txt")] {
+            fs::write(repo.join(path), message).unwrap();
+            git(repo, &["add", path]);
+            git(repo, &["commit", "-q", "-m", message]);
+        }
+        fs::write(dir.path().join("LICENSE"), "license\n").unwrap();
+        let fhash = git(repo, &["hash-object", "-w", "LICENSE"]);
+
+        let code = r#"
+import git_filter_repo as fr
+
+def fixup_commits(commit, metadata):
+  if len(commit.parents) == 0:
+    commit.file_changes.append(fr.FileChange(b'M', b'LICENSE', fhash, b'100644'))
+
+fr_args = fr.FilteringOptions.parse_args(['--preserve-commit-encoding',
+                                          '--force',
+                                          '--replace-refs', 'update-no-add'])
+fr_args.source = repo
+fr_args.quiet = True
+filter = fr.RepoFilter(fr_args, commit_callback=fixup_commits)
+filter.run()
+"#;
+        with_gil(|py| {
+            module(py).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("repo", repo).unwrap();
+            globals
+                .set_item("fhash", PyBytes::new(py, fhash.trim_end().as_bytes()))
+                .unwrap();
+            py.run(code, Some(globals), None).unwrap();
+        });
+        assert_eq!(git(repo, &["log", "--format=%s"]), "second\nfirst\n");
+        assert_eq!(git(repo, &["show", "main~1:LICENSE"]), "license\n");
+        assert_eq!(git(repo, &["show", "main:LICENSE"]), "license\n");
+    }
+
+    /// Runs `contrib/filter-repo-demos/signed-off-by` from filter-repo, with
+    /// the repo passed as `repo` instead of the working directory, and the
+    /// revisions passed as `rev_list_args` instead of arguments.
+    #[test]
+    fn signed_off_by_demo() {
+        let dir = init_repo();
+        let repo = dir.path();
+        let messages = [
+            "first",
+            "second\n\nCc: Someone <someone@example.com>",
+            "third\n\nSigned-off-by: A U Thor <author@example.com>",
+        ];
+        for (i, message) in messages.into_iter().enumerate() {
+            fs::write(repo.join("a.txt"), i.to_string()).unwrap();
+            git(repo, &["add", "a.txt"]);
+            git(repo, &["commit", "-q", "-m", message]);
+        }
+
+        let code = r#"
+import re
+import subprocess
+import git_filter_repo as fr
+
+user_name = subprocess.check_output(['git', '-C', repo] + 'config user.name'.split()).rstrip()
+user_email = subprocess.check_output(['git', '-C', repo] + 'config user.email'.split()).rstrip()
+trailer = b'Signed-off-by: %s <%s>' % (user_name, user_email)
+
+def add_signed_off_by_trailer(commit, metadata):
+  if trailer in commit.message:
+    return
+
+  # We want to add the trailer, but we want it to be separated from any
+  # existing paragraphs by a blank line.  However, if the commit message
+  # already ends with trailers, we want all trailers to be in the same
+  # paragraph.
+  sep = b''
+  if commit.message[-1:] != b'\n':
+    sep += b'\n'
+
+  last_paragraph = commit.message.rstrip().split(b'\n\n')[-1]
+  if not all(re.match(b'[A-Za-z0-9-]+: ', line)
+             for line in last_paragraph.splitlines()):
+    sep += b'\n'
+
+  commit.message += sep + trailer
+
+# Setting source and target to anything prevents:
+#   * remapping origin remote tracking branches to regular branches
+#   * deletion of the origin remote
+#   * nuking unused refs
+#   * nuking reflogs
+#   * repacking
+# so we cheat and set source and target both to '.'
+fr_args = fr.FilteringOptions.parse_args(['--force',
+                                          '--refs'] + rev_list_args)
+fr_args.refs = rev_list_args
+fr_args.source = repo
+fr_args.target = repo
+fr_args.quiet = True
+filter = fr.RepoFilter(fr_args, commit_callback=add_signed_off_by_trailer)
+filter.run()
+"#;
+        with_gil(|py| {
+            module(py).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("repo", repo).unwrap();
+            globals
+                .set_item("rev_list_args", vec!["main~2..main"])
+                .unwrap();
+            py.run(code, Some(globals), None).unwrap();
+        });
+        assert_eq!(
+            git(repo, &["log", "--format=%B%x00"]),
+            "third\n\nSigned-off-by: A U Thor <author@example.com>\n\0\n\
+             second\n\nCc: Someone <someone@example.com>\n\
+             Signed-off-by: A U Thor <author@example.com>\0\n\
+             first\n\0\n",
+        );
+    }
+
+    /// Runs `contrib/filter-repo-demos/lint-history` from filter-repo, with
+    /// the repo passed as `repo` instead of the working directory, and the
+    /// arguments passed as `lint_args`. With `--filenames-important`, commit
+    /// callbacks read blobs by ID, which needs them to be left out of the
+    /// stream.
+    #[test]
+    fn lint_history_demo() {
+        let dir = init_repo();
+        let repo = dir.path();
+        for (message, path, contents) in [
+            ("first", "a.txt", "TODO a\n"),
+            ("second", "b.md", "TODO b\n"),
+            ("third", "c.txt", "TODO a\n"),
+        ] {
+            fs::write(repo.join(path), contents).unwrap();
+            git(repo, &["add", path]);
+            git(repo, &["commit", "-q", "-m", message]);
+        }
+
+        let code = r#"
+import argparse
+import os
+import subprocess
+import tempfile
+import git_filter_repo as fr
+
+parser = argparse.ArgumentParser(description='Lint all files in history')
+parser.add_argument('--relevant', metavar="FUNCTION_BODY")
+parser.add_argument('--filenames-important', action='store_true')
+parser.add_argument('--refs', nargs='+')
+parser.add_argument('command', nargs=argparse.REMAINDER)
+lint_args = parser.parse_args(lint_args)
+if not lint_args.command:
+  raise SystemExit("Error: Need to specify a lint command")
+if len(lint_args.command) > 1 and lint_args.command[0] == '--':
+  lint_args.command.pop(0)
+
+tmpdir = None
+blobs_handled = {}
+cat_file_process = None
+def lint_with_real_filenames(commit, metadata):
+  for change in commit.file_changes:
+    if change.blob_id in blobs_handled:
+      change.blob_id = blobs_handled[change.blob_id]
+    elif change.type == b'D':
+      continue
+    elif not is_relevant(change.filename):
+      continue
+    else:
+      # Get the old blob contents
+      cat_file_process.stdin.write(change.blob_id + b'\n')
+      cat_file_process.stdin.flush()
+      objhash, objtype, objsize = cat_file_process.stdout.readline().split()
+      contents_plus_newline = cat_file_process.stdout.read(int(objsize)+1)
+
+      # Write it out to a file with the same basename
+      filename = os.path.join(tmpdir, os.path.basename(change.filename))
+      with open(filename, "wb") as f:
+        f.write(contents_plus_newline[:-1])
+
+      # Lint the file
+      subprocess.check_call(lint_args.command + [filename.decode('utf-8')])
+
+      # Get the new contents
+      with open(filename, "rb") as f:
+        blob = fr.Blob(f.read())
+
+      # Insert the new file into the filter's stream, and remove the tempfile
+      filter.insert(blob)
+      os.remove(filename)
+
+      # Record our handling of the blob and use it for this change
+      blobs_handled[change.blob_id] = blob.id
+      change.blob_id = blob.id
+
+def lint_non_binary_blobs(blob, metadata):
+  if not b"\0" in blob.data[0:8192]:
+    filename = os.path.join(repo, '.git/info/tmpfile')
+    with open(filename, "wb") as f:
+      f.write(blob.data)
+    subprocess.check_call(lint_args.command + [filename])
+    with open(filename, "rb") as f:
+      blob.data = f.read()
+    os.remove(filename)
+
+if lint_args.filenames_important and not lint_args.relevant:
+  lint_args.relevant = 'return True'
+if lint_args.relevant:
+  body = lint_args.relevant
+  exec('def is_relevant(filename):\n  '+'\n  '.join(body.splitlines()),
+       globals())
+  lint_args.filenames_important = True
+input_args = []
+if lint_args.refs:
+  input_args += ["--refs"] + lint_args.refs
+args = fr.FilteringOptions.parse_args(input_args, error_on_empty = False)
+args.force = True
+args.source = repo
+args.quiet = True
+if lint_args.filenames_important:
+  tmpdir = tempfile.mkdtemp().encode()
+  cat_file_process = subprocess.Popen(['git', 'cat-file', '--batch'],
+                                      stdin = subprocess.PIPE,
+                                      stdout = subprocess.PIPE,
+                                      cwd = repo)
+  filter = fr.RepoFilter(args, commit_callback=lint_with_real_filenames)
+  filter.run()
+  cat_file_process.stdin.close()
+  cat_file_process.wait()
+else:
+  if not os.path.exists(os.path.join(repo, '.git/info')):
+    os.makedirs(os.path.join(repo, '.git/info'))
+  filter = fr.RepoFilter(args, blob_callback=lint_non_binary_blobs)
+  filter.run()
+"#;
+        let run = |lint_args: &[&str]| {
+            with_gil(|py| {
+                module(py).unwrap();
+                let globals = PyDict::new(py);
+                globals.set_item("repo", repo).unwrap();
+                globals.set_item("lint_args", lint_args.to_vec()).unwrap();
+                py.run(code, Some(globals), None).unwrap();
+            });
+        };
+        run(&[
+            "--relevant",
+            "return filename.endswith(b'.txt')",
+            "sed",
+            "-i",
+            "s/TODO/DONE/",
+        ]);
+        assert_eq!(git(repo, &["show", "main:a.txt"]), "DONE a\n");
+        assert_eq!(git(repo, &["show", "main:b.md"]), "TODO b\n");
+        assert_eq!(git(repo, &["show", "main:c.txt"]), "DONE a\n");
+        assert_eq!(git(repo, &["log", "--format=%s"]), "third\nsecond\nfirst\n");
+        run(&["sed", "-i", "s/TODO/FIXED/"]);
+        assert_eq!(git(repo, &["show", "main:b.md"]), "FIXED b\n");
+        assert_eq!(git(repo, &["show", "main:a.txt"]), "DONE a\n");
+    }
+}
@@ -3,87 +3,111 @@
 // This file is part of git-transform-repo, distributed under the GPL 2.0 with a
 // linking exception. For the full terms, see the included COPYING file.
 
-use std::hash::{Hash, Hasher};
+use std::borrow::Cow;
 
-use fast_export::FromBytes;
-use pyo3::{types::PyBytes, Python};
+use pyo3::{types::PyBytes, Py, Python};
 
-#[derive(Clone, Copy, Debug)]
-pub enum PyLazyBytes<'a, 'py> {
-    Borrowed(&'a [u8]),
-    Python(&'py PyBytes),
-}
+use crate::parser::Bytes;
 
-impl<'a, 'py> PyLazyBytes<'a, 'py> {
-    #[inline]
-    pub fn new(bytes: &'a [u8]) -> Self {
-        PyLazyBytes::Borrowed(bytes)
-    }
+/// Bytes of an object given to a Python callback, which are copied into a
+/// `PyBytes` only when Python reads them. Until Python assigns to them, the
+/// original bytes are kept, so that unmodified fields are given back to Rust
+/// without a round-trip through Python.
+///
+/// Unlike Rust callbacks, these do not borrow from the parser's buffer: Python
+/// can keep an object past the command it was parsed from, so the bytes are
+/// copied once, when the view is created.
+#[derive(Debug)]
+pub struct PyLazyBytes {
+    /// The bytes from Rust, owned, until Python assigns to them.
+    rust: Option<Bytes<'static>>,
+    /// The bytes as a Python object, once Python has read or assigned them.
+    python: Option<Py<PyBytes>>,
+}
 
+impl PyLazyBytes {
+    /// Wraps bytes from Rust, copying them, if they are borrowed.
     #[inline]
-    pub fn as_bytes(&self) -> &[u8] {
-        match *self {
-            PyLazyBytes::Borrowed(bytes) => bytes,
-            PyLazyBytes::Python(bytes) => bytes.as_bytes(),
+    pub fn new(bytes: Bytes<'_>) -> Self {
+        PyLazyBytes {
+            rust: Some(Cow::Owned(bytes.into_owned())),
+            python: None,
         }
     }
 
     #[inline]
-    pub fn to_python(&self, py: Python<'py>) -> &'py PyBytes {
-        match *self {
-            PyLazyBytes::Borrowed(bytes) => PyBytes::new(py, bytes),
-            PyLazyBytes::Python(bytes) => bytes,
+    pub fn from_python(bytes: Py<PyBytes>) -> Self {
+        PyLazyBytes {
+            rust: None,
+            python: Some(bytes),
         }
     }
-}
 
-impl<'a> From<&'a [u8]> for PyLazyBytes<'a, 'static> {
-    #[inline]
-    fn from(bytes: &'a [u8]) -> Self {
-        PyLazyBytes::Borrowed(bytes)
+    /// Returns the bytes as a Python object, which is copied from the Rust
+    /// bytes on the first read.
+    pub fn get(&mut self, py: Python<'_>) -> Py<PyBytes> {
+        let rust = &self.rust;
+        self.python
+            .get_or_insert_with(|| PyBytes::new(py, rust.as_deref().unwrap_or_default()).into())
+            .clone_ref(py)
     }
-}
 
-impl<'py> From<&'py PyBytes> for PyLazyBytes<'static, 'py> {
+    /// Replaces the bytes with a value assigned by Python.
     #[inline]
-    fn from(bytes: &'py PyBytes) -> Self {
-        PyLazyBytes::Python(bytes)
+    pub fn set(&mut self, bytes: Py<PyBytes>) {
+        self.rust = None;
+        self.python = Some(bytes);
     }
-}
 
-impl<'a> FromBytes<'a> for PyLazyBytes<'a, 'static> {
     #[inline]
-    fn from_bytes(bytes: &'a [u8]) -> Self {
-        PyLazyBytes::Borrowed(bytes)
+    pub fn as_bytes<'a>(&'a self, py: Python<'a>) -> &'a [u8] {
+        match (&self.rust, &self.python) {
+            (Some(bytes), _) => bytes,
+            (None, Some(bytes)) => bytes.as_bytes(py),
+            (None, None) => &[],
+        }
     }
-}
 
-impl PartialEq for PyLazyBytes<'_, '_> {
+    /// Copies the bytes for Rust.
     #[inline]
-    fn eq(&self, other: &Self) -> bool {
-        self.as_bytes() == other.as_bytes()
+    pub fn to_bytes(&self, py: Python<'_>) -> Bytes<'static> {
+        match &self.rust {
+            Some(bytes) => bytes.clone(),
+            None => Cow::Owned(self.as_bytes(py).to_vec()),
+        }
     }
-}
 
-impl Eq for PyLazyBytes<'_, '_> {}
-
-impl PartialEq<[u8]> for PyLazyBytes<'_, '_> {
+    /// Moves the bytes out for Rust, which only copies them when Python has
+    /// assigned to them. Afterwards, they are empty.
     #[inline]
-    fn eq(&self, other: &[u8]) -> bool {
-        self.as_bytes() == other
+    pub fn take(&mut self, py: Python<'_>) -> Bytes<'static> {
+        let bytes = match self.rust.take() {
+            Some(bytes) => bytes,
+            None => self.to_bytes(py),
+        };
+        self.python = None;
+        bytes
     }
 }
 
-impl PartialEq<PyLazyBytes<'_, '_>> for [u8] {
-    #[inline]
-    fn eq(&self, other: &PyLazyBytes<'_, '_>) -> bool {
-        self == other.as_bytes()
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Hash for PyLazyBytes<'_, '_> {
-    #[inline]
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.as_bytes().hash(state)
+    #[test]
+    fn copy_on_access() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let data = b"unmodified".to_vec();
+            let ptr = data.as_ptr();
+            let mut bytes = PyLazyBytes::new(Cow::Owned(data));
+            assert_eq!(bytes.get(py).as_bytes(py), b"unmodified");
+            assert_eq!(bytes.take(py).as_ptr(), ptr);
+
+            let mut bytes = PyLazyBytes::new(Cow::Borrowed(b"original"));
+            bytes.set(PyBytes::new(py, b"assigned").into());
+            assert_eq!(bytes.as_bytes(py), b"assigned");
+            assert_eq!(&*bytes.take(py), b"assigned");
+        });
     }
 }
@@ -0,0 +1,511 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Transcoding of commit and tag metadata to UTF-8.
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use bstr::ByteSlice;
+use encoding_rs::{DecoderResult, Encoding};
+use fast_export::command::{Commit, PersonIdent, Tag};
+use thiserror::Error;
+
+/// How to handle commits with an `encoding` directive.
+///
+// Corresponds to `--reencode` in `git.git/builtin/fast-export.c`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Reencode {
+    /// Transcode the message and idents to UTF-8 and drop the `encoding`
+    /// directive.
+    #[default]
+    Yes,
+    /// Keep the message, idents, and `encoding` directive as they are.
+    No,
+    /// Fail when a commit has an `encoding` directive.
+    Abort,
+}
+
+/// Transcodes commit and tag metadata from their declared encoding to UTF-8.
+#[derive(Clone, Debug, Default)]
+pub struct Reencoder {
+    mode: Reencode,
+    /// The encodings tried, in order, for text that has no `encoding`
+    /// directive, but is not valid UTF-8. Tags never have a directive.
+    fallbacks: Vec<Charset>,
+}
+
+/// A character encoding that can be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    /// ISO-8859-1, where each byte is the Unicode scalar of the same value.
+    /// This is what iconv, which Git uses, means by Latin-1. The WHATWG label
+    /// `latin1` instead means windows-1252, which maps 0x80–0x9F differently.
+    Latin1,
+    /// An encoding from the WHATWG Encoding Standard.
+    Whatwg(&'static Encoding),
+}
+
+/// A text field of a commit or tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+    Message,
+    AuthorName,
+    AuthorEmail,
+    CommitterName,
+    CommitterEmail,
+    TaggerName,
+    TaggerEmail,
+}
+
+/// An error from re-encoding a commit or tag.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ReencodeError {
+    #[error("commit has encoding {encoding:?}, but --reencode=abort")]
+    Abort { encoding: String },
+    #[error("unknown encoding {encoding:?}")]
+    UnknownEncoding { encoding: String },
+    #[error("{field} is not valid {charset} at byte offset {offset}")]
+    Undecodable {
+        field: Field,
+        charset: Charset,
+        /// The offset of the first undecodable byte in the field.
+        offset: usize,
+    },
+}
+
+impl Reencoder {
+    /// Creates a new `Reencoder` with the given mode and no fallback encodings.
+    #[inline]
+    pub fn new(mode: Reencode) -> Self {
+        Reencoder {
+            mode,
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Sets the encodings tried for text without a declared encoding, which is
+    /// not valid UTF-8. This is common in histories converted from CVS, which
+    /// may mix several encodings.
+    ///
+    /// Each commit or tag is detected separately and uses the first encoding,
+    /// which decodes all of its fields. Encodings which decode any bytes, like
+    /// ISO-8859-1, should therefore be last.
+    #[inline]
+    pub fn fallbacks(mut self, charsets: Vec<Charset>) -> Self {
+        self.fallbacks = charsets;
+        self
+    }
+
+    #[inline]
+    pub fn mode(&self) -> Reencode {
+        self.mode
+    }
+
+    /// Transcodes the message and idents of a commit to UTF-8 and drops its
+    /// `encoding` directive. On error, the commit is not modified.
+    pub fn reencode_commit(&self, commit: &mut Commit<Cow<'_, [u8]>>) -> Result<(), ReencodeError> {
+        let decoded = match &commit.encoding {
+            Some(encoding) => match self.mode {
+                Reencode::Yes => {
+                    decode_commit(Charset::for_label(&encoding.encoding)?, commit, true)?
+                }
+                Reencode::No => return Ok(()),
+                Reencode::Abort => {
+                    return Err(ReencodeError::Abort {
+                        encoding: encoding.encoding.to_str_lossy().into_owned(),
+                    })
+                }
+            },
+            None => self.detect(|charset| decode_commit(charset, commit, false))?,
+        };
+
+        let [message, author_name, author_email, committer_name, committer_email] = decoded;
+        set_field(&mut commit.message, message);
+        if let Some(author) = &mut commit.author {
+            set_field(&mut author.name, author_name);
+            set_field(&mut author.email, author_email);
+        }
+        set_field(&mut commit.committer.name, committer_name);
+        set_field(&mut commit.committer.email, committer_email);
+        commit.encoding = None;
+        Ok(())
+    }
+
+    /// Transcodes the message and tagger of a tag to UTF-8 using the fallback
+    /// encodings, since fast-import has no `encoding` directive for tags. On
+    /// error, the tag is not modified.
+    pub fn reencode_tag(&self, tag: &mut Tag<Cow<'_, [u8]>>) -> Result<(), ReencodeError> {
+        let [message, tagger_name, tagger_email] = self.detect(|charset| {
+            let (name, email) = match &tag.tagger {
+                Some(tagger) => decode_ident(
+                    charset,
+                    tagger,
+                    Field::TaggerName,
+                    Field::TaggerEmail,
+                    false,
+                )?,
+                None => (None, None),
+            };
+            let message = decode_field(charset, &tag.message, Field::Message, false)?;
+            Ok([message, name, email])
+        })?;
+
+        set_field(&mut tag.message, message);
+        if let Some(tagger) = &mut tag.tagger {
+            set_field(&mut tagger.name, tagger_name);
+            set_field(&mut tagger.email, tagger_email);
+        }
+        Ok(())
+    }
+
+    /// Decodes the fields of an object without a declared encoding with the
+    /// first fallback encoding, which decodes all of them. When none does, the
+    /// error from the last is returned.
+    fn detect<T: Default>(
+        &self,
+        mut decode: impl FnMut(Charset) -> Result<T, ReencodeError>,
+    ) -> Result<T, ReencodeError> {
+        if self.mode == Reencode::No {
+            return Ok(T::default());
+        }
+        let mut res = Ok(T::default());
+        for &charset in &self.fallbacks {
+            res = decode(charset);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+}
+
+impl Charset {
+    /// Looks up an encoding by a label, as used in the `encoding` header of
+    /// commits.
+    pub fn for_label(label: &[u8]) -> Result<Self, ReencodeError> {
+        let trimmed = label.trim();
+        let is_latin1 = [
+            &b"iso-8859-1"[..],
+            b"iso8859-1",
+            b"iso_8859-1",
+            b"iso88591",
+            b"latin1",
+            b"latin-1",
+            b"l1",
+        ]
+        .iter()
+        .any(|name| trimmed.eq_ignore_ascii_case(name));
+        if is_latin1 {
+            return Ok(Charset::Latin1);
+        }
+        Encoding::for_label(trimmed)
+            .map(Charset::Whatwg)
+            .ok_or_else(|| ReencodeError::UnknownEncoding {
+                encoding: label.to_str_lossy().into_owned(),
+            })
+    }
+
+    /// Returns whether text in this encoding is already UTF-8.
+    #[inline]
+    pub fn is_utf8(&self) -> bool {
+        matches!(self, Charset::Whatwg(encoding) if *encoding == encoding_rs::UTF_8)
+    }
+
+    /// Decodes the bytes to UTF-8 or returns the offset of the first
+    /// undecodable byte.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, str>, usize> {
+        match self {
+            Charset::Latin1 => Ok(encoding_rs::mem::decode_latin1(bytes)),
+            Charset::Whatwg(encoding) => {
+                let mut decoder = encoding.new_decoder_without_bom_handling();
+                let mut out = String::with_capacity(
+                    decoder
+                        .max_utf8_buffer_length_without_replacement(bytes.len())
+                        .unwrap_or(bytes.len()),
+                );
+                let mut read = 0;
+                loop {
+                    let (res, n) = decoder.decode_to_string_without_replacement(
+                        &bytes[read..],
+                        &mut out,
+                        true,
+                    );
+                    read += n;
+                    match res {
+                        DecoderResult::InputEmpty => return Ok(Cow::Owned(out)),
+                        DecoderResult::OutputFull => out.reserve(bytes.len() - read + 4),
+                        DecoderResult::Malformed(bad, extra) => {
+                            return Err(read - bad as usize - extra as usize)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a field, or returns `None` when it does not need to change. When
+/// the charset is a fallback, text that is valid UTF-8 is kept as is.
+fn decode_field(
+    charset: Charset,
+    bytes: &[u8],
+    field: Field,
+    declared: bool,
+) -> Result<Option<String>, ReencodeError> {
+    if bytes.is_ascii() || (!declared || charset.is_utf8()) && bytes.to_str().is_ok() {
+        return Ok(None);
+    }
+    match charset.decode(bytes) {
+        Ok(Cow::Borrowed(_)) => Ok(None),
+        Ok(Cow::Owned(s)) => Ok(Some(s)),
+        Err(offset) => Err(ReencodeError::Undecodable {
+            field,
+            charset,
+            offset,
+        }),
+    }
+}
+
+/// Decodes the message, author, and committer of a commit, or returns `None`
+/// for each field that does not need to change.
+fn decode_commit(
+    charset: Charset,
+    commit: &Commit<Cow<'_, [u8]>>,
+    declared: bool,
+) -> Result<[Option<String>; 5], ReencodeError> {
+    let message = decode_field(charset, &commit.message, Field::Message, declared)?;
+    let (author_name, author_email) = match &commit.author {
+        Some(author) => decode_ident(
+            charset,
+            author,
+            Field::AuthorName,
+            Field::AuthorEmail,
+            declared,
+        )?,
+        None => (None, None),
+    };
+    let (committer_name, committer_email) = decode_ident(
+        charset,
+        &commit.committer,
+        Field::CommitterName,
+        Field::CommitterEmail,
+        declared,
+    )?;
+    Ok([
+        message,
+        author_name,
+        author_email,
+        committer_name,
+        committer_email,
+    ])
+}
+
+fn decode_ident(
+    charset: Charset,
+    ident: &PersonIdent<Cow<'_, [u8]>>,
+    name_field: Field,
+    email_field: Field,
+    declared: bool,
+) -> Result<(Option<String>, Option<String>), ReencodeError> {
+    Ok((
+        decode_field(charset, &ident.name, name_field, declared)?,
+        decode_field(charset, &ident.email, email_field, declared)?,
+    ))
+}
+
+#[inline]
+fn set_field(field: &mut Cow<'_, [u8]>, value: Option<String>) {
+    if let Some(value) = value {
+        *field = Cow::Owned(value.into_bytes());
+    }
+}
+
+impl FromStr for Reencode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yes" | "true" => Ok(Reencode::Yes),
+            "no" | "false" => Ok(Reencode::No),
+            "abort" => Ok(Reencode::Abort),
+            _ => Err(format!("unknown reencoding mode: {s}")),
+        }
+    }
+}
+
+impl FromStr for Charset {
+    type Err = ReencodeError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Charset::for_label(s.as_bytes())
+    }
+}
+
+impl Display for Charset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Charset::Latin1 => f.write_str("ISO-8859-1"),
+            Charset::Whatwg(encoding) => f.write_str(encoding.name()),
+        }
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Message => "message",
+            Field::AuthorName => "author name",
+            Field::AuthorEmail => "author email",
+            Field::CommitterName => "committer name",
+            Field::CommitterEmail => "committer email",
+            Field::TaggerName => "tagger name",
+            Field::TaggerEmail => "tagger email",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_export::command::{Branch, Encoding as EncodingDirective};
+
+    use super::*;
+
+    fn ident(name: &'static [u8]) -> PersonIdent<Cow<'static, [u8]>> {
+        PersonIdent {
+            name: Cow::Borrowed(name),
+            email: Cow::Borrowed(b"user@example.com"),
+            date: Cow::Borrowed(b"1700000000 +0000"),
+        }
+    }
+
+    fn commit(
+        encoding: Option<&'static [u8]>,
+        name: &'static [u8],
+        message: &'static [u8],
+    ) -> Commit<Cow<'static, [u8]>> {
+        Commit {
+            branch: Branch {
+                branch: Cow::Borrowed(b"refs/heads/main"),
+            },
+            mark: None,
+            original_oid: None,
+            author: Some(ident(name)),
+            committer: ident(b"Committer"),
+            signatures: Vec::new(),
+            encoding: encoding.map(|encoding| EncodingDirective {
+                encoding: Cow::Borrowed(encoding),
+            }),
+            message: Cow::Borrowed(message),
+            from: None,
+            merge: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn latin1() {
+        let mut c = commit(Some(b"ISO-8859-1"), b"Ren\xe9", b"caf\xe9 \x80\n");
+        Reencoder::new(Reencode::Yes)
+            .reencode_commit(&mut c)
+            .unwrap();
+        assert_eq!(c.encoding, None);
+        assert_eq!(c.author.unwrap().name.as_bstr(), "René");
+        // True Latin-1 maps 0x80 to U+0080, unlike windows-1252.
+        assert_eq!(c.message.as_bstr(), "café \u{80}\n");
+    }
+
+    #[test]
+    fn shift_jis() {
+        let mut c = commit(Some(b"Shift_JIS"), b"\x93\xfa\x96\x7b", b"\x82\xa0\n");
+        Reencoder::new(Reencode::Yes)
+            .reencode_commit(&mut c)
+            .unwrap();
+        assert_eq!(c.author.unwrap().name.as_bstr(), "日本");
+        assert_eq!(c.message.as_bstr(), "あ\n");
+    }
+
+    #[test]
+    fn undecodable() {
+        let mut c = commit(Some(b"sjis"), b"Name", b"ok \x82\xa0 \xff\n");
+        let orig = c.clone();
+        let err = Reencoder::new(Reencode::Yes).reencode_commit(&mut c);
+        assert_eq!(
+            err,
+            Err(ReencodeError::Undecodable {
+                field: Field::Message,
+                charset: Charset::Whatwg(encoding_rs::SHIFT_JIS),
+                offset: 6,
+            }),
+        );
+        assert_eq!(c, orig);
+    }
+
+    #[test]
+    fn modes() {
+        let c = commit(Some(b"latin1"), b"Ren\xe9", b"\n");
+        let mut c1 = c.clone();
+        Reencoder::new(Reencode::No)
+            .reencode_commit(&mut c1)
+            .unwrap();
+        assert_eq!(c1, c);
+        let mut c2 = c.clone();
+        assert!(matches!(
+            Reencoder::new(Reencode::Abort).reencode_commit(&mut c2),
+            Err(ReencodeError::Abort { .. }),
+        ));
+        let mut c3 = commit(Some(b"x-unknown"), b"Name", b"\n");
+        assert!(matches!(
+            Reencoder::new(Reencode::Yes).reencode_commit(&mut c3),
+            Err(ReencodeError::UnknownEncoding { .. }),
+        ));
+    }
+
+    #[test]
+    fn fallback() {
+        let r = Reencoder::new(Reencode::Yes).fallbacks(vec![Charset::Latin1]);
+        let mut c = commit(None, "Ren\u{e9}".as_bytes(), b"caf\xe9\n");
+        r.reencode_commit(&mut c).unwrap();
+        // Valid UTF-8 is kept and only invalid fields are decoded.
+        assert_eq!(c.author.unwrap().name.as_bstr(), "René");
+        assert_eq!(c.message.as_bstr(), "café\n");
+    }
+
+    #[test]
+    fn mixed_fallbacks() {
+        let r = Reencoder::new(Reencode::Yes).fallbacks(vec![
+            Charset::Whatwg(encoding_rs::SHIFT_JIS),
+            Charset::Latin1,
+        ]);
+        let mut sjis = commit(None, b"\x93\xfa\x96\x7b", b"\x82\xa0\n");
+        r.reencode_commit(&mut sjis).unwrap();
+        assert_eq!(sjis.author.unwrap().name.as_bstr(), "日本");
+        assert_eq!(sjis.message.as_bstr(), "あ\n");
+        // The message is not valid Shift_JIS, so the whole commit is Latin-1.
+        let mut latin1 = commit(None, b"Ren\xe9", b"caf\xe9\n");
+        r.reencode_commit(&mut latin1).unwrap();
+        assert_eq!(latin1.author.unwrap().name.as_bstr(), "René");
+        assert_eq!(latin1.message.as_bstr(), "café\n");
+
+        let r = Reencoder::new(Reencode::Yes).fallbacks(vec![
+            Charset::Whatwg(encoding_rs::SHIFT_JIS),
+            Charset::Whatwg(encoding_rs::UTF_8),
+        ]);
+        let mut c = commit(None, b"Name", b"caf\xe9\n");
+        let orig = c.clone();
+        assert_eq!(
+            r.reencode_commit(&mut c),
+            Err(ReencodeError::Undecodable {
+                field: Field::Message,
+                charset: Charset::Whatwg(encoding_rs::UTF_8),
+                offset: 3,
+            }),
+        );
+        assert_eq!(c, orig);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! The mapping from original commits to rewritten commits.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Bound,
+};
+
+use fast_export::command::Mark;
+
+/// The mapping from original commits to the commits they were rewritten to.
+/// Filtering commits gives them new hashes, so this is recorded both for
+/// diagnostics and so that references to old commits can be rewritten.
+#[derive(Clone, Debug, Default)]
+pub struct CommitRenames {
+    /// Original commit IDs mapped to their new IDs, or to `None`, when the
+    /// commit became empty and was pruned or was otherwise dropped.
+    oids: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Marks of commits that were pruned or dropped.
+    pruned_marks: HashSet<Mark>,
+    /// The new IDs, ordered for prefix lookups.
+    new_oids: BTreeSet<Vec<u8>>,
+}
+
+/// What an original commit became.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renamed<'a> {
+    /// The commit was not part of the filtered history, so is unchanged.
+    Unknown,
+    /// The commit was rewritten to the commit with this ID.
+    Rewritten(&'a [u8]),
+    /// The commit was pruned or dropped.
+    Pruned,
+}
+
+impl CommitRenames {
+    #[inline]
+    pub fn new() -> Self {
+        CommitRenames::default()
+    }
+
+    /// Records that the commit `old` was rewritten to `new`, or was pruned,
+    /// when `new` is `None`.
+    pub fn insert(&mut self, old: &[u8], new: Option<&[u8]>) {
+        if let Some(new) = new {
+            self.new_oids.insert(new.to_owned());
+        }
+        self.oids.insert(old.to_owned(), new.map(<[u8]>::to_owned));
+    }
+
+    /// Records that the commit with this mark was pruned.
+    pub fn insert_pruned_mark(&mut self, mark: Mark) {
+        self.pruned_marks.insert(mark);
+    }
+
+    /// Returns what the commit with this original ID became.
+    pub fn get(&self, old: &[u8]) -> Renamed<'_> {
+        match self.oids.get(old) {
+            Some(Some(new)) => Renamed::Rewritten(new),
+            Some(None) => Renamed::Pruned,
+            None => Renamed::Unknown,
+        }
+    }
+
+    /// Abbreviates a new commit ID to `len` hex digits, or longer, when that
+    /// would be ambiguous with another new commit ID recorded so far or, as
+    /// checked by `is_ambiguous`, with another object, such as those in the
+    /// source repo.
+    ///
+    /// Commits which are recorded later can still make the abbreviation
+    /// ambiguous, which [`CommitRenames::is_ambiguous`] checks once all are
+    /// recorded.
+    pub fn abbreviate<'a, E>(
+        &self,
+        new: &'a [u8],
+        len: usize,
+        mut is_ambiguous: impl FnMut(&[u8]) -> Result<bool, E>,
+    ) -> Result<&'a [u8], E> {
+        let mut len = len.min(new.len());
+        while len < new.len() {
+            let prefix = &new[..len];
+            if !self.is_ambiguous(prefix) && !is_ambiguous(prefix)? {
+                break;
+            }
+            len += 1;
+        }
+        Ok(&new[..len])
+    }
+
+    /// Returns whether more than one new commit ID starts with the prefix.
+    pub fn is_ambiguous(&self, prefix: &[u8]) -> bool {
+        let mut matches = self
+            .new_oids
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|oid| oid.starts_with(prefix));
+        matches.nth(1).is_some()
+    }
+
+    /// Returns whether the commit with this mark was pruned.
+    #[inline]
+    pub fn is_pruned_mark(&self, mark: Mark) -> bool {
+        self.pruned_marks.contains(&mark)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.oids.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.oids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abbreviate() {
+        let unambiguous = |_: &[u8]| Ok::<_, ()>(false);
+        let mut renames = CommitRenames::new();
+        renames.insert(b"1111111", Some(b"abcdef01"));
+        renames.insert(b"2222222", Some(b"abcd0000"));
+        renames.insert(b"3333333", None);
+        assert_eq!(
+            renames.abbreviate(b"abcdef01", 3, unambiguous),
+            Ok(&b"abcde"[..])
+        );
+        assert_eq!(
+            renames.abbreviate(b"abcd0000", 6, unambiguous),
+            Ok(&b"abcd00"[..])
+        );
+        assert_eq!(
+            renames.abbreviate(b"abcd0000", 20, unambiguous),
+            Ok(&b"abcd0000"[..])
+        );
+        assert_eq!(renames.get(b"3333333"), Renamed::Pruned);
+
+        // Other objects can be ambiguous with it, too.
+        let in_repo = |prefix: &[u8]| Ok::<_, ()>(b"abcdef0".starts_with(prefix));
+        assert_eq!(
+            renames.abbreviate(b"abcdef01", 3, in_repo),
+            Ok(&b"abcdef01"[..])
+        );
+
+        // Commits recorded later can make an abbreviation ambiguous.
+        assert!(!renames.is_ambiguous(b"abcde"));
+        renames.insert(b"4444444", Some(b"abcde999"));
+        assert!(renames.is_ambiguous(b"abcde"));
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Replacement of text in blobs and messages.
+
+use std::borrow::Cow;
+
+use bstr::ByteSlice;
+use regex::bytes::Regex;
+
+use crate::paths::{python_replacement, translate_glob};
+
+/// The replacement for expressions without an explicit `==>` replacement.
+///
+// Corresponds to `git-filter-repo:FilteringOptions.default_replace_text`.
+pub const DEFAULT_REPLACEMENT: &[u8] = b"***REMOVED***";
+
+/// Text replacements from an expressions file, as used by `--replace-text`
+/// and `--replace-message`.
+///
+/// Literals are applied as a group before regexes, regardless of the order in
+/// which they were given, and globs are converted to regexes. See
+/// “Text replacement precedence” in `docs/filter-repo.md`.
+#[derive(Clone, Debug, Default)]
+pub struct ReplaceText {
+    literals: Vec<(Vec<u8>, Vec<u8>)>,
+    regexes: Vec<(Regex, Vec<u8>)>,
+}
+
+impl ReplaceText {
+    /// Parses the contents of an expressions file. Each line is a literal,
+    /// optionally prefixed with `literal:`, or a pattern prefixed with `glob:`
+    /// or `regex:`, and is optionally followed by `==>` and its replacement.
+    ///
+    // Corresponds to `git-filter-repo:FilteringOptions.get_replace_text`.
+    pub fn parse(contents: &[u8]) -> Result<Self, regex::Error> {
+        let mut replace = ReplaceText::default();
+        for line in contents.split_str(b"\n") {
+            let line = line.trim_end_with(|c| c == '\r' || c == '\n');
+            let (line, replacement) = match line.rfind(b"==>") {
+                Some(i) => (&line[..i], &line[i + 3..]),
+                None => (line, DEFAULT_REPLACEMENT),
+            };
+
+            // filter-repo tests the pattern for truthiness, so an empty
+            // `regex:` or `glob:` falls through to be a literal.
+            let regex = if let Some(regex) = line.strip_prefix(b"regex:") {
+                (!regex.is_empty()).then(|| regex.to_str_lossy().into_owned())
+            } else if let Some(glob) = line.strip_prefix(b"glob:") {
+                (!glob.is_empty()).then(|| format!("(?-u){}", translate_glob(glob)))
+            } else {
+                None
+            };
+            match regex {
+                Some(regex) => {
+                    let regex = Regex::new(&regex)?;
+                    replace
+                        .regexes
+                        .push((regex, python_replacement(replacement)));
+                }
+                None => {
+                    let literal = line.strip_prefix(b"literal:").unwrap_or(line);
+                    if !literal.is_empty() {
+                        replace
+                            .literals
+                            .push((literal.to_vec(), replacement.to_vec()));
+                    }
+                }
+            }
+        }
+        Ok(replace)
+    }
+
+    /// Returns whether there are no replacements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.literals.is_empty() && self.regexes.is_empty()
+    }
+
+    /// Applies the replacements to the text. Text is only copied when
+    /// something was replaced.
+    ///
+    // Corresponds to the replacement loops in `git-filter-repo:RepoFilter._tweak_blob`,
+    // `RepoFilter._tweak_commit`, and `RepoFilter._tweak_tag`.
+    pub fn replace<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut text = Cow::Borrowed(text);
+        for (literal, replacement) in &self.literals {
+            if text.find(literal).is_some() {
+                text = Cow::Owned(text.replace(literal, replacement));
+            }
+        }
+        for (regex, replacement) in &self.regexes {
+            if let Cow::Owned(replaced) = regex.replace_all(&text, replacement.as_slice()) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// Applies the replacements to owned text. Returns whether anything was
+    /// replaced.
+    pub fn replace_vec(&self, text: &mut Vec<u8>) -> bool {
+        match self.replace(text) {
+            Cow::Owned(replaced) => {
+                *text = replaced;
+                true
+            }
+            Cow::Borrowed(_) => false,
+        }
+    }
+
+    /// Applies the replacements to text which has been copied out of the
+    /// parser.
+    pub fn replace_bytes(&self, text: &mut Cow<'_, [u8]>) {
+        if let Cow::Owned(replaced) = self.replace(text) {
+            *text = Cow::Owned(replaced);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(expressions: &[u8], text: &[u8]) -> String {
+        let replace = ReplaceText::parse(expressions).unwrap();
+        replace.replace(text).to_str_lossy().into_owned()
+    }
+
+    #[test]
+    fn parse() {
+        let replace = ReplaceText::parse(
+            b"# not a comment\r\n\
+              \n\
+              password\n\
+              literal:regex:x==>y\n\
+              glob:key-*==>\n\
+              regex:(\\w+)@example\\.com==>\\1@example.org\n\
+              regex:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            replace.literals,
+            [
+                (b"# not a comment".to_vec(), DEFAULT_REPLACEMENT.to_vec()),
+                (b"password".to_vec(), DEFAULT_REPLACEMENT.to_vec()),
+                (b"regex:x".to_vec(), b"y".to_vec()),
+                (b"regex:".to_vec(), DEFAULT_REPLACEMENT.to_vec()),
+            ],
+        );
+        let regexes = replace
+            .regexes
+            .iter()
+            .map(|(regex, replacement)| (regex.as_str(), replacement.as_bstr()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            regexes,
+            [
+                ("(?-u)key\\x2d.*", b"".as_bstr()),
+                ("(\\w+)@example\\.com", b"${1}@example.org".as_bstr()),
+            ],
+        );
+    }
+
+    #[test]
+    fn literals_before_regexes() {
+        let expressions = b"regex:[a-z]+==>word\nfoo==>bar\n";
+        assert_eq!(replace(expressions, b"foo baz"), "word word");
+        assert_eq!(replace(b"foo==>bar\nbar==>baz\n", b"foo"), "baz");
+        assert_eq!(
+            replace(b"glob:s?cr*t==>[redacted]", b"a secret\nand a sacred text"),
+            "a [redacted]\nand a [redacted]",
+        );
+        assert_eq!(replace(b"password", b"my password"), "my ***REMOVED***");
+    }
+
+    #[test]
+    fn borrows_when_unchanged() {
+        let replace = ReplaceText::parse(b"foo\nregex:ba+r\n").unwrap();
+        assert!(matches!(replace.replace(b"baz"), Cow::Borrowed(_)));
+        assert!(!replace.is_empty());
+        assert!(ReplaceText::parse(b"\n\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Checks that filtering will not destroy work in the repos it writes to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+
+/// Checks that a repo looks like a fresh clone, before its history is
+/// rewritten in place. A fresh clone is fully packed, has a single remote named
+/// `origin`, has no reflog history, stash, or changes in the working tree, has
+/// all branches matching `origin`, and has a single worktree.
+///
+/// When filtering already ran in the repo, its history is expected to have been
+/// rewritten, so only the checks that protect work which would be lost are
+/// done: that there is no stash, no changes in the working tree, and a single
+/// worktree.
+///
+/// # Differences from filter-repo
+///
+/// On a case-insensitive filesystem, tracked paths that only differ by case
+/// appear modified in a fresh checkout, because they are checked out to the
+/// same file. filter-repo reports such repos as having unstaged changes, but
+/// here, changes to such paths are ignored.
+///
+// Corresponds to `git-filter-repo:RepoFilter.sanity_check`.
+pub fn check_fresh_clone(repo: &Path) -> Result<()> {
+    let git_dir = git_dir(repo)?;
+    let fresh = !already_ran(&git_dir);
+    let abort = |reason: &str| -> Result<()> {
+        bail!(
+            "Aborting: Refusing to destructively overwrite repo history since this does not \
+             look like a fresh clone.\n  ({reason})\nPlease operate on a fresh clone instead. \
+             If you want to proceed anyway, use --force."
+        );
+    };
+
+    if fresh {
+        // Make sure the repo is fully packed, just like a fresh clone would be.
+        let output = git(repo, &["count-objects", "-v"])?;
+        let stats = output
+            .lines()
+            .filter_map(|line| line.split_once_str(b": "))
+            .collect::<HashMap<_, _>>();
+        let stat = |name: &[u8]| -> u64 {
+            stats
+                .get(name)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .unwrap_or(0)
+        };
+        let (num_packs, num_loose_objects) = (stat(b"packs"), stat(b"count"));
+        if num_packs > 1 || (num_packs == 1 && num_loose_objects > 0) {
+            return abort("expected freshly packed repo");
+        }
+
+        // Make sure there is precisely one remote, named "origin", or that this
+        // is a new repo with no packs and no remotes.
+        let remotes = git(repo, &["remote"])?;
+        let remotes = remotes.trim();
+        if !(remotes == b"origin" || (num_packs == 0 && remotes.is_empty())) {
+            return abort("expected one remote, origin");
+        }
+
+        // Make sure that all reflogs have at most one entry.
+        let reflog_dir = git_dir.join("logs");
+        let mut to_visit = vec![reflog_dir.clone()];
+        while let Some(dir) = to_visit.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    to_visit.push(path);
+                } else if fs::read(&path)?.lines().count() > 1 {
+                    let short_path = path.strip_prefix(&reflog_dir).unwrap_or(&path);
+                    return abort(&format!(
+                        "expected at most one entry in the reflog for {}",
+                        short_path.display(),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Make sure there are no stashed changes.
+    let refs = refs(repo)?;
+    if refs.contains_key(&b"refs/stash"[..]) {
+        return abort("has stashed changes");
+    }
+
+    // Avoid uncommitted, unstaged, or untracked changes in non-bare repos.
+    if git(repo, &["rev-parse", "--is-bare-repository"])?.trim() == b"true" {
+        return Ok(());
+    }
+    if !git_succeeds(repo, &["diff", "--staged", "--quiet"])? {
+        return abort("you have uncommitted changes");
+    }
+    let unstaged = git(repo, &["diff", "--name-only", "-z"])?;
+    let mut unstaged = unstaged.split_str(b"\0").filter(|path| !path.is_empty());
+    let has_unstaged = if config_bool(repo, "core.ignorecase")? {
+        let tracked = git(repo, &["ls-files", "-z"])?;
+        let colliding = case_colliding_paths(&tracked);
+        unstaged.any(|path| !colliding.contains(path))
+    } else {
+        unstaged.next().is_some()
+    };
+    if has_unstaged {
+        return abort("you have unstaged changes");
+    }
+    if !git(repo, &["ls-files", "-o"])?.is_empty() {
+        return abort("you have untracked changes");
+    }
+
+    // Avoid unpushed changes. Branches are expected to differ from origin once
+    // they have been rewritten.
+    for (refname, oid) in refs.iter().filter(|_| fresh) {
+        let Some(branch) = refname.strip_prefix(b"refs/heads/") else {
+            continue;
+        };
+        let origin_ref = [&b"refs/remotes/origin/"[..], branch].concat();
+        match refs.get(&origin_ref[..]) {
+            None => {
+                return abort(&format!(
+                    "{} exists, but {} not found",
+                    refname.as_bstr(),
+                    origin_ref.as_bstr(),
+                ));
+            }
+            Some(origin_oid) if origin_oid != oid => {
+                return abort(&format!(
+                    "{} does not match {}",
+                    refname.as_bstr(),
+                    origin_ref.as_bstr(),
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Make sure there is only one worktree.
+    if git(repo, &["worktree", "list"])?.lines().count() > 1 {
+        return abort("you have multiple worktrees");
+    }
+    Ok(())
+}
+
+/// Prepares a separate repo to write filtered history to. It is created, if it
+/// does not exist. Unless `force` or filtering already wrote to it, it must not
+/// have any refs, so that filtering does not overwrite history in it.
+pub fn prepare_target(repo: &Path, force: bool) -> Result<()> {
+    let Some(git_dir) = repo_git_dir(repo) else {
+        fs::create_dir_all(repo)
+            .with_context(|| format!("could not create target repo {}", repo.display()))?;
+        git(repo, &["init", "--quiet"])?;
+        return Ok(());
+    };
+    if !force && !already_ran(&git_dir) && !refs(repo)?.is_empty() {
+        bail!(
+            "Aborting: Refusing to overwrite the history in the target repo {}, since it is \
+             not empty.\nPlease write to a new repo instead. If you want to proceed anyway, \
+             use --force.",
+            repo.display(),
+        );
+    }
+    Ok(())
+}
+
+/// Returns whether two paths refer to the same repo.
+pub fn is_same_repo(a: &Path, b: &Path) -> bool {
+    match (git_dir(a), git_dir(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Returns whether filtering has already written to the repo with this git
+/// directory, in which case it is expected to have been rewritten.
+pub fn already_ran(git_dir: &Path) -> bool {
+    git_dir.join("filter-repo/already_ran").is_file()
+}
+
+/// Returns the git directory of a repo, when `repo` is the top level of a
+/// repo. Unlike `git_dir`, a directory within another repo is not a repo.
+pub fn repo_git_dir(repo: &Path) -> Option<PathBuf> {
+    let git_dir = git_dir(repo).ok()?;
+    let repo = repo.canonicalize().ok()?;
+    (git_dir == repo || git_dir == repo.join(".git")).then_some(git_dir)
+}
+
+/// Returns the absolute path of the git directory of a repo.
+pub fn git_dir(repo: &Path) -> Result<PathBuf> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["rev-parse", "--absolute-git-dir"])
+        .output()
+        .context("could not run git rev-parse")?;
+    if !output.status.success() {
+        bail!("{} is not a git repo", repo.display());
+    }
+    let dir = output.stdout.trim_end_with(|c| c == '\n').to_path()?;
+    Ok(dir.canonicalize().unwrap_or_else(|_| dir.to_owned()))
+}
+
+/// Returns the refs in a repo, mapped to the IDs they point to.
+pub fn refs(repo: &Path) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let output = git(repo, &["for-each-ref", "--format=%(objectname) %(refname)"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once_str(b" "))
+        .map(|(oid, refname)| (refname.to_vec(), oid.to_vec()))
+        .collect())
+}
+
+/// Returns the paths from NUL-separated `paths`, which only differ by case from
+/// another path.
+fn case_colliding_paths(paths: &[u8]) -> HashSet<&[u8]> {
+    let mut by_lowercase: HashMap<Vec<u8>, Vec<&[u8]>> = HashMap::new();
+    for path in paths.split_str(b"\0").filter(|path| !path.is_empty()) {
+        by_lowercase
+            .entry(path.to_lowercase())
+            .or_default()
+            .push(path);
+    }
+    by_lowercase
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .flatten()
+        .collect()
+}
+
+/// Runs a git command in a repo and returns its stdout, failing if it does not
+/// succeed.
+pub(crate) fn git(repo: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .with_context(|| format!("could not run git {}", args[0]))?;
+    if !output.status.success() {
+        bail!("git {} failed: {}", args.join(" "), output.status);
+    }
+    Ok(output.stdout)
+}
+
+/// Returns the value of a boolean config variable, which defaults to false.
+fn config_bool(repo: &Path, name: &str) -> Result<bool> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["config", "--type=bool", "--get", name])
+        .output()
+        .context("could not run git config")?;
+    Ok(output.status.success() && output.stdout.trim() == b"true")
+}
+
+/// Runs a git command in a repo and returns whether it succeeded.
+fn git_succeeds(repo: &Path, args: &[&str]) -> Result<bool> {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .status()
+        .with_context(|| format!("could not run git {}", args[0]))?;
+    Ok(status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::git;
+
+    #[test]
+    fn fresh_clone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, clone) = (dir.path().join("src"), dir.path().join("clone"));
+        fs::create_dir(&src).unwrap();
+        git(&src, &["init", "-q", "-b", "main"]);
+        fs::write(src.join("a.txt"), "a\n").unwrap();
+        git(&src, &["add", "."]);
+        git(&src, &["commit", "-q", "-m", "Add a"]);
+        git(dir.path(), &["clone", "-q", "--no-local", "src", "clone"]);
+
+        check_fresh_clone(&clone).unwrap();
+        let err = check_fresh_clone(&src).unwrap_err().to_string();
+        assert!(
+            err.contains("(refs/heads/main exists, but refs/remotes/origin/main not found)"),
+            "{err}",
+        );
+
+        fs::write(clone.join("a.txt"), "b\n").unwrap();
+        let err = check_fresh_clone(&clone).unwrap_err().to_string();
+        assert!(err.contains("(you have unstaged changes)"), "{err}");
+        fs::write(clone.join("a.txt"), "a\n").unwrap();
+        fs::write(clone.join("b.txt"), "b\n").unwrap();
+        let err = check_fresh_clone(&clone).unwrap_err().to_string();
+        assert!(err.contains("(you have untracked changes)"), "{err}");
+        fs::remove_file(clone.join("b.txt")).unwrap();
+
+        // After a previous run, rewritten history is expected, but changes in
+        // the working tree must still not be lost.
+        let clone_git_dir = git_dir(&clone).unwrap();
+        fs::create_dir(clone_git_dir.join("filter-repo")).unwrap();
+        fs::write(clone_git_dir.join("filter-repo/already_ran"), "").unwrap();
+        git(
+            &clone,
+            &["commit", "-q", "--allow-empty", "-m", "Rewritten"],
+        );
+        check_fresh_clone(&clone).unwrap();
+        fs::write(clone.join("a.txt"), "b\n").unwrap();
+        let err = check_fresh_clone(&clone).unwrap_err().to_string();
+        assert!(err.contains("(you have unstaged changes)"), "{err}");
+        git(&clone, &["stash", "-q"]);
+        let err = check_fresh_clone(&clone).unwrap_err().to_string();
+        assert!(err.contains("(has stashed changes)"), "{err}");
+    }
+
+    #[test]
+    fn target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("new");
+        prepare_target(&target, false).unwrap();
+        assert!(is_same_repo(&target, &target.join(".")));
+        assert!(!is_same_repo(&target, dir.path()));
+
+        fs::write(target.join("a.txt"), "a\n").unwrap();
+        git(&target, &["add", "."]);
+        git(&target, &["commit", "-q", "-m", "Add a"]);
+        let err = prepare_target(&target, false).unwrap_err().to_string();
+        assert!(err.contains("since it is not empty"), "{err}");
+        prepare_target(&target, true).unwrap();
+    }
+
+    #[test]
+    fn case_collisions() {
+        let paths = b"README\0src/a.rs\0readme\0Src/a.rs\0src/b.rs\0";
+        let mut colliding = case_colliding_paths(paths).into_iter().collect::<Vec<_>>();
+        colliding.sort();
+        assert_eq!(
+            colliding,
+            [&b"README"[..], b"Src/a.rs", b"readme", b"src/a.rs"],
+        );
+    }
+}
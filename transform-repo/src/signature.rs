@@ -0,0 +1,201 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Handling of commit and tag signatures that are invalidated by a rewrite.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use bstr::ByteSlice;
+use clap::ValueEnum;
+use fast_export::command::{Commit, Tag};
+
+/// What to do with the signature of a commit or tag, when rewriting it has
+/// invalidated the signature.
+///
+// Corresponds to `--signed-commits` and `--signed-tags` in
+// `git.git/builtin/fast-export.c`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum SignaturePolicy {
+    /// Keep the invalid signature as is.
+    #[value(alias = "verbatim")]
+    Keep,
+    /// Silently drop the signature.
+    #[default]
+    Strip,
+    /// Drop the signature and report it.
+    #[value(alias = "warn-strip")]
+    Warn,
+}
+
+/// A signature that was dropped, because a rewrite invalidated it. It is
+/// reported under [`SignaturePolicy::Warn`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidatedSignature {
+    pub object: SignedObject,
+    /// The original object ID, when the stream has `original-oid` directives.
+    pub original_oid: Option<String>,
+}
+
+/// The kind of object a signature was on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SignedObject {
+    Commit,
+    Tag,
+}
+
+impl SignaturePolicy {
+    /// Applies the policy to a commit. Signatures of commits which were not
+    /// rewritten are still valid and are always kept.
+    pub fn apply_commit<B: AsRef<[u8]>>(
+        self,
+        commit: &mut Commit<B>,
+        rewritten: bool,
+    ) -> Option<InvalidatedSignature> {
+        if !rewritten || commit.signatures.is_empty() || self == SignaturePolicy::Keep {
+            return None;
+        }
+        commit.signatures.clear();
+        self.report(
+            SignedObject::Commit,
+            commit.original_oid.as_ref().map(|oid| &oid.oid),
+        )
+    }
+
+    /// Applies the policy to a tag. Signatures of tags which were not
+    /// rewritten are still valid and are always kept.
+    pub fn apply_tag<B: AsRef<[u8]>>(
+        self,
+        tag: &mut Tag<B>,
+        rewritten: bool,
+    ) -> Option<InvalidatedSignature> {
+        if !rewritten || tag.signature.is_none() || self == SignaturePolicy::Keep {
+            return None;
+        }
+        tag.signature = None;
+        self.report(
+            SignedObject::Tag,
+            tag.original_oid.as_ref().map(|oid| &oid.oid),
+        )
+    }
+
+    fn report<B: AsRef<[u8]>>(
+        self,
+        object: SignedObject,
+        original_oid: Option<&B>,
+    ) -> Option<InvalidatedSignature> {
+        (self == SignaturePolicy::Warn).then(|| InvalidatedSignature {
+            object,
+            original_oid: original_oid.map(|oid| oid.as_ref().to_str_lossy().into_owned()),
+        })
+    }
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" | "verbatim" => Ok(SignaturePolicy::Keep),
+            "strip" => Ok(SignaturePolicy::Strip),
+            "warn" | "warn-strip" => Ok(SignaturePolicy::Warn),
+            _ => Err(format!("unknown signature policy: {s}")),
+        }
+    }
+}
+
+impl Display for InvalidatedSignature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let object = match self.object {
+            SignedObject::Commit => "commit",
+            SignedObject::Tag => "tag",
+        };
+        match &self.original_oid {
+            Some(oid) => write!(f, "stripped invalidated signature from {object} {oid}"),
+            None => write!(f, "stripped invalidated signature from {object}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_export::command::{
+        Branch, CommitSignature, HashAlgorithm, Objectish, OriginalOid, PersonIdent, TagName,
+    };
+
+    use super::*;
+
+    fn ident() -> PersonIdent<&'static [u8]> {
+        PersonIdent {
+            name: b"Name",
+            email: b"user@example.com",
+            date: b"1700000000 +0000",
+        }
+    }
+
+    fn commit() -> Commit<&'static [u8]> {
+        Commit {
+            branch: Branch {
+                branch: b"refs/heads/main",
+            },
+            mark: None,
+            original_oid: Some(OriginalOid { oid: b"0123abcd" }),
+            author: None,
+            committer: ident(),
+            signatures: vec![CommitSignature {
+                hash_algo: HashAlgorithm::Sha1,
+                format: None,
+                signature: b"sig",
+            }],
+            encoding: None,
+            message: b"msg\n",
+            from: None,
+            merge: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn commit_policies() {
+        let mut c = commit();
+        assert_eq!(SignaturePolicy::Warn.apply_commit(&mut c, false), None);
+        assert_eq!(c, commit());
+        assert_eq!(SignaturePolicy::Keep.apply_commit(&mut c, true), None);
+        assert_eq!(c, commit());
+        assert_eq!(SignaturePolicy::Strip.apply_commit(&mut c, true), None);
+        assert!(c.signatures.is_empty());
+
+        let mut c = commit();
+        let warning = SignaturePolicy::Warn.apply_commit(&mut c, true).unwrap();
+        assert!(c.signatures.is_empty());
+        assert_eq!(
+            warning.to_string(),
+            "stripped invalidated signature from commit 0123abcd",
+        );
+    }
+
+    #[test]
+    fn tag_policies() {
+        let tag = Tag {
+            name: TagName { name: &b"v1.0"[..] },
+            mark: None,
+            from: Objectish::BranchOrOid(&b"refs/heads/main"[..]),
+            original_oid: None,
+            tagger: Some(ident()),
+            message: b"Release\n",
+            signature: Some(b"-----BEGIN SSH SIGNATURE-----\n"),
+        };
+        let mut t = tag.clone();
+        assert_eq!(SignaturePolicy::Keep.apply_tag(&mut t, true), None);
+        assert_eq!(t, tag);
+        let warning = SignaturePolicy::Warn.apply_tag(&mut t, true).unwrap();
+        assert_eq!(t.signature, None);
+        assert_eq!(
+            warning.to_string(),
+            "stripped invalidated signature from tag"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! The sources of fast-export streams and the destinations of fast-import
+//! streams.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{options::FilteringOptions, reencode::Reencode};
+
+/// Where the fast-export stream is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Run `git fast-export` in the repo at this path.
+    Repo(PathBuf),
+    /// Read a fast-export stream from the file at this path.
+    Stream(PathBuf),
+    /// Read a fast-export stream from stdin.
+    Stdin,
+}
+
+/// Where the filtered fast-import stream is written to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Run `git fast-import` in the repo at this path.
+    Repo(PathBuf),
+    /// Write the fast-import stream to the file at this path.
+    Stream(PathBuf),
+    /// Write the fast-import stream to stdout.
+    Stdout,
+}
+
+impl Input {
+    /// Opens the input for reading. When it is a repo, this spawns
+    /// `git fast-export`, which must be waited on after reading to the end.
+    /// With `no_data`, it exports no blobs and refers to them by ID.
+    pub(crate) fn open(
+        &self,
+        args: &FilteringOptions,
+        no_data: bool,
+    ) -> Result<(Box<dyn BufRead>, Option<Child>)> {
+        match self {
+            Input::Repo(repo) => {
+                // Corresponds to `fep_cmd` in
+                // `git-filter-repo:RepoFilter._setup_input`.
+                let mut child = Command::new("git")
+                    .arg("-C")
+                    .arg(repo)
+                    .args([
+                        "-c",
+                        "core.quotepath=false",
+                        "fast-export",
+                        "--show-original-ids",
+                        "--tag-of-filtered-object=rewrite",
+                        "--fake-missing-tagger",
+                        "--reference-excluded-parents",
+                        "--use-done-feature",
+                        "--mark-tags",
+                    ])
+                    .args(args.date_order.then_some("--date-order"))
+                    .args(no_data.then_some("--no-data"))
+                    // Signatures are always exported, so that those of objects
+                    // which filtering does not change stay valid. The
+                    // signature policy only applies to rewritten objects.
+                    .arg("--signed-tags=verbatim")
+                    .args(exports_signed_commits().then_some("--signed-commits=verbatim"))
+                    // Commits are only reencoded by fast-export for `yes`, so
+                    // that `abort` reports which commit failed.
+                    .arg(if args.reencode == Reencode::Yes {
+                        "--reencode=yes"
+                    } else {
+                        "--reencode=no"
+                    })
+                    .args(&args.refs)
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("could not run git fast-export")?;
+                let stdout = child.stdout.take().unwrap();
+                Ok((Box::new(BufReader::new(stdout)), Some(child)))
+            }
+            Input::Stream(path) => {
+                let f = File::open(path)
+                    .with_context(|| format!("could not open {}", path.display()))?;
+                Ok((Box::new(BufReader::new(f)), None))
+            }
+            Input::Stdin => Ok((Box::new(io::stdin().lock()), None)),
+        }
+    }
+}
+
+/// Returns whether `git fast-export` accepts `--signed-commits`, which was added
+/// in git 2.50. Older git rejects it and drops commit signatures.
+fn exports_signed_commits() -> bool {
+    let Ok(output) = Command::new("git").arg("version").output() else {
+        return false;
+    };
+    // The version may have a suffix, like `git version 2.50.0.windows.1`.
+    let version = String::from_utf8_lossy(&output.stdout);
+    let mut parts = version
+        .trim()
+        .trim_start_matches("git version ")
+        .split('.')
+        .map(str::parse::<u32>);
+    matches!(
+        (parts.next(), parts.next()),
+        (Some(Ok(major)), Some(Ok(minor))) if (major, minor) >= (2, 50),
+    )
+}
+
+impl Output {
+    /// Opens the output for writing. When it is a repo, this spawns
+    /// `git fast-import`, which must be waited on after closing the writer.
+    /// Its stdout is piped for responses to `get-mark` commands.
+    pub(crate) fn open(&self) -> Result<(Box<dyn Write>, Option<Child>)> {
+        match self {
+            Output::Repo(repo) => {
+                // Corresponds to `fip_cmd` in
+                // `git-filter-repo:RepoFilter._setup_output`.
+                let mut child = Command::new("git")
+                    .arg("-C")
+                    .arg(repo)
+                    .args([
+                        "-c",
+                        "core.ignorecase=false",
+                        "fast-import",
+                        "--date-format=raw-permissive",
+                        "--force",
+                        "--quiet",
+                    ])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("could not run git fast-import")?;
+                let stdin = child.stdin.take().unwrap();
+                Ok((Box::new(BufWriter::new(stdin)), Some(child)))
+            }
+            Output::Stream(path) => {
+                let f = File::create(path)
+                    .with_context(|| format!("could not create {}", path.display()))?;
+                Ok((Box::new(BufWriter::new(f)), None))
+            }
+            Output::Stdout => Ok((Box::new(BufWriter::new(io::stdout().lock())), None)),
+        }
+    }
+}
+
+/// Waits for a spawned git process to exit and checks that it succeeded.
+pub(crate) fn wait(child: Option<Child>, name: &str) -> Result<()> {
+    if let Some(mut child) = child {
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("git {name} failed: {status}");
+        }
+    }
+    Ok(())
+}
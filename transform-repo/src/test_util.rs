@@ -0,0 +1,37 @@
+// Copyright (C) Thalia Archibald. All rights reserved.
+//
+// This file is part of git-transform-repo, distributed under the GPL 2.0 with a
+// linking exception. For the full terms, see the included COPYING file.
+
+//! Helpers for tests, which run Git in temporary repos.
+
+use std::{path::Path, process::Command};
+
+use tempfile::TempDir;
+
+/// Creates an empty repo in a temporary directory, with `main` as its initial
+/// branch. The identity is also configured, for scripts which read it.
+pub(crate) fn init_repo() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    git(dir.path(), &["init", "-q", "-b", "main"]);
+    git(dir.path(), &["config", "user.name", "A U Thor"]);
+    git(dir.path(), &["config", "user.email", "author@example.com"]);
+    dir
+}
+
+/// Runs Git in `dir` with a fixed identity, asserts that it succeeded, and
+/// returns its output.
+pub(crate) fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_COMMITTER_NAME", "C O Mitter")
+        .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?} failed: {output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}